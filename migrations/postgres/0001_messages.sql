@@ -0,0 +1,11 @@
+-- Known messages, with `chat_id` 0 for the shared namespace.
+CREATE TABLE messages (
+    chat_id BIGINT NOT NULL,
+    hash BYTEA NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('seen', 'allowed', 'forbidden')),
+    first_seen BIGINT NOT NULL,
+    count BIGINT NOT NULL,
+    first_message_id INTEGER,
+    poster_id BIGINT,
+    PRIMARY KEY (chat_id, hash)
+);
@@ -0,0 +1,7 @@
+-- Bots sharing the database keep their messages apart, with `bot` empty for the main one.
+ALTER TABLE messages ADD COLUMN bot TEXT NOT NULL DEFAULT '';
+ALTER TABLE messages ALTER COLUMN bot DROP DEFAULT;
+ALTER TABLE messages DROP CONSTRAINT messages_pkey, ADD PRIMARY KEY (bot, chat_id, hash);
+
+DROP INDEX messages_first_seen;
+CREATE INDEX messages_first_seen ON messages (bot, status, first_seen);
@@ -0,0 +1,2 @@
+-- Copies an allowed message may still be posted, unlimited when null.
+ALTER TABLE messages ADD COLUMN repeats_left BIGINT;
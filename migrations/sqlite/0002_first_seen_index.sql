@@ -0,0 +1,2 @@
+-- Finds the oldest seen messages to evict without scanning the whole table.
+CREATE INDEX messages_first_seen ON messages (status, first_seen);
@@ -0,0 +1,23 @@
+-- Bots sharing the database keep their messages apart, with `bot` empty for the main one.
+-- SQLite can't change the primary key in place, so the table is copied.
+ALTER TABLE messages RENAME TO messages_without_bots;
+
+CREATE TABLE messages (
+    bot TEXT NOT NULL,
+    chat_id BIGINT NOT NULL,
+    hash BLOB NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('seen', 'allowed', 'forbidden')),
+    first_seen BIGINT NOT NULL,
+    count BIGINT NOT NULL,
+    first_message_id INTEGER,
+    poster_id BIGINT,
+    PRIMARY KEY (bot, chat_id, hash)
+);
+
+INSERT INTO messages (bot, chat_id, hash, status, first_seen, count, first_message_id, poster_id)
+SELECT '', chat_id, hash, status, first_seen, count, first_message_id, poster_id
+FROM messages_without_bots;
+
+DROP TABLE messages_without_bots;
+
+CREATE INDEX messages_first_seen ON messages (bot, status, first_seen);
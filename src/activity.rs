@@ -0,0 +1,397 @@
+//! Per-chat daily activity counters, so statistics have history
+//! instead of only totals since forever.
+
+use std::{collections::HashMap, fmt::Write as _, ops::Range};
+
+use chrono::DateTime;
+use color_eyre::eyre;
+use serde::Serialize;
+use teloxide::types::{ChatId, UserId};
+
+use crate::{
+    robot::Robot9000,
+    storage::{Entry, Status},
+};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How many days of counters are kept for each chat.
+pub(crate) const RETENTION_DAYS: i64 = 90;
+
+const ACTIVITY_SIZE: usize = 24;
+
+/// How many users `/stats_export` and the admin API list.
+const TOP_OFFENDERS: usize = 10;
+
+/// Recent statistics of a chat, as the admin API returns them.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatStats {
+    /// Oldest first, including days nothing happened.
+    days: Vec<DayStats>,
+    top_offenders: Vec<Offender>,
+}
+
+#[derive(Debug, Serialize)]
+struct DayStats {
+    date: String,
+    #[serde(flatten)]
+    activity: Activity,
+}
+
+#[derive(Debug, Serialize)]
+struct Offender {
+    user_id: UserId,
+    deleted: u64,
+}
+
+/// What happened in a chat during one (UTC) day.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Activity {
+    /// Messages checked for duplicates.
+    pub seen: u64,
+    /// Messages that weren't known yet and got stored.
+    pub unique: u64,
+    /// Duplicates deleted.
+    pub deleted: u64,
+}
+
+impl Activity {
+    fn decode(value: &[u8]) -> eyre::Result<Self> {
+        let value: &[u8; ACTIVITY_SIZE] = value
+            .try_into()
+            .map_err(|_| eyre::eyre!("malformed activity counters: {value:?}"))?;
+        let counter = |range: Range<usize>| u64::from_be_bytes(value[range].try_into().unwrap());
+        Ok(Self {
+            seen: counter(0..8),
+            unique: counter(8..16),
+            deleted: counter(16..24),
+        })
+    }
+
+    fn encode(&self) -> [u8; ACTIVITY_SIZE] {
+        let mut value = [0; ACTIVITY_SIZE];
+        value[0..8].copy_from_slice(&self.seen.to_be_bytes());
+        value[8..16].copy_from_slice(&self.unique.to_be_bytes());
+        value[16..24].copy_from_slice(&self.deleted.to_be_bytes());
+        value
+    }
+}
+
+impl std::ops::AddAssign for Activity {
+    fn add_assign(&mut self, other: Self) {
+        self.seen += other.seen;
+        self.unique += other.unique;
+        self.deleted += other.deleted;
+    }
+}
+
+/// Days since the unix epoch, in UTC.
+pub fn day_of(timestamp: i64) -> i64 {
+    timestamp.div_euclid(SECONDS_PER_DAY)
+}
+
+fn activity_key(chat_id: ChatId, day: i64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+    key[8..].copy_from_slice(&day.to_be_bytes());
+    key
+}
+
+/// Counters of every chat from `since` (in days since the epoch) on,
+/// by chat and then by day, oldest first.
+pub fn activity_since(
+    tree: &sled::Tree,
+    since: i64,
+) -> impl Iterator<Item = eyre::Result<(ChatId, i64, Activity)>> {
+    tree.iter()
+        .map(|item| {
+            let (key, value) = item?;
+            let chat_id = ChatId(i64::from_be_bytes(key[..8].try_into()?));
+            let day = i64::from_be_bytes(key[8..].try_into()?);
+            Ok((chat_id, day, Activity::decode(&value)?))
+        })
+        .filter(move |item| !matches!(item, Ok((_, day, _)) if *day < since))
+}
+
+/// Counters of a chat from `since` (in days since the epoch) on, oldest first.
+pub(crate) fn chat_activity_since(
+    tree: &sled::Tree,
+    chat_id: ChatId,
+    since: i64,
+) -> eyre::Result<Vec<(i64, Activity)>> {
+    tree.range(activity_key(chat_id, since)..=activity_key(chat_id, i64::MAX))
+        .map(|item| {
+            let (key, value) = item?;
+            Ok((
+                i64::from_be_bytes(key[8..].try_into()?),
+                Activity::decode(&value)?,
+            ))
+        })
+        .collect()
+}
+
+fn offence_key(chat_id: ChatId, day: i64, user_id: UserId) -> [u8; 24] {
+    let mut key = [0; 24];
+    key[..16].copy_from_slice(&activity_key(chat_id, day));
+    key[16..].copy_from_slice(&user_id.0.to_be_bytes());
+    key
+}
+
+/// Counts a duplicate deleted from `user_id` on `day`, kept as long as the activity counters.
+fn record_offence(
+    tree: &sled::Tree,
+    chat_id: ChatId,
+    day: i64,
+    user_id: UserId,
+) -> eyre::Result<()> {
+    let count = tree.update_and_fetch(offence_key(chat_id, day, user_id), |value| {
+        let count = value
+            .and_then(|value| value.try_into().ok())
+            .map_or(0, u64::from_be_bytes);
+        Some((count + 1).to_be_bytes().to_vec())
+    })?;
+    if count.is_some_and(|count| count[..] == 1_u64.to_be_bytes()) {
+        let expired = offence_key(chat_id, 0, UserId(0))
+            ..offence_key(chat_id, day - RETENTION_DAYS, UserId(0));
+        for key in tree.range(expired).keys() {
+            tree.remove(key?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Users with the most duplicates deleted in a chat from `since` (in days
+/// since the epoch) on, most first.
+pub(crate) fn top_offenders(
+    tree: &sled::Tree,
+    chat_id: ChatId,
+    since: i64,
+    limit: usize,
+) -> eyre::Result<Vec<(UserId, u64)>> {
+    let mut counts = HashMap::<UserId, u64>::new();
+    let range = offence_key(chat_id, since, UserId(0))..=offence_key(chat_id, i64::MAX, UserId(0));
+    for item in tree.range(range) {
+        let (key, value) = item?;
+        let user_id = UserId(u64::from_be_bytes(key[16..].try_into()?));
+        *counts.entry(user_id).or_default() += u64::from_be_bytes(value[..].try_into()?);
+    }
+    let mut counts = Vec::from_iter(counts);
+    counts.sort_by_key(|&(user_id, count)| (std::cmp::Reverse(count), user_id.0));
+    counts.truncate(limit);
+    Ok(counts)
+}
+
+/// Counters of every day from `since` to `until` (in days since the epoch),
+/// with zeros for days nothing was recorded.
+fn every_day(since: i64, until: i64, days: &[(i64, Activity)]) -> Vec<DayStats> {
+    let mut days = days.iter().peekable();
+    (since..=until)
+        .map(|day| {
+            let activity = days
+                .next_if(|&&(recorded, _)| recorded == day)
+                .map_or_else(Activity::default, |&(_, activity)| activity);
+            let date = DateTime::from_timestamp(day * SECONDS_PER_DAY, 0)
+                .map(|date| date.date_naive().to_string())
+                .unwrap_or_default();
+            DayStats { date, activity }
+        })
+        .collect()
+}
+
+/// Writes daily counters from `since` to `until` (in days since the epoch),
+/// then the top offenders, as CSV for spreadsheets.
+fn stats_csv(
+    since: i64,
+    until: i64,
+    days: &[(i64, Activity)],
+    offenders: &[(UserId, u64)],
+) -> String {
+    let mut csv = String::from("date,seen,unique,deleted\n");
+    for DayStats { date, activity } in every_day(since, until, days) {
+        let _ = writeln!(
+            csv,
+            "{date},{},{},{}",
+            activity.seen, activity.unique, activity.deleted
+        );
+    }
+    csv.push_str("\nuser_id,deleted\n");
+    for (user_id, deleted) in offenders {
+        let _ = writeln!(csv, "{user_id},{deleted}");
+    }
+    csv
+}
+
+/// Adds `delta` to the counters of `day`, dropping days too old to keep when a new one starts.
+fn record(tree: &sled::Tree, chat_id: ChatId, day: i64, delta: Activity) -> eyre::Result<()> {
+    let mut started = false;
+    tree.fetch_and_update(activity_key(chat_id, day), |value| {
+        let mut activity = match value {
+            Some(value) => Activity::decode(value).unwrap_or_else(|err| {
+                tracing::warn!(err = format_args!("{err}"), "resetting activity counters");
+                Activity::default()
+            }),
+            None => Activity::default(),
+        };
+        started = value.is_none();
+        activity += delta;
+        Some(activity.encode().to_vec())
+    })?;
+    if started {
+        let expired = activity_key(chat_id, 0)..activity_key(chat_id, day - RETENTION_DAYS);
+        for key in tree.range(expired).keys() {
+            tree.remove(key?)?;
+        }
+    }
+    Ok(())
+}
+
+impl Robot9000 {
+    /// Counts a message checked for duplicates, given its stored entry.
+    pub(crate) async fn count_seen(
+        &self,
+        chat_id: ChatId,
+        timestamp: i64,
+        entry: &Entry,
+    ) -> eyre::Result<()> {
+        let unique = entry.status == Status::Seen && entry.count == 1;
+        let delta = Activity {
+            seen: 1,
+            unique: u64::from(unique),
+            deleted: 0,
+        };
+        self.record_activity(chat_id, timestamp, delta).await
+    }
+
+    /// Counts a duplicate deleted, and who posted it unless it's a channel post.
+    pub(crate) async fn count_deleted(
+        &self,
+        chat_id: ChatId,
+        timestamp: i64,
+        user_id: Option<UserId>,
+    ) -> eyre::Result<()> {
+        let delta = Activity {
+            deleted: 1,
+            ..Activity::default()
+        };
+        self.record_activity(chat_id, timestamp, delta).await?;
+        let Some(user_id) = user_id else {
+            return Ok(());
+        };
+        let day = self.settings(chat_id)?.local_day(timestamp);
+        let tree = self.offenders.clone();
+        tokio::task::spawn_blocking(move || record_offence(&tree, chat_id, day, user_id)).await?
+    }
+
+    /// Daily counters of a chat over the days they're kept, and its top offenders, as CSV.
+    pub(crate) fn stats_csv(&self, chat_id: ChatId, now: i64) -> eyre::Result<String> {
+        let until = self.settings(chat_id)?.local_day(now);
+        let days = chat_activity_since(&self.activity, chat_id, until - RETENTION_DAYS)?;
+        let since = days.first().map_or(until, |&(day, _)| day);
+        let offenders = top_offenders(&self.offenders, chat_id, since, TOP_OFFENDERS)?;
+        Ok(stats_csv(since, until, &days, &offenders))
+    }
+
+    /// Counters of a chat over its last `days` days (at most as many as are kept),
+    /// and its top offenders over them.
+    pub(crate) fn chat_stats(
+        &self,
+        chat_id: ChatId,
+        now: i64,
+        days: i64,
+    ) -> eyre::Result<ChatStats> {
+        let until = self.settings(chat_id)?.local_day(now);
+        let since = until - days.clamp(1, RETENTION_DAYS) + 1;
+        let recorded = chat_activity_since(&self.activity, chat_id, since)?;
+        let offenders = top_offenders(&self.offenders, chat_id, since, TOP_OFFENDERS)?;
+        Ok(ChatStats {
+            days: every_day(since, until, &recorded),
+            top_offenders: offenders
+                .into_iter()
+                .map(|(user_id, deleted)| Offender { user_id, deleted })
+                .collect(),
+        })
+    }
+
+    /// Records off the async executor, since it's a disk write for every message.
+    /// Days start at midnight in the chat's time zone.
+    async fn record_activity(
+        &self,
+        chat_id: ChatId,
+        timestamp: i64,
+        delta: Activity,
+    ) -> eyre::Result<()> {
+        let day = self.settings(chat_id)?.local_day(timestamp);
+        let tree = self.activity.clone();
+        tokio::task::spawn_blocking(move || record(&tree, chat_id, day, delta)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_expires_days() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let tree = db.open_tree("activity")?;
+        let chat_id = ChatId(-100);
+        let day = 20_000;
+        let seen = Activity {
+            seen: 1,
+            ..Activity::default()
+        };
+
+        record(&tree, chat_id, day, seen)?;
+        record(&tree, chat_id, day, seen)?;
+        record(&tree, ChatId(-200), day, seen)?;
+        let twice = Activity {
+            seen: 2,
+            ..Activity::default()
+        };
+        let history = activity_since(&tree, 0).collect::<eyre::Result<Vec<_>>>()?;
+        assert_eq!(history, [(ChatId(-200), day, seen), (chat_id, day, twice)]);
+
+        let later = day + RETENTION_DAYS + 1;
+        record(&tree, chat_id, later, seen)?;
+        let history = activity_since(&tree, 0).collect::<eyre::Result<Vec<_>>>()?;
+        assert_eq!(history, [(ChatId(-200), day, seen), (chat_id, later, seen)]);
+        assert_eq!(activity_since(&tree, later).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_stats_csv() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let offenders = db.open_tree("offenders")?;
+        let chat_id = ChatId(-100);
+        let day = 20_000;
+        for (user_id, times) in [(UserId(2), 1), (UserId(3), 2)] {
+            for _ in 0..times {
+                record_offence(&offenders, chat_id, day, user_id)?;
+            }
+        }
+        record_offence(&offenders, chat_id, day - 1, UserId(2))?;
+        record_offence(&offenders, ChatId(-200), day, UserId(4))?;
+        let top = top_offenders(&offenders, chat_id, day, 10)?;
+        assert_eq!(top, [(UserId(3), 2), (UserId(2), 1)]);
+
+        let deleted = Activity {
+            seen: 3,
+            unique: 1,
+            deleted: 2,
+        };
+        let csv = stats_csv(day - 1, day + 1, &[(day, deleted)], &top);
+        assert_eq!(
+            csv,
+            "date,seen,unique,deleted\n\
+             2024-10-03,0,0,0\n\
+             2024-10-04,3,1,2\n\
+             2024-10-05,0,0,0\n\
+             \n\
+             user_id,deleted\n\
+             3,2\n\
+             2,1\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+//! Local HTTP API doing what admin commands do, for operators' scripts
+//! and moderation dashboards. Every request needs an
+//! `Authorization: Bearer <admin_api_token>` header.
+//!
+//! - `GET /chats/<chat_id>/entries/<hash>`: what's known about a hash.
+//! - `POST /chats/<chat_id>/check` with `{"text": "..."}`: the hash of a text,
+//!   and what's known about it.
+//! - `POST /chats/<chat_id>/entries` with `{"text": "..."}`: remembers a text
+//!   as if it was posted now.
+//! - `POST /chats/<chat_id>/entries/<hash>/allow`, with `{"repeats": 5}`
+//!   to only allow a number of copies, and `.../forbid`.
+//! - `GET /chats/<chat_id>/stats?days=7`: daily counters and top offenders, for up to 90 days.
+//! - `GET /chats/<chat_id>/deletions?limit=50`: latest deletions from the audit log.
+//! - `GET /chats/<chat_id>/settings`, and `PUT /chats/<chat_id>/settings/<name>`
+//!   with `{"value": "..."}`, or `{"value": null}` to reset it, like `/set`.
+//! - `GET /chats`: chats the bot is in.
+//! - `GET /database`: size of the database and how many messages it knows.
+//! - `POST /backup`: makes a backup like `backup_interval` does.
+//!
+//! Hashes are 32 hex digits, like in the audit log and exports.
+//! `GET /` is a dashboard using the API, which asks for the token itself.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use color_eyre::eyre;
+use serde::{
+    de::{self, value::BorrowedStrDeserializer, DeserializeOwned},
+    Deserialize, Serialize,
+};
+use serde_json::json;
+use teloxide::types::{ChatId, UserId};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    activity::RETENTION_DAYS,
+    api::TelegramApi,
+    audit::{hex_hash, AuditEvent},
+    http::{self, Request},
+    policy::Setting,
+    robot::{hex, Robot9000},
+    storage::{unix_now, Entry, Key, Post, Status},
+};
+
+/// Admin recorded in the audit log for changes made through the API.
+const API_ADMIN: UserId = UserId(0);
+
+/// Days the stats cover without a `days` parameter.
+const DEFAULT_STATS_DAYS: i64 = 7;
+
+/// Deletions listed without a `limit` parameter.
+const DEFAULT_DELETIONS: usize = 50;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// An entry as the API returns it.
+#[derive(Debug, Serialize)]
+struct ApiEntry {
+    status: Status,
+    first_seen: i64,
+    count: u32,
+    first_message_id: Option<i32>,
+    poster_id: Option<UserId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeats_left: Option<u32>,
+}
+
+impl From<Entry> for ApiEntry {
+    fn from(entry: Entry) -> Self {
+        Self {
+            status: entry.status,
+            first_seen: entry.first_seen,
+            count: entry.count,
+            first_message_id: entry.first_message_id.map(|id| id.0),
+            poster_id: entry.poster_id,
+            repeats_left: entry.repeats_left,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Known {
+    #[serde(with = "hex_hash")]
+    hash: [u8; 16],
+    entry: Option<ApiEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextBody {
+    text: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AllowBody {
+    repeats: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SettingBody {
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SettingValue {
+    name: &'static str,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatInfo {
+    chat_id: ChatId,
+    /// The bot can't delete messages there.
+    suspended: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DatabaseInfo {
+    size_on_disk: u64,
+    entries: usize,
+}
+
+/// A request that can't be answered, with the status saying why.
+struct Failure {
+    status: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new("400 Bad Request", message)
+    }
+
+    fn not_found() -> Self {
+        Self::new("404 Not Found", "not found")
+    }
+}
+
+impl From<eyre::Report> for Failure {
+    fn from(err: eyre::Report) -> Self {
+        tracing::error!(err = format_args!("{err}"), "admin API request failed");
+        Self::new("500 Internal Server Error", "internal error")
+    }
+}
+
+type Answer = Result<(&'static str, String), Failure>;
+
+/// Binds `addr`, so a taken port fails at startup rather than in the background.
+pub(crate) async fn bind(addr: SocketAddr) -> eyre::Result<TcpListener> {
+    Ok(TcpListener::bind(addr).await?)
+}
+
+/// Answers API requests until the bot stops.
+pub(crate) async fn serve(listener: TcpListener, robot: Robot9000, bot: Arc<dyn TelegramApi>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!(err = format_args!("{err}"), "couldn't accept API request");
+                continue;
+            }
+        };
+        let robot = robot.clone();
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &robot, &*bot).await {
+                tracing::debug!(err = format_args!("{err}"), "API connection failed");
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, robot: &Robot9000, bot: &dyn TelegramApi) -> eyre::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    if matches!(request.method.as_str(), "GET" | "HEAD") && request.path == "/" {
+        let html = "text/html; charset=utf-8";
+        return http::respond(
+            &mut stream,
+            &request.method,
+            "200 OK",
+            html,
+            DASHBOARD.as_bytes(),
+        )
+        .await;
+    }
+    let (status, body) = match route(&request, robot, bot).await {
+        Ok(answer) => answer,
+        Err(failure) => (
+            failure.status,
+            json!({ "error": failure.message }).to_string(),
+        ),
+    };
+    http::respond(
+        &mut stream,
+        &request.method,
+        status,
+        "application/json",
+        body.as_bytes(),
+    )
+    .await
+}
+
+/// Compares in constant time, so the token can't be guessed byte by byte.
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn route(request: &Request, robot: &Robot9000, bot: &dyn TelegramApi) -> Answer {
+    let config = robot.config();
+    let given = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = match (given, &config.admin_api_token) {
+        (Some(given), Some(token)) => tokens_match(given.as_bytes(), token.0.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return Err(Failure::new("401 Unauthorized", "missing or wrong token"));
+    }
+
+    let segments = Vec::from_iter(request.path.trim_matches('/').split('/'));
+    match (request.method.as_str(), &segments[..]) {
+        ("POST", ["backup"]) => {
+            robot.back_up(bot).await?;
+            tracing::info!("made a backup through the admin API");
+            Ok(("200 OK", json!({}).to_string()))
+        }
+        ("GET", ["chats"]) => {
+            let chats = robot
+                .chats
+                .iter()
+                .map(|item| {
+                    let (chat_id, health) = item?;
+                    Ok(ChatInfo {
+                        chat_id: ChatId(i64::from_be_bytes(chat_id[..].try_into()?)),
+                        suspended: health[..] == [1],
+                    })
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            json_answer(chats)
+        }
+        ("GET", ["database"]) => json_answer(DatabaseInfo {
+            size_on_disk: robot.db.size_on_disk().map_err(eyre::Report::from)?,
+            entries: robot.store.len().await?,
+        }),
+        (method, ["chats", chat_id, rest @ ..]) => {
+            let chat_id = chat_id
+                .parse()
+                .map(ChatId)
+                .map_err(|_| Failure::bad_request("malformed chat id"))?;
+            route_chat(method, chat_id, rest, request, robot, bot).await
+        }
+        _ => Err(Failure::not_found()),
+    }
+}
+
+async fn route_chat(
+    method: &str,
+    chat_id: ChatId,
+    segments: &[&str],
+    request: &Request,
+    robot: &Robot9000,
+    bot: &dyn TelegramApi,
+) -> Answer {
+    match (method, segments) {
+        ("GET", ["entries", hash]) => {
+            let key = key(robot, chat_id, hash)?;
+            match robot.store.get(key).await? {
+                Some(entry) => json_answer(ApiEntry::from(entry)),
+                None => Err(Failure::not_found()),
+            }
+        }
+        ("POST", ["check"]) => {
+            let TextBody { text } = parse_body(request)?;
+            let key = robot.hash_message(robot.topic_scope(chat_id, None), &text)?;
+            let entry = robot.store.get(key).await?;
+            json_answer(Known {
+                hash: key.hash,
+                entry: entry.map(ApiEntry::from),
+            })
+        }
+        ("POST", ["entries"]) => {
+            let TextBody { text } = parse_body(request)?;
+            let settings = robot.settings(chat_id)?;
+            let key = robot.hash_message(robot.topic_scope(chat_id, None), &text)?;
+            let post = api_post();
+            let entry = robot.store_hash(key, post, &settings).await?;
+            robot.audit(AuditEvent::store(chat_id, key, post, &entry));
+            json_answer(Known {
+                hash: key.hash,
+                entry: Some(entry.into()),
+            })
+        }
+        ("POST", ["entries", hash, action @ ("allow" | "forbid")]) => {
+            let key = key(robot, chat_id, hash)?;
+            let (status, event) = if *action == "allow" {
+                let AllowBody { repeats } = if request.body.is_empty() {
+                    AllowBody::default()
+                } else {
+                    parse_body(request)?
+                };
+                let event = AuditEvent::Allow {
+                    chat_id,
+                    hash: key.hash,
+                    admin_id: API_ADMIN,
+                    repeats,
+                };
+                robot
+                    .set_hash_status(key, Status::Allowed, repeats, api_post())
+                    .await?;
+                (Status::Allowed, event)
+            } else {
+                let event = AuditEvent::Forbid {
+                    chat_id,
+                    hash: key.hash,
+                    admin_id: API_ADMIN,
+                };
+                robot
+                    .set_hash_status(key, Status::Forbidden, None, api_post())
+                    .await?;
+                (Status::Forbidden, event)
+            };
+            robot.audit(event);
+            let event = format!(
+                "{status:?} in chat {chat_id} through the admin API\nHash: {}",
+                hex(&key.hash),
+            );
+            robot.log_event(bot, event).await;
+            let entry = robot.store.get(key).await?;
+            json_answer(entry.map(ApiEntry::from))
+        }
+        ("GET", ["deletions"]) => {
+            let limit = match request.param("limit") {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| Failure::bad_request("malformed limit"))?,
+                None => DEFAULT_DELETIONS,
+            };
+            json_answer(robot.recent_deletions(chat_id, limit)?)
+        }
+        ("GET", ["settings"]) => {
+            let settings = robot.settings(chat_id)?;
+            json_answer(Setting::ALL.map(|setting| SettingValue {
+                name: setting.name(),
+                value: setting.show(&settings),
+            }))
+        }
+        ("PUT", ["settings", name]) => {
+            let setting = name.parse::<Setting>().map_err(|_| Failure::not_found())?;
+            let SettingBody { value } = parse_body(request)?;
+            if let Some(value) = &value {
+                robot
+                    .check_setting(chat_id, setting, value)
+                    .map_err(|err| Failure::bad_request(err.to_string()))?;
+            }
+            tracing::info!(
+                chat_id = chat_id.0,
+                setting = setting.name(),
+                value,
+                "changing setting through the admin API"
+            );
+            let shown = robot.change_setting(chat_id, setting, value.as_deref(), API_ADMIN)?;
+            let event = format!(
+                "Set {} to {shown} in chat {chat_id} through the admin API",
+                setting.name(),
+            );
+            robot.log_event(bot, event).await;
+            json_answer(SettingValue {
+                name: setting.name(),
+                value: shown,
+            })
+        }
+        ("GET", ["stats"]) => {
+            let days = match request.param("days") {
+                Some(days) => days
+                    .parse()
+                    .map_err(|_| Failure::bad_request("malformed number of days"))?,
+                None => DEFAULT_STATS_DAYS,
+            };
+            if !(1..=RETENTION_DAYS).contains(&days) {
+                return Err(Failure::bad_request(format!(
+                    "number of days must be from 1 to {RETENTION_DAYS}"
+                )));
+            }
+            json_answer(robot.chat_stats(chat_id, unix_now(), days)?)
+        }
+        _ => Err(Failure::not_found()),
+    }
+}
+
+/// Key of a hash given in the path, in the chat's namespace.
+fn key(robot: &Robot9000, chat_id: ChatId, hash: &str) -> Result<Key, Failure> {
+    let hash = BorrowedStrDeserializer::<de::value::Error>::new(hash);
+    let hash = hex_hash::deserialize(hash).map_err(|_| Failure::bad_request("malformed hash"))?;
+    Ok(Key {
+        namespace: robot.namespace(chat_id),
+        hash,
+    })
+}
+
+/// Messages added through the API are posted now, by nobody in particular.
+fn api_post() -> Post {
+    Post {
+        timestamp: unix_now(),
+        message_id: None,
+        poster_id: None,
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(request: &Request) -> Result<T, Failure> {
+    serde_json::from_slice(&request.body)
+        .map_err(|err| Failure::bad_request(format!("malformed body: {err}")))
+}
+
+fn json_answer(value: impl Serialize) -> Answer {
+    let body = serde_json::to_string(&value).map_err(eyre::Report::from)?;
+    Ok(("200 OK", body))
+}
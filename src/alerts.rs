@@ -0,0 +1,147 @@
+//! Telling the owners when something goes wrong, since logs are rarely watched.
+//!
+//! Alerts are for the operator and stay in English, like log chat reports.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre;
+use teloxide::{types::ChatId, RequestError};
+
+use crate::{
+    api::{SendOptions, TelegramApi},
+    robot::{snippet, Robot9000},
+};
+
+/// Errors within the alert window, and when the owners were last alerted.
+#[derive(Default)]
+struct RecentErrors {
+    /// When each error happened, and whether it came from Telegram.
+    errors: VecDeque<(Instant, bool)>,
+    last_alert: Option<Instant>,
+}
+
+/// Recent errors of handling updates, kept in memory only.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorTracker {
+    recent: Arc<Mutex<RecentErrors>>,
+}
+
+/// What went wrong within the alert window, once there was enough of it.
+struct ErrorSummary {
+    count: usize,
+    from_telegram: usize,
+    latest: String,
+}
+
+impl ErrorTracker {
+    fn lock(&self) -> MutexGuard<'_, RecentErrors> {
+        self.recent.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records an error, returning a summary if there were at least `threshold`
+    /// within `window` and the owners weren't alerted within it already.
+    fn record(
+        &self,
+        err: &eyre::Report,
+        threshold: usize,
+        window: Duration,
+    ) -> Option<ErrorSummary> {
+        let now = Instant::now();
+        let mut recent = self.lock();
+        while recent
+            .errors
+            .front()
+            .is_some_and(|&(at, _)| now.duration_since(at) >= window)
+        {
+            recent.errors.pop_front();
+        }
+        let from_telegram = err.downcast_ref::<RequestError>().is_some();
+        recent.errors.push_back((now, from_telegram));
+        // Only the errors that make up an alert are needed.
+        if recent.errors.len() > threshold {
+            recent.errors.pop_front();
+        }
+        if recent.errors.len() < threshold
+            || recent
+                .last_alert
+                .is_some_and(|at| now.duration_since(at) < window)
+        {
+            return None;
+        }
+        recent.last_alert = Some(now);
+        Some(ErrorSummary {
+            count: recent.errors.len(),
+            from_telegram: recent
+                .errors
+                .iter()
+                .filter(|&&(_, telegram)| telegram)
+                .count(),
+            latest: format!("{err}"),
+        })
+    }
+}
+
+impl Robot9000 {
+    /// Sends an alert to every owner in private, if `owner_alerts` is on.
+    pub(crate) async fn alert_owners(&self, bot: &dyn TelegramApi, alert: String) {
+        if !self.config().owner_alerts {
+            return;
+        }
+        for &owner in &self.config().owners {
+            let result = bot
+                .send_message(ChatId(owner as i64), alert.clone(), SendOptions::default())
+                .await;
+            if let Err(err) = result {
+                tracing::warn!(
+                    owner,
+                    err = format_args!("{err}"),
+                    "couldn't alert an owner"
+                );
+            }
+        }
+    }
+
+    /// Counts an error of handling an update, alerting the owners once
+    /// there are `error_alert_threshold` of them within `error_alert_window` seconds.
+    pub(crate) async fn record_error(&self, bot: &dyn TelegramApi, err: &eyre::Report) {
+        let config = self.config();
+        if !config.owner_alerts {
+            return;
+        }
+        let window = Duration::from_secs(config.error_alert_window);
+        let threshold = config.error_alert_threshold.max(1);
+        let Some(summary) = self.errors.record(err, threshold, window) else {
+            return;
+        };
+        tracing::warn!(errors = summary.count, "alerting owners about errors");
+        let alert = format!(
+            "{} errors in the last {} seconds, {} of them from Telegram\nLatest: {}",
+            summary.count,
+            window.as_secs(),
+            summary.from_telegram,
+            snippet(&summary.latest),
+        );
+        self.alert_owners(bot, alert).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_window() {
+        let errors = ErrorTracker::default();
+        let window = Duration::from_secs(60);
+        let err = || eyre::eyre!("database is on fire");
+        assert!(errors.record(&err(), 3, window).is_none());
+        assert!(errors.record(&err(), 3, window).is_none());
+        let summary = errors.record(&err(), 3, window).unwrap();
+        assert_eq!((summary.count, summary.from_telegram), (3, 0));
+        assert!(errors.record(&err(), 3, window).is_none());
+    }
+}
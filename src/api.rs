@@ -0,0 +1,470 @@
+//! Bot API methods the bot uses, behind a trait so tests can script Telegram.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt as _, StreamExt as _};
+use teloxide::{
+    net::Download,
+    payloads::{
+        AnswerCallbackQuerySetters as _, AnswerInlineQuerySetters as _,
+        EditMessageTextSetters as _, RestrictChatMemberSetters as _, SendMessageSetters as _,
+        SetMessageReactionSetters as _,
+    },
+    prelude::{Request, Requester},
+    requests::Output,
+    sugar::request::RequestReplyExt as _,
+    types::{
+        BotCommand, CallbackQueryId, ChatId, ChatMember, ChatPermissions, File, FileId,
+        InlineKeyboardMarkup, InlineQueryId, InlineQueryResult, InputFile, Me, Message,
+        MessageEntity, MessageId, ReactionType, ThreadId, UserId,
+    },
+    Bot, RequestError,
+};
+
+use crate::throttle::Throttle;
+
+/// Optional parts of a message sent with [`TelegramApi::send_message`].
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    pub reply_to: Option<MessageId>,
+    pub thread_id: Option<ThreadId>,
+    pub markup: Option<InlineKeyboardMarkup>,
+    pub entities: Option<Vec<MessageEntity>>,
+}
+
+/// Everything the bot asks Telegram to do.
+pub trait TelegramApi: Send + Sync {
+    fn get_me(&self) -> BoxFuture<'_, eyre::Result<Me>>;
+
+    fn set_my_commands(&self, commands: Vec<BotCommand>) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        options: SendOptions,
+    ) -> BoxFuture<'_, eyre::Result<Message>>;
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        markup: Option<InlineKeyboardMarkup>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Deletes several messages of a chat at once, skipping the ones already gone.
+    fn delete_messages(
+        &self,
+        chat_id: ChatId,
+        message_ids: Vec<MessageId>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Copies a message, media and all, without linking to the original.
+    fn copy_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, eyre::Result<MessageId>>;
+
+    fn set_message_reaction(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        reaction: ReactionType,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Takes every permission away from a chat member until `until`.
+    fn mute(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        until: DateTime<Utc>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Gives a chat member every permission back, lifting a mute.
+    fn unmute(&self, chat_id: ChatId, user_id: UserId) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn get_chat_member(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, eyre::Result<ChatMember>>;
+
+    fn get_chat_administrators(
+        &self,
+        chat_id: ChatId,
+    ) -> BoxFuture<'_, eyre::Result<Vec<ChatMember>>>;
+
+    fn leave_chat(&self, chat_id: ChatId) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn answer_callback_query(
+        &self,
+        id: CallbackQueryId,
+        text: Option<String>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Answers only the person who asked, without caching the answer.
+    fn answer_inline_query(
+        &self,
+        id: InlineQueryId,
+        results: Vec<InlineQueryResult>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn send_document(&self, chat_id: ChatId, file: InputFile) -> BoxFuture<'_, eyre::Result<()>>;
+
+    fn get_file(&self, file_id: FileId) -> BoxFuture<'_, eyre::Result<File>>;
+
+    /// Downloads a file by the path from `get_file`, a chunk at a time.
+    fn download_file(&self, path: &str) -> BoxStream<'static, eyre::Result<Vec<u8>>>;
+}
+
+/// Most messages `deleteMessages` takes at once.
+const MAX_DELETE_BATCH: usize = 100;
+
+/// How many times enforcement requests and replies are tried before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before retrying after the first network error, doubled after each one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Sends a request until it goes through, waiting as long as Telegram asks
+/// when hitting flood control, and backing off after network errors.
+async fn with_retries<T, Fut>(mut send: impl FnMut() -> Fut) -> eyre::Result<T>
+where
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let wait = match send().await {
+            Ok(response) => return Ok(response),
+            Err(RequestError::RetryAfter(seconds)) if attempt < MAX_ATTEMPTS => seconds.duration(),
+            Err(RequestError::Network(_) | RequestError::Io(_)) if attempt < MAX_ATTEMPTS => {
+                let wait = backoff;
+                backoff *= 2;
+                wait
+            }
+            Err(err) => return Err(err.into()),
+        };
+        tracing::warn!(attempt, wait = format_args!("{wait:?}"), "retrying request");
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+/// [`Bot`] whose messages wait for Telegram's limits on each chat and on
+/// the bot overall. Other requests only retry after flood control.
+#[derive(Debug, Clone)]
+pub struct ThrottledBot {
+    bot: Bot,
+    throttle: Arc<Throttle>,
+}
+
+impl ThrottledBot {
+    pub fn new(bot: Bot) -> Self {
+        Self {
+            bot,
+            throttle: Arc::default(),
+        }
+    }
+
+    /// Sends a message like [`with_retries`], waiting for the chat's turn
+    /// before every attempt.
+    async fn send_throttled<R>(&self, chat_id: ChatId, request: &R) -> eyre::Result<Output<R>>
+    where
+        R: Request<Err = RequestError>,
+    {
+        with_retries(|| async {
+            self.throttle.wait(chat_id).await;
+            request.send_ref().await
+        })
+        .await
+    }
+}
+
+impl TelegramApi for ThrottledBot {
+    fn get_me(&self) -> BoxFuture<'_, eyre::Result<Me>> {
+        async move { Ok(Requester::get_me(&self.bot).send().await?) }.boxed()
+    }
+
+    fn set_my_commands(&self, commands: Vec<BotCommand>) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            Requester::set_my_commands(&self.bot, commands)
+                .send()
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        options: SendOptions,
+    ) -> BoxFuture<'_, eyre::Result<Message>> {
+        async move {
+            let mut request = Requester::send_message(&self.bot, chat_id, text);
+            if let Some(reply_to) = options.reply_to {
+                request = request.reply_to(reply_to);
+            }
+            if let Some(thread_id) = options.thread_id {
+                request = request.message_thread_id(thread_id);
+            }
+            if let Some(markup) = options.markup {
+                request = request.reply_markup(markup);
+            }
+            if let Some(entities) = options.entities {
+                request = request.entities(entities);
+            }
+            self.send_throttled(chat_id, &request).await
+        }
+        .boxed()
+    }
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        markup: Option<InlineKeyboardMarkup>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let mut request = Requester::edit_message_text(&self.bot, chat_id, message_id, text);
+            if let Some(markup) = markup {
+                request = request.reply_markup(markup);
+            }
+            request.send().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let request = Requester::delete_message(&self.bot, chat_id, message_id);
+            with_retries(|| request.send_ref()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_messages(
+        &self,
+        chat_id: ChatId,
+        message_ids: Vec<MessageId>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            for batch in message_ids.chunks(MAX_DELETE_BATCH) {
+                let request = Requester::delete_messages(&self.bot, chat_id, batch.iter().copied());
+                with_retries(|| request.send_ref()).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, eyre::Result<MessageId>> {
+        async move {
+            let request = Requester::copy_message(&self.bot, chat_id, from_chat_id, message_id);
+            self.send_throttled(chat_id, &request).await
+        }
+        .boxed()
+    }
+
+    fn set_message_reaction(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        reaction: ReactionType,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let request = Requester::set_message_reaction(&self.bot, chat_id, message_id)
+                .reaction([reaction]);
+            with_retries(|| request.send_ref()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn mute(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        until: DateTime<Utc>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let request = Requester::restrict_chat_member(
+                &self.bot,
+                chat_id,
+                user_id,
+                ChatPermissions::empty(),
+            )
+            .until_date(until);
+            with_retries(|| request.send_ref()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn unmute(&self, chat_id: ChatId, user_id: UserId) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let request = Requester::restrict_chat_member(
+                &self.bot,
+                chat_id,
+                user_id,
+                ChatPermissions::all(),
+            );
+            with_retries(|| request.send_ref()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_chat_member(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, eyre::Result<ChatMember>> {
+        async move {
+            Ok(Requester::get_chat_member(&self.bot, chat_id, user_id)
+                .send()
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn get_chat_administrators(
+        &self,
+        chat_id: ChatId,
+    ) -> BoxFuture<'_, eyre::Result<Vec<ChatMember>>> {
+        async move {
+            Ok(Requester::get_chat_administrators(&self.bot, chat_id)
+                .send()
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn leave_chat(&self, chat_id: ChatId) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            Requester::leave_chat(&self.bot, chat_id).send().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn answer_callback_query(
+        &self,
+        id: CallbackQueryId,
+        text: Option<String>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let mut request = Requester::answer_callback_query(&self.bot, id);
+            if let Some(text) = text {
+                request = request.text(text);
+            }
+            request.send().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn answer_inline_query(
+        &self,
+        id: InlineQueryId,
+        results: Vec<InlineQueryResult>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            Requester::answer_inline_query(&self.bot, id, results)
+                .cache_time(0)
+                .is_personal(true)
+                .send()
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send_document(&self, chat_id: ChatId, file: InputFile) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            self.throttle.wait(chat_id).await;
+            Requester::send_document(&self.bot, chat_id, file)
+                .send()
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_file(&self, file_id: FileId) -> BoxFuture<'_, eyre::Result<File>> {
+        async move { Ok(Requester::get_file(&self.bot, file_id).send().await?) }.boxed()
+    }
+
+    fn download_file(&self, path: &str) -> BoxStream<'static, eyre::Result<Vec<u8>>> {
+        self.bot
+            .download_file_stream(path)
+            .map(|chunk| Ok(chunk?.to_vec()))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::future;
+    use teloxide::{types::Seconds, ApiError};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn retries_after_flood_control() -> eyre::Result<()> {
+        let attempts = Cell::new(0);
+        let result = with_retries(|| {
+            attempts.set(attempts.get() + 1);
+            let result = match attempts.get() {
+                1 | 2 => Err(RequestError::RetryAfter(Seconds::from_seconds(0))),
+                _ => Ok(()),
+            };
+            future::ready(result)
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_api_errors() {
+        let attempts = Cell::new(0);
+        let result = with_retries(|| {
+            attempts.set(attempts.get() + 1);
+            future::ready(Err::<(), _>(RequestError::Api(
+                ApiError::MessageToDeleteNotFound,
+            )))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
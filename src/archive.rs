@@ -0,0 +1,149 @@
+//! Reading imports uploaded as `.gz` or `.zip` files.
+
+use std::io::{self, BufRead, Cursor, Read};
+
+use flate2::bufread::MultiGzDecoder;
+use zip::ZipArchive;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+
+/// Decompresses a gzip file or the first file with the given extension inside
+/// a zip archive, telling them apart by their first bytes. Anything else is
+/// read as is.
+///
+/// Reading more than `limit` bytes fails, so a small archive can't expand
+/// into something that doesn't fit in memory. Zip archives keep their index
+/// at the end, so they're read into memory, as is the file taken out of them.
+pub fn decompress<'a>(
+    mut reader: impl BufRead + 'a,
+    limit: u64,
+    extension: &str,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let start = reader.fill_buf()?;
+    let inner: Box<dyn Read + 'a> = if start.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else if start.starts_with(&ZIP_MAGIC) {
+        Box::new(zip_entry(reader, limit, extension)?)
+    } else {
+        Box::new(reader)
+    };
+    Ok(Box::new(Limit::new(inner, limit)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Takes the first file with the extension out of a zip archive.
+fn zip_entry(reader: impl Read, limit: u64, extension: &str) -> io::Result<Cursor<Vec<u8>>> {
+    let mut archive = Vec::new();
+    Limit::new(reader, limit).read_to_end(&mut archive)?;
+    let mut archive = ZipArchive::new(Cursor::new(archive))
+        .map_err(|err| invalid(&format!("malformed zip archive: {err}")))?;
+    let index = (0..archive.len())
+        .find(|&index| {
+            archive
+                .name_for_index(index)
+                .is_some_and(|name| name.ends_with(extension))
+        })
+        .ok_or_else(|| invalid(&format!("no {extension} file in the zip archive")))?;
+    let mut entry = archive
+        .by_index(index)
+        .map_err(|err| invalid(&format!("unsupported zip archive: {err}")))?;
+    let mut file = Vec::new();
+    Limit::new(&mut entry, limit).read_to_end(&mut file)?;
+    Ok(Cursor::new(file))
+}
+
+/// Reader failing once more than a limited number of bytes is read.
+struct Limit<R> {
+    inner: R,
+    remaining: u64,
+    limit: u64,
+}
+
+impl<R> Limit<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for Limit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.remaining = self.remaining.checked_sub(len as u64).ok_or_else(|| {
+            invalid(&format!(
+                "decompressed file is larger than the limit of {} bytes",
+                self.limit
+            ))
+        })?;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use color_eyre::eyre;
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    const EXPORT: &[u8] = br#"{"messages": []}"#;
+
+    fn read(file: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        decompress(file, limit, ".json")?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> eyre::Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, contents) in files {
+            zip.start_file(*name, options)?;
+            zip.write_all(contents)?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+
+    #[test]
+    fn reads_gzip() -> eyre::Result<()> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(EXPORT)?;
+        assert_eq!(read(&gzip.finish()?, 1024)?, EXPORT);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_the_file_with_the_extension_from_zip() -> eyre::Result<()> {
+        let archive = zip(&[("photos/1.jpg", b"\xff\xd8"), ("result.json", EXPORT)])?;
+        assert_eq!(read(&archive, 1024)?, EXPORT);
+        let archive = zip(&[("photos/1.jpg", b"\xff\xd8")])?;
+        assert!(read(&archive, 1024).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_plain_files_as_is() -> eyre::Result<()> {
+        assert_eq!(read(EXPORT, 1024)?, EXPORT);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_decompressed_size() -> eyre::Result<()> {
+        let bomb = vec![b' '; 1 << 20];
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&bomb)?;
+        assert!(read(&gzip.finish()?, 1024).is_err());
+        assert!(read(&zip(&[("result.json", &bomb)])?, 1024).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+//! Audit log of every decision, which the database can be rebuilt from.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use chrono::Utc;
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, MessageId, UserId};
+
+use crate::{
+    config::Enforcement,
+    robot::{hex, Robot9000},
+    storage::{unix_now, Entry, Key, Post, Status},
+};
+
+pub mod hex_hash {
+    use serde::{de, Deserialize as _, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::hex(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 16], D::Error> {
+        let raw = <&str>::deserialize(deserializer)?;
+        let mut hash = [0; 16];
+        if raw.len() != 32 || !raw.is_ascii() {
+            return Err(de::Error::custom(format!("malformed hash: {raw:?}")));
+        }
+        for (byte, digits) in hash.iter_mut().zip(raw.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(de::Error::custom)?;
+            *byte = u8::from_str_radix(digits, 16).map_err(de::Error::custom)?;
+        }
+        Ok(hash)
+    }
+}
+
+/// Decision recorded in the audit log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A message was remembered, either live or during an import.
+    Store {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        posted_at: i64,
+        message_id: Option<i32>,
+        user_id: Option<UserId>,
+        count: u32,
+    },
+    Enforce {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        message_id: i32,
+        /// Missing for channel posts.
+        user_id: Option<UserId>,
+        enforcement: Enforcement,
+    },
+    /// A message was deleted for being posted too many times in a row by its author.
+    Flood {
+        chat_id: ChatId,
+        message_id: i32,
+        user_id: UserId,
+    },
+    /// A message was deleted for being sent too soon while a duplicate storm slowed the chat down.
+    SlowMode {
+        chat_id: ChatId,
+        message_id: i32,
+        user_id: UserId,
+    },
+    /// Messages were deleted for making up a known message together.
+    Split {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        message_ids: Vec<i32>,
+        user_id: UserId,
+    },
+    Allow {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        admin_id: UserId,
+        /// How many more copies were allowed, if not all of them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        repeats: Option<u32>,
+    },
+    Forbid {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        admin_id: UserId,
+    },
+    Forget {
+        chat_id: ChatId,
+        #[serde(with = "hex_hash")]
+        hash: [u8; 16],
+        admin_id: UserId,
+    },
+    Import {
+        chat_id: ChatId,
+        admin_id: UserId,
+        imported: usize,
+    },
+    /// Every message known in the chat's namespace was forgotten.
+    Reset {
+        chat_id: ChatId,
+        admin_id: UserId,
+    },
+    /// The chat's namespace got a new salt.
+    Rotate {
+        chat_id: ChatId,
+        admin_id: UserId,
+        #[serde(with = "hex_hash")]
+        salt: [u8; 16],
+    },
+    /// A user was excluded from duplicate checks in the chat.
+    Exempt {
+        chat_id: ChatId,
+        user_id: UserId,
+        admin_id: UserId,
+    },
+    Unexempt {
+        chat_id: ChatId,
+        user_id: UserId,
+        admin_id: UserId,
+    },
+    /// A user was allowed to run some admin commands in the chat.
+    Mod {
+        chat_id: ChatId,
+        user_id: UserId,
+        admin_id: UserId,
+    },
+    Unmod {
+        chat_id: ChatId,
+        user_id: UserId,
+        admin_id: UserId,
+    },
+    /// A phrase was added to the common phrases of the chat, or removed from them.
+    Phrase {
+        chat_id: ChatId,
+        admin_id: UserId,
+        phrase: String,
+        common: bool,
+    },
+    /// Enforcement was paused in the chat until the given unix time.
+    Pause {
+        chat_id: ChatId,
+        admin_id: UserId,
+        until: i64,
+    },
+    Resume {
+        chat_id: ChatId,
+        admin_id: UserId,
+    },
+    /// Mutes the bot gave out in the chat were lifted, only the user's if there's one.
+    Amnesty {
+        chat_id: ChatId,
+        admin_id: UserId,
+        user_id: Option<UserId>,
+    },
+    /// A setting was overridden in the chat, or reset to the config with no value.
+    Set {
+        chat_id: ChatId,
+        admin_id: UserId,
+        setting: String,
+        value: Option<String>,
+    },
+}
+
+impl AuditEvent {
+    pub(crate) fn store(chat_id: ChatId, key: Key, post: Post, entry: &Entry) -> Self {
+        AuditEvent::Store {
+            chat_id,
+            hash: key.hash,
+            posted_at: post.timestamp,
+            message_id: post.message_id.map(|id| id.0),
+            user_id: post.poster_id,
+            count: entry.count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    timestamp: i64,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+impl AuditRecord {
+    /// Whether something was deleted in the chat, or reacted to instead.
+    fn is_deletion_in(&self, chat: ChatId) -> bool {
+        match self.event {
+            AuditEvent::Enforce { chat_id, .. }
+            | AuditEvent::Flood { chat_id, .. }
+            | AuditEvent::SlowMode { chat_id, .. }
+            | AuditEvent::Split { chat_id, .. } => chat_id == chat,
+            _ => false,
+        }
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+    /// Days since the epoch when the file was last written.
+    day: i64,
+}
+
+/// Append-only JSON lines log of every decision, rotated by size or date.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    rotate_daily: bool,
+    current: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+    /// How much of the end of the log [`AuditLog::recent`] reads, in bytes.
+    const RECENT_SIZE: u64 = 1024 * 1024;
+
+    pub(crate) fn open(
+        path: &Path,
+        max_size: Option<u64>,
+        rotate_daily: bool,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            rotate_daily,
+            current: Mutex::new(Self::open_file(path)?),
+        })
+    }
+
+    fn open_file(path: &Path) -> eyre::Result<AuditFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        Ok(AuditFile {
+            file,
+            size: metadata.len(),
+            day: modified.div_euclid(Self::SECONDS_PER_DAY),
+        })
+    }
+
+    /// Latest records of the current file, oldest first, skipping malformed ones.
+    /// Only its last `RECENT_SIZE` bytes are read.
+    pub(crate) fn recent(&self) -> eyre::Result<Vec<AuditRecord>> {
+        let mut file = File::open(&self.path)?;
+        let start = file.metadata()?.len().saturating_sub(Self::RECENT_SIZE);
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let mut lines = tail.split(|&byte| byte == b'\n');
+        if start > 0 {
+            // Most likely the end of a line that was cut off.
+            lines.next();
+        }
+        Ok(lines
+            .filter_map(|line| serde_json::from_slice(line).ok())
+            .collect())
+    }
+
+    fn record(&self, event: AuditEvent) -> eyre::Result<()> {
+        let timestamp = unix_now();
+        let mut line = serde_json::to_vec(&AuditRecord { timestamp, event })?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock().unwrap_or_else(|err| err.into_inner());
+        let today = timestamp.div_euclid(Self::SECONDS_PER_DAY);
+        let too_big = self
+            .max_size
+            .is_some_and(|max_size| current.size + line.len() as u64 > max_size);
+        let too_old = self.rotate_daily && current.day != today;
+        if current.size > 0 && (too_big || too_old) {
+            let suffix = Utc::now().format("%Y%m%d-%H%M%S");
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(format!(".{suffix}"));
+            fs::rename(&self.path, rotated)?;
+            *current = Self::open_file(&self.path)?;
+        }
+
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+        current.day = today;
+        Ok(())
+    }
+}
+
+impl Robot9000 {
+    /// Applies an audit log record to the database, as if it happened again.
+    pub(crate) async fn replay(&self, record: AuditRecord) -> eyre::Result<()> {
+        let admin_post = Post {
+            timestamp: record.timestamp,
+            message_id: None,
+            poster_id: None,
+        };
+        let key = |chat_id, hash| Key {
+            namespace: self.namespace(chat_id),
+            hash,
+        };
+        match record.event {
+            AuditEvent::Store {
+                chat_id,
+                hash,
+                posted_at,
+                message_id,
+                user_id,
+                ..
+            } => {
+                let post = Post {
+                    timestamp: posted_at,
+                    message_id: message_id.map(MessageId),
+                    poster_id: user_id,
+                };
+                self.store_hash(key(chat_id, hash), post, &self.settings(chat_id)?)
+                    .await?;
+            }
+            AuditEvent::Allow {
+                chat_id,
+                hash,
+                repeats,
+                ..
+            } => {
+                self.set_hash_status(key(chat_id, hash), Status::Allowed, repeats, admin_post)
+                    .await?;
+            }
+            AuditEvent::Forbid { chat_id, hash, .. } => {
+                self.set_hash_status(key(chat_id, hash), Status::Forbidden, None, admin_post)
+                    .await?;
+            }
+            AuditEvent::Forget { chat_id, hash, .. } => {
+                self.store.remove(key(chat_id, hash)).await?;
+            }
+            AuditEvent::Reset { chat_id, .. } => {
+                self.reset_namespace(self.namespace(chat_id)).await?;
+            }
+            AuditEvent::Set {
+                chat_id,
+                setting,
+                value,
+                ..
+            } => {
+                self.set_setting(chat_id, setting.parse()?, value.as_deref())?;
+            }
+            AuditEvent::Exempt {
+                chat_id, user_id, ..
+            } => {
+                self.set_exempt(chat_id, user_id, true)?;
+            }
+            AuditEvent::Unexempt {
+                chat_id, user_id, ..
+            } => {
+                self.set_exempt(chat_id, user_id, false)?;
+            }
+            AuditEvent::Mod {
+                chat_id, user_id, ..
+            } => {
+                self.set_moderator(chat_id, user_id, true)?;
+            }
+            AuditEvent::Unmod {
+                chat_id, user_id, ..
+            } => {
+                self.set_moderator(chat_id, user_id, false)?;
+            }
+            AuditEvent::Phrase {
+                chat_id,
+                phrase,
+                common,
+                ..
+            } => {
+                self.set_common_phrase(chat_id, &phrase, common)?;
+            }
+            AuditEvent::Pause { chat_id, until, .. } => {
+                self.set_pause(chat_id, Some(until))?;
+            }
+            AuditEvent::Resume { chat_id, .. } => {
+                self.set_pause(chat_id, None)?;
+            }
+            AuditEvent::Amnesty {
+                chat_id, user_id, ..
+            } => {
+                self.forget_mutes(chat_id, user_id)?;
+            }
+            AuditEvent::Rotate { chat_id, salt, .. } => {
+                self.salts.insert(self.namespace(chat_id).prefix(), &salt)?;
+            }
+            AuditEvent::Enforce { .. }
+            | AuditEvent::Flood { .. }
+            | AuditEvent::SlowMode { .. }
+            | AuditEvent::Split { .. }
+            | AuditEvent::Import { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Latest deletions in a chat, newest first, if there's an audit log.
+    pub(crate) fn recent_deletions(
+        &self,
+        chat_id: ChatId,
+        limit: usize,
+    ) -> eyre::Result<Vec<AuditRecord>> {
+        let Some(audit_log) = &self.audit_log else {
+            return Ok(Vec::new());
+        };
+        let mut records = audit_log.recent()?;
+        records.retain(|record| record.is_deletion_in(chat_id));
+        records.reverse();
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    pub(crate) fn audit(&self, event: AuditEvent) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+        if let Err(err) = audit_log.record(event) {
+            tracing::error!(
+                err = format_args!("{err}"),
+                "couldn't write to the audit log"
+            );
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Backups made on a schedule, kept in a directory or sent to the backup chat,
+//! and restores of backups sent to the bot.
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use color_eyre::eyre;
+use futures::StreamExt as _;
+use teloxide::types::{ChatId, InputFile, MediaDocument, MediaKind, Message, MessageKind, User};
+
+use crate::{
+    api::TelegramApi,
+    i18n::Text,
+    robot::{describe_user, explicit_reply, reply, Robot9000},
+    storage::{read_backup, restore_into, write_backup},
+};
+
+const PREFIX: &str = "r9ktg-backup-";
+const EXTENSION: &str = ".zlib";
+
+/// Name of a backup made now. Names sort in the order backups were made.
+pub(crate) fn backup_file_name() -> String {
+    format!("{PREFIX}{}{EXTENSION}", Utc::now().format("%Y%m%d-%H%M%S"))
+}
+
+/// Writes a backup into `dir`, renaming it into place once it's complete,
+/// so an interrupted write never looks like a backup.
+fn write_to_dir(dir: &Path, backup: &[u8]) -> eyre::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(backup_file_name());
+    let partial = path.with_extension("partial");
+    fs::write(&partial, backup)?;
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// Deletes all but the `keep` latest backups in `dir`. Returns how many were deleted.
+fn prune_dir(dir: &Path, keep: usize) -> eyre::Result<usize> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+            backups.push(name.to_owned());
+        }
+    }
+    backups.sort_unstable();
+    let excess = backups.len().saturating_sub(keep);
+    for name in &backups[..excess] {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(excess)
+}
+
+/// `path` with `suffix` appended to its last component.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Where a backup sent with `/restore` waits to replace the database on the next start.
+fn pending_restore_path(db_path: &Path) -> PathBuf {
+    with_suffix(db_path, ".pending-restore")
+}
+
+/// Replaces the database with the backup waiting for it, if there's one.
+///
+/// The backup is restored next to the database, which is then swapped with it
+/// by renaming, so a crash leaves either the old database or the new one.
+/// The old one is kept as `<db_path>.pre-restore-<time>`.
+pub(crate) fn apply_pending_restore(db_path: &Path) -> eyre::Result<()> {
+    let pending = pending_restore_path(db_path);
+    if !pending.exists() {
+        return Ok(());
+    }
+    let backup = read_backup(&fs::read(&pending)?)?;
+    let restoring = with_suffix(db_path, ".restoring");
+    if restoring.exists() {
+        // Left over from an interrupted restore.
+        fs::remove_dir_all(&restoring)?;
+    }
+    let entries = restore_into(&sled::open(&restoring)?, backup)?;
+    let old = with_suffix(
+        db_path,
+        &format!(".pre-restore-{}", Utc::now().format("%Y%m%d-%H%M%S")),
+    );
+    if db_path.exists() {
+        fs::rename(db_path, &old)?;
+    }
+    fs::rename(&restoring, db_path)?;
+    fs::remove_file(&pending)?;
+    tracing::info!(
+        entries,
+        old = format_args!("{}", old.display()),
+        "Restored the database from a backup sent with /restore"
+    );
+    Ok(())
+}
+
+/// Downloads a document in full, reading it directly from a local Bot API server.
+pub(crate) async fn download(
+    bot: &dyn TelegramApi,
+    message: &Message,
+) -> eyre::Result<Option<Vec<u8>>> {
+    let MessageKind::Common(kind) = &message.kind else {
+        return Ok(None);
+    };
+    let MediaKind::Document(MediaDocument { document, .. }) = &kind.media_kind else {
+        return Ok(None);
+    };
+    let file_info = bot.get_file(document.file.id.clone()).await?;
+    if Path::new(&file_info.path).is_absolute() {
+        return Ok(Some(tokio::fs::read(&file_info.path).await?));
+    }
+    let mut contents = Vec::new();
+    let mut chunks = bot.download_file(&file_info.path);
+    while let Some(chunk) = chunks.next().await {
+        contents.extend_from_slice(&chunk?);
+    }
+    Ok(Some(contents))
+}
+
+impl Robot9000 {
+    /// Handles `/restore` sent in private as a reply to a backup,
+    /// which replaces the database on the next start once it's checked.
+    pub(crate) async fn restore(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let backup = match explicit_reply(message) {
+                Some(reply_to) if message.chat.is_private() => download(bot, reply_to).await?,
+                _ => None,
+            };
+            let Some(backup) = backup else {
+                return reply(bot, message, locale.text(Text::RestoreUsage)).await;
+            };
+            let size = backup.len();
+            let db_path = self.config().db_path.clone();
+            let staged = tokio::task::spawn_blocking(move || {
+                read_backup(&backup)?;
+                let pending = pending_restore_path(&db_path);
+                let partial = with_suffix(&pending, ".partial");
+                fs::write(&partial, &backup)?;
+                fs::rename(&partial, &pending)?;
+                Ok::<_, eyre::Report>(())
+            })
+            .await?;
+            if let Err(err) = staged {
+                tracing::info!(
+                    user_id = user.id.0,
+                    err = format_args!("{err}"),
+                    "/restore failed"
+                );
+                let err = err.to_string();
+                return reply(bot, message, locale.text(Text::RestoreFailed { err: &err })).await;
+            }
+            tracing::info!(user_id = user.id.0, size, "staged a restore");
+            let event = format!(
+                "Staged a restore from a backup, applied on the next start\nOwner: {}",
+                describe_user(user),
+            );
+            self.log_event(bot, event).await;
+            reply(bot, message, locale.text(Text::RestoreStaged)).await
+        })
+        .await
+    }
+
+    /// Makes a backup every `backup_interval` seconds, while it's set.
+    pub(crate) async fn back_up_periodically(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            let Some(interval) = self.config().backup_interval else {
+                // Checked again in a while, since a reload may set it.
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                continue;
+            };
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            if let Err(err) = self.back_up(&*bot).await {
+                tracing::error!(err = format_args!("{err}"), "scheduled backup failed");
+            }
+        }
+    }
+
+    /// Writes a backup to `backup_dir`, keeping the `backup_keep` latest ones,
+    /// or sends it to the backup chat if there's no directory.
+    pub(crate) async fn back_up(&self, bot: &dyn TelegramApi) -> eyre::Result<()> {
+        let config = self.config();
+        let db = self.db.clone();
+        let backup = tokio::task::spawn_blocking(move || write_backup(&db)).await??;
+        match config.backup_dir.clone() {
+            Some(dir) => {
+                let keep = config.backup_keep;
+                let size = backup.len();
+                let (path, pruned) = tokio::task::spawn_blocking(move || {
+                    let path = write_to_dir(&dir, &backup)?;
+                    Ok::<_, eyre::Report>((path, prune_dir(&dir, keep)?))
+                })
+                .await??;
+                tracing::info!(
+                    path = format_args!("{}", path.display()),
+                    size,
+                    pruned,
+                    "made a scheduled backup"
+                );
+            }
+            None => {
+                let Some(chat_id) = config.backup_chat_id else {
+                    eyre::bail!("`backup_interval` needs a `backup_dir` or a `backup_chat_id`");
+                };
+                tracing::info!(size = backup.len(), "sending a scheduled backup");
+                let file = InputFile::memory(backup).file_name(backup_file_name());
+                bot.send_document(ChatId(chat_id), file).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_backups() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("r9ktg-test-backups-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        for name in [
+            "r9ktg-backup-20240101-000000.zlib",
+            "r9ktg-backup-20240103-000000.zlib",
+            "r9ktg-backup-20240102-000000.zlib",
+            "unrelated.txt",
+        ] {
+            fs::write(dir.join(name), b"")?;
+        }
+        assert_eq!(prune_dir(&dir, 2)?, 1);
+        let mut left = fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "r9ktg-backup-20240102-000000.zlib",
+                "r9ktg-backup-20240103-000000.zlib",
+                "unrelated.txt",
+            ]
+        );
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Shared lists of known spam texts chats can subscribe to, so communities
+//! can pool what they know about recurring scams.
+//!
+//! A list is a JSON array of texts. Texts rather than hashes, since hashes
+//! depend on each instance's `hash_key`, hash algorithm and salts. They're
+//! hashed like messages and forbidden in every namespace subscribed to them,
+//! unless admins allowed them, and forgotten again once they leave the list.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use color_eyre::eyre;
+use teloxide::types::ChatId;
+use url::Url;
+
+use crate::{
+    normalize::canonical_text,
+    robot::Robot9000,
+    storage::{unix_now, Entry, Key, Namespace, Post, Status},
+};
+
+/// Largest list accepted, in bytes.
+const MAX_SIZE: usize = 16 << 20;
+
+async fn fetch(client: &reqwest::Client, url: &Url) -> eyre::Result<Vec<String>> {
+    let response = client.get(url.clone()).send().await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_SIZE as u64)
+    {
+        eyre::bail!("blocklist is larger than {MAX_SIZE} bytes");
+    }
+    let body = response.bytes().await?;
+    if body.len() > MAX_SIZE {
+        eyre::bail!("blocklist is larger than {MAX_SIZE} bytes");
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// What a refresh of the blocklists changed.
+#[derive(Debug, Default)]
+pub(crate) struct BlocklistRefresh {
+    pub(crate) forbidden: usize,
+    pub(crate) unforbidden: usize,
+}
+
+impl Robot9000 {
+    /// Namespaces subscribed to each blocklist.
+    fn blocklist_subscriptions(&self) -> eyre::Result<HashMap<Url, HashSet<Namespace>>> {
+        let config = self.config();
+        let mut subscriptions = HashMap::<Url, HashSet<Namespace>>::new();
+        if !config.blocklists.is_empty() {
+            let mut namespaces = HashSet::new();
+            for key in self.chats.iter().keys() {
+                let chat_id = ChatId(i64::from_be_bytes(key?[..].try_into()?));
+                namespaces.insert(self.namespace(chat_id));
+            }
+            for url in &config.blocklists {
+                subscriptions
+                    .entry(url.clone())
+                    .or_default()
+                    .extend(&namespaces);
+            }
+        }
+        for subscription in &config.chat_blocklists {
+            subscriptions
+                .entry(subscription.value().clone())
+                .or_default()
+                .insert(self.namespace(subscription.chat_id()));
+        }
+        Ok(subscriptions)
+    }
+
+    /// Refreshes the blocklists every `blocklist_refresh_interval` seconds.
+    pub(crate) async fn refresh_blocklists_periodically(self) {
+        loop {
+            let config = self.config();
+            let subscribed = !config.blocklists.is_empty() || !config.chat_blocklists.is_empty();
+            // Unsubscribing forgets what the lists forbade.
+            if subscribed || !self.blocklisted.is_empty() {
+                match self.refresh_blocklists().await {
+                    Ok(refresh) => tracing::info!(
+                        forbidden = refresh.forbidden,
+                        unforbidden = refresh.unforbidden,
+                        "refreshed blocklists"
+                    ),
+                    Err(err) => {
+                        tracing::error!(err = format_args!("{err}"), "couldn't refresh blocklists");
+                    }
+                }
+            }
+            let interval = Duration::from_secs(config.blocklist_refresh_interval);
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Fetches every blocklist, forbidding their texts where they're subscribed to
+    /// and forgetting the ones that left them.
+    pub(crate) async fn refresh_blocklists(&self) -> eyre::Result<BlocklistRefresh> {
+        let client = self.config().http_client(reqwest::Client::builder())?;
+        let mut keys = HashSet::new();
+        let mut complete = true;
+        for (url, namespaces) in self.blocklist_subscriptions()? {
+            let texts = match fetch(&client, &url).await {
+                Ok(texts) => texts,
+                Err(err) => {
+                    tracing::warn!(
+                        url = format_args!("{url}"),
+                        err = format_args!("{err}"),
+                        "couldn't fetch blocklist"
+                    );
+                    complete = false;
+                    continue;
+                }
+            };
+            for text in &texts {
+                let text = canonical_text(text, &[]);
+                for &namespace in &namespaces {
+                    keys.insert(self.hash_message(namespace.into(), &text)?);
+                }
+            }
+        }
+
+        let mut refresh = BlocklistRefresh::default();
+        let post = Post {
+            timestamp: unix_now(),
+            message_id: None,
+            poster_id: None,
+        };
+        for &key in &keys {
+            let mut forbidden = false;
+            self.store
+                .update(key, None, &mut |entry| match entry {
+                    // Admins know better than the list.
+                    Some(Entry {
+                        status: Status::Allowed | Status::Forbidden,
+                        ..
+                    }) => None,
+                    entry => {
+                        forbidden = true;
+                        Some(Entry {
+                            status: Status::Forbidden,
+                            repeats_left: None,
+                            ..entry.unwrap_or_else(|| Entry::new(Status::Forbidden, post))
+                        })
+                    }
+                })
+                .await?;
+            if forbidden {
+                self.blocklisted.insert(key.encode(), &[])?;
+                refresh.forbidden += 1;
+            }
+        }
+
+        // Texts of a list that couldn't be fetched stay forbidden until it can be.
+        if !complete {
+            return Ok(refresh);
+        }
+        for raw_key in self.blocklisted.iter().keys() {
+            let raw_key = raw_key?;
+            let key = Key::decode(&raw_key)?;
+            if keys.contains(&key) {
+                continue;
+            }
+            let entry = self.store.peek(key).await?;
+            if entry.is_some_and(|entry| entry.status == Status::Forbidden) {
+                self.store.remove(key).await?;
+                refresh.unforbidden += 1;
+            }
+            self.blocklisted.remove(raw_key)?;
+        }
+        Ok(refresh)
+    }
+}
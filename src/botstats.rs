@@ -0,0 +1,90 @@
+//! `/botstats`, a quick look at how the bot is doing for owners without shell access.
+
+use std::{fs, sync::atomic::Ordering};
+
+use color_eyre::eyre;
+use size_format::SizeFormatterBinary;
+use teloxide::types::{Message, User};
+
+use crate::{
+    api::TelegramApi,
+    health::{check_database, check_store},
+    i18n::Text,
+    robot::{reply, Robot9000},
+    storage::unix_now,
+};
+
+/// Memory the process takes, in bytes, where the OS tells.
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kibibytes = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kibibytes * 1024)
+}
+
+/// Duration like `3d 4h 5m`, down to minutes.
+fn format_uptime(seconds: i64) -> String {
+    let minutes = seconds.max(0) / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+impl Robot9000 {
+    /// Handles `/botstats`.
+    pub(crate) async fn bot_stats(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let uptime = format_uptime(unix_now() - self.started_at);
+            let memory = resident_memory().map_or_else(
+                || "?".to_owned(),
+                |bytes| format!("{}B", SizeFormatterBinary::new(bytes)),
+            );
+            let (mut chats, mut suspended) = (0, 0);
+            for value in self.chats.iter().values() {
+                chats += 1;
+                if value?[..] == [1] {
+                    suspended += 1;
+                }
+            }
+            let updates = self.updates.load(Ordering::Relaxed);
+            let api_errors = self.error_counts.api_errors();
+            let error_rate = format!("{:.1}%", 100.0 * api_errors as f64 / updates.max(1) as f64);
+            let answer = locale.text(Text::BotStats {
+                uptime: &uptime,
+                memory: &memory,
+                chats,
+                suspended,
+                updates,
+                api_errors,
+                error_rate: &error_rate,
+                database: check_database(self).await,
+                storage: check_store(self).await,
+            });
+            reply(bot, message, answer).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_uptime() {
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(2 * 60 * 60 + 5 * 60), "2h 5m");
+        assert_eq!(format_uptime(3 * 24 * 60 * 60 + 60), "3d 0h 1m");
+    }
+}
@@ -0,0 +1,306 @@
+//! Subcommands working with the database directly, without the bot.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use size_format::SizeFormatterBinary;
+use teloxide::{
+    requests::Requester as _,
+    types::{ChatId, ThreadId},
+};
+
+use crate::{
+    activity::{activity_since, day_of, Activity},
+    archive,
+    audit::AuditRecord,
+    config::Config,
+    import::{read_into, ImportFormat, Importer},
+    robot::{hex, Robot9000},
+    storage::{
+        messages_tree, open_tree, read_backup, restore_into, schema_version, unix_now,
+        write_backup, Key, Namespace,
+    },
+};
+
+/// Rebuilds an empty database from audit logs or directories containing them.
+pub async fn replay_audit_logs(config: Config, paths: Vec<PathBuf>) -> eyre::Result<()> {
+    let robot = Robot9000::open(config)?;
+    if !robot.store.is_empty().await? {
+        eyre::bail!(
+            "refusing to replay into non-empty database at {}",
+            robot.config().db_path.display()
+        );
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    files.push((entry.metadata()?.modified()?, entry.path()));
+                }
+            }
+        } else {
+            files.push((fs::metadata(&path)?.modified()?, path));
+        }
+    }
+    // Rotated logs are never written again, so they're older than the current one.
+    files.sort();
+
+    let mut replayed = 0;
+    for (_, path) in &files {
+        let reader = BufReader::new(File::open(path)?);
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str::<AuditRecord>(&line)
+                .wrap_err_with(|| format!("{}:{}", path.display(), line_idx + 1))?;
+            robot.replay(record).await?;
+            replayed += 1;
+        }
+    }
+    robot.flush().await?;
+
+    tracing::info!(
+        files = files.len(),
+        records = replayed,
+        "Replayed audit logs"
+    );
+    Ok(())
+}
+
+/// Imports a chat history file like `/import` does, but without size limits.
+/// `source` is the chat read from Telegram's export of every chat.
+pub async fn import_file(
+    config: Config,
+    chat_id: ChatId,
+    source: ChatId,
+    format: ImportFormat,
+    path: PathBuf,
+) -> eyre::Result<()> {
+    let audit_log = config.open_audit_log()?;
+    let mut robot = Robot9000::open(config)?;
+    robot.audit_log = audit_log;
+    if robot.scopes_topics(chat_id) {
+        eyre::bail!("chat {chat_id} checks its topics separately, which exports don't record");
+    }
+    let importer = Importer::new(robot.clone(), chat_id)?;
+    let file = path.clone();
+    let open = move || {
+        let reader = BufReader::new(File::open(&file)?);
+        let reader = archive::decompress(reader, u64::MAX, format.extension());
+        Ok(reader.map(|reader| Box::new(BufReader::new(reader)) as Box<dyn BufRead>))
+    };
+    let summary = read_into(format, open, unix_now(), source, importer)
+        .await?
+        .wrap_err_with(|| format!("malformed export at {}", path.display()))?
+        .finish()
+        .await?;
+    robot.flush().await?;
+
+    tracing::info!(
+        chat_id = chat_id.0,
+        count = summary.imported,
+        duplicates = summary.duplicates,
+        service = summary.service,
+        unsupported = summary.unsupported,
+        malformed = summary.malformed,
+        "Imported chat history"
+    );
+    Ok(())
+}
+
+/// Prints what would happen to a message, hashing it exactly as the bot does.
+pub async fn check_text(
+    config: Config,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    text: &str,
+) -> eyre::Result<()> {
+    let robot = Robot9000::open(config)?;
+    let scope = robot.topic_scope(chat_id, thread_id);
+    let key = robot.hash_message(scope, text)?;
+    let settings = robot.settings(chat_id)?;
+    println!("Namespace: {}", scope.namespace);
+    println!("Hash: {}", hex(&key.hash));
+    println!("{}", robot.check_hash(&settings, key, unix_now()).await?);
+    Ok(())
+}
+
+/// Checks what loading the configs can't: that tokens look right, that the bot
+/// can write where it keeps files, and, if `online`, that Telegram accepts the tokens.
+/// Prints every problem found and fails if there were any.
+pub async fn check_config(configs: Vec<Config>, online: bool) -> eyre::Result<()> {
+    let mut problems = 0;
+    let mut tokens = HashMap::new();
+    for config in &configs {
+        let bot = match config.bot_name() {
+            Some(name) => format!("bot {name:?}"),
+            None => "main bot".to_owned(),
+        };
+        let mut report = Vec::new();
+        if let Err(err) = config.validate() {
+            report.push(format!("{err:#}"));
+        }
+        if !config.token.is_well_formed() {
+            report.push("token doesn't look like one from @BotFather".to_owned());
+        } else if let Some(other) = tokens.insert(config.token.0.as_str(), bot.clone()) {
+            report.push(format!("token is the same as the one of the {other}"));
+        }
+        // Bots share the database, so its directory is only checked once.
+        let mut dirs = Vec::new();
+        if config.bot_name().is_none() {
+            dirs.push(("database", config.db_path.as_path()));
+            if let Some(parent) = config.redb_path.as_deref().and_then(Path::parent) {
+                dirs.push(("redb storage", parent));
+            }
+        }
+        if let Some(parent) = config.audit_log.as_deref().and_then(Path::parent) {
+            dirs.push(("audit log", parent));
+        }
+        if let Some(backup_dir) = &config.backup_dir {
+            dirs.push(("backup", backup_dir));
+        }
+        for (what, dir) in dirs {
+            if let Err(err) = check_writable(dir) {
+                report.push(format!("{what} directory {}: {err:#}", dir.display()));
+            }
+        }
+        if online && report.is_empty() {
+            match config.bot()?.get_me().await {
+                Ok(me) => println!("{bot}: logs in as @{}", me.username()),
+                Err(err) => report.push(format!("Telegram rejected the token: {err}")),
+            }
+        }
+        if report.is_empty() {
+            println!("{bot}: ok");
+        }
+        for problem in &report {
+            println!("{bot}: {problem}");
+        }
+        problems += report.len();
+    }
+    if problems > 0 {
+        eyre::bail!("found {problems} problem(s) in the config");
+    }
+    Ok(())
+}
+
+/// Creates and removes a file in the directory, or in its closest existing
+/// ancestor if it's still to be created.
+fn check_writable(dir: &Path) -> eyre::Result<()> {
+    let mut existing = dir;
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => existing = parent,
+            _ => {
+                existing = Path::new(".");
+                break;
+            }
+        }
+    }
+    if !existing.is_dir() {
+        eyre::bail!("{} is not a directory", existing.display());
+    }
+    let probe = existing.join(format!(".r9ktg-check-{}", std::process::id()));
+    File::create(&probe).wrap_err("not writable")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// How many recent days `db-stats` sums activity over.
+const ACTIVITY_DAYS: i64 = 7;
+
+/// Prints statistics of the database without migrating it, so it's safe
+/// to run against databases of newer versions too.
+pub fn print_db_stats(config: Config) -> eyre::Result<()> {
+    let db = sled::open(&config.db_path)?;
+    let version = schema_version(&db)?;
+    println!("Schema version: {version}");
+    println!(
+        "Size on disk: {}B",
+        SizeFormatterBinary::new(db.size_on_disk()?)
+    );
+    let bot_name = config.bot_name();
+    let messages = messages_tree(&db, bot_name)?;
+    println!("Entries: {}", messages.len());
+    // Keys only have namespaces since schema v3.
+    if version < 3 {
+        return Ok(());
+    }
+    let legacy = open_tree(&db, bot_name, "legacy")?.len();
+    if legacy > 0 {
+        println!("Entries from before namespaces, not adopted yet: {legacy}");
+    }
+
+    let mut counts = HashMap::<Namespace, usize>::new();
+    for key in messages.iter().keys() {
+        *counts.entry(Key::decode(&key?)?.namespace).or_default() += 1;
+    }
+    let mut counts = Vec::from_iter(counts);
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    for (namespace, count) in counts {
+        println!("{namespace}: {count}");
+    }
+
+    let mut activity = BTreeMap::<ChatId, Activity>::new();
+    let since = day_of(unix_now()) - (ACTIVITY_DAYS - 1);
+    for item in activity_since(&open_tree(&db, bot_name, "activity")?, since) {
+        let (chat_id, _, day) = item?;
+        *activity.entry(chat_id).or_default() += day;
+    }
+    if !activity.is_empty() {
+        println!("Activity over the last {ACTIVITY_DAYS} days:");
+    }
+    for (chat_id, activity) in activity {
+        println!(
+            "{chat_id}: {} seen, {} unique, {} deleted",
+            activity.seen, activity.unique, activity.deleted
+        );
+    }
+    Ok(())
+}
+
+/// Writes a backup of the whole database to `path`, in the same format as `/backup`.
+/// The bot must be stopped, since it keeps the database locked.
+pub fn write_backup_file(config: Config, path: &Path) -> eyre::Result<()> {
+    if !config.db_path.exists() {
+        eyre::bail!("no database at {}", config.db_path.display());
+    }
+    let db = sled::open(&config.db_path)?;
+    let backup = write_backup(&db)?;
+    fs::write(path, &backup)
+        .wrap_err_with(|| format!("couldn't write backup to {}", path.display()))?;
+    tracing::info!(
+        trees = db.tree_names().len(),
+        size = backup.len(),
+        "Wrote backup"
+    );
+    Ok(())
+}
+
+/// Restores an empty database from a backup made by `/backup` or `backup`.
+pub fn restore_backup(config: Config, path: &Path) -> eyre::Result<()> {
+    let db = sled::open(&config.db_path)?;
+    // A fresh database only has the default tree.
+    if !db.is_empty() || db.tree_names().len() > 1 {
+        eyre::bail!(
+            "refusing to restore into non-empty database at {}",
+            config.db_path.display()
+        );
+    }
+    let backup = read_backup(&fs::read(path)?)?;
+    let (trees, schema_version) = (backup.trees.len(), backup.schema_version);
+    let entries = restore_into(&db, backup)?;
+    // Backups made by older versions are migrated on the next start.
+    tracing::info!(trees, entries, schema_version, "Restored backup");
+    Ok(())
+}
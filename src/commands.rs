@@ -0,0 +1,1670 @@
+//! Bot commands and the buttons they send.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use futures::{StreamExt as _, TryStreamExt as _};
+use serde::Serialize;
+use size_format::SizeFormatterBinary;
+use teloxide::{
+    types::{
+        BotCommand, CallbackQuery, Chat, ChatId, InlineKeyboardButton, InlineKeyboardMarkup,
+        InputFile, Message, User, UserId,
+    },
+    utils::command::{BotCommands, ParseError},
+};
+
+use crate::{
+    api::{SendOptions, TelegramApi},
+    audit::{hex_hash, AuditEvent},
+    backups::backup_file_name,
+    config::Config,
+    i18n::{Locale, Text},
+    normalize::message_text,
+    policy::{Appeal, Setting},
+    robot::{
+        describe_chat, describe_user, explicit_reply, format_timestamp, hex, is_anonymous_admin,
+        reply, snippet, Robot9000,
+    },
+    storage::{unix_now, write_backup, Entry, Key, Namespace, Status},
+};
+
+/// Parses durations like `90s`, `30m`, `1h30m` or `2d` into seconds.
+fn parse_duration(s: &str) -> eyre::Result<u64> {
+    if s.is_empty() {
+        eyre::bail!("no duration given");
+    }
+    let mut seconds = 0_u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (number, tail) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+        let (unit, tail) = tail.split_at(
+            tail.find(|c: char| c.is_ascii_digit())
+                .unwrap_or(tail.len()),
+        );
+        let number = number
+            .parse::<u64>()
+            .wrap_err_with(|| format!("invalid duration: {s:?}"))?;
+        let unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => eyre::bail!("unknown unit {unit:?}, expected s, m, h or d"),
+        };
+        seconds = seconds.saturating_add(number.saturating_mul(unit));
+        rest = tail;
+    }
+    Ok(seconds)
+}
+
+/// Everything known in a namespace, as sent by `/export`.
+#[derive(Debug, Serialize)]
+struct Export {
+    chat_id: ChatId,
+    shared: bool,
+    /// Needed to hash new messages the same way, if the namespace was ever rotated.
+    salt: Option<String>,
+    exported_at: i64,
+    entries: Vec<ExportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportEntry {
+    #[serde(with = "hex_hash")]
+    hash: [u8; 16],
+    status: Status,
+    first_seen: i64,
+    count: u32,
+    first_message_id: Option<i32>,
+    poster_id: Option<UserId>,
+    /// Only set for messages allowed a number of times.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeats_left: Option<u32>,
+    /// Only known in chats that keep texts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// How many messages `/search` lists.
+const SEARCH_LIMIT: usize = 10;
+
+/// How many messages `/top` lists.
+const TOP_LIMIT: usize = 10;
+
+/// Admin command sent as a reply to the message it's about.
+#[derive(Debug, Clone, Copy)]
+pub enum ReplyCommand {
+    /// Allows every copy, or only the given number of them.
+    Allow(Option<u32>),
+    Forbid,
+    Forget,
+    Check,
+    /// Explains why the message is or isn't a duplicate.
+    Why,
+}
+
+/// Command sent to the bot, with or without an `@botname` suffix,
+/// listed by /help in this order.
+#[derive(Debug, Clone, BotCommands)]
+#[command(rename_rule = "snake_case")]
+pub enum Command {
+    #[command(aliases = ["start"], description = "show this message")]
+    Help,
+    #[command(description = "show settings for this chat")]
+    Settings,
+    #[command(description = "change a setting for this chat, or reset it with no value")]
+    Set(String),
+    #[command(description = "start enforcing in a newly added group")]
+    Activate,
+    #[command(description = "stop deleting duplicates for a while, like /pause 1h")]
+    Pause(String),
+    #[command(description = "delete duplicates again before the pause ends")]
+    Resume,
+    #[command(
+        description = "(in reply) never delete copies of a message, \
+                       or only the next few, like /allow 5",
+        parse_with = parse_allow
+    )]
+    Allow(Option<u32>),
+    #[command(description = "(in reply) always delete copies of a message")]
+    Forbid,
+    #[command(description = "(in reply) treat the next copy of a message as the first one")]
+    Forget,
+    #[command(description = "(in reply) show what's known about a message")]
+    Check,
+    #[command(description = "(in reply) explain why a message is or isn't a duplicate")]
+    Why,
+    #[command(description = "(in reply) stop checking messages from a user")]
+    Exempt,
+    #[command(description = "(in reply) check messages from a user again")]
+    Unexempt,
+    #[command(
+        description = "(in reply) let a user /allow, /forbid and /check without being an admin"
+    )]
+    Mod,
+    #[command(description = "(in reply) take back what /mod gave a user")]
+    Unmod,
+    #[command(description = "lift the mutes given for duplicates here, or (in reply) a user's")]
+    Amnesty,
+    #[command(description = "check drafts against this chat by typing them after the bot's name")]
+    Precheck,
+    #[command(description = "forget every message seen in this chat")]
+    Reset,
+    #[command(description = "change the salt, making old hashes useless")]
+    Rotate,
+    #[command(description = "send this chat's entries as JSON in private")]
+    Export,
+    #[command(description = "send this chat's daily statistics and top offenders as CSV")]
+    StatsExport,
+    #[command(description = "send this chat's settings, common phrases and exemptions as JSON")]
+    SettingsExport,
+    #[command(description = "(in reply to a file from /settings_export) apply its settings here")]
+    SettingsImport,
+    #[command(description = "find known messages containing some text, if this chat keeps texts")]
+    Search(String),
+    #[command(description = "list the messages people tried to post the most times")]
+    Top,
+    #[command(description = "list phrases never checked, or /phrases add|remove <phrase>")]
+    Phrases(String),
+    #[command(
+        description = "(as a document caption) import entries from an export or a text file"
+    )]
+    Import(String),
+    #[command(description = "(as a document caption) show what importing an export would do")]
+    Simulate(String),
+    #[command(description = "(owners only) send a backup of the whole database")]
+    Backup,
+    #[command(
+        description = "(owners only, in reply to a backup) replace the database on the next start"
+    )]
+    Restore,
+    #[command(
+        description = "(owners only) copy another chat's allowed and forbidden messages, \
+                      or everything with /sync_from <chat_id> all"
+    )]
+    SyncFrom(String),
+    #[command(description = "(owners only) forget expired entries and reclaim disk space now")]
+    Gc,
+    #[command(
+        rename = "dbsize",
+        description = "(owners only) show the size of the database and how many entries it has"
+    )]
+    DbSize,
+    #[command(
+        rename = "botstats",
+        description = "(owners only) show uptime, memory, load and whether storage works"
+    )]
+    BotStats,
+    #[command(description = "(owners only) reload the config file")]
+    Reload,
+}
+
+/// Commands only owners can run, left out of the autocomplete list.
+const OWNER_COMMANDS: &[&str] = &[
+    "backup",
+    "restore",
+    "sync_from",
+    "gc",
+    "dbsize",
+    "botstats",
+    "reload",
+];
+
+impl Command {
+    /// Commands offered in the autocomplete list, without the owner ones.
+    pub(crate) fn autocompleted() -> Vec<BotCommand> {
+        Command::bot_commands()
+            .into_iter()
+            .filter(|command| !OWNER_COMMANDS.contains(&command.command.trim_start_matches('/')))
+            .collect()
+    }
+}
+
+/// Parses the number of copies `/allow` takes, if there's one.
+fn parse_allow(args: String) -> Result<(Option<u32>,), ParseError> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Ok((None,)),
+        [repeats] => match repeats.parse() {
+            Ok(repeats) if repeats < u32::MAX => Ok((Some(repeats),)),
+            _ => Err(ParseError::IncorrectFormat(
+                format!("invalid number of copies: {repeats:?}").into(),
+            )),
+        },
+        ref found => Err(ParseError::TooManyArguments {
+            expected: 1,
+            found: found.len(),
+            message: args.clone(),
+        }),
+    }
+}
+
+/// Whether each user is an admin of each chat, and when that was checked.
+type AdminStatuses = HashMap<(ChatId, UserId), (bool, Instant)>;
+
+/// When each user was denied admin commands in each chat, oldest first.
+type Denials = HashMap<(ChatId, UserId), VecDeque<i64>>;
+
+/// Recently checked admin statuses, so busy chats don't ask Telegram every time,
+/// and recently denied commands, so people spamming them are ignored.
+#[derive(Clone, Default)]
+pub(crate) struct AdminCache {
+    statuses: Arc<Mutex<AdminStatuses>>,
+    denials: Arc<Mutex<Denials>>,
+}
+
+impl AdminCache {
+    /// How many statuses are kept before expired ones are dropped.
+    const PRUNE_AT: usize = 10_000;
+
+    fn lock(&self) -> MutexGuard<'_, AdminStatuses> {
+        self.statuses.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn get(&self, chat_id: ChatId, user_id: UserId, ttl: Duration) -> Option<bool> {
+        self.lock()
+            .get(&(chat_id, user_id))
+            .filter(|(_, checked_at)| checked_at.elapsed() < ttl)
+            .map(|&(is_admin, _)| is_admin)
+    }
+
+    fn insert(&self, chat_id: ChatId, user_id: UserId, is_admin: bool, ttl: Duration) {
+        let mut statuses = self.lock();
+        if statuses.len() >= Self::PRUNE_AT {
+            statuses.retain(|_, (_, checked_at)| checked_at.elapsed() < ttl);
+        }
+        statuses.insert((chat_id, user_id), (is_admin, Instant::now()));
+    }
+
+    /// Forgets a status that just changed, along with the commands denied before.
+    pub(crate) fn invalidate(&self, chat_id: ChatId, user_id: UserId) {
+        self.lock().remove(&(chat_id, user_id));
+        self.lock_denials().remove(&(chat_id, user_id));
+    }
+
+    fn lock_denials(&self) -> MutexGuard<'_, Denials> {
+        self.denials.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records a denied command, returning how many were denied within the window.
+    fn deny(&self, chat_id: ChatId, user_id: UserId, now: i64, window: i64) -> usize {
+        let mut denials = self.lock_denials();
+        if denials.len() >= Self::PRUNE_AT {
+            denials.retain(|_, times| times.back().is_some_and(|&at| now - at < window));
+        }
+        let times = denials.entry((chat_id, user_id)).or_default();
+        while times.front().is_some_and(|&at| now - at >= window) {
+            times.pop_front();
+        }
+        times.push_back(now);
+        times.len()
+    }
+
+    /// Whether the commands of the user are ignored for now, after being denied
+    /// within the cooldown, or too many times within the window.
+    pub(crate) fn is_ignored(
+        &self,
+        config: &Config,
+        chat_id: ChatId,
+        user_id: UserId,
+        now: i64,
+    ) -> bool {
+        let window = i64::try_from(config.denied_command_window).unwrap_or(i64::MAX);
+        let cooldown = i64::try_from(config.command_cooldown).unwrap_or(i64::MAX);
+        let denials = self.lock_denials();
+        let Some(times) = denials.get(&(chat_id, user_id)) else {
+            return false;
+        };
+        let recent = times.iter().filter(|&&at| now - at < window).count();
+        times.back().is_some_and(|&at| now - at < cooldown) || recent >= config.denied_command_limit
+    }
+}
+
+impl Robot9000 {
+    /// Checks whether the user can moderate the chat. Owners can moderate any chat.
+    pub(crate) async fn is_admin(
+        config: &Config,
+        admins: &AdminCache,
+        bot: &dyn TelegramApi,
+        chat: &Chat,
+        user: &User,
+    ) -> eyre::Result<bool> {
+        if chat.is_private() {
+            return Ok(true);
+        }
+        Self::is_chat_admin(config, admins, bot, chat.id, user.id).await
+    }
+
+    /// Like [`Self::is_admin`], for a group known only by its id.
+    pub(crate) async fn is_chat_admin(
+        config: &Config,
+        admins: &AdminCache,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> eyre::Result<bool> {
+        if config.owners.contains(&user_id.0) {
+            return Ok(true);
+        }
+        let ttl = Duration::from_secs(config.admin_cache_ttl);
+        if let Some(is_admin) = admins.get(chat_id, user_id, ttl) {
+            return Ok(is_admin);
+        }
+        let is_admin = bot
+            .get_chat_member(chat_id, user_id)
+            .await?
+            .can_delete_messages();
+        if !ttl.is_zero() {
+            admins.insert(chat_id, user_id, is_admin, ttl);
+        }
+        Ok(is_admin)
+    }
+
+    pub(crate) async fn ensure_admin<Fut>(
+        config: &Config,
+        admins: &AdminCache,
+        locale: &Locale,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        f: Fut,
+    ) -> eyre::Result<()>
+    where
+        Fut: Future<Output = eyre::Result<()>>,
+    {
+        if !is_anonymous_admin(message)
+            && !Self::is_admin(config, admins, bot, &message.chat, user).await?
+        {
+            let window = i64::try_from(config.denied_command_window).unwrap_or(i64::MAX);
+            let denied = admins.deny(message.chat.id, user.id, unix_now(), window);
+            tracing::info!(
+                user_id = user.id.0,
+                denied,
+                "someone tried to run admin command"
+            );
+            reply(bot, message, locale.text(Text::NiceTry)).await
+        } else {
+            f.await
+        }
+    }
+
+    pub(crate) async fn ensure_owner<Fut>(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        f: Fut,
+    ) -> eyre::Result<()>
+    where
+        Fut: Future<Output = eyre::Result<()>>,
+    {
+        let config = self.config();
+        if !config.owners.contains(&user.id.0) {
+            let window = i64::try_from(config.denied_command_window).unwrap_or(i64::MAX);
+            let denied = self
+                .admins
+                .deny(message.chat.id, user.id, unix_now(), window);
+            tracing::info!(
+                user_id = user.id.0,
+                denied,
+                "someone tried to run owner command"
+            );
+            let locale = self.chat_locale(message.chat.id)?;
+            reply(bot, message, locale.text(Text::NiceTry)).await
+        } else {
+            f.await
+        }
+    }
+
+    async fn reply_command(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        reply_to: &Message,
+        user: &User,
+        command: ReplyCommand,
+    ) -> eyre::Result<()> {
+        let scope = self.scope(reply_to, &self.settings(reply_to.chat.id)?);
+        tracing::info!(
+            message_id = reply_to.id.0,
+            namespace = format_args!("{}", scope.namespace),
+            command = format_args!("{command:?}"),
+            "running reply command"
+        );
+        let config = self.config();
+        let admins = self.admins.clone();
+        let locale = self.chat_locale(message.chat.id)?;
+        // Moderators can do everything but forgetting messages.
+        let moderator = !matches!(command, ReplyCommand::Forget)
+            && self.is_moderator(message.chat.id, user.id)?;
+        let run = async {
+            let Some(reply_to_text) = message_text(reply_to) else {
+                return reply(bot, message, locale.text(Text::UnsupportedMessage)).await;
+            };
+            let key = self.hash_message(scope, &reply_to_text)?;
+            let hash = key.hash;
+            let shared = scope.namespace == Namespace::Shared;
+            let result = match command {
+                ReplyCommand::Allow(repeats) => self
+                    .set_hash_status(key, Status::Allowed, repeats, reply_to.into())
+                    .await
+                    .map(|()| match repeats {
+                        Some(repeats) => locale.text(Text::AllowedRepeats { repeats, shared }),
+                        None => locale.text(Text::Allowed { shared }),
+                    }),
+                ReplyCommand::Forbid => self
+                    .set_hash_status(key, Status::Forbidden, None, reply_to.into())
+                    .await
+                    .map(|()| locale.text(Text::Forbidden { shared })),
+                ReplyCommand::Forget => self.store.remove(key).await.and_then(|removed| {
+                    self.forget_text(message.chat.id, key)?;
+                    Ok(if removed {
+                        locale.text(Text::Forgot { shared })
+                    } else {
+                        locale.text(Text::DidntKnow)
+                    })
+                }),
+                ReplyCommand::Check => match self.settings(message.chat.id) {
+                    Ok(settings) => {
+                        self.check_hash(&settings, key, message.date.timestamp())
+                            .await
+                    }
+                    Err(err) => Err(err),
+                },
+                ReplyCommand::Why => match self.settings(reply_to.chat.id) {
+                    Ok(settings) => {
+                        self.explain(reply_to, &settings, message.date.timestamp())
+                            .await
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            let confirmation = match result {
+                Ok(confirmation) => confirmation,
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "reply command failed");
+                    return reply(bot, message, locale.text(Text::SomethingWentWrong)).await;
+                }
+            };
+
+            let chat_id = message.chat.id;
+            let admin_id = user.id;
+            let (event, action) = match command {
+                ReplyCommand::Allow(repeats) => (
+                    AuditEvent::Allow {
+                        chat_id,
+                        hash,
+                        admin_id,
+                        repeats,
+                    },
+                    "Allowed",
+                ),
+                ReplyCommand::Forbid => (
+                    AuditEvent::Forbid {
+                        chat_id,
+                        hash,
+                        admin_id,
+                    },
+                    "Forbidden",
+                ),
+                ReplyCommand::Forget => (
+                    AuditEvent::Forget {
+                        chat_id,
+                        hash,
+                        admin_id,
+                    },
+                    "Forgot",
+                ),
+                ReplyCommand::Check | ReplyCommand::Why => {
+                    return reply(bot, message, confirmation).await
+                }
+            };
+            self.audit(event);
+            let event = format!(
+                "{action} in {}\nAdmin: {}\nHash: {}\nText: {}",
+                describe_chat(&message.chat),
+                describe_user(user),
+                hex(&hash),
+                snippet(&reply_to_text),
+            );
+            self.log_event(bot, event).await;
+            reply(bot, message, confirmation).await
+        };
+        if moderator {
+            run.await
+        } else {
+            Self::ensure_admin(&config, &admins, &locale, bot, message, user, run).await
+        }
+    }
+
+    pub(crate) async fn command(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        command: Command,
+    ) -> eyre::Result<()> {
+        let reply_to = explicit_reply(message);
+        match command {
+            Command::Help => {
+                let help = Command::descriptions().username(&self.username).to_string();
+                reply(bot, message, help).await
+            }
+            Command::Settings => self.show_settings(bot, message).await,
+            Command::Set(args) => self.set(bot, message, user, args.trim()).await,
+            Command::Activate => self.activate(bot, message, user).await,
+            Command::Pause(args) => self.pause(bot, message, user, args.trim()).await,
+            Command::Resume => self.resume(bot, message, user).await,
+            Command::Reset => self.request_reset(bot, message, user).await,
+            Command::Rotate => self.rotate(bot, message, user).await,
+            Command::Export => self.export(bot, message, user).await,
+            Command::StatsExport => self.stats_export(bot, message, user).await,
+            Command::SettingsExport => self.settings_export(bot, message, user).await,
+            Command::SettingsImport => self.settings_import(bot, message, user).await,
+            Command::Search(query) => self.search(bot, message, user, query.trim()).await,
+            Command::Top => self.top(bot, message, user).await,
+            Command::Precheck => self.precheck(bot, message, user).await,
+            Command::Amnesty => self.amnesty(bot, message, user).await,
+            Command::Phrases(args) => self.phrases(bot, message, user, args.trim()).await,
+            Command::Backup => self.backup(bot, message, user).await,
+            Command::Restore => self.restore(bot, message, user).await,
+            Command::SyncFrom(args) => self.sync_from(bot, message, user, args.trim()).await,
+            Command::Gc => self.gc(bot, message, user).await,
+            Command::DbSize => self.db_size(bot, message, user).await,
+            Command::BotStats => self.bot_stats(bot, message, user).await,
+            Command::Reload => self.reload(bot, message, user).await,
+            Command::Import(_) | Command::Simulate(_) => {
+                let locale = self.chat_locale(message.chat.id)?;
+                reply(bot, message, locale.text(Text::ImportUsage)).await
+            }
+            Command::Exempt
+            | Command::Unexempt
+            | Command::Mod
+            | Command::Unmod
+            | Command::Allow(_)
+            | Command::Forbid
+            | Command::Forget
+            | Command::Check
+            | Command::Why
+                if reply_to.is_none() =>
+            {
+                let locale = self.chat_locale(message.chat.id)?;
+                reply(bot, message, locale.text(Text::ReplyRequired)).await
+            }
+            Command::Exempt => {
+                self.exempt(bot, message, reply_to.unwrap(), user, true)
+                    .await
+            }
+            Command::Unexempt => {
+                self.exempt(bot, message, reply_to.unwrap(), user, false)
+                    .await
+            }
+            Command::Mod => {
+                self.appoint(bot, message, reply_to.unwrap(), user, true)
+                    .await
+            }
+            Command::Unmod => {
+                self.appoint(bot, message, reply_to.unwrap(), user, false)
+                    .await
+            }
+            Command::Allow(repeats) => {
+                let command = ReplyCommand::Allow(repeats);
+                self.reply_command(bot, message, reply_to.unwrap(), user, command)
+                    .await
+            }
+            Command::Forbid => {
+                let command = ReplyCommand::Forbid;
+                self.reply_command(bot, message, reply_to.unwrap(), user, command)
+                    .await
+            }
+            Command::Forget => {
+                let command = ReplyCommand::Forget;
+                self.reply_command(bot, message, reply_to.unwrap(), user, command)
+                    .await
+            }
+            Command::Check => {
+                let command = ReplyCommand::Check;
+                self.reply_command(bot, message, reply_to.unwrap(), user, command)
+                    .await
+            }
+            Command::Why => {
+                let command = ReplyCommand::Why;
+                self.reply_command(bot, message, reply_to.unwrap(), user, command)
+                    .await
+            }
+        }
+    }
+
+    /// Asks for confirmation before forgetting everything known in the chat.
+    async fn request_reset(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let namespace = self.namespace(message.chat.id);
+                let (mut known, mut decided) = (0, 0);
+                let mut entries = self.store.entries(namespace);
+                while let Some(item) = entries.next().await {
+                    let (_, entry) = item?;
+                    known += 1;
+                    if entry.status != Status::Seen {
+                        decided += 1;
+                    }
+                }
+                let text = locale.text(Text::ResetPrompt {
+                    known,
+                    decided,
+                    shared: namespace == Namespace::Shared,
+                });
+                let options = SendOptions {
+                    reply_to: Some(message.id),
+                    markup: Some(InlineKeyboardMarkup::new([[
+                        InlineKeyboardButton::callback(
+                            locale.text(Text::ResetButton),
+                            "reset confirm",
+                        ),
+                        InlineKeyboardButton::callback(
+                            locale.text(Text::CancelButton),
+                            "reset cancel",
+                        ),
+                    ]])),
+                    ..SendOptions::default()
+                };
+                bot.send_message(message.chat.id, text, options).await?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn rotate(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let namespace = self.namespace(message.chat.id);
+                tracing::info!(
+                    user_id = user.id.0,
+                    namespace = format_args!("{namespace}"),
+                    "rotating salt"
+                );
+                let salt = self.rotate_salt(namespace)?;
+                self.audit(AuditEvent::Rotate {
+                    chat_id: message.chat.id,
+                    admin_id: user.id,
+                    salt,
+                });
+                let event = format!(
+                    "Rotated salt in {}\nAdmin: {}",
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                let confirmation = locale.text(Text::Rotated {
+                    shared: namespace == Namespace::Shared,
+                });
+                reply(bot, message, confirmation).await
+            },
+        )
+        .await
+    }
+
+    async fn export(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let namespace = self.namespace(message.chat.id);
+                let mut texts = if self.settings(message.chat.id)?.retain_texts {
+                    self.retained_texts(message.chat.id)?
+                } else {
+                    HashMap::new()
+                };
+                let entries = self
+                    .store
+                    .entries(namespace)
+                    .map_ok(|(key, entry)| ExportEntry {
+                        hash: key.hash,
+                        status: entry.status,
+                        first_seen: entry.first_seen,
+                        count: entry.count,
+                        first_message_id: entry.first_message_id.map(|id| id.0),
+                        poster_id: entry.poster_id,
+                        repeats_left: entry.repeats_left,
+                        text: texts
+                            .remove(&key)
+                            .filter(|retained| retained.first_seen == entry.first_seen)
+                            .map(|retained| retained.text),
+                    })
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let export = Export {
+                    chat_id: message.chat.id,
+                    shared: namespace == Namespace::Shared,
+                    salt: self.salts.get(namespace.prefix())?.map(|salt| hex(&salt)),
+                    exported_at: unix_now(),
+                    entries,
+                };
+                tracing::info!(
+                    user_id = user.id.0,
+                    entries = export.entries.len(),
+                    "exporting known messages"
+                );
+                let file = InputFile::memory(serde_json::to_vec_pretty(&export)?)
+                    .file_name(format!("r9ktg-export-{}.json", message.chat.id));
+                // The salt is what keeps hashes from being matched against
+                // guessed texts, so it's not posted in the chat.
+                if let Err(err) = bot.send_document(user.id.into(), file).await {
+                    tracing::info!(
+                        user_id = user.id.0,
+                        err = format_args!("{err}"),
+                        "couldn't send export",
+                    );
+                    return reply(bot, message, locale.text(Text::CantMessageYou)).await;
+                }
+                reply(bot, message, locale.text(Text::ExportSent)).await
+            },
+        )
+        .await
+    }
+
+    /// Handles `/stats_export`, sending the chat's statistics for spreadsheets.
+    async fn stats_export(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let csv = self.stats_csv(message.chat.id, unix_now())?;
+                tracing::info!(user_id = user.id.0, "exporting statistics");
+                let file = InputFile::memory(csv)
+                    .file_name(format!("r9ktg-stats-{}.csv", message.chat.id));
+                bot.send_document(message.chat.id, file).await
+            },
+        )
+        .await
+    }
+
+    /// Handles `/search <text>`, listing known messages of the chat containing it.
+    async fn search(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        query: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                if !self.settings(message.chat.id)?.retain_texts {
+                    return reply(bot, message, locale.text(Text::TextsNotRetained)).await;
+                }
+                if query.is_empty() {
+                    return reply(bot, message, locale.text(Text::SearchUsage)).await;
+                }
+                let found = self
+                    .search_texts(message.chat.id, query, SEARCH_LIMIT)
+                    .await?;
+                if found.is_empty() {
+                    return reply(bot, message, locale.text(Text::NothingFound)).await;
+                }
+                let answer = found
+                    .iter()
+                    .map(|(text, entry)| {
+                        locale.text(Text::SearchResult {
+                            text: &snippet(text),
+                            count: entry.count,
+                            first_seen: &format_timestamp(entry.first_seen),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                reply(bot, message, answer).await
+            },
+        )
+        .await
+    }
+
+    /// Handles `/top`, listing the messages posted the most times,
+    /// with their texts if the chat keeps them.
+    async fn top(&self, bot: &dyn TelegramApi, message: &Message, user: &User) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let mut top = self
+                    .store
+                    .entries(self.namespace(message.chat.id))
+                    .try_filter(|(_, entry)| futures::future::ready(entry.count > 1))
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                if top.is_empty() {
+                    return reply(bot, message, locale.text(Text::NothingRepeated)).await;
+                }
+                top.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.count));
+                top.truncate(TOP_LIMIT);
+                let mut texts = if self.settings(message.chat.id)?.retain_texts {
+                    self.retained_texts(message.chat.id)?
+                } else {
+                    HashMap::new()
+                };
+                let answer = top
+                    .iter()
+                    .map(|(key, entry)| {
+                        let first_seen = format_timestamp(entry.first_seen);
+                        match texts.remove(key).filter(|text| text.belongs_to(entry)) {
+                            Some(retained) => locale.text(Text::SearchResult {
+                                text: &snippet(&retained.text),
+                                count: entry.count,
+                                first_seen: &first_seen,
+                            }),
+                            None => locale.text(Text::TopHash {
+                                hash: &hex(&key.hash)[..8],
+                                count: entry.count,
+                                first_seen: &first_seen,
+                            }),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                reply(bot, message, answer).await
+            },
+        )
+        .await
+    }
+
+    /// Lists the common phrases of the chat, or adds or removes one.
+    async fn phrases(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let (action, phrase) = args.split_once(' ').unwrap_or((args, ""));
+                let common = match action {
+                    "" => {
+                        let phrases = self.common_phrases(message.chat.id)?;
+                        let text = if phrases.is_empty() {
+                            locale.text(Text::NoCommonPhrases)
+                        } else {
+                            locale.text(Text::CommonPhrases {
+                                phrases: &phrases.join(", "),
+                            })
+                        };
+                        return reply(bot, message, text).await;
+                    }
+                    "add" => true,
+                    "remove" => false,
+                    _ => return reply(bot, message, locale.text(Text::PhrasesUsage)).await,
+                };
+                let Ok(phrase) = self.set_common_phrase(message.chat.id, phrase, common) else {
+                    return reply(bot, message, locale.text(Text::PhrasesUsage)).await;
+                };
+                tracing::info!(
+                    admin_id = user.id.0,
+                    phrase = format_args!("{phrase:?}"),
+                    common,
+                    "changing common phrase"
+                );
+                self.audit(AuditEvent::Phrase {
+                    chat_id: message.chat.id,
+                    admin_id: user.id,
+                    phrase: phrase.clone(),
+                    common,
+                });
+                let event = format!(
+                    "{} common phrase {phrase:?} in {}\nAdmin: {}",
+                    if common { "Added" } else { "Removed" },
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                let confirmation = if common {
+                    Text::PhraseAdded { phrase: &phrase }
+                } else {
+                    Text::PhraseRemoved { phrase: &phrase }
+                };
+                reply(bot, message, locale.text(confirmation)).await
+            },
+        )
+        .await
+    }
+
+    /// Sends a backup of the whole database to the owner or the backup chat.
+    async fn backup(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let db = self.db.clone();
+            let backup = tokio::task::spawn_blocking(move || write_backup(&db)).await??;
+            tracing::info!(user_id = user.id.0, size = backup.len(), "made a backup");
+            let chat_id = self
+                .config()
+                .backup_chat_id
+                .map_or(ChatId::from(user.id), ChatId);
+            let file = InputFile::memory(backup).file_name(backup_file_name());
+            if let Err(err) = bot.send_document(chat_id, file).await {
+                tracing::error!(err = format_args!("{err}"), "couldn't send backup");
+                return reply(bot, message, locale.text(Text::BackupFailed)).await;
+            }
+            if chat_id != message.chat.id {
+                reply(bot, message, locale.text(Text::BackupSent)).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Runs the periodic maintenance right away, reporting what it freed.
+    ///
+    /// sled has no explicit compaction, but flushing lets it reuse
+    /// the segments freed by the removals.
+    async fn gc(&self, bot: &dyn TelegramApi, message: &Message, user: &User) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let size_before = self.db.size_on_disk()?;
+            let removed = self.maintain().await;
+            self.flush().await?;
+            let size_after = self.db.size_on_disk()?;
+            let reclaimed = size_before.saturating_sub(size_after);
+            tracing::info!(user_id = user.id.0, removed, reclaimed, "collected garbage");
+            let reclaimed = SizeFormatterBinary::new(reclaimed).to_string();
+            let size = SizeFormatterBinary::new(size_after).to_string();
+            let answer = locale.text(Text::GcDone {
+                removed,
+                reclaimed: &reclaimed,
+                size: &size,
+            });
+            reply(bot, message, answer).await
+        })
+        .await
+    }
+
+    /// Shows how much space the database takes and how many messages are known,
+    /// in total and in the chat.
+    async fn db_size(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let size = SizeFormatterBinary::new(self.db.size_on_disk()?).to_string();
+            let entries = self.store.len().await?;
+            let namespace = self.namespace(message.chat.id);
+            let chat_entries = self
+                .store
+                .entries(namespace)
+                .try_fold(0, |count, _| async move { Ok(count + 1) })
+                .await?;
+            let answer = locale.text(Text::DbSize {
+                size: &size,
+                entries,
+                chat_entries,
+                shared: namespace == Namespace::Shared,
+            });
+            reply(bot, message, answer).await
+        })
+        .await
+    }
+
+    async fn activate(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                if self
+                    .pending_chats
+                    .remove(message.chat.id.0.to_be_bytes())?
+                    .is_none()
+                {
+                    return reply(bot, message, locale.text(Text::AlreadyActive)).await;
+                }
+                tracing::info!(user_id = user.id.0, "activated");
+                let event = format!(
+                    "Activated in {}\nAdmin: {}",
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                reply(bot, message, locale.text(Text::Activated)).await
+            },
+        )
+        .await
+    }
+
+    /// Handles `/pause <duration>`, tolerating duplicates until it passes.
+    async fn pause(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let duration = match parse_duration(args) {
+                    Ok(duration) => duration,
+                    Err(err) => {
+                        let err = err.to_string();
+                        return reply(bot, message, locale.text(Text::PauseUsage { err: &err }))
+                            .await;
+                    }
+                };
+                let until = message
+                    .date
+                    .timestamp()
+                    .saturating_add(duration.try_into().unwrap_or(i64::MAX));
+                tracing::info!(user_id = user.id.0, until, "pausing enforcement");
+                self.set_pause(message.chat.id, Some(until))?;
+                self.audit(AuditEvent::Pause {
+                    chat_id: message.chat.id,
+                    admin_id: user.id,
+                    until,
+                });
+                let until = format_timestamp(until);
+                let event = format!(
+                    "Paused in {} until {until}\nAdmin: {}",
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                reply(bot, message, locale.text(Text::Paused { until: &until })).await
+            },
+        )
+        .await
+    }
+
+    async fn resume(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                if !self.is_paused(message.chat.id, message.date.timestamp())? {
+                    return reply(bot, message, locale.text(Text::NotPaused)).await;
+                }
+                tracing::info!(user_id = user.id.0, "resuming enforcement");
+                self.set_pause(message.chat.id, None)?;
+                self.audit(AuditEvent::Resume {
+                    chat_id: message.chat.id,
+                    admin_id: user.id,
+                });
+                let event = format!(
+                    "Unpaused in {}\nAdmin: {}",
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                reply(bot, message, locale.text(Text::Unpaused)).await
+            },
+        )
+        .await
+    }
+
+    async fn reload(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            match self.reload_config() {
+                Ok(()) => reply(bot, message, locale.text(Text::Reloaded)).await,
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "couldn't reload config");
+                    let err = err.to_string();
+                    reply(bot, message, locale.text(Text::ReloadFailed { err: &err })).await
+                }
+            }
+        })
+        .await
+    }
+
+    /// Handles `/set <setting> <value>`, with `default` going back to the config.
+    async fn set(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let Some((setting, value)) = args.split_once(char::is_whitespace) else {
+                    let settings = Setting::ALL.map(Setting::name).join(", ");
+                    let answer = locale.text(Text::SetUsage {
+                        settings: &settings,
+                    });
+                    return reply(bot, message, answer).await;
+                };
+                let Ok(setting) = setting.parse::<Setting>() else {
+                    return reply(bot, message, locale.text(Text::UnknownSetting { setting }))
+                        .await;
+                };
+                let value = Some(value.trim()).filter(|&value| value != "default");
+                if let Some(Err(err)) =
+                    value.map(|value| self.check_setting(message.chat.id, setting, value))
+                {
+                    let err = err.to_string();
+                    let answer = locale.text(Text::InvalidValue {
+                        setting: setting.name(),
+                        err: &err,
+                    });
+                    return reply(bot, message, answer).await;
+                }
+                tracing::info!(
+                    user_id = user.id.0,
+                    setting = setting.name(),
+                    value,
+                    "changing setting"
+                );
+                let shown = self.change_setting(message.chat.id, setting, value, user.id)?;
+                let event = format!(
+                    "Set {} to {shown} in {}\nAdmin: {}",
+                    setting.name(),
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                // The language may have just changed.
+                let answer = self
+                    .chat_locale(message.chat.id)?
+                    .text(Text::SettingChanged {
+                        setting: setting.name(),
+                        value: &shown,
+                    });
+                reply(bot, message, answer).await
+            },
+        )
+        .await
+    }
+
+    /// Checks a value before it's stored, so stored values always apply cleanly.
+    pub(crate) fn check_setting(
+        &self,
+        chat_id: ChatId,
+        setting: Setting,
+        value: &str,
+    ) -> eyre::Result<()> {
+        let mut settings = self.settings(chat_id)?;
+        setting.apply(&mut settings, value)?;
+        if settings.retain_texts && !self.config().retain_texts {
+            eyre::bail!("this bot isn't configured to keep texts");
+        }
+        Ok(())
+    }
+
+    /// Stores a checked value of a setting, or resets it with `None`.
+    /// Returns how the setting is shown now.
+    pub(crate) fn change_setting(
+        &self,
+        chat_id: ChatId,
+        setting: Setting,
+        value: Option<&str>,
+        admin_id: UserId,
+    ) -> eyre::Result<String> {
+        self.set_setting(chat_id, setting, value)?;
+        let settings = self.settings(chat_id)?;
+        if !settings.retain_texts {
+            // Consent was withdrawn, or never given.
+            self.forget_texts(chat_id)?;
+        }
+        self.audit(AuditEvent::Set {
+            chat_id,
+            admin_id,
+            setting: setting.name().into(),
+            value: value.map(Into::into),
+        });
+        Ok(setting.show(&settings))
+    }
+
+    async fn show_settings(&self, bot: &dyn TelegramApi, message: &Message) -> eyre::Result<()> {
+        let settings = self.settings(message.chat.id)?;
+        let text = Setting::ALL
+            .map(|setting| format!("{}: {}", setting.name(), setting.show(&settings)))
+            .join("\n");
+        reply(bot, message, text).await
+    }
+
+    async fn confirm_reset(
+        &self,
+        bot: &dyn TelegramApi,
+        query: &CallbackQuery,
+        prompt: &Message,
+        choice: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(prompt.chat.id)?;
+        if !Self::is_admin(&self.config(), &self.admins, bot, &prompt.chat, &query.from).await? {
+            bot.answer_callback_query(query.id.clone(), Some(locale.text(Text::NiceTry)))
+                .await?;
+            return Ok(());
+        }
+        let text = match choice {
+            "confirm" => {
+                let namespace = self.namespace(prompt.chat.id);
+                tracing::info!(
+                    user_id = query.from.id.0,
+                    namespace = format_args!("{namespace}"),
+                    "resetting known messages"
+                );
+                let removed = self.reset_namespace(namespace).await?;
+                self.audit(AuditEvent::Reset {
+                    chat_id: prompt.chat.id,
+                    admin_id: query.from.id,
+                });
+                let event = format!(
+                    "Reset {} ({removed} messages)\nAdmin: {}",
+                    describe_chat(&prompt.chat),
+                    describe_user(&query.from),
+                );
+                self.log_event(bot, event).await;
+                locale.text(Text::ResetDone { count: removed })
+            }
+            _ => locale.text(Text::ResetCancelled),
+        };
+        bot.edit_message_text(prompt.chat.id, prompt.id, text, None)
+            .await?;
+        bot.answer_callback_query(query.id.clone(), None).await?;
+        Ok(())
+    }
+
+    /// Excludes the author of `reply_to` from duplicate checks, or includes them again.
+    async fn exempt(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        reply_to: &Message,
+        user: &User,
+        exempt: bool,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let Some(target) = &reply_to.from else {
+                    return reply(bot, message, locale.text(Text::CantTellSender)).await;
+                };
+                tracing::info!(
+                    user_id = target.id.0,
+                    admin_id = user.id.0,
+                    exempt,
+                    "changing exemption"
+                );
+                self.set_exempt(message.chat.id, target.id, exempt)?;
+                let (chat_id, user_id, admin_id) = (message.chat.id, target.id, user.id);
+                let (event, action) = if exempt {
+                    (
+                        AuditEvent::Exempt {
+                            chat_id,
+                            user_id,
+                            admin_id,
+                        },
+                        "Exempted",
+                    )
+                } else {
+                    (
+                        AuditEvent::Unexempt {
+                            chat_id,
+                            user_id,
+                            admin_id,
+                        },
+                        "Unexempted",
+                    )
+                };
+                self.audit(event);
+                let event = format!(
+                    "{action} {} in {}\nAdmin: {}",
+                    describe_user(target),
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                let who = target.mention().unwrap_or_else(|| target.full_name());
+                let confirmation = if exempt {
+                    Text::Exempted { user: &who }
+                } else {
+                    Text::Unexempted { user: &who }
+                };
+                reply(bot, message, locale.text(confirmation)).await
+            },
+        )
+        .await
+    }
+
+    /// Makes the author of `reply_to` a moderator of the chat, or takes that back.
+    async fn appoint(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        reply_to: &Message,
+        user: &User,
+        moderator: bool,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let Some(target) = &reply_to.from else {
+                    return reply(bot, message, locale.text(Text::CantTellSender)).await;
+                };
+                tracing::info!(
+                    user_id = target.id.0,
+                    admin_id = user.id.0,
+                    moderator,
+                    "changing moderator"
+                );
+                self.set_moderator(message.chat.id, target.id, moderator)?;
+                self.admins.invalidate(message.chat.id, target.id);
+                let (chat_id, user_id, admin_id) = (message.chat.id, target.id, user.id);
+                let (event, action) = if moderator {
+                    (
+                        AuditEvent::Mod {
+                            chat_id,
+                            user_id,
+                            admin_id,
+                        },
+                        "Made moderator",
+                    )
+                } else {
+                    (
+                        AuditEvent::Unmod {
+                            chat_id,
+                            user_id,
+                            admin_id,
+                        },
+                        "No longer moderator",
+                    )
+                };
+                self.audit(event);
+                let event = format!(
+                    "{action}: {} in {}\nAdmin: {}",
+                    describe_user(target),
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                let who = target.mention().unwrap_or_else(|| target.full_name());
+                let confirmation = if moderator {
+                    Text::Modded { user: &who }
+                } else {
+                    Text::Unmodded { user: &who }
+                };
+                reply(bot, message, locale.text(confirmation)).await
+            },
+        )
+        .await
+    }
+
+    pub async fn process_callback(
+        &self,
+        query: CallbackQuery,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        let (Some(data), Some(notice)) = (&query.data, query.regular_message()) else {
+            return Ok(());
+        };
+        let Some((action, appeal_id)) = data.split_once(' ') else {
+            return Ok(());
+        };
+        if action == "reset" {
+            return self.confirm_reset(&*bot, &query, notice, appeal_id).await;
+        }
+        let locale = self.chat_locale(notice.chat.id)?;
+        let appeal_key = Appeal::key(self.namespace(notice.chat.id), appeal_id.parse()?);
+        let appeal = match self.appeals.get(appeal_key)? {
+            Some(appeal) => serde_json::from_slice::<Appeal>(&appeal)?,
+            None => {
+                bot.answer_callback_query(query.id, Some(locale.text(Text::AppealUnavailable)))
+                    .await?;
+                return Ok(());
+            }
+        };
+        let notice_text = notice.text().unwrap_or_default();
+        let who = query
+            .from
+            .mention()
+            .unwrap_or_else(|| query.from.full_name());
+
+        match action {
+            "appeal" => {
+                if query.from.id != appeal.user_id {
+                    bot.answer_callback_query(
+                        query.id,
+                        Some(locale.text(Text::OnlyAuthorCanAppeal)),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                tracing::info!(user_id = query.from.id.0, "deletion appealed");
+                let text = format!(
+                    "{notice_text}\n\n{}",
+                    locale.text(Text::Appealed { user: &who })
+                );
+                let markup = InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+                    locale.text(Text::AllowButton),
+                    format!("allow {appeal_id}"),
+                )]]);
+                bot.edit_message_text(notice.chat.id, notice.id, text, Some(markup))
+                    .await?;
+                bot.answer_callback_query(query.id, Some(locale.text(Text::AdminsWillTakeALook)))
+                    .await?;
+            }
+            "allow" => {
+                if !Self::is_admin(
+                    &self.config(),
+                    &self.admins,
+                    &*bot,
+                    &notice.chat,
+                    &query.from,
+                )
+                .await?
+                {
+                    bot.answer_callback_query(query.id, Some(locale.text(Text::NiceTry)))
+                        .await?;
+                    return Ok(());
+                }
+                tracing::info!(user_id = query.from.id.0, "allowed appealed message");
+                let key = Key {
+                    namespace: self.namespace(notice.chat.id),
+                    hash: appeal.hash,
+                };
+                self.store
+                    .update(key, None, &mut |entry| {
+                        entry.map(|entry| Entry {
+                            status: Status::Allowed,
+                            repeats_left: None,
+                            ..entry
+                        })
+                    })
+                    .await?;
+                self.appeals.remove(appeal_key)?;
+                self.audit(AuditEvent::Allow {
+                    chat_id: notice.chat.id,
+                    hash: appeal.hash,
+                    admin_id: query.from.id,
+                    repeats: None,
+                });
+                let event = format!(
+                    "Allowed on appeal in {}\nAdmin: {}\nHash: {}",
+                    describe_chat(&notice.chat),
+                    describe_user(&query.from),
+                    hex(&appeal.hash),
+                );
+                self.log_event(&*bot, event).await;
+                let text = format!(
+                    "{notice_text}\n\n{}",
+                    locale.text(Text::AllowedOnAppeal { user: &who })
+                );
+                bot.edit_message_text(notice.chat.id, notice.id, text, None)
+                    .await?;
+                bot.answer_callback_query(query.id, None).await?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_listed_command() {
+        for command in Command::bot_commands() {
+            let parsed = Command::parse(&command.command, "r9ktg_bot");
+            assert!(
+                parsed.is_ok(),
+                "{} is listed, but doesn't parse",
+                command.command
+            );
+        }
+        assert!(Command::parse("/start", "r9ktg_bot").is_ok());
+        assert!(Command::parse("/undescribed", "r9ktg_bot").is_err());
+    }
+
+    #[test]
+    fn parses_allowed_copies() {
+        let parse = |text| Command::parse(text, "r9ktg_bot");
+        assert!(matches!(parse("/allow"), Ok(Command::Allow(None))));
+        assert!(matches!(parse("/allow 5"), Ok(Command::Allow(Some(5)))));
+        assert!(parse("/allow five").is_err());
+        assert!(parse("/allow 5 6").is_err());
+    }
+
+    #[test]
+    fn hides_owner_commands() {
+        let listed = Command::bot_commands();
+        for command in &listed {
+            let name = command.command.trim_start_matches('/');
+            assert_eq!(
+                OWNER_COMMANDS.contains(&name),
+                command.description.starts_with("(owners only"),
+                "/{name} is described as owner-only, but isn't hidden, or the other way around",
+            );
+        }
+        assert_eq!(
+            Command::autocompleted().len(),
+            listed.len() - OWNER_COMMANDS.len()
+        );
+    }
+}
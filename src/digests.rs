@@ -0,0 +1,141 @@
+//! Weekly digests, summing up a chat's week for its admins.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Datelike as _, Timelike as _, Utc, Weekday};
+use color_eyre::eyre;
+use futures::TryStreamExt as _;
+use teloxide::types::ChatId;
+
+use crate::{
+    activity::{chat_activity_since, top_offenders, Activity},
+    api::{SendOptions, TelegramApi},
+    config::Digest,
+    i18n::Text,
+    robot::Robot9000,
+};
+
+/// How many users a digest names.
+const DIGEST_OFFENDERS: usize = 5;
+
+const SECONDS_PER_WEEK: i64 = 7 * 24 * 60 * 60;
+
+impl Robot9000 {
+    /// Sends the digests that are due every hour.
+    pub(crate) async fn post_digests_periodically(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            match self.post_digests(&*bot, Utc::now()).await {
+                Ok(0) => {}
+                Ok(sent) => tracing::info!(sent, "sent weekly digests"),
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "couldn't send weekly digests");
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    /// Sends a digest to every chat that opted in and hasn't had one this week,
+    /// once it's Monday past `digest_hour` there. Returns how many were sent.
+    pub(crate) async fn post_digests(
+        &self,
+        bot: &dyn TelegramApi,
+        now: DateTime<Utc>,
+    ) -> eyre::Result<usize> {
+        let config = self.config();
+        let mut sent = 0;
+        for key in self.chats.iter().keys() {
+            let key = key?;
+            let chat_id = ChatId(i64::from_be_bytes(key[..].try_into()?));
+            let settings = self.settings(chat_id)?;
+            if settings.weekly_digest == Digest::Off {
+                continue;
+            }
+            let local = settings.local_time(now);
+            if local.weekday() != Weekday::Mon || local.hour() < config.digest_hour {
+                continue;
+            }
+            let last_sent = self
+                .digests
+                .get(&key)?
+                .map(|value| value[..].try_into().map(i64::from_be_bytes))
+                .transpose()?;
+            // Already sent this Monday if it was less than six days ago.
+            if last_sent
+                .is_some_and(|sent_at| now.timestamp() - sent_at < SECONDS_PER_WEEK - 24 * 60 * 60)
+            {
+                continue;
+            }
+            let digest = self.weekly_digest(chat_id, now.timestamp()).await?;
+            match settings.weekly_digest {
+                Digest::Off => {}
+                Digest::Chat => {
+                    bot.send_message(chat_id, digest, SendOptions::default())
+                        .await?;
+                }
+                Digest::Private => {
+                    for admin in bot.get_chat_administrators(chat_id).await? {
+                        if admin.user.is_bot {
+                            continue;
+                        }
+                        let result = bot
+                            .send_message(
+                                admin.user.id.into(),
+                                digest.clone(),
+                                SendOptions::default(),
+                            )
+                            .await;
+                        if let Err(err) = result {
+                            tracing::info!(
+                                user_id = admin.user.id.0,
+                                err = format_args!("{err}"),
+                                "couldn't send weekly digest",
+                            );
+                        }
+                    }
+                }
+            }
+            self.digests.insert(&key, &now.timestamp().to_be_bytes())?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Sums up the last seven days of a chat.
+    async fn weekly_digest(&self, chat_id: ChatId, now: i64) -> eyre::Result<String> {
+        let since = now - SECONDS_PER_WEEK;
+        let since_day = self.settings(chat_id)?.local_day(since);
+        let mut activity = Activity::default();
+        for (_, day) in chat_activity_since(&self.activity, chat_id, since_day)? {
+            activity += day;
+        }
+        let (mut known, mut most_repeated) = (0, 0);
+        let mut entries = self.store.entries(self.namespace(chat_id));
+        while let Some((_, entry)) = entries.try_next().await? {
+            known += 1;
+            if entry.first_seen >= since {
+                most_repeated = most_repeated.max(entry.count);
+            }
+        }
+        let locale = self.chat_locale(chat_id)?;
+        let mut digest = locale.text(Text::WeeklyDigest {
+            deleted: activity.deleted,
+            unique: activity.unique,
+            known,
+            most_repeated,
+        });
+        let offenders = top_offenders(&self.offenders, chat_id, since_day, DIGEST_OFFENDERS)?;
+        if !offenders.is_empty() {
+            let offenders = offenders
+                .iter()
+                .map(|(user_id, count)| format!("{user_id} ({count})"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            digest.push_str("\n\n");
+            digest.push_str(&locale.text(Text::DigestOffenders {
+                offenders: &offenders,
+            }));
+        }
+        Ok(digest)
+    }
+}
@@ -0,0 +1,242 @@
+//! Handling errors of updates the dispatcher gives up on: classifying them,
+//! counting them for `/healthz`, and reporting them to the log chat and owners.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    num::ParseIntError,
+    string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::eyre;
+use futures::future::BoxFuture;
+use teloxide::{error_handlers::ErrorHandler, ApiError, DownloadError, RequestError};
+
+use crate::{
+    api::TelegramApi,
+    robot::{snippet, Robot9000},
+};
+
+/// What kind of thing went wrong, as far as an operator is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /// The bot isn't allowed to do something, usually for lack of admin rights.
+    Permission,
+    /// Telegram asked to slow down.
+    RateLimit,
+    /// Something couldn't be parsed, either from Telegram or from a user.
+    Parse,
+    /// The database or the filesystem failed.
+    Storage,
+    /// Any other failure of talking to Telegram, like a network error.
+    Telegram,
+    Other,
+}
+
+impl ErrorKind {
+    const ALL: [Self; 6] = [
+        Self::Permission,
+        Self::RateLimit,
+        Self::Parse,
+        Self::Storage,
+        Self::Telegram,
+        Self::Other,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Permission => "permission",
+            Self::RateLimit => "rate_limit",
+            Self::Parse => "parse",
+            Self::Storage => "storage",
+            Self::Telegram => "telegram",
+            Self::Other => "other",
+        }
+    }
+}
+
+fn classify_api_error(err: &ApiError) -> ErrorKind {
+    match err {
+        ApiError::BotBlocked
+        | ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::BotKickedFromChannel
+        | ApiError::CantInitiateConversation
+        | ApiError::CantTalkWithBots
+        | ApiError::CantRestrictSelf
+        | ApiError::MessageCantBeDeleted
+        | ApiError::NotEnoughRightsToPinMessage
+        | ApiError::NotEnoughRightsToManagePins
+        | ApiError::NotEnoughRightsToChangeChatPermissions
+        | ApiError::NotEnoughRightsToRestrict
+        | ApiError::NotEnoughRightsToPostMessages => ErrorKind::Permission,
+        ApiError::CantParseEntities(_) => ErrorKind::Parse,
+        // Telegram adds errors faster than teloxide learns about them.
+        ApiError::Unknown(text)
+            if text.starts_with("Forbidden") || text.contains("not enough rights") =>
+        {
+            ErrorKind::Permission
+        }
+        _ => ErrorKind::Telegram,
+    }
+}
+
+/// Classifies an error by the first cause in its chain that tells what it is.
+pub(crate) fn classify(err: &eyre::Report) -> ErrorKind {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<RequestError>() {
+            return match err {
+                RequestError::Api(err) => classify_api_error(err),
+                RequestError::RetryAfter(_) => ErrorKind::RateLimit,
+                RequestError::InvalidJson { .. } => ErrorKind::Parse,
+                _ => ErrorKind::Telegram,
+            };
+        }
+        if cause.is::<DownloadError>() {
+            return ErrorKind::Telegram;
+        }
+        if cause.is::<serde_json::Error>()
+            || cause.is::<toml::de::Error>()
+            || cause.is::<ParseIntError>()
+            || cause.is::<FromUtf8Error>()
+        {
+            return ErrorKind::Parse;
+        }
+        if cause.is::<sled::Error>() || cause.is::<io::Error>() {
+            return ErrorKind::Storage;
+        }
+    }
+    ErrorKind::Other
+}
+
+/// How many errors of each kind there were since the start, kept in memory only.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorCounts {
+    counts: Arc<[AtomicU64; ErrorKind::ALL.len()]>,
+}
+
+impl ErrorCounts {
+    fn increment(&self, kind: ErrorKind) {
+        self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts by the name of each kind, zeros included.
+    pub(crate) fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        ErrorKind::ALL
+            .into_iter()
+            .map(|kind| {
+                (
+                    kind.name(),
+                    self.counts[kind as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// Errors Telegram answered with, or failed to answer at all.
+    pub(crate) fn api_errors(&self) -> u64 {
+        [
+            ErrorKind::Permission,
+            ErrorKind::RateLimit,
+            ErrorKind::Telegram,
+        ]
+        .into_iter()
+        .map(|kind| self.counts[kind as usize].load(Ordering::Relaxed))
+        .sum()
+    }
+}
+
+impl Robot9000 {
+    /// Logs, counts and reports an error of handling an update.
+    pub async fn report_error(&self, bot: &dyn TelegramApi, err: &eyre::Report) {
+        let kind = classify(err);
+        self.error_counts.increment(kind);
+        tracing::error!(
+            kind = kind.name(),
+            err = format_args!("{err:?}"),
+            "couldn't handle an update"
+        );
+        // Reporting a rate limit would only make it worse.
+        if self.config().report_errors && kind != ErrorKind::RateLimit {
+            let event = format!(
+                "Couldn't handle an update ({})\n{}",
+                kind.name(),
+                snippet(&err.to_string()),
+            );
+            self.log_event(bot, event).await;
+        }
+        self.record_error(bot, err).await;
+    }
+}
+
+/// Error handler of the dispatcher, used instead of the one that only logs.
+pub struct ErrorReporter {
+    robot: Arc<Robot9000>,
+    bot: Arc<dyn TelegramApi>,
+}
+
+impl ErrorReporter {
+    pub fn new(robot: Arc<Robot9000>, bot: Arc<dyn TelegramApi>) -> Arc<Self> {
+        Arc::new(Self { robot, bot })
+    }
+}
+
+impl ErrorHandler<eyre::Report> for ErrorReporter {
+    fn handle_error(self: Arc<Self>, error: eyre::Report) -> BoxFuture<'static, ()> {
+        Box::pin(async move { self.robot.report_error(&*self.bot, &error).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::Seconds;
+
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        let kind = |err: eyre::Report| classify(&err.wrap_err("handling an update"));
+        assert_eq!(
+            kind(RequestError::Api(ApiError::MessageCantBeDeleted).into()),
+            ErrorKind::Permission
+        );
+        assert_eq!(
+            kind(
+                RequestError::Api(ApiError::Unknown("Forbidden: bot is not a member".into()))
+                    .into()
+            ),
+            ErrorKind::Permission
+        );
+        assert_eq!(
+            kind(RequestError::RetryAfter(Seconds::from_seconds(5)).into()),
+            ErrorKind::RateLimit
+        );
+        assert_eq!(
+            kind(serde_json::from_str::<u8>("{").unwrap_err().into()),
+            ErrorKind::Parse
+        );
+        assert_eq!(
+            kind(sled::Error::Unsupported("nope".into()).into()),
+            ErrorKind::Storage
+        );
+        assert_eq!(
+            kind(RequestError::Api(ApiError::ChatNotFound).into()),
+            ErrorKind::Telegram
+        );
+        assert_eq!(kind(eyre::eyre!("database is on fire")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn counts_errors() {
+        let counts = ErrorCounts::default();
+        counts.increment(ErrorKind::Storage);
+        counts.increment(ErrorKind::Storage);
+        let snapshot = counts.snapshot();
+        assert_eq!(snapshot["storage"], 2);
+        assert_eq!(snapshot["permission"], 0);
+    }
+}
@@ -0,0 +1,149 @@
+//! Catching one person posting the same text over and over in a short time,
+//! whatever the known messages say about it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use color_eyre::eyre;
+use teloxide::types::{ChatId, Message, User, UserId};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    config::Action,
+    policy::{Caught, Detection},
+    robot::{describe_chat, describe_user, is_anonymous_admin, snippet, Robot9000},
+};
+
+/// Recent texts of each user in each chat, as hashes with the times they were posted.
+type RecentPosts = HashMap<(ChatId, UserId), Vec<(u64, i64)>>;
+
+/// Recent posts of every user, kept in memory only, since bursts are short.
+#[derive(Clone, Default)]
+pub(crate) struct FloodTracker {
+    posts: Arc<Mutex<RecentPosts>>,
+}
+
+impl FloodTracker {
+    /// How many users are tracked before the ones that went quiet are dropped.
+    const PRUNE_AT: usize = 10_000;
+
+    fn lock(&self) -> MutexGuard<'_, RecentPosts> {
+        self.posts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records a post, returning how many times the user posted
+    /// the same text in the chat within `window` seconds, this one included.
+    pub(crate) fn record(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        text: &str,
+        timestamp: i64,
+        window: i64,
+    ) -> usize {
+        let since = timestamp.saturating_sub(window);
+        let mut posts = self.lock();
+        if posts.len() >= Self::PRUNE_AT {
+            posts.retain(|_, recent| recent.iter().any(|&(_, posted_at)| posted_at > since));
+        }
+        let recent = posts.entry((chat_id, user_id)).or_default();
+        recent.retain(|&(_, posted_at)| posted_at > since);
+        let hash = xxh3_64(text.as_bytes());
+        recent.push((hash, timestamp));
+        recent.iter().filter(|&&(other, _)| other == hash).count()
+    }
+
+    /// How many times the user posted the text in the chat within `window` seconds
+    /// up to `timestamp`, like [`Self::record`] counts, but without recording anything.
+    pub(crate) fn copies(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        text: &str,
+        timestamp: i64,
+        window: i64,
+    ) -> usize {
+        let since = timestamp.saturating_sub(window);
+        let hash = xxh3_64(text.as_bytes());
+        self.lock().get(&(chat_id, user_id)).map_or(0, |recent| {
+            recent
+                .iter()
+                .filter(|&&(other, posted_at)| other == hash && posted_at > since)
+                .filter(|&&(_, posted_at)| posted_at <= timestamp)
+                .count()
+        })
+    }
+}
+
+impl Robot9000 {
+    /// Takes the burst actions on a message if its author posted it more than
+    /// `flood_limit` times within `flood_window` seconds. Returns whether it was caught.
+    pub(crate) async fn check_flood(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        text: &str,
+    ) -> eyre::Result<bool> {
+        let config = self.config();
+        let Some(limit) = config.flood_limit else {
+            return Ok(false);
+        };
+        let window = config.flood_window.try_into().unwrap_or(i64::MAX);
+        let copies = self.floods.record(
+            message.chat.id,
+            user.id,
+            text,
+            message.date.timestamp(),
+            window,
+        );
+        if copies <= limit as usize {
+            return Ok(false);
+        }
+        let settings = self.settings(message.chat.id)?;
+        let actions = settings.actions(Detection::Burst);
+        if actions.contains(Action::Delete) && self.is_suspended(message.chat.id)? {
+            tracing::debug!("can't delete messages, ignoring flood");
+            return Ok(false);
+        }
+        if settings.exempt_admins
+            && (is_anonymous_admin(message)
+                || Self::is_admin(&config, &self.admins, bot, &message.chat, user).await?)
+        {
+            tracing::debug!(user_id = user.id.0, "ignoring flood from an admin");
+            return Ok(false);
+        }
+
+        tracing::debug!(
+            user_id = user.id.0,
+            copies,
+            actions = format_args!("{actions}"),
+            "caught flood"
+        );
+        let event = format!(
+            "Flood in {}\nUser: {}\nCopies: {copies}\nActions: {actions}\nText: {}",
+            describe_chat(&message.chat),
+            describe_user(user),
+            snippet(text),
+        );
+        if actions.contains(Action::Delete) {
+            self.audit(AuditEvent::Flood {
+                chat_id: message.chat.id,
+                message_id: message.id.0,
+                user_id: user.id,
+            });
+        }
+        let caught = Caught {
+            message,
+            user: Some(user),
+            original: None,
+            text: None,
+        };
+        self.take_actions(bot, &caught, actions, event).await?;
+        Ok(true)
+    }
+}
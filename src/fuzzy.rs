@@ -0,0 +1,281 @@
+//! Catching copies of long messages with a word changed or a sentence added.
+//!
+//! Every long message gets a MinHash signature over its word triples, which
+//! estimates how many triples two messages have in common. Signatures are
+//! split into bands indexed by their hash, so only messages agreeing on at
+//! least one band are compared.
+
+use std::collections::HashSet;
+
+use color_eyre::eyre;
+use teloxide::types::{ThreadId, UserId};
+use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
+
+use crate::{
+    robot::{hex, Robot9000},
+    storage::{remove_prefix, Key, Namespace, Scope},
+};
+
+/// Number of hashes in a signature.
+const SIGNATURE_LEN: usize = 64;
+
+/// Hashes per band. More of them make candidates rarer, but only for closer copies.
+const BAND_ROWS: usize = 4;
+
+/// Words in a shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Smallest hash of the message's shingles under each of the hash functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature([u64; SIGNATURE_LEN]);
+
+impl Signature {
+    /// Signature of a text, or `None` if it has less than `min_words` words.
+    ///
+    /// Words are compared ignoring case and punctuation around them.
+    pub(crate) fn of(text: &str, min_words: usize, seed: u64) -> Option<Self> {
+        let words = text
+            .split_whitespace()
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+            })
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+        if words.len() < min_words.max(SHINGLE_WORDS) {
+            return None;
+        }
+        let mut mins = [u64::MAX; SIGNATURE_LEN];
+        for shingle in words.windows(SHINGLE_WORDS) {
+            let shingle = xxh3_64_with_seed(shingle.join(" ").as_bytes(), seed);
+            for (i, min) in mins.iter_mut().enumerate() {
+                *min = (*min).min(xxh3_64_with_seed(&shingle.to_le_bytes(), i as u64));
+            }
+        }
+        Some(Self(mins))
+    }
+
+    /// Estimated Jaccard similarity of the shingles of both messages.
+    pub(crate) fn similarity(&self, other: &Signature) -> f64 {
+        let same = self.0.iter().zip(&other.0).filter(|(a, b)| a == b).count();
+        same as f64 / SIGNATURE_LEN as f64
+    }
+
+    fn bands(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        self.0.chunks(BAND_ROWS).enumerate().map(|(band, rows)| {
+            let rows = rows
+                .iter()
+                .flat_map(|row| row.to_le_bytes())
+                .collect::<Vec<_>>();
+            (band as u8, xxh3_64(&rows))
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|min| min.to_le_bytes()).collect()
+    }
+
+    fn decode(value: &[u8]) -> eyre::Result<Self> {
+        if value.len() != SIGNATURE_LEN * 8 {
+            eyre::bail!("malformed signature of {} bytes", value.len());
+        }
+        let mut mins = [0; SIGNATURE_LEN];
+        for (min, bytes) in mins.iter_mut().zip(value.chunks_exact(8)) {
+            *min = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(Self(mins))
+    }
+}
+
+/// Prefix of the index entries of one band of a signature in a namespace.
+/// Index keys are followed by the hash of the message the signature is of.
+fn band_prefix(namespace: Namespace, band: u8, band_hash: u64) -> [u8; 17] {
+    let mut prefix = [0; 17];
+    prefix[..8].copy_from_slice(&namespace.prefix());
+    prefix[8] = band;
+    prefix[9..].copy_from_slice(&band_hash.to_be_bytes());
+    prefix
+}
+
+impl Robot9000 {
+    /// Signature of a text in a scope, if fuzzy matching is on and the text is long enough.
+    ///
+    /// Shingles are hashed with the salt of the namespace, keyed by the
+    /// encryption key if there's one, like the hashes of messages are.
+    pub(crate) fn signature(&self, scope: Scope, text: &str) -> eyre::Result<Option<Signature>> {
+        let config = self.config();
+        if config.fuzzy_threshold.is_none() {
+            return Ok(None);
+        }
+        let mut seed = [0; 16];
+        if let Some(salt) = self.salts.get(scope.namespace.prefix())? {
+            seed.copy_from_slice(&salt);
+        }
+        // Topics are only matched within themselves, like exact copies.
+        if let Some(ThreadId(thread_root)) = scope.thread_id {
+            for (byte, root) in seed.iter_mut().zip(thread_root.0.to_le_bytes()) {
+                *byte ^= root;
+            }
+        }
+        // And so are people's own messages, in chats only checking those.
+        if let Some(UserId(poster_id)) = scope.poster_id {
+            for (byte, poster) in seed[4..].iter_mut().zip(poster_id.to_le_bytes()) {
+                *byte ^= poster;
+            }
+        }
+        if self.hash_function.is_keyed() {
+            let mut hasher = self.hash_function.hasher();
+            hasher.update(&seed);
+            seed = hasher.finish();
+        }
+        if let Some(cipher) = &self.cipher {
+            seed = cipher.hash(seed);
+        }
+        Ok(Signature::of(text, config.fuzzy_min_words, xxh3_64(&seed)))
+    }
+
+    /// Known message in the namespace most similar to the one with `signature`,
+    /// if it's at least as similar as `fuzzy_threshold`.
+    pub(crate) async fn find_similar(
+        &self,
+        namespace: Namespace,
+        signature: &Signature,
+    ) -> eyre::Result<Option<Key>> {
+        let Some(threshold) = self.config().fuzzy_threshold else {
+            return Ok(None);
+        };
+        let mut candidates = HashSet::new();
+        for (band, band_hash) in signature.bands() {
+            let prefix = band_prefix(namespace, band, band_hash);
+            for db_key in self.minhash_bands.scan_prefix(prefix).keys() {
+                candidates.insert(<[u8; 16]>::try_from(&db_key?[17..])?);
+            }
+        }
+        let mut best = None;
+        for hash in candidates {
+            let key = Key { namespace, hash };
+            let Some(other) = self.minhash_signatures.get(key.encode())? else {
+                continue;
+            };
+            let similarity = signature.similarity(&Signature::decode(&other)?);
+            if similarity >= threshold.0
+                && best.is_none_or(|(_, best)| similarity > best)
+                && self.store.peek(key).await?.is_some()
+            {
+                best = Some((key, similarity));
+            }
+        }
+        Ok(best.map(|(key, _)| key))
+    }
+
+    /// How alike a text with `signature` is to a known message, if that one has a signature.
+    pub(crate) fn similarity(&self, key: Key, signature: &Signature) -> eyre::Result<Option<f64>> {
+        let Some(other) = self.minhash_signatures.get(key.encode())? else {
+            return Ok(None);
+        };
+        Ok(Some(signature.similarity(&Signature::decode(&other)?)))
+    }
+
+    /// Key a message is stored under: its own if it's known already or short,
+    /// otherwise the one of a known message it's a close enough copy of.
+    /// Also returns the signature to index once a long message is stored as new.
+    pub(crate) async fn fuzzy_key(
+        &self,
+        scope: Scope,
+        key: Key,
+        text: &str,
+    ) -> eyre::Result<(Key, Option<Signature>)> {
+        match self.signature(scope, text)? {
+            Some(signature) => self.match_signature(key, signature).await,
+            None => Ok((key, None)),
+        }
+    }
+
+    /// Like [`Robot9000::fuzzy_key`], for a long message with a known signature.
+    pub(crate) async fn match_signature(
+        &self,
+        key: Key,
+        signature: Signature,
+    ) -> eyre::Result<(Key, Option<Signature>)> {
+        if self.store.peek(key).await?.is_some() {
+            return Ok((key, None));
+        }
+        match self.find_similar(key.namespace, &signature).await? {
+            Some(similar) => {
+                tracing::debug!(hash = hex(&similar.hash), "found a similar message");
+                Ok((similar, None))
+            }
+            None => Ok((key, Some(signature))),
+        }
+    }
+
+    /// Remembers the signature of a message that was just stored as new.
+    pub(crate) fn index_signature(&self, key: Key, signature: &Signature) -> eyre::Result<()> {
+        self.minhash_signatures
+            .insert(key.encode(), signature.encode())?;
+        for (band, band_hash) in signature.bands() {
+            let prefix = band_prefix(key.namespace, band, band_hash);
+            self.minhash_bands
+                .insert([&prefix[..], &key.hash].concat(), &[])?;
+        }
+        Ok(())
+    }
+
+    fn unindex_signature(&self, key: Key, signature: &Signature) -> eyre::Result<()> {
+        for (band, band_hash) in signature.bands() {
+            let prefix = band_prefix(key.namespace, band, band_hash);
+            self.minhash_bands
+                .remove([&prefix[..], &key.hash].concat())?;
+        }
+        self.minhash_signatures.remove(key.encode())?;
+        Ok(())
+    }
+
+    /// Forgets the signatures of every message in a namespace.
+    pub(crate) fn forget_signatures(&self, namespace: Namespace) -> eyre::Result<()> {
+        remove_prefix(&self.minhash_bands, namespace.prefix())?;
+        remove_prefix(&self.minhash_signatures, namespace.prefix())?;
+        Ok(())
+    }
+
+    /// Forgets the signatures of messages that aren't known anymore.
+    pub(crate) async fn prune_signatures(&self) -> eyre::Result<usize> {
+        let mut removed = 0;
+        for item in self.minhash_signatures.iter() {
+            let (db_key, value) = item?;
+            let key = Key::decode(&db_key)?;
+            if self.store.peek(key).await?.is_none() {
+                self.unindex_signature(key, &Signature::decode(&value)?)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_similarity() {
+        let text = "the quick brown fox jumps over the lazy dog while the cat \
+                    sleeps on the warm windowsill and the birds sing in the old oak tree \
+                    outside the little house at the end of the long and winding road";
+        let original = Signature::of(text, 20, 1).unwrap();
+        let edited = Signature::of(&text.replace("lazy", "sleepy"), 20, 1).unwrap();
+        let extended = Signature::of(&format!("{text}, or so they say."), 20, 1).unwrap();
+        let other = Signature::of(&text.split(' ').rev().collect::<Vec<_>>().join(" "), 20, 1);
+
+        assert_eq!(
+            original,
+            Signature::of(&text.to_uppercase(), 20, 1).unwrap()
+        );
+        assert!(original.similarity(&edited) > 0.7);
+        assert!(original.similarity(&extended) > 0.8);
+        assert!(original.similarity(&other.unwrap()) < 0.2);
+        assert_ne!(original, Signature::of(text, 20, 2).unwrap());
+        assert_eq!(Signature::of("too short", 20, 1), None);
+        assert_eq!(Signature::decode(&original.encode()).unwrap(), original);
+    }
+}
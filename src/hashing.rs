@@ -0,0 +1,191 @@
+//! Hash functions texts can be hashed with, keyed with `hash_key` if it's set.
+
+use color_eyre::eyre;
+use ring::{digest, hmac};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    config::{HashAlgorithm, SecretKey},
+    storage::{has_known_messages, open_tree},
+};
+
+const FUNCTION_KEY: &[u8] = b"function";
+
+/// Hash function picked by the config, fixed once the bot starts,
+/// since known messages can only be recognized by the function they were hashed with.
+pub struct HashFunction {
+    algorithm: HashAlgorithm,
+    key: Option<[u8; 32]>,
+}
+
+/// Hash of a text in progress.
+pub(crate) enum Hasher {
+    Xxh3(Box<Xxh3>),
+    Sha256(digest::Context),
+    HmacSha256(hmac::Context),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl HashFunction {
+    pub fn new(algorithm: HashAlgorithm, key: Option<&SecretKey>) -> eyre::Result<Self> {
+        if algorithm == HashAlgorithm::HmacSha256 && key.is_none() {
+            eyre::bail!("`hash_algorithm = \"hmac-sha256\"` needs a `hash_key`");
+        }
+        Ok(Self {
+            algorithm,
+            key: key.map(|key| key.0),
+        })
+    }
+
+    pub(crate) fn hasher(&self) -> Hasher {
+        let mut hasher = match self.algorithm {
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(digest::Context::new(&digest::SHA256)),
+            HashAlgorithm::HmacSha256 => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, &self.key.unwrap_or_default());
+                return Hasher::HmacSha256(hmac::Context::with_key(&key));
+            }
+            HashAlgorithm::Blake3 => {
+                let hasher = match &self.key {
+                    Some(key) => blake3::Hasher::new_keyed(key),
+                    None => blake3::Hasher::new(),
+                };
+                return Hasher::Blake3(Box::new(hasher));
+            }
+        };
+        // Unkeyed functions get the key in front of the text.
+        if let Some(key) = &self.key {
+            hasher.update(key);
+        }
+        hasher
+    }
+
+    /// Whether hashes can't be computed without the key.
+    pub(crate) fn is_keyed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Identifies the function without revealing the key.
+    fn fingerprint(&self) -> Vec<u8> {
+        let mut fingerprint = self.algorithm.as_str().as_bytes().to_vec();
+        if let Some(key) = &self.key {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+            fingerprint.push(b':');
+            fingerprint.extend_from_slice(hmac::sign(&key, b"r9ktg hash key").as_ref());
+        }
+        fingerprint
+    }
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Xxh3(hasher) => hasher.update(bytes),
+            Hasher::Sha256(context) => context.update(bytes),
+            Hasher::HmacSha256(context) => context.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    /// First 128 bits of the hash.
+    pub(crate) fn finish(self) -> [u8; 16] {
+        match self {
+            Hasher::Xxh3(hasher) => hasher.digest128().to_le_bytes(),
+            Hasher::Sha256(context) => context.finish().as_ref()[..16].try_into().unwrap(),
+            Hasher::HmacSha256(context) => context.sign().as_ref()[..16].try_into().unwrap(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes()[..16].try_into().unwrap(),
+        }
+    }
+}
+
+/// Makes sure the database of a bot is hashed with `function`, like it was
+/// the first time. Databases from before the function could be picked
+/// were hashed with xxh3 without a key, unless they're empty. Messages
+/// in Redis can't be checked for here, so they're assumed to be hashed
+/// with whatever the function was when the bot first started.
+pub(crate) fn prepare(
+    db: &sled::Db,
+    bot_name: Option<&str>,
+    function: &HashFunction,
+) -> eyre::Result<()> {
+    let state = open_tree(db, bot_name, "hashing")?;
+    let fingerprint = function.fingerprint();
+    let recorded = match state.get(FUNCTION_KEY)? {
+        Some(recorded) => recorded.to_vec(),
+        None if has_known_messages(db, bot_name)? => {
+            HashAlgorithm::Xxh3.as_str().as_bytes().to_vec()
+        }
+        None => fingerprint.clone(),
+    };
+    if recorded != fingerprint {
+        let algorithm = recorded
+            .split(|&byte| byte == b':')
+            .next()
+            .unwrap_or_default();
+        eyre::bail!(
+            "known messages were hashed with {}{}, which doesn't match \
+             `hash_algorithm` and `hash_key`",
+            String::from_utf8_lossy(algorithm),
+            if recorded.contains(&b':') {
+                " and another key"
+            } else {
+                ""
+            },
+        );
+    }
+    state.insert(FUNCTION_KEY, fingerprint)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_another_function() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let key = SecretKey([7; 32]);
+        let hmac = HashFunction::new(HashAlgorithm::HmacSha256, Some(&key))?;
+        prepare(&db, None, &hmac)?;
+        prepare(&db, None, &hmac)?;
+
+        let xxh3 = HashFunction::new(HashAlgorithm::Xxh3, None)?;
+        assert!(prepare(&db, None, &xxh3).is_err());
+        let other_key = HashFunction::new(HashAlgorithm::HmacSha256, Some(&SecretKey([8; 32])))?;
+        assert!(prepare(&db, None, &other_key).is_err());
+        assert!(HashFunction::new(HashAlgorithm::HmacSha256, None).is_err());
+
+        let hash = |function: &HashFunction| {
+            let mut hasher = function.hasher();
+            hasher.update(b"hello");
+            hasher.finish()
+        };
+        assert_eq!(hash(&hmac), hash(&hmac));
+        assert_ne!(hash(&hmac), hash(&other_key));
+        assert_ne!(
+            hash(&xxh3),
+            hash(&HashFunction::new(HashAlgorithm::Xxh3, Some(&key))?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_with_blake3() -> eyre::Result<()> {
+        let hash = |key: Option<&SecretKey>| -> eyre::Result<String> {
+            let mut hasher = HashFunction::new(HashAlgorithm::Blake3, key)?.hasher();
+            hasher.update(b"");
+            Ok(hasher
+                .finish()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect())
+        };
+        // Vectors from the BLAKE3 reference implementation.
+        assert_eq!(hash(None)?, "af1349b9f5f9a1a6a0404dea36dcc949");
+        let key = SecretKey(*b"whats the Elvish word for friend");
+        assert_eq!(hash(Some(&key))?, "92b2b75604ed3c761f9d6f62392c8a92");
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! `/healthz` endpoint for container orchestrators and uptime monitors.
+//!
+//! Answers a `GET` and closes the connection.
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use color_eyre::eyre;
+use serde::Serialize;
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{api::TelegramApi, http, robot::Robot9000, storage::unix_now};
+
+/// How long each check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct Health {
+    /// Telegram answered `getMe`.
+    telegram: bool,
+    /// The database accepted and flushed a write.
+    database: bool,
+    /// Errors of handling updates since the start, by kind. They don't make the bot unhealthy.
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Health {
+    fn is_ok(&self) -> bool {
+        self.telegram && self.database
+    }
+}
+
+/// Binds `addr`, so a taken port fails at startup rather than in the background.
+pub(crate) async fn bind(addr: SocketAddr) -> eyre::Result<TcpListener> {
+    Ok(TcpListener::bind(addr).await?)
+}
+
+/// Answers health checks until the bot stops.
+pub(crate) async fn serve(listener: TcpListener, robot: Robot9000, bot: Arc<dyn TelegramApi>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!(err = format_args!("{err}"), "couldn't accept health check");
+                continue;
+            }
+        };
+        let robot = robot.clone();
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &robot, &*bot).await {
+                tracing::debug!(
+                    err = format_args!("{err}"),
+                    "health check connection failed"
+                );
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, robot: &Robot9000, bot: &dyn TelegramApi) -> eyre::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    let method = request.method.as_str();
+    let (status, body) = match (method, request.path.as_str()) {
+        ("GET" | "HEAD", "/healthz") => {
+            let health = check(robot, bot).await;
+            let status = if health.is_ok() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, serde_json::to_string(&health)?)
+        }
+        (_, "/healthz") => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    http::respond(
+        &mut stream,
+        method,
+        status,
+        "application/json",
+        body.as_bytes(),
+    )
+    .await
+}
+
+async fn check(robot: &Robot9000, bot: &dyn TelegramApi) -> Health {
+    let telegram = tokio::time::timeout(CHECK_TIMEOUT, bot.get_me()).await;
+    if let Ok(Err(err)) = &telegram {
+        tracing::warn!(
+            err = format_args!("{err}"),
+            "health check couldn't reach Telegram"
+        );
+    }
+    Health {
+        telegram: matches!(telegram, Ok(Ok(_))),
+        database: check_database(robot).await,
+        errors: robot.error_counts.snapshot(),
+    }
+}
+
+/// Whether the database accepts and flushes a write in time.
+pub(crate) async fn check_database(robot: &Robot9000) -> bool {
+    let database = tokio::time::timeout(CHECK_TIMEOUT, async {
+        robot
+            .health
+            .insert("last_check", &unix_now().to_be_bytes())?;
+        robot.health.flush_async().await?;
+        Ok::<_, eyre::Report>(())
+    })
+    .await;
+    if let Ok(Err(err)) = &database {
+        tracing::warn!(
+            err = format_args!("{err}"),
+            "health check couldn't write to the database"
+        );
+    }
+    matches!(database, Ok(Ok(())))
+}
+
+/// Whether the storage of known messages answers in time.
+pub(crate) async fn check_store(robot: &Robot9000) -> bool {
+    let store = tokio::time::timeout(CHECK_TIMEOUT, robot.store.is_empty()).await;
+    if let Ok(Err(err)) = &store {
+        tracing::warn!(
+            err = format_args!("{err}"),
+            "health check couldn't reach the storage"
+        );
+    }
+    matches!(store, Ok(Ok(_)))
+}
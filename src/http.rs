@@ -0,0 +1,127 @@
+//! Just enough HTTP/1.1 for the local endpoints: one request per connection,
+//! answered and then closed.
+
+use std::time::Duration;
+
+use color_eyre::eyre;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::TcpStream,
+};
+
+/// Longest request line or header accepted, in bytes.
+const MAX_LINE: usize = 8192;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Most headers accepted in a request.
+const MAX_HEADERS: usize = 100;
+
+/// How long a client may take to send the whole request, so slow ones
+/// can't keep connections open.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Path without the query string.
+    pub(crate) path: String,
+    pub(crate) query: String,
+    /// Names are lowercase.
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a query string parameter.
+    pub(crate) fn param(&self, name: &str) -> Option<String> {
+        url::form_urlencoded::parse(self.query.as_bytes())
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
+pub(crate) async fn read_request(stream: &mut BufReader<TcpStream>) -> eyre::Result<Request> {
+    tokio::time::timeout(READ_TIMEOUT, read_request_in_time(stream))
+        .await
+        .map_err(|_| eyre::eyre!("timed out reading the request"))?
+}
+
+async fn read_request_in_time(stream: &mut BufReader<TcpStream>) -> eyre::Result<Request> {
+    let request_line = read_line(stream).await?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            eyre::bail!("too many headers");
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: Vec::new(),
+    };
+    let length = match request.header("content-length") {
+        Some(length) => length.parse::<usize>()?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        eyre::bail!("request body of {length} bytes is too large");
+    }
+    request.body.resize(length, 0);
+    stream.read_exact(&mut request.body).await?;
+    Ok(request)
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> eyre::Result<String> {
+    let mut line = Vec::new();
+    let read = (&mut *stream)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 || line.last() != Some(&b'\n') {
+        eyre::bail!("truncated request");
+    }
+    Ok(String::from_utf8(line)?.trim_end().to_owned())
+}
+
+/// Writes the response and closes the connection. Bodies of answers
+/// to `HEAD` are left out, but still counted in the length.
+pub(crate) async fn respond(
+    stream: &mut BufReader<TcpStream>,
+    request_method: &str,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> eyre::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len(),
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    if request_method != "HEAD" {
+        stream.write_all(body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,1638 @@
+//! Translations of everything the bot says in chats.
+//!
+//! Reports sent to the log chat are for the operator and stay in English.
+
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
+
+use color_eyre::eyre;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Language {
+    #[default]
+    #[serde(rename = "en")]
+    English,
+    #[serde(rename = "ru")]
+    Russian,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::Russian];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Russian => "ru",
+        }
+    }
+
+    pub fn text(self, text: Text<'_>) -> String {
+        match self {
+            Language::English => english(text),
+            Language::Russian => russian(text),
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Language::ALL
+            .into_iter()
+            .find(|language| language.code() == s)
+            .ok_or_else(|| eyre::eyre!("unknown language: {s:?}"))
+    }
+}
+
+/// Something the bot says, with the values it mentions.
+#[derive(Debug, Clone, Copy)]
+pub enum Text<'a> {
+    Intro,
+    NiceTry,
+    SomethingWentWrong,
+    Resumed,
+    Suspended,
+    CheckUnknown,
+    CheckAllowed,
+    CheckForbidden,
+    CheckExpired,
+    CheckSeen {
+        count: u32,
+        first_seen: &'a str,
+        will_be_deleted: bool,
+    },
+    DeletedDuplicate {
+        user: &'a str,
+        original: Option<&'a str>,
+    },
+    DeletedYourDuplicate {
+        chat: Option<&'a str>,
+        original: Option<&'a str>,
+        text_follows: bool,
+    },
+    AppealButton,
+    AppealUnavailable,
+    OnlyAuthorCanAppeal,
+    Appealed {
+        user: &'a str,
+    },
+    AdminsWillTakeALook,
+    AllowButton,
+    AllowedOnAppeal {
+        user: &'a str,
+    },
+    ImportTooLarge {
+        size: &'a str,
+        limit: &'a str,
+    },
+    Imported {
+        count: usize,
+        duplicates: usize,
+        service: usize,
+        unsupported: usize,
+        malformed: usize,
+        shared: bool,
+    },
+    ImportFailed {
+        err: &'a str,
+    },
+    ImportUsage,
+    ImportTopics,
+    Simulated {
+        new: usize,
+        known: usize,
+        collisions: usize,
+    },
+    ReplyRequired,
+    UnsupportedMessage,
+    Allowed {
+        shared: bool,
+    },
+    Forbidden {
+        shared: bool,
+    },
+    Forgot {
+        shared: bool,
+    },
+    DidntKnow,
+    ResetPrompt {
+        known: usize,
+        decided: usize,
+        shared: bool,
+    },
+    ResetButton,
+    CancelButton,
+    ResetDone {
+        count: usize,
+    },
+    ResetCancelled,
+    Rotated {
+        shared: bool,
+    },
+    CantMessageYou,
+    ExportSent,
+    BackupFailed,
+    BackupSent,
+    RestoreUsage,
+    RestoreFailed {
+        err: &'a str,
+    },
+    RestoreStaged,
+    AlreadyActive,
+    Activated,
+    PauseUsage {
+        err: &'a str,
+    },
+    Paused {
+        until: &'a str,
+    },
+    Unpaused,
+    NotPaused,
+    Reloaded,
+    ReloadFailed {
+        err: &'a str,
+    },
+    SetUsage {
+        settings: &'a str,
+    },
+    UnknownSetting {
+        setting: &'a str,
+    },
+    InvalidValue {
+        setting: &'a str,
+        err: &'a str,
+    },
+    SettingChanged {
+        setting: &'a str,
+        value: &'a str,
+    },
+    CantTellSender,
+    Exempted {
+        user: &'a str,
+    },
+    Unexempted {
+        user: &'a str,
+    },
+    DuplicateOf {
+        text: &'a str,
+    },
+    TextsNotRetained,
+    SearchUsage,
+    NothingFound,
+    SearchResult {
+        text: &'a str,
+        count: u32,
+        first_seen: &'a str,
+    },
+    PhrasesUsage,
+    CommonPhrases {
+        phrases: &'a str,
+    },
+    NoCommonPhrases,
+    PhraseAdded {
+        phrase: &'a str,
+    },
+    PhraseRemoved {
+        phrase: &'a str,
+    },
+    AllowedRepeats {
+        repeats: u32,
+        shared: bool,
+    },
+    CheckAllowedRepeats {
+        repeats: u32,
+    },
+    DuplicateHistory {
+        first_seen: &'a str,
+        seconds_ago: i64,
+        /// Copies posted after the first one, this one included.
+        reposts: u32,
+    },
+    NewcomerWarning {
+        user: &'a str,
+    },
+    GcDone {
+        removed: usize,
+        reclaimed: &'a str,
+        size: &'a str,
+    },
+    DbSize {
+        size: &'a str,
+        entries: usize,
+        chat_entries: usize,
+        shared: bool,
+    },
+    DeletedDuplicatesSummary {
+        count: u32,
+        seconds: u64,
+    },
+    SlowModeOn {
+        cooldown: u64,
+        delay: u64,
+    },
+    SlowModeOff,
+    SyncFromUsage,
+    Synced {
+        copied: usize,
+        skipped: usize,
+    },
+    WeeklyDigest {
+        deleted: u64,
+        unique: u64,
+        known: usize,
+        most_repeated: u32,
+    },
+    DigestOffenders {
+        offenders: &'a str,
+    },
+    NothingRepeated,
+    TopHash {
+        hash: &'a str,
+        count: u32,
+        first_seen: &'a str,
+    },
+    Modded {
+        user: &'a str,
+    },
+    Unmodded {
+        user: &'a str,
+    },
+    PrecheckUsage,
+    PrecheckPicked {
+        bot: &'a str,
+    },
+    DuplicateWarning {
+        user: &'a str,
+    },
+    WhyNormalized {
+        /// Names of the steps that changed the text, comma-separated.
+        steps: &'a str,
+    },
+    WhyHash {
+        hash: &'a str,
+        namespace: &'a str,
+    },
+    WhyMatchedExactly,
+    WhyMatchedSimilar {
+        /// Percent.
+        similarity: u32,
+    },
+    WhyBurst {
+        copies: usize,
+        limit: u32,
+    },
+    WhyCommonPhrase,
+    WhyExempt,
+    WhyOriginal,
+    SettingsImportUsage,
+    SettingsImportFailed {
+        err: &'a str,
+    },
+    SettingsImported {
+        settings: usize,
+        phrases: usize,
+        exemptions: usize,
+    },
+    Amnesty {
+        count: usize,
+    },
+    BotStats {
+        uptime: &'a str,
+        memory: &'a str,
+        chats: usize,
+        suspended: usize,
+        updates: u64,
+        api_errors: u64,
+        error_rate: &'a str,
+        database: bool,
+        storage: bool,
+    },
+    SandboxUsage,
+    RaidAlert {
+        chat: &'a str,
+        count: usize,
+        texts: &'a str,
+        users: &'a str,
+    },
+}
+
+impl Text<'_> {
+    /// Names of every text, as used in the config's `[templates]`.
+    pub const NAMES: &'static [&'static str] = &[
+        "intro",
+        "nice_try",
+        "something_went_wrong",
+        "resumed",
+        "suspended",
+        "check_unknown",
+        "check_allowed",
+        "check_forbidden",
+        "check_expired",
+        "check_seen",
+        "deleted_duplicate",
+        "deleted_your_duplicate",
+        "appeal_button",
+        "appeal_unavailable",
+        "only_author_can_appeal",
+        "appealed",
+        "admins_will_take_a_look",
+        "allow_button",
+        "allowed_on_appeal",
+        "import_too_large",
+        "imported",
+        "import_failed",
+        "import_usage",
+        "import_topics",
+        "simulated",
+        "reply_required",
+        "unsupported_message",
+        "allowed",
+        "forbidden",
+        "forgot",
+        "didnt_know",
+        "reset_prompt",
+        "reset_button",
+        "cancel_button",
+        "reset_done",
+        "reset_cancelled",
+        "rotated",
+        "cant_message_you",
+        "export_sent",
+        "backup_failed",
+        "backup_sent",
+        "restore_usage",
+        "restore_failed",
+        "restore_staged",
+        "already_active",
+        "activated",
+        "pause_usage",
+        "paused",
+        "unpaused",
+        "not_paused",
+        "reloaded",
+        "reload_failed",
+        "set_usage",
+        "unknown_setting",
+        "invalid_value",
+        "setting_changed",
+        "cant_tell_sender",
+        "exempted",
+        "unexempted",
+        "duplicate_of",
+        "texts_not_retained",
+        "search_usage",
+        "nothing_found",
+        "search_result",
+        "phrases_usage",
+        "common_phrases",
+        "no_common_phrases",
+        "phrase_added",
+        "phrase_removed",
+        "allowed_repeats",
+        "check_allowed_repeats",
+        "duplicate_history",
+        "newcomer_warning",
+        "gc_done",
+        "db_size",
+        "deleted_duplicates_summary",
+        "slow_mode_on",
+        "slow_mode_off",
+        "sync_from_usage",
+        "synced",
+        "weekly_digest",
+        "digest_offenders",
+        "nothing_repeated",
+        "top_hash",
+        "modded",
+        "unmodded",
+        "precheck_usage",
+        "precheck_picked",
+        "duplicate_warning",
+        "why_normalized",
+        "why_hash",
+        "why_matched_exactly",
+        "why_matched_similar",
+        "why_burst",
+        "why_common_phrase",
+        "why_exempt",
+        "why_original",
+        "settings_import_usage",
+        "settings_import_failed",
+        "settings_imported",
+        "amnesty",
+        "bot_stats",
+        "sandbox_usage",
+        "raid_alert",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Text::Intro => "intro",
+            Text::NiceTry => "nice_try",
+            Text::SomethingWentWrong => "something_went_wrong",
+            Text::Resumed => "resumed",
+            Text::Suspended => "suspended",
+            Text::CheckUnknown => "check_unknown",
+            Text::CheckAllowed => "check_allowed",
+            Text::CheckForbidden => "check_forbidden",
+            Text::CheckExpired => "check_expired",
+            Text::CheckSeen { .. } => "check_seen",
+            Text::DeletedDuplicate { .. } => "deleted_duplicate",
+            Text::DeletedYourDuplicate { .. } => "deleted_your_duplicate",
+            Text::AppealButton => "appeal_button",
+            Text::AppealUnavailable => "appeal_unavailable",
+            Text::OnlyAuthorCanAppeal => "only_author_can_appeal",
+            Text::Appealed { .. } => "appealed",
+            Text::AdminsWillTakeALook => "admins_will_take_a_look",
+            Text::AllowButton => "allow_button",
+            Text::AllowedOnAppeal { .. } => "allowed_on_appeal",
+            Text::ImportTooLarge { .. } => "import_too_large",
+            Text::Imported { .. } => "imported",
+            Text::ImportFailed { .. } => "import_failed",
+            Text::ImportUsage => "import_usage",
+            Text::ImportTopics => "import_topics",
+            Text::Simulated { .. } => "simulated",
+            Text::ReplyRequired => "reply_required",
+            Text::UnsupportedMessage => "unsupported_message",
+            Text::Allowed { .. } => "allowed",
+            Text::Forbidden { .. } => "forbidden",
+            Text::Forgot { .. } => "forgot",
+            Text::DidntKnow => "didnt_know",
+            Text::ResetPrompt { .. } => "reset_prompt",
+            Text::ResetButton => "reset_button",
+            Text::CancelButton => "cancel_button",
+            Text::ResetDone { .. } => "reset_done",
+            Text::ResetCancelled => "reset_cancelled",
+            Text::Rotated { .. } => "rotated",
+            Text::CantMessageYou => "cant_message_you",
+            Text::ExportSent => "export_sent",
+            Text::BackupFailed => "backup_failed",
+            Text::BackupSent => "backup_sent",
+            Text::RestoreUsage => "restore_usage",
+            Text::RestoreFailed { .. } => "restore_failed",
+            Text::RestoreStaged => "restore_staged",
+            Text::AlreadyActive => "already_active",
+            Text::Activated => "activated",
+            Text::PauseUsage { .. } => "pause_usage",
+            Text::Paused { .. } => "paused",
+            Text::Unpaused => "unpaused",
+            Text::NotPaused => "not_paused",
+            Text::Reloaded => "reloaded",
+            Text::ReloadFailed { .. } => "reload_failed",
+            Text::SetUsage { .. } => "set_usage",
+            Text::UnknownSetting { .. } => "unknown_setting",
+            Text::InvalidValue { .. } => "invalid_value",
+            Text::SettingChanged { .. } => "setting_changed",
+            Text::CantTellSender => "cant_tell_sender",
+            Text::Exempted { .. } => "exempted",
+            Text::Unexempted { .. } => "unexempted",
+            Text::DuplicateOf { .. } => "duplicate_of",
+            Text::TextsNotRetained => "texts_not_retained",
+            Text::SearchUsage => "search_usage",
+            Text::NothingFound => "nothing_found",
+            Text::SearchResult { .. } => "search_result",
+            Text::PhrasesUsage => "phrases_usage",
+            Text::CommonPhrases { .. } => "common_phrases",
+            Text::NoCommonPhrases => "no_common_phrases",
+            Text::PhraseAdded { .. } => "phrase_added",
+            Text::PhraseRemoved { .. } => "phrase_removed",
+            Text::AllowedRepeats { .. } => "allowed_repeats",
+            Text::CheckAllowedRepeats { .. } => "check_allowed_repeats",
+            Text::DuplicateHistory { .. } => "duplicate_history",
+            Text::NewcomerWarning { .. } => "newcomer_warning",
+            Text::GcDone { .. } => "gc_done",
+            Text::DbSize { .. } => "db_size",
+            Text::DeletedDuplicatesSummary { .. } => "deleted_duplicates_summary",
+            Text::SlowModeOn { .. } => "slow_mode_on",
+            Text::SlowModeOff => "slow_mode_off",
+            Text::SyncFromUsage => "sync_from_usage",
+            Text::Synced { .. } => "synced",
+            Text::WeeklyDigest { .. } => "weekly_digest",
+            Text::DigestOffenders { .. } => "digest_offenders",
+            Text::NothingRepeated => "nothing_repeated",
+            Text::TopHash { .. } => "top_hash",
+            Text::Modded { .. } => "modded",
+            Text::Unmodded { .. } => "unmodded",
+            Text::PrecheckUsage => "precheck_usage",
+            Text::PrecheckPicked { .. } => "precheck_picked",
+            Text::DuplicateWarning { .. } => "duplicate_warning",
+            Text::WhyNormalized { .. } => "why_normalized",
+            Text::WhyHash { .. } => "why_hash",
+            Text::WhyMatchedExactly => "why_matched_exactly",
+            Text::WhyMatchedSimilar { .. } => "why_matched_similar",
+            Text::WhyBurst { .. } => "why_burst",
+            Text::WhyCommonPhrase => "why_common_phrase",
+            Text::WhyExempt => "why_exempt",
+            Text::WhyOriginal => "why_original",
+            Text::SettingsImportUsage => "settings_import_usage",
+            Text::SettingsImportFailed { .. } => "settings_import_failed",
+            Text::SettingsImported { .. } => "settings_imported",
+            Text::Amnesty { .. } => "amnesty",
+            Text::BotStats { .. } => "bot_stats",
+            Text::SandboxUsage => "sandbox_usage",
+            Text::RaidAlert { .. } => "raid_alert",
+        }
+    }
+
+    /// Values templates can refer to as `{name}`.
+    fn placeholders(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            Text::CheckSeen {
+                count,
+                first_seen,
+                will_be_deleted: _,
+            } => vec![
+                ("count", count.to_string()),
+                ("first_seen", first_seen.into()),
+            ],
+            Text::DeletedDuplicate { user, original } => vec![
+                ("user", user.into()),
+                ("original", original.unwrap_or_default().into()),
+            ],
+            Text::DeletedYourDuplicate {
+                chat,
+                original,
+                text_follows: _,
+            } => vec![
+                ("chat", chat.unwrap_or_default().into()),
+                ("original", original.unwrap_or_default().into()),
+            ],
+            Text::Appealed { user }
+            | Text::AllowedOnAppeal { user }
+            | Text::Exempted { user }
+            | Text::Unexempted { user }
+            | Text::Modded { user }
+            | Text::Unmodded { user }
+            | Text::NewcomerWarning { user }
+            | Text::DuplicateWarning { user } => vec![("user", user.into())],
+            Text::ImportTooLarge { size, limit } => {
+                vec![("size", size.into()), ("limit", limit.into())]
+            }
+            Text::Imported {
+                count,
+                duplicates,
+                service,
+                unsupported,
+                malformed,
+                shared: _,
+            } => vec![
+                ("count", count.to_string()),
+                ("duplicates", duplicates.to_string()),
+                ("service", service.to_string()),
+                ("unsupported", unsupported.to_string()),
+                ("malformed", malformed.to_string()),
+            ],
+            Text::ResetDone { count } => vec![("count", count.to_string())],
+            Text::ImportFailed { err }
+            | Text::ReloadFailed { err }
+            | Text::PauseUsage { err }
+            | Text::RestoreFailed { err } => vec![("err", err.into())],
+            Text::Paused { until } => vec![("until", until.into())],
+            Text::Simulated {
+                new,
+                known,
+                collisions,
+            } => vec![
+                ("new", new.to_string()),
+                ("known", known.to_string()),
+                ("collisions", collisions.to_string()),
+            ],
+            Text::ResetPrompt {
+                known,
+                decided,
+                shared: _,
+            } => vec![
+                ("count", known.to_string()),
+                ("decided", decided.to_string()),
+            ],
+            Text::SetUsage { settings } => vec![("settings", settings.into())],
+            Text::UnknownSetting { setting } => vec![("setting", setting.into())],
+            Text::InvalidValue { setting, err } => {
+                vec![("setting", setting.into()), ("err", err.into())]
+            }
+            Text::SettingChanged { setting, value } => {
+                vec![("setting", setting.into()), ("value", value.into())]
+            }
+            Text::DuplicateOf { text } => vec![("text", text.into())],
+            Text::SearchResult {
+                text,
+                count,
+                first_seen,
+            } => vec![
+                ("text", text.into()),
+                ("count", count.to_string()),
+                ("first_seen", first_seen.into()),
+            ],
+            Text::CommonPhrases { phrases } => vec![("phrases", phrases.into())],
+            Text::PhraseAdded { phrase } | Text::PhraseRemoved { phrase } => {
+                vec![("phrase", phrase.into())]
+            }
+            Text::AllowedRepeats { repeats, shared: _ } | Text::CheckAllowedRepeats { repeats } => {
+                vec![("repeats", repeats.to_string())]
+            }
+            Text::DuplicateHistory {
+                first_seen,
+                seconds_ago: _,
+                reposts,
+            } => vec![
+                ("first_seen", first_seen.into()),
+                ("reposts", reposts.to_string()),
+            ],
+            Text::GcDone {
+                removed,
+                reclaimed,
+                size,
+            } => vec![
+                ("removed", removed.to_string()),
+                ("reclaimed", reclaimed.into()),
+                ("size", size.into()),
+            ],
+            Text::DbSize {
+                size,
+                entries,
+                chat_entries,
+                shared: _,
+            } => vec![
+                ("size", size.into()),
+                ("entries", entries.to_string()),
+                ("chat_entries", chat_entries.to_string()),
+            ],
+            Text::DeletedDuplicatesSummary { count, seconds } => vec![
+                ("count", count.to_string()),
+                ("seconds", seconds.to_string()),
+            ],
+            Text::SlowModeOn { cooldown, delay } => vec![
+                ("cooldown", cooldown.to_string()),
+                ("delay", delay.to_string()),
+            ],
+            Text::Synced { copied, skipped } => vec![
+                ("copied", copied.to_string()),
+                ("skipped", skipped.to_string()),
+            ],
+            Text::WeeklyDigest {
+                deleted,
+                unique,
+                known,
+                most_repeated,
+            } => vec![
+                ("deleted", deleted.to_string()),
+                ("unique", unique.to_string()),
+                ("known", known.to_string()),
+                ("most_repeated", most_repeated.to_string()),
+            ],
+            Text::DigestOffenders { offenders } => vec![("offenders", offenders.into())],
+            Text::TopHash {
+                hash,
+                count,
+                first_seen,
+            } => vec![
+                ("hash", hash.into()),
+                ("count", count.to_string()),
+                ("first_seen", first_seen.into()),
+            ],
+            Text::PrecheckPicked { bot } => vec![("bot", bot.into())],
+            Text::WhyNormalized { steps } => vec![("steps", steps.into())],
+            Text::WhyHash { hash, namespace } => {
+                vec![("hash", hash.into()), ("namespace", namespace.into())]
+            }
+            Text::WhyMatchedSimilar { similarity } => {
+                vec![("similarity", similarity.to_string())]
+            }
+            Text::WhyBurst { copies, limit } => {
+                vec![("copies", copies.to_string()), ("limit", limit.to_string())]
+            }
+            Text::SettingsImportFailed { err } => vec![("err", err.into())],
+            Text::SettingsImported {
+                settings,
+                phrases,
+                exemptions,
+            } => vec![
+                ("settings", settings.to_string()),
+                ("phrases", phrases.to_string()),
+                ("exemptions", exemptions.to_string()),
+            ],
+            Text::Amnesty { count } => vec![("count", count.to_string())],
+            Text::BotStats {
+                uptime,
+                memory,
+                chats,
+                suspended,
+                updates,
+                api_errors,
+                error_rate,
+                database,
+                storage,
+            } => vec![
+                ("uptime", uptime.into()),
+                ("memory", memory.into()),
+                ("chats", chats.to_string()),
+                ("suspended", suspended.to_string()),
+                ("updates", updates.to_string()),
+                ("api_errors", api_errors.to_string()),
+                ("error_rate", error_rate.into()),
+                ("database", if database { "ok" } else { "failing" }.into()),
+                ("storage", if storage { "ok" } else { "failing" }.into()),
+            ],
+            Text::RaidAlert {
+                chat,
+                count,
+                texts,
+                users,
+            } => vec![
+                ("chat", chat.into()),
+                ("count", count.to_string()),
+                ("texts", texts.into()),
+                ("users", users.into()),
+            ],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Operator's replacements for built-in texts, from the config's `[templates]`.
+///
+/// Keys are text names, optionally prefixed with a language code and a dot
+/// to only apply in that language. Each text has a pool of templates,
+/// and a random one is used every time.
+pub type Templates = BTreeMap<String, Vec<String>>;
+
+/// Checks that every template replaces a text that exists.
+pub fn validate_templates(templates: &Templates) -> eyre::Result<()> {
+    for (key, pool) in templates {
+        if pool.is_empty() {
+            eyre::bail!("template {key:?} has no options");
+        }
+        let name = match key.split_once('.') {
+            Some((code, name)) => {
+                code.parse::<Language>()?;
+                name
+            }
+            None => key,
+        };
+        if !Text::NAMES.contains(&name) {
+            eyre::bail!("unknown template: {key:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Language of a chat, along with the templates overriding its texts.
+#[derive(Debug, Clone)]
+pub struct Locale {
+    pub language: Language,
+    templates: Arc<Templates>,
+}
+
+impl Locale {
+    pub fn new(language: Language, templates: Arc<Templates>) -> Self {
+        Self {
+            language,
+            templates,
+        }
+    }
+
+    pub fn text(&self, text: Text<'_>) -> String {
+        let name = text.name();
+        let template = self
+            .templates
+            .get(&format!("{}.{name}", self.language.code()))
+            .or_else(|| self.templates.get(name));
+        match template {
+            Some(pool) => fill(pick::<String>(pool), &text.placeholders()),
+            None => self.language.text(text),
+        }
+    }
+}
+
+/// Picks a random option, for the bot not to sound the same every time.
+fn pick<T>(options: &[T]) -> &T {
+    let mut index = [0; 4];
+    // Variety isn't worth failing over, so this falls back to the first option.
+    let _ = getrandom::getrandom(&mut index);
+    &options[u32::from_ne_bytes(index) as usize % options.len()]
+}
+
+/// Replaces `{name}` placeholders in a template, leaving unknown ones as they are.
+fn fill(template: &str, placeholders: &[(&str, String)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            placeholders
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// How long ago something happened, in the largest whole unit.
+fn english_ago(seconds: i64) -> String {
+    let (count, unit) = match seconds {
+        ..60 => return "less than a minute ago".into(),
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+fn english_times(count: u32) -> String {
+    match count {
+        1 => "once".into(),
+        2 => "twice".into(),
+        _ => format!("{count} times"),
+    }
+}
+
+/// Russian form of a noun for `count`: one, a few, or many of it.
+fn russian_plural<'a>(count: i64, one: &'a str, few: &'a str, many: &'a str) -> &'a str {
+    match (count % 10, count % 100) {
+        (_, 11..=14) => many,
+        (1, _) => one,
+        (2..=4, _) => few,
+        _ => many,
+    }
+}
+
+fn russian_ago(seconds: i64) -> String {
+    let (count, unit) = match seconds {
+        ..60 => return "меньше минуты назад".into(),
+        60..3600 => {
+            let minutes = seconds / 60;
+            (
+                minutes,
+                russian_plural(minutes, "минуту", "минуты", "минут"),
+            )
+        }
+        3600..86400 => {
+            let hours = seconds / 3600;
+            (hours, russian_plural(hours, "час", "часа", "часов"))
+        }
+        _ => {
+            let days = seconds / 86400;
+            (days, russian_plural(days, "день", "дня", "дней"))
+        }
+    };
+    format!("{count} {unit} назад")
+}
+
+fn english(text: Text<'_>) -> String {
+    let shared_suffix = |shared| {
+        if shared {
+            " in all chats sharing the database"
+        } else {
+            ""
+        }
+    };
+    match text {
+        Text::Intro => "Hi! I delete messages that were already posted in this chat. \
+                        I need permission to delete messages, and an admin needs to \
+                        send /activate before I start."
+            .into(),
+        Text::NiceTry => (*pick(&[
+            "Nice try",
+            "You wish",
+            "Not so fast",
+            "That's for admins, and you're not one",
+            "Cute",
+        ]))
+        .into(),
+        Text::SomethingWentWrong => "Something went wrong, sorry :(".into(),
+        Text::Resumed => "I can delete messages again, duplicates will be deleted".into(),
+        Text::Suspended => "I can't delete messages here, so I'll ignore duplicates \
+                            until an admin gives me the permission"
+            .into(),
+        Text::CheckUnknown => {
+            "I don't know this message, the next copy will count as the first one".into()
+        }
+        Text::CheckAllowed => "This message is allowed, copies won't be deleted".into(),
+        Text::CheckForbidden => "This message is forbidden, copies will be deleted".into(),
+        Text::CheckExpired => {
+            "I've seen this message long enough ago, the next copy will count as the first one"
+                .into()
+        }
+        Text::CheckSeen {
+            count,
+            first_seen,
+            will_be_deleted,
+        } => {
+            let fate = if will_be_deleted {
+                "will be deleted"
+            } else {
+                "won't be deleted yet"
+            };
+            format!("I've seen this message {count} times since {first_seen}, the next copy {fate}")
+        }
+        Text::DeletedDuplicate { user, original } => match original {
+            Some(url) => format!("Deleted a duplicate from {user}, the original: {url}"),
+            None => {
+                let snark = pick(&[
+                    "Deleted a duplicate from {user}",
+                    "{user}, that was posted before. Deleted",
+                    "Deleted a duplicate from {user}, try being original",
+                    "{user} has been caught reposting. Deleted",
+                ]);
+                snark.replace("{user}", user)
+            }
+        },
+        Text::DeletedYourDuplicate {
+            chat,
+            original,
+            text_follows,
+        } => {
+            let chat = chat.unwrap_or("the chat");
+            let mut notice = match original {
+                Some(url) => format!("Your message in {chat} was deleted as a duplicate of {url}"),
+                None => format!("Your message in {chat} was deleted as a duplicate"),
+            };
+            if text_follows {
+                notice.push_str(". Here's its text, so you don't lose it:");
+            }
+            notice
+        }
+        Text::AppealButton => "Appeal".into(),
+        Text::AppealUnavailable => "This appeal is no longer available".into(),
+        Text::OnlyAuthorCanAppeal => "Only the author can appeal".into(),
+        Text::Appealed { user } => format!("Appealed by {user}, admins can allow it"),
+        Text::AdminsWillTakeALook => "Admins will take a look".into(),
+        Text::AllowButton => "Allow".into(),
+        Text::AllowedOnAppeal { user } => format!("Allowed by {user}"),
+        Text::ImportTooLarge { size, limit } => {
+            format!("Come on, there's no way I'll import a {size}B file (my limit is {limit}B)")
+        }
+        Text::Imported {
+            count,
+            duplicates,
+            service,
+            unsupported,
+            malformed,
+            shared,
+        } => {
+            let mut text = format!("Sucessfully imported {count} messages");
+            if shared {
+                text.push_str(" into the database shared with other chats");
+            }
+            text.push_str(&format!(
+                "\nAlready known duplicates: {duplicates}\
+                 \nService messages ignored: {service}\
+                 \nMessages without text ignored: {unsupported}\
+                 \nMalformed entries skipped: {malformed}"
+            ));
+            text
+        }
+        Text::ImportFailed { err } => {
+            format!("Failed to parse your import, sorry :(\nError: {err}")
+        }
+        Text::ImportUsage => "Send an export as a document with /import as its caption, \
+                              or /simulate to see what importing it would do. \
+                              Use /import plain for a text file with one message per line, \
+                              /import discord for a DiscordChatExporter JSON export \
+                              or /import whatsapp for a WhatsApp chat export. \
+                              From an export of every chat, this chat's history is imported, \
+                              or another one's with its id, like /import -1001234567890"
+            .into(),
+        Text::ImportTopics => "Topics of this chat are checked separately, \
+                               but exports don't say which topic a message was in, \
+                               so they can't be imported here"
+            .into(),
+        Text::Simulated {
+            new,
+            known,
+            collisions,
+        } => format!(
+            "Importing this would add {new} new messages. {known} are already known, \
+             and {collisions} repeat earlier messages in the export"
+        ),
+        Text::ReplyRequired => "Reply to a message with this command".into(),
+        Text::UnsupportedMessage => "I only know about text messages".into(),
+        Text::Allowed { shared } => format!(
+            "Allowed, copies of this message won't be deleted{}",
+            shared_suffix(shared)
+        ),
+        Text::Forbidden { shared } => format!(
+            "Forbidden, copies of this message will be deleted{}",
+            shared_suffix(shared)
+        ),
+        Text::Forgot { shared } => format!(
+            "Forgot it, the next copy will count as the first one{}",
+            shared_suffix(shared)
+        ),
+        Text::DidntKnow => "I didn't know this message anyway".into(),
+        Text::ResetPrompt {
+            known,
+            decided,
+            shared,
+        } => {
+            let mut text = format!(
+                "This will forget all {known} messages I know in this chat, \
+                 including {decided} allowed or forbidden by admins. \
+                 Every next copy will count as the first one."
+            );
+            if shared {
+                text.push_str(" The database is shared, so other chats will be reset too.");
+            }
+            text
+        }
+        Text::ResetButton => "Reset".into(),
+        Text::CancelButton => "Cancel".into(),
+        Text::ResetDone { count } => format!("Forgot {count} messages"),
+        Text::ResetCancelled => "Reset cancelled".into(),
+        Text::Rotated { shared } => format!(
+            "Rotated, every next copy will count as the first one{}",
+            shared_suffix(shared)
+        ),
+        Text::CantMessageYou => "I can't message you, start a chat with me first".into(),
+        Text::ExportSent => "Sent you the export privately".into(),
+        Text::BackupFailed => "Couldn't send the backup, see the logs".into(),
+        Text::BackupSent => "Backup sent".into(),
+        Text::RestoreUsage => "Send me a backup in private and reply to it with /restore".into(),
+        Text::RestoreFailed { err } => format!("That's not a backup I can restore: {err}"),
+        Text::RestoreStaged => {
+            "The backup is fine. It'll replace the database once I'm restarted, \
+                                and the current one will be kept next to it"
+                .into()
+        }
+        Text::AlreadyActive => "I'm already active here".into(),
+        Text::Activated => "Activated, duplicates will be deleted from now on".into(),
+        Text::PauseUsage { err } => {
+            format!("Usage: /pause <duration>, like /pause 1h or /pause 1h30m\nError: {err}")
+        }
+        Text::Paused { until } => format!("Paused, duplicates won't be deleted until {until}"),
+        Text::Unpaused => "Back to work, duplicates will be deleted again".into(),
+        Text::NotPaused => "I'm not paused".into(),
+        Text::Reloaded => "Reloaded the config".into(),
+        Text::ReloadFailed { err } => format!("Couldn't reload the config: {err}"),
+        Text::SetUsage { settings } => {
+            format!("Usage: /set <setting> <value|default>\nSettings: {settings}")
+        }
+        Text::UnknownSetting { setting } => format!("Unknown setting: {setting}"),
+        Text::InvalidValue { setting, err } => format!("Invalid value for {setting}: {err}"),
+        Text::SettingChanged { setting, value } => format!("{setting} is now {value}"),
+        Text::CantTellSender => "I can't tell who sent this message".into(),
+        Text::Exempted { user } => format!("{user} won't be checked for duplicates anymore"),
+        Text::Unexempted { user } => format!("{user} will be checked for duplicates again"),
+        Text::DuplicateOf { text } => format!("Duplicated: {text}"),
+        Text::TextsNotRetained => {
+            "This chat doesn't keep texts, an admin can turn it on with /set retain_texts true"
+                .into()
+        }
+        Text::SearchUsage => "Usage: /search <text>".into(),
+        Text::NothingFound => "No known message contains that".into(),
+        Text::SearchResult {
+            text,
+            count,
+            first_seen,
+        } => format!("{text} (posted {count} times since {first_seen})"),
+        Text::PhrasesUsage => {
+            "Usage: /phrases, /phrases add <phrase> or /phrases remove <phrase>".into()
+        }
+        Text::CommonPhrases { phrases } => format!("Never checked: {phrases}"),
+        Text::NoCommonPhrases => "Every message is checked, there are no common phrases".into(),
+        Text::PhraseAdded { phrase } => {
+            format!("\"{phrase}\" won't be checked for duplicates anymore")
+        }
+        Text::PhraseRemoved { phrase } => {
+            format!("\"{phrase}\" will be checked for duplicates again")
+        }
+        Text::AllowedRepeats { repeats, shared } => format!(
+            "Allowed, the next {repeats} copies of this message won't be deleted{}",
+            shared_suffix(shared)
+        ),
+        Text::CheckAllowedRepeats { repeats } => {
+            format!("This message is allowed, the next {repeats} copies won't be deleted")
+        }
+        Text::DuplicateHistory {
+            first_seen: _,
+            seconds_ago,
+            reposts,
+        } => format!(
+            "First posted {}, reposted {} since",
+            english_ago(seconds_ago),
+            english_times(reposts)
+        ),
+        Text::NewcomerWarning { user } => format!(
+            "Welcome, {user}! This was already posted here, so please don't repeat it. \
+             Next time, duplicates will be deleted"
+        ),
+        Text::GcDone {
+            removed,
+            reclaimed,
+            size,
+        } => format!(
+            "Removed {removed} entries and reclaimed {reclaimed}B, the database takes {size}B now"
+        ),
+        Text::DbSize {
+            size,
+            entries,
+            chat_entries,
+            shared,
+        } => format!(
+            "The database takes {size}B and knows {entries} messages, {chat_entries} of them {}",
+            if shared {
+                "shared with this chat"
+            } else {
+                "from this chat"
+            }
+        ),
+        Text::DeletedDuplicatesSummary { count, seconds } => {
+            format!("Deleted {count} more duplicates in the last {seconds} seconds")
+        }
+        Text::SlowModeOn { cooldown, delay } => format!(
+            "Too many duplicates at once! For the next {cooldown} seconds, \
+             everyone may only post once every {delay} seconds"
+        ),
+        Text::SlowModeOff => "Slow mode is off, post as usual".into(),
+        Text::SyncFromUsage => "Usage in the chat to copy into: /sync_from <chat_id> to copy \
+                                 the allowed and forbidden messages of another chat, \
+                                 or /sync_from <chat_id> all to copy everything it knows"
+            .into(),
+        Text::Synced { copied, skipped } => format!(
+            "Copied {copied} messages. {skipped} more couldn't be copied, \
+             since the other chat doesn't keep their texts"
+        ),
+        Text::WeeklyDigest {
+            deleted,
+            unique,
+            known,
+            most_repeated,
+        } => format!(
+            "This week: {deleted} duplicates deleted, {unique} new messages remembered \
+             ({known} known in total), the most copies of one message posted this week: {most_repeated}"
+        ),
+        Text::DigestOffenders { offenders } => format!("Most duplicates deleted from: {offenders}"),
+        Text::NothingRepeated => "No message was posted more than once yet".into(),
+        Text::TopHash {
+            hash,
+            count,
+            first_seen,
+        } => format!("Message {hash} (posted {count} times since {first_seen})"),
+        Text::Modded { user } => {
+            format!("{user} can now /allow, /forbid and /check messages here")
+        }
+        Text::Unmodded { user } => format!("{user} is no longer a moderator here"),
+        Text::PrecheckUsage => "Send /precheck in a chat to check drafts against it".into(),
+        Text::PrecheckPicked { bot } => {
+            format!("Type @{bot} and a draft anywhere to see what would happen to it here")
+        }
+        Text::DuplicateWarning { user } => {
+            format!("{user}, this was already posted here, please don't repeat messages")
+        }
+        Text::WhyNormalized { steps } => match steps {
+            "" => "The text was hashed as it is".into(),
+            steps => format!("Before hashing: {steps}"),
+        },
+        Text::WhyHash { hash, namespace } => format!("Hash: {hash} ({namespace})"),
+        Text::WhyMatchedExactly => "Matched a known message exactly".into(),
+        Text::WhyMatchedSimilar { similarity } => {
+            format!("Matched a known message that's {similarity}% alike")
+        }
+        Text::WhyBurst { copies, limit } => format!(
+            "Its author posted it {copies} times in a row recently, more than {limit} is a flood"
+        ),
+        Text::WhyCommonPhrase => "It's a common phrase, so it's never checked".into(),
+        Text::WhyExempt => "Its author is exempt, so their messages are never checked".into(),
+        Text::WhyOriginal => "It's the first known copy of this message".into(),
+        Text::SettingsImportUsage => {
+            "Reply with /settings_import to a file sent by /settings_export".into()
+        }
+        Text::SettingsImportFailed { err } => {
+            format!("Couldn't import these settings, nothing was changed: {err}")
+        }
+        Text::SettingsImported {
+            settings,
+            phrases,
+            exemptions,
+        } => format!(
+            "Imported the settings: {settings} settings and {phrases} common phrases changed, \
+             {exemptions} users exempted"
+        ),
+        Text::Amnesty { count } => format!("Amnesty: lifted {count} mutes given for duplicates"),
+        Text::BotStats {
+            uptime,
+            memory,
+            chats,
+            suspended,
+            updates,
+            api_errors,
+            error_rate,
+            database,
+            storage,
+        } => {
+            let health = |ok| if ok { "OK" } else { "failing" };
+            format!(
+                "Up for {uptime}, using {memory} of memory\n\
+                 Chats: {chats}, {suspended} of them without the rights to delete\n\
+                 Updates since the start: {updates}\n\
+                 Telegram API errors: {api_errors} ({error_rate} of updates)\n\
+                 Database: {}\nStorage: {}",
+                health(database),
+                health(storage),
+            )
+        }
+        Text::SandboxUsage => {
+            "Send /precheck in a chat you administer, then send messages here to see what \
+             would happen to them there"
+                .into()
+        }
+        Text::RaidAlert {
+            chat,
+            count,
+            texts,
+            users,
+        } => format!(
+            "Looks like a raid in {chat}: {count} duplicates deleted within a minute. \
+             I keep deleting them, but you may want to ban whoever posts them.\n\n\
+             Texts:\n{texts}\n\nUsers:\n{users}"
+        ),
+    }
+}
+
+fn russian(text: Text<'_>) -> String {
+    let shared_suffix = |shared| {
+        if shared {
+            " во всех чатах с общей базой"
+        } else {
+            ""
+        }
+    };
+    match text {
+        Text::Intro => "Привет! Я удаляю сообщения, которые уже были в этом чате. \
+                        Мне нужно право удалять сообщения, а админу нужно \
+                        отправить /activate, чтобы я начал работать."
+            .into(),
+        Text::NiceTry => (*pick(&[
+            "Хорошая попытка",
+            "Размечтались",
+            "Не так быстро",
+            "Это для админов, а вы не админ",
+            "Мило",
+        ]))
+        .into(),
+        Text::SomethingWentWrong => "Что-то пошло не так, извините :(".into(),
+        Text::Resumed => "Я снова могу удалять сообщения, повторы будут удаляться".into(),
+        Text::Suspended => "Я не могу удалять сообщения здесь, поэтому буду пропускать \
+                            повторы, пока админ не даст мне это право"
+            .into(),
+        Text::CheckUnknown => {
+            "Я не знаю этого сообщения, следующая копия будет считаться первой".into()
+        }
+        Text::CheckAllowed => "Это сообщение разрешено, копии не будут удаляться".into(),
+        Text::CheckForbidden => "Это сообщение запрещено, копии будут удаляться".into(),
+        Text::CheckExpired => {
+            "Я видел это сообщение достаточно давно, следующая копия будет считаться первой".into()
+        }
+        Text::CheckSeen {
+            count,
+            first_seen,
+            will_be_deleted,
+        } => {
+            let fate = if will_be_deleted {
+                "будет удалена"
+            } else {
+                "пока не будет удалена"
+            };
+            format!("Я видел это сообщение {count} раз(а) с {first_seen}, следующая копия {fate}")
+        }
+        Text::DeletedDuplicate { user, original } => match original {
+            Some(url) => format!("Удалил повтор от {user}, оригинал: {url}"),
+            None => {
+                let snark = pick(&[
+                    "Удалил повтор от {user}",
+                    "{user}, это уже было. Удалено",
+                    "Удалил повтор от {user}, попробуйте быть оригинальнее",
+                    "{user} попался на баяне. Удалено",
+                ]);
+                snark.replace("{user}", user)
+            }
+        },
+        Text::DeletedYourDuplicate {
+            chat,
+            original,
+            text_follows,
+        } => {
+            let chat = chat.map_or_else(|| "чате".to_owned(), |chat| format!("«{chat}»"));
+            let mut notice = match original {
+                Some(url) => format!("Ваше сообщение в {chat} удалено как повтор {url}"),
+                None => format!("Ваше сообщение в {chat} удалено как повтор"),
+            };
+            if text_follows {
+                notice.push_str(". Вот его текст, чтобы он не потерялся:");
+            }
+            notice
+        }
+        Text::AppealButton => "Обжаловать".into(),
+        Text::AppealUnavailable => "Эта апелляция больше недоступна".into(),
+        Text::OnlyAuthorCanAppeal => "Обжаловать может только автор".into(),
+        Text::Appealed { user } => format!("{user} обжалует, админы могут разрешить сообщение"),
+        Text::AdminsWillTakeALook => "Админы посмотрят".into(),
+        Text::AllowButton => "Разрешить".into(),
+        Text::AllowedOnAppeal { user } => format!("{user} разрешил сообщение"),
+        Text::ImportTooLarge { size, limit } => {
+            format!("Ну уж нет, файл на {size}B я импортировать не буду (мой предел — {limit}B)")
+        }
+        Text::Imported {
+            count,
+            duplicates,
+            service,
+            unsupported,
+            malformed,
+            shared,
+        } => {
+            let mut text = format!("Импортировано сообщений: {count}");
+            if shared {
+                text.push_str(", в общую с другими чатами базу");
+            }
+            text.push_str(&format!(
+                "\nУже известных повторов: {duplicates}\
+                 \nПропущено служебных сообщений: {service}\
+                 \nПропущено сообщений без текста: {unsupported}\
+                 \nПропущено повреждённых записей: {malformed}"
+            ));
+            text
+        }
+        Text::ImportFailed { err } => {
+            format!("Не получилось разобрать импорт, извините :(\nОшибка: {err}")
+        }
+        Text::ImportUsage => "Отправьте экспорт документом с подписью /import \
+                              или /simulate, чтобы узнать, что сделает импорт. \
+                              Для текстового файла с одним сообщением на строку \
+                              используйте /import plain, для JSON-экспорта \
+                              DiscordChatExporter — /import discord, \
+                              для экспорта чата WhatsApp — /import whatsapp. \
+                              Из экспорта всех чатов импортируется история этого чата \
+                              или другого по его id, например /import -1001234567890"
+            .into(),
+        Text::ImportTopics => "Темы этого чата проверяются по отдельности, \
+                               а в экспорте не указано, в какой теме было сообщение, \
+                               поэтому его сюда не импортировать"
+            .into(),
+        Text::Simulated {
+            new,
+            known,
+            collisions,
+        } => format!(
+            "Импорт добавит новых сообщений: {new}. Уже известно: {known}, \
+             повторяют более ранние сообщения экспорта: {collisions}"
+        ),
+        Text::ReplyRequired => "Отправьте эту команду ответом на сообщение".into(),
+        Text::UnsupportedMessage => "Я разбираюсь только в текстовых сообщениях".into(),
+        Text::Allowed { shared } => format!(
+            "Разрешено, копии этого сообщения не будут удаляться{}",
+            shared_suffix(shared)
+        ),
+        Text::Forbidden { shared } => format!(
+            "Запрещено, копии этого сообщения будут удаляться{}",
+            shared_suffix(shared)
+        ),
+        Text::Forgot { shared } => format!(
+            "Забыл, следующая копия будет считаться первой{}",
+            shared_suffix(shared)
+        ),
+        Text::DidntKnow => "Я и так не знал этого сообщения".into(),
+        Text::ResetPrompt {
+            known,
+            decided,
+            shared,
+        } => {
+            let mut text = format!(
+                "Я забуду все известные мне сообщения в этом чате ({known}), \
+                 в том числе разрешённые или запрещённые админами ({decided}). \
+                 Каждая следующая копия будет считаться первой."
+            );
+            if shared {
+                text.push_str(" База общая, так что другие чаты тоже будут сброшены.");
+            }
+            text
+        }
+        Text::ResetButton => "Сбросить".into(),
+        Text::CancelButton => "Отмена".into(),
+        Text::ResetDone { count } => format!("Забыл сообщений: {count}"),
+        Text::ResetCancelled => "Сброс отменён".into(),
+        Text::Rotated { shared } => format!(
+            "Соль сменена, каждая следующая копия будет считаться первой{}",
+            shared_suffix(shared)
+        ),
+        Text::CantMessageYou => "Я не могу вам написать, сначала начните чат со мной".into(),
+        Text::ExportSent => "Отправил экспорт в личные сообщения".into(),
+        Text::BackupFailed => "Не получилось отправить бэкап, подробности в логах".into(),
+        Text::BackupSent => "Бэкап отправлен".into(),
+        Text::RestoreUsage => {
+            "Пришлите мне бэкап в личку и ответьте на него командой /restore".into()
+        }
+        Text::RestoreFailed { err } => format!("Этот бэкап восстановить не получится: {err}"),
+        Text::RestoreStaged => "С бэкапом всё в порядке. Он заменит базу после перезапуска, \
+                                а текущая база сохранится рядом"
+            .into(),
+        Text::AlreadyActive => "Я уже работаю здесь".into(),
+        Text::Activated => "Готово, теперь повторы будут удаляться".into(),
+        Text::PauseUsage { err } => {
+            format!("Использование: /pause <длительность>, например /pause 1h или /pause 1h30m\nОшибка: {err}")
+        }
+        Text::Paused { until } => format!("Пауза, повторы не будут удаляться до {until}"),
+        Text::Unpaused => "Снова за работой, повторы опять будут удаляться".into(),
+        Text::NotPaused => "Я и не на паузе".into(),
+        Text::Reloaded => "Конфиг перезагружен".into(),
+        Text::ReloadFailed { err } => format!("Не получилось перезагрузить конфиг: {err}"),
+        Text::SetUsage { settings } => {
+            format!("Использование: /set <настройка> <значение|default>\nНастройки: {settings}")
+        }
+        Text::UnknownSetting { setting } => format!("Неизвестная настройка: {setting}"),
+        Text::InvalidValue { setting, err } => {
+            format!("Неправильное значение для {setting}: {err}")
+        }
+        Text::SettingChanged { setting, value } => format!("{setting} теперь {value}"),
+        Text::CantTellSender => "Я не могу понять, кто отправил это сообщение".into(),
+        Text::Exempted { user } => format!("Сообщения {user} больше не проверяются на повторы"),
+        Text::Unexempted { user } => format!("Сообщения {user} снова проверяются на повторы"),
+        Text::DuplicateOf { text } => format!("Повтор: {text}"),
+        Text::TextsNotRetained => "В этом чате тексты не хранятся, админ может включить это \
+                                   командой /set retain_texts true"
+            .into(),
+        Text::SearchUsage => "Использование: /search <текст>".into(),
+        Text::NothingFound => "Ни одно известное сообщение этого не содержит".into(),
+        Text::SearchResult {
+            text,
+            count,
+            first_seen,
+        } => format!("{text} (отправлено {count} раз с {first_seen})"),
+        Text::PhrasesUsage => {
+            "Использование: /phrases, /phrases add <фраза> или /phrases remove <фраза>".into()
+        }
+        Text::CommonPhrases { phrases } => format!("Не проверяются: {phrases}"),
+        Text::NoCommonPhrases => "Проверяются все сообщения, общих фраз нет".into(),
+        Text::PhraseAdded { phrase } => format!("«{phrase}» больше не проверяется на повторы"),
+        Text::PhraseRemoved { phrase } => format!("«{phrase}» снова проверяется на повторы"),
+        Text::AllowedRepeats { repeats, shared } => format!(
+            "Разрешено, следующие копии этого сообщения не будут удаляться: {repeats}{}",
+            shared_suffix(shared)
+        ),
+        Text::CheckAllowedRepeats { repeats } => {
+            format!("Это сообщение разрешено, следующие копии не будут удаляться: {repeats}")
+        }
+        Text::DuplicateHistory {
+            first_seen: _,
+            seconds_ago,
+            reposts,
+        } => format!(
+            "Впервые отправлено {}, повторов с тех пор: {reposts}",
+            russian_ago(seconds_ago)
+        ),
+        Text::NewcomerWarning { user } => format!(
+            "Добро пожаловать, {user}! Это здесь уже писали, пожалуйста, не повторяйтесь. \
+             Потом повторы будут удаляться"
+        ),
+        Text::GcDone {
+            removed,
+            reclaimed,
+            size,
+        } => format!(
+            "Удалено записей: {removed}, освобождено {reclaimed}B, теперь база занимает {size}B"
+        ),
+        Text::DbSize {
+            size,
+            entries,
+            chat_entries,
+            shared,
+        } => format!(
+            "База занимает {size}B, известных сообщений: {entries}, из них {}: {chat_entries}",
+            if shared {
+                "общих с этим чатом"
+            } else {
+                "из этого чата"
+            }
+        ),
+        Text::DeletedDuplicatesSummary { count, seconds } => format!(
+            "Удалено ещё повторов за последние {seconds} {}: {count}",
+            russian_plural(seconds as i64, "секунду", "секунды", "секунд")
+        ),
+        Text::SlowModeOn { cooldown, delay } => format!(
+            "Слишком много повторов! Следующие {cooldown} {} писать можно не чаще раза в {delay} {}",
+            russian_plural(cooldown as i64, "секунду", "секунды", "секунд"),
+            russian_plural(delay as i64, "секунду", "секунды", "секунд"),
+        ),
+        Text::SlowModeOff => "Медленный режим выключен, пишите как обычно".into(),
+        Text::SyncFromUsage => "Использование в чате, куда копировать: /sync_from <chat_id>, \
+                                 чтобы скопировать разрешённые и запрещённые сообщения другого чата, \
+                                 или /sync_from <chat_id> all, чтобы скопировать всё, что о нём известно"
+            .into(),
+        Text::Synced { copied, skipped } => format!(
+            "Скопировано сообщений: {copied}. Не удалось скопировать: {skipped}, \
+             потому что другой чат не хранит их тексты"
+        ),
+        Text::WeeklyDigest {
+            deleted,
+            unique,
+            known,
+            most_repeated,
+        } => format!(
+            "За неделю удалено повторов: {deleted}, запомнено новых сообщений: {unique} \
+             (всего известно: {known}), больше всего копий одного сообщения за неделю: {most_repeated}"
+        ),
+        Text::DigestOffenders { offenders } => {
+            format!("Больше всего повторов удалено у: {offenders}")
+        }
+        Text::NothingRepeated => "Ещё ни одно сообщение не отправляли больше одного раза".into(),
+        Text::TopHash {
+            hash,
+            count,
+            first_seen,
+        } => format!("Сообщение {hash} (отправлено {count} раз с {first_seen})"),
+        Text::Modded { user } => {
+            format!("{user} теперь может использовать /allow, /forbid и /check в этом чате")
+        }
+        Text::Unmodded { user } => format!("{user} больше не модератор в этом чате"),
+        Text::PrecheckUsage => {
+            "Отправьте /precheck в чате, чтобы проверять черновики для него".into()
+        }
+        Text::PrecheckPicked { bot } => {
+            format!("Наберите @{bot} и черновик в любом чате, чтобы узнать, что с ним будет здесь")
+        }
+        Text::DuplicateWarning { user } => {
+            format!("{user}, это здесь уже писали, пожалуйста, не повторяйтесь")
+        }
+        Text::WhyNormalized { steps } => match steps {
+            "" => "Текст хешировался как есть".into(),
+            steps => format!("Перед хешированием: {steps}"),
+        },
+        Text::WhyHash { hash, namespace } => format!("Хеш: {hash} ({namespace})"),
+        Text::WhyMatchedExactly => "Точно совпало с известным сообщением".into(),
+        Text::WhyMatchedSimilar { similarity } => {
+            format!("Совпало с известным сообщением, похожим на {similarity}%")
+        }
+        Text::WhyBurst { copies, limit } => format!(
+            "Автор недавно отправил это {copies} раз(а) подряд, больше {limit} считается флудом"
+        ),
+        Text::WhyCommonPhrase => "Это частая фраза, такие не проверяются".into(),
+        Text::WhyExempt => "Автор в исключениях, его сообщения не проверяются".into(),
+        Text::WhyOriginal => "Это первая известная копия сообщения".into(),
+        Text::SettingsImportUsage => {
+            "Ответьте /settings_import на файл, присланный командой /settings_export".into()
+        }
+        Text::SettingsImportFailed { err } => {
+            format!("Не получилось импортировать настройки, ничего не изменилось: {err}")
+        }
+        Text::SettingsImported {
+            settings,
+            phrases,
+            exemptions,
+        } => format!(
+            "Настройки импортированы: изменено настроек — {settings}, частых фраз — {phrases}, \
+             освобождено от проверки пользователей — {exemptions}"
+        ),
+        Text::Amnesty { count } => format!("Амнистия: снято мьютов за повторы — {count}"),
+        Text::BotStats {
+            uptime,
+            memory,
+            chats,
+            suspended,
+            updates,
+            api_errors,
+            error_rate,
+            database,
+            storage,
+        } => {
+            let health = |ok| if ok { "в порядке" } else { "не работает" };
+            format!(
+                "Работает {uptime}, занимает {memory} памяти\n\
+                 Чатов: {chats}, из них без прав на удаление: {suspended}\n\
+                 Обновлений с запуска: {updates}\n\
+                 Ошибок Telegram API: {api_errors} ({error_rate} обновлений)\n\
+                 База: {}\nХранилище: {}",
+                health(database),
+                health(storage),
+            )
+        }
+        Text::SandboxUsage => {
+            "Отправьте /precheck в чате, где вы админ, а потом присылайте сюда сообщения, \
+             чтобы узнать, что с ними там будет"
+                .into()
+        }
+        Text::RaidAlert {
+            chat,
+            count,
+            texts,
+            users,
+        } => format!(
+            "Похоже на рейд в {chat}: за минуту удалено повторов — {count}. \
+             Я продолжаю их удалять, но, возможно, стоит забанить тех, кто их шлёт.\n\n\
+             Тексты:\n{texts}\n\nПользователи:\n{users}"
+        ),
+    }
+}
@@ -0,0 +1,1439 @@
+//! Importing chat histories exported from Telegram and other messengers.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    future::Future,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use chrono::DateTime;
+use color_eyre::eyre;
+use futures::{stream, StreamExt as _, TryStreamExt as _};
+use serde::{
+    de::{self, DeserializeSeed},
+    Deserialize, Deserializer,
+};
+use size_format::SizeFormatterBinary;
+use teloxide::{
+    types::{
+        ChatId, Document, MediaDocument, MediaKind, Message, MessageId, MessageKind, User, UserId,
+    },
+    utils::command::BotCommands as _,
+};
+
+use crate::{
+    api::TelegramApi,
+    archive,
+    audit::AuditEvent,
+    commands::Command,
+    fuzzy::Signature,
+    i18n::Text,
+    normalize::{location_placeholder, phone_placeholder, venue_placeholder},
+    policy::Settings,
+    robot::{describe_chat, describe_user, reply, Robot9000},
+    storage::{Key, Namespace, Post, Scope},
+};
+
+/// Format of an uploaded chat history, picked by the `/import` argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// JSON export from Telegram Desktop.
+    Telegram,
+    /// Plain text with one message per line.
+    Plain,
+    /// JSON export from DiscordChatExporter.
+    Discord,
+    /// `_chat.txt` from WhatsApp.
+    WhatsApp,
+}
+
+impl FromStr for ImportFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "telegram" => Ok(ImportFormat::Telegram),
+            "plain" => Ok(ImportFormat::Plain),
+            "discord" => Ok(ImportFormat::Discord),
+            "whatsapp" => Ok(ImportFormat::WhatsApp),
+            _ => Err(eyre::eyre!("unknown import format: {s:?}")),
+        }
+    }
+}
+
+/// Parses what follows `/import` or `/simulate`: the format, and for Telegram's
+/// export of every chat, the id of the chat to read if it's not `chat_id`.
+/// Either can be left out.
+pub(crate) fn parse_import_args(
+    args: &str,
+    chat_id: ChatId,
+) -> eyre::Result<(ImportFormat, ChatId)> {
+    let (mut format, mut source) = (None, None);
+    for arg in args.split_whitespace() {
+        match arg.parse::<i64>() {
+            Ok(id) if source.is_none() => source = Some(ChatId(id)),
+            Err(_) if format.is_none() => format = Some(arg.parse()?),
+            _ => eyre::bail!("unexpected argument: {arg:?}"),
+        }
+    }
+    Ok((
+        format.unwrap_or(ImportFormat::Telegram),
+        source.unwrap_or(chat_id),
+    ))
+}
+
+impl ImportFormat {
+    /// Extension of the file to import when a zip archive is uploaded.
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            ImportFormat::Telegram | ImportFormat::Discord => ".json",
+            ImportFormat::Plain | ImportFormat::WhatsApp => ".txt",
+        }
+    }
+
+    /// Reads an export, calling `f` with every item as soon as it's parsed.
+    /// Only the history of `source` is read from Telegram's export of every chat.
+    /// Errors from `f` are returned as is, while a malformed export results
+    /// in an inner error to show to the user.
+    pub(crate) fn read(
+        self,
+        reader: impl BufRead,
+        now: i64,
+        source: ChatId,
+        f: &mut impl FnMut(ImportItem<'_>) -> eyre::Result<()>,
+    ) -> eyre::Result<io::Result<()>> {
+        // Dates in WhatsApp exports are written in the exporting phone's locale,
+        // so they can't be parsed reliably. Text files have none at all.
+        let post = Post {
+            timestamp: now,
+            message_id: None,
+            poster_id: None,
+        };
+        match self {
+            ImportFormat::Telegram => {
+                read_json(reader, source, &mut |message: Option<ImportMessage<'_>>| {
+                    let Some(message) = message else {
+                        return f(ImportItem::Malformed);
+                    };
+                    match &*message.r#type {
+                        "message" => {
+                            let post = message.post(now);
+                            match message.hashed_text() {
+                                Some(text) => f(ImportItem::Message(&text, post)),
+                                None => f(ImportItem::Unsupported),
+                            }
+                        }
+                        "service" => f(ImportItem::Service),
+                        _ => f(ImportItem::Unsupported),
+                    }
+                })
+            }
+            ImportFormat::Discord => read_json(reader, source, &mut |message: Option<
+                DiscordMessage<'_>,
+            >| {
+                let Some(message) = message else {
+                    return f(ImportItem::Malformed);
+                };
+                match &*message.r#type {
+                    "Default" | "Reply" if message.content.is_empty() => f(ImportItem::Unsupported),
+                    "Default" | "Reply" => {
+                        f(ImportItem::Message(&message.content, message.post(now)))
+                    }
+                    _ => f(ImportItem::Service),
+                }
+            }),
+            ImportFormat::Plain => read_lines(reader, |line| match line {
+                Some(line) if line.trim().is_empty() => Ok(()),
+                Some(line) => f(ImportItem::Message(line, post)),
+                None => f(ImportItem::Malformed),
+            }),
+            ImportFormat::WhatsApp => {
+                let mut body: Option<String> = None;
+                let result = read_lines(reader, |line| {
+                    let Some(line) = line else {
+                        return f(ImportItem::Malformed);
+                    };
+                    let item = match WhatsAppLine::parse(line) {
+                        WhatsAppLine::Message(text) => {
+                            if let Some(body) = body.replace(text.to_owned()) {
+                                f(ImportItem::Message(&body, post))?;
+                            }
+                            return Ok(());
+                        }
+                        WhatsAppLine::Continuation => {
+                            if let Some(body) = &mut body {
+                                body.push('\n');
+                                body.push_str(line);
+                            }
+                            return Ok(());
+                        }
+                        WhatsAppLine::Service => ImportItem::Service,
+                        WhatsAppLine::Media => ImportItem::Unsupported,
+                    };
+                    if let Some(body) = body.take() {
+                        f(ImportItem::Message(&body, post))?;
+                    }
+                    f(item)
+                })?;
+                if let Some(body) = body {
+                    f(ImportItem::Message(&body, post))?;
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+/// Something read from an export.
+pub enum ImportItem<'a> {
+    /// Text message to store.
+    Message(&'a str, Post),
+    /// Notification like someone joining the chat.
+    Service,
+    /// Message that isn't checked when it's posted, like a photo or a sticker.
+    Unsupported,
+    /// Entry that couldn't be parsed, skipped instead of failing the whole import.
+    Malformed,
+}
+
+/// [`ImportItem`] sent from the thread parsing an export.
+enum ReadItem {
+    Message(String, Post),
+    Service,
+    Unsupported,
+    Malformed,
+}
+
+impl ReadItem {
+    fn of(item: ImportItem<'_>) -> Self {
+        match item {
+            ImportItem::Message(text, post) => ReadItem::Message(text.to_owned(), post),
+            ImportItem::Service => ReadItem::Service,
+            ImportItem::Unsupported => ReadItem::Unsupported,
+            ImportItem::Malformed => ReadItem::Malformed,
+        }
+    }
+
+    fn as_item(&self) -> ImportItem<'_> {
+        match self {
+            ReadItem::Message(text, post) => ImportItem::Message(text, *post),
+            ReadItem::Service => ImportItem::Service,
+            ReadItem::Unsupported => ImportItem::Unsupported,
+            ReadItem::Malformed => ImportItem::Malformed,
+        }
+    }
+}
+
+/// Items read ahead of the one being stored.
+const READ_AHEAD: usize = 1024;
+
+/// Parses an export on a blocking thread, opened there by `open`, passing
+/// what's read to `sink` as it goes. Errors from the sink are returned as is,
+/// while a malformed export results in an inner error to show to the user.
+pub(crate) async fn read_into<S: ImportSink>(
+    format: ImportFormat,
+    open: impl FnOnce() -> eyre::Result<io::Result<Box<dyn BufRead>>> + Send + 'static,
+    now: i64,
+    source: ChatId,
+    mut sink: S,
+) -> eyre::Result<io::Result<S>> {
+    let (sender, mut items) = tokio::sync::mpsc::channel(READ_AHEAD);
+    let parser = tokio::task::spawn_blocking(move || {
+        let reader = match open()? {
+            Ok(reader) => reader,
+            Err(err) => return Ok(Err(err)),
+        };
+        format.read(reader, now, source, &mut |item| {
+            sender
+                .blocking_send(ReadItem::of(item))
+                .map_err(|_| eyre::eyre!("import aborted"))
+        })
+    });
+    let stored = async {
+        while let Some(item) = items.recv().await {
+            sink.consume(item.as_item()).await?;
+        }
+        Ok::<_, eyre::Report>(())
+    }
+    .await;
+    // Stops the parser if storing failed.
+    drop(items);
+    let parsed = parser.await?;
+    stored?;
+    Ok(parsed?.map(|()| sink))
+}
+
+/// Receives what's read from an export, in order.
+pub(crate) trait ImportSink: Send {
+    fn consume(&mut self, item: ImportItem<'_>) -> impl Future<Output = eyre::Result<()>> + Send;
+}
+
+/// Number of imported messages written to the database at once.
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// Hashes of a batch written at the same time.
+const IMPORT_CONCURRENCY: usize = 64;
+
+/// Message of an `/import` not written to the database yet, since
+/// waiting for the store after every message is too slow for large histories.
+struct Imported {
+    key: Key,
+    post: Post,
+    /// Signature of a long message, if fuzzy matching is on.
+    signature: Option<Signature>,
+}
+
+/// Stores what's read from an export into the known messages of a chat.
+pub struct Importer {
+    robot: Robot9000,
+    chat_id: ChatId,
+    namespace: Namespace,
+    settings: Settings,
+    batch: Vec<Imported>,
+    summary: ImportSummary,
+}
+
+impl Importer {
+    pub(crate) fn new(robot: Robot9000, chat_id: ChatId) -> eyre::Result<Self> {
+        Ok(Self {
+            namespace: robot.namespace(chat_id),
+            settings: robot.settings(chat_id)?,
+            robot,
+            chat_id,
+            batch: Vec::new(),
+            summary: ImportSummary::default(),
+        })
+    }
+
+    /// Writes the batch, then records its audit events in the order of the messages.
+    ///
+    /// Long messages are stored one by one, so they're matched against the ones
+    /// before them like live messages are. The rest are replayed onto whatever is
+    /// stored by the time of the write, with an update per hash, so messages
+    /// posted or allowed meanwhile aren't overwritten.
+    async fn commit(&mut self) -> eyre::Result<()> {
+        let mut batch = std::mem::take(&mut self.batch);
+        let mut entries = vec![None; batch.len()];
+        let mut copies = HashMap::<Key, Vec<usize>>::new();
+        for (i, message) in batch.iter_mut().enumerate() {
+            let Some(signature) = message.signature.take() else {
+                copies.entry(message.key).or_default().push(i);
+                continue;
+            };
+            let (key, signature) = self.robot.match_signature(message.key, signature).await?;
+            let entry = self
+                .robot
+                .store_hash(key, message.post, &self.settings)
+                .await?;
+            if let Some(signature) = signature {
+                self.robot.index_signature(key, &signature)?;
+            }
+            message.key = key;
+            entries[i] = Some(entry);
+        }
+        let (batch, settings, store) = (&batch, &self.settings, &self.robot.store);
+        let mut updates = stream::iter(copies)
+            .map(|(key, indices)| async move {
+                let mut replayed = Vec::with_capacity(indices.len());
+                store
+                    .update(key, settings.dedup_window, &mut |mut entry| {
+                        replayed.clear();
+                        let mut changed = false;
+                        for &i in &indices {
+                            if let Some(next) = settings.next_entry(entry, batch[i].post) {
+                                entry = Some(next);
+                                changed = true;
+                            }
+                            replayed.push((i, entry.expect("entry is always created")));
+                        }
+                        entry.filter(|_| changed)
+                    })
+                    .await?;
+                Ok::<_, eyre::Report>(replayed)
+            })
+            .buffer_unordered(IMPORT_CONCURRENCY);
+        while let Some(replayed) = updates.try_next().await? {
+            for (i, entry) in replayed {
+                entries[i] = Some(entry);
+            }
+        }
+        drop(updates);
+        for (message, entry) in batch.iter().zip(entries) {
+            let entry = entry.expect("every message is stored");
+            self.robot.audit(AuditEvent::store(
+                self.chat_id,
+                message.key,
+                message.post,
+                &entry,
+            ));
+            if self.settings.is_duplicate(&entry) {
+                self.summary.duplicates += 1;
+            } else {
+                self.summary.imported += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the batch to the database.
+    pub(crate) async fn finish(mut self) -> eyre::Result<ImportSummary> {
+        self.commit().await?;
+        Ok(self.summary)
+    }
+}
+
+impl ImportSink for Importer {
+    async fn consume(&mut self, item: ImportItem<'_>) -> eyre::Result<()> {
+        match item {
+            ImportItem::Message(text, post) => {
+                let scope = Scope {
+                    poster_id: post.poster_id.filter(|_| self.settings.per_user),
+                    ..self.namespace.into()
+                };
+                self.batch.push(Imported {
+                    key: self.robot.hash_message(scope, text)?,
+                    post,
+                    signature: self.robot.signature(scope, text)?,
+                });
+                if self.batch.len() >= IMPORT_BATCH_SIZE {
+                    self.commit().await?;
+                }
+            }
+            ImportItem::Service => self.summary.service += 1,
+            ImportItem::Unsupported => self.summary.unsupported += 1,
+            ImportItem::Malformed => self.summary.malformed += 1,
+        }
+        Ok(())
+    }
+}
+
+/// Counts what `/import` would do with an export, for `/simulate`.
+struct Simulation {
+    robot: Robot9000,
+    namespace: Namespace,
+    settings: Settings,
+    now: i64,
+    /// Hashes read so far, to tell apart messages colliding within the export.
+    seen: HashSet<[u8; 16]>,
+    /// New, known and colliding messages.
+    counts: [usize; 3],
+}
+
+impl ImportSink for Simulation {
+    async fn consume(&mut self, item: ImportItem<'_>) -> eyre::Result<()> {
+        let ImportItem::Message(text, _) = item else {
+            return Ok(());
+        };
+        let [new, known, collisions] = &mut self.counts;
+        let key = self.robot.hash_message(self.namespace.into(), text)?;
+        if !self.seen.insert(key.hash) {
+            *collisions += 1;
+        } else if self
+            .robot
+            .store
+            .peek(key)
+            .await?
+            .is_some_and(|entry| !self.settings.is_expired(entry.first_seen, self.now))
+        {
+            *known += 1;
+        } else {
+            *new += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Counts of everything read by `/import`, reported back to the admin.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportSummary {
+    pub(crate) imported: usize,
+    pub(crate) duplicates: usize,
+    pub(crate) service: usize,
+    pub(crate) unsupported: usize,
+    pub(crate) malformed: usize,
+}
+
+/// Line of a WhatsApp chat export, like `12/31/21, 23:59 - Name: text`
+/// on Android or `[31.12.21, 23:59:59] Name: text` on iOS.
+enum WhatsAppLine<'a> {
+    /// First line of a message, without the timestamp and the sender.
+    Message(&'a str),
+    /// Notification like someone joining.
+    Service,
+    /// Media left out of the export.
+    Media,
+    /// Next line of a multiline message.
+    Continuation,
+}
+
+impl<'a> WhatsAppLine<'a> {
+    fn parse(line: &'a str) -> Self {
+        let line = line.trim_start_matches(['\u{feff}', '\u{200e}']);
+        let split = match line.strip_prefix('[') {
+            Some(line) => line.split_once("] "),
+            None => line.split_once(" - "),
+        };
+        let Some((_, rest)) = split.filter(|(timestamp, _)| is_whatsapp_timestamp(timestamp))
+        else {
+            return WhatsAppLine::Continuation;
+        };
+        let Some((_sender, text)) = rest.split_once(": ") else {
+            return WhatsAppLine::Service;
+        };
+        let text = text
+            .strip_suffix(" <This message was edited>")
+            .unwrap_or(text);
+        // iOS marks attachments with a left-to-right mark.
+        if text.is_empty() || text.starts_with('\u{200e}') || text == "<Media omitted>" {
+            return WhatsAppLine::Media;
+        }
+        WhatsAppLine::Message(text)
+    }
+}
+
+/// Checks whether this looks like `date, time` in any of the formats
+/// WhatsApp uses in different locales.
+fn is_whatsapp_timestamp(timestamp: &str) -> bool {
+    let Some((date, time)) = timestamp.split_once(' ') else {
+        return false;
+    };
+    let date = date.strip_suffix(',').unwrap_or(date);
+    date.contains(['/', '.', '-'])
+        && date
+            .chars()
+            .all(|c| c.is_ascii_digit() || "/.-".contains(c))
+        && time.starts_with(|c: char| c.is_ascii_digit())
+        && time.contains(':')
+}
+
+/// Reads lines like [`BufRead::lines`], but passes `None` for lines that
+/// aren't valid UTF-8 instead of failing.
+fn read_lines(
+    mut reader: impl BufRead,
+    mut f: impl FnMut(Option<&str>) -> eyre::Result<()>,
+) -> eyre::Result<io::Result<()>> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => return Ok(Ok(())),
+            Ok(_) => (),
+            Err(err) => return Ok(Err(err)),
+        }
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        f(std::str::from_utf8(line).ok())?;
+    }
+}
+
+/// Parses a JSON export with [`ForEachMessage`].
+fn read_json<'de, M: Deserialize<'de>>(
+    reader: impl BufRead,
+    source: ChatId,
+    f: &mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+) -> eyre::Result<io::Result<()>> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut error = None;
+    let result = ForEachMessage {
+        f,
+        error: &mut error,
+        source,
+    }
+    .deserialize(&mut deserializer)
+    .and_then(|()| deserializer.end());
+    match (result, error) {
+        (_, Some(err)) => Err(err),
+        (result, None) => Ok(result.map_err(io::Error::from)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImportTextChunk<'a> {
+    Simple(#[serde(borrow)] Cow<'a, str>),
+    Typed {
+        #[serde(borrow)]
+        text: Cow<'a, str>,
+        /// Where a `text_link` leads, hashed instead of its text like live messages.
+        #[serde(default, borrow)]
+        href: Option<Cow<'a, str>>,
+    },
+}
+
+impl ImportTextChunk<'_> {
+    fn as_str(&self) -> &str {
+        match self {
+            ImportTextChunk::Simple(text) => text,
+            ImportTextChunk::Typed { text, href } => href.as_deref().unwrap_or(text),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImportText<'a> {
+    Simple(#[serde(borrow)] Cow<'a, str>),
+    Chunked(#[serde(borrow)] Vec<ImportTextChunk<'a>>),
+}
+
+impl<'a> ImportText<'a> {
+    fn moo(self) -> Cow<'a, str> {
+        match self {
+            ImportText::Simple(cow) => cow,
+            ImportText::Chunked(chunks) => chunks.iter().map(ImportTextChunk::as_str).collect(),
+        }
+    }
+}
+
+/// Message of a Telegram Desktop export. Unknown fields, like `forwarded_from`
+/// or `reply_to_message_id`, are ignored: forwards and replies are checked
+/// like any other message when they're posted too.
+#[derive(Deserialize)]
+struct ImportMessage<'a> {
+    #[serde(borrow)]
+    r#type: Cow<'a, str>,
+    /// Missing from some service messages.
+    #[serde(default, borrow)]
+    text: Option<ImportText<'a>>,
+    /// The same text as `text`, but always split into entities.
+    /// Only written by newer versions of Telegram Desktop.
+    #[serde(default, borrow)]
+    text_entities: Option<Vec<ImportTextChunk<'a>>>,
+    #[serde(default, borrow)]
+    date_unixtime: Option<Cow<'a, str>>,
+    #[serde(default)]
+    id: Option<i32>,
+    #[serde(default, borrow)]
+    from_id: Option<Cow<'a, str>>,
+    /// Path of a photo, which has no `media_type`.
+    #[serde(default)]
+    photo: Option<de::IgnoredAny>,
+    /// Path of any other file, like a video or a sticker.
+    #[serde(default)]
+    file: Option<de::IgnoredAny>,
+    #[serde(default)]
+    media_type: Option<de::IgnoredAny>,
+    #[serde(default)]
+    poll: Option<de::IgnoredAny>,
+    #[serde(default, borrow)]
+    contact_information: Option<ImportContact<'a>>,
+    #[serde(default)]
+    location_information: Option<ImportLocation>,
+    #[serde(default)]
+    live_location_period_seconds: Option<de::IgnoredAny>,
+    /// Title of a venue, which also has `location_information`.
+    #[serde(default, borrow)]
+    place_name: Option<Cow<'a, str>>,
+}
+
+#[derive(Deserialize)]
+struct ImportContact<'a> {
+    #[serde(borrow)]
+    phone_number: Cow<'a, str>,
+}
+
+#[derive(Deserialize)]
+struct ImportLocation {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl<'a> ImportMessage<'a> {
+    fn post(&self, default_timestamp: i64) -> Post {
+        Post {
+            timestamp: self
+                .date_unixtime
+                .as_ref()
+                .and_then(|date| date.parse().ok())
+                .unwrap_or(default_timestamp),
+            message_id: self.id.map(MessageId),
+            poster_id: self
+                .from_id
+                .as_ref()
+                .and_then(|from_id| from_id.strip_prefix("user")?.parse().ok())
+                .map(UserId),
+        }
+    }
+
+    /// What the message is hashed as when it's posted, if it's checked at all.
+    ///
+    /// Like live messages, captions and media other than contacts and places
+    /// aren't checked, and hidden links are spelled out.
+    fn hashed_text(self) -> Option<Cow<'a, str>> {
+        if let Some(contact) = self.contact_information {
+            return Some(phone_placeholder(&contact.phone_number).into());
+        }
+        if let Some(location) = self.location_information {
+            if self.live_location_period_seconds.is_some() {
+                return None;
+            }
+            let placeholder = match &self.place_name {
+                Some(title) => venue_placeholder(location.latitude, location.longitude, title),
+                None => location_placeholder(location.latitude, location.longitude),
+            };
+            return Some(placeholder.into());
+        }
+        if self.photo.is_some()
+            || self.file.is_some()
+            || self.media_type.is_some()
+            || self.poll.is_some()
+        {
+            return None;
+        }
+        let text = match (self.text_entities, self.text) {
+            (Some(entities), _) => ImportText::Chunked(entities).moo(),
+            (None, Some(text)) => text.moo(),
+            (None, None) => return None,
+        };
+        Some(text).filter(|text| !text.is_empty())
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscordMessage<'a> {
+    #[serde(borrow)]
+    r#type: Cow<'a, str>,
+    #[serde(borrow)]
+    content: Cow<'a, str>,
+    #[serde(default, borrow)]
+    timestamp: Option<Cow<'a, str>>,
+}
+
+impl DiscordMessage<'_> {
+    /// Discord message and user ids don't mean anything in Telegram,
+    /// so only the time is kept.
+    fn post(&self, default_timestamp: i64) -> Post {
+        Post {
+            timestamp: self
+                .timestamp
+                .as_ref()
+                .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+                .map_or(default_timestamp, |timestamp| timestamp.timestamp()),
+            message_id: None,
+            poster_id: None,
+        }
+    }
+}
+
+/// Parses a JSON chat export with a `messages` array, calling `f` with every
+/// message as soon as it's parsed, so the whole export never has to be in memory.
+/// Malformed messages are passed as `None`.
+///
+/// Telegram's export of every chat has chats with their own `messages`
+/// in `chats` and `left_chats`, and only the messages of `source` are read.
+struct ForEachMessage<'f, M> {
+    f: &'f mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+    /// Error returned by `f`, which serde could only carry as a string.
+    error: &'f mut Option<eyre::Report>,
+    source: ChatId,
+}
+
+impl<'de, M: Deserialize<'de>> DeserializeSeed<'de> for ForEachMessage<'_, M> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, M: Deserialize<'de>> de::Visitor<'de> for ForEachMessage<'_, M> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a chat export")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut found = false;
+        let mut chats = Vec::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "messages" && !found {
+                map.next_value_seed(MessageSeq {
+                    f: &mut *self.f,
+                    error: &mut *self.error,
+                })?;
+                found = true;
+            } else if (key == "chats" || key == "left_chats") && !found {
+                found = map.next_value_seed(ChatList {
+                    f: &mut *self.f,
+                    error: &mut *self.error,
+                    source: self.source,
+                    chats: &mut chats,
+                })?;
+            } else {
+                map.next_value::<de::IgnoredAny>()?;
+            }
+        }
+        if !found && !chats.is_empty() {
+            return Err(de::Error::custom(format_args!(
+                "there's no chat {} in the export, only {}",
+                self.source,
+                list_chats(&chats),
+            )));
+        }
+        if !found {
+            return Err(de::Error::missing_field("messages"));
+        }
+        Ok(())
+    }
+}
+
+/// How many chats missing one to import lists.
+const LISTED_CHATS: usize = 20;
+
+fn list_chats(chats: &[ExportedChat]) -> String {
+    let mut list = (chats.iter().take(LISTED_CHATS))
+        .map(|chat| match &chat.name {
+            Some(name) => format!("{name:?} ({})", chat.id),
+            None => chat.id.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    if chats.len() > LISTED_CHATS {
+        list.push_str(&format!(" and {} more", chats.len() - LISTED_CHATS));
+    }
+    list
+}
+
+/// Chat found in Telegram's export of every chat.
+struct ExportedChat {
+    /// Missing for deleted accounts.
+    name: Option<String>,
+    /// As the Bot API knows it.
+    id: ChatId,
+}
+
+/// Converts a chat id from an export, which leaves out the prefixes
+/// the Bot API adds to tell groups and channels apart from users.
+fn bot_api_chat_id(r#type: &str, id: i64) -> ChatId {
+    match r#type {
+        "private_supergroup" | "public_supergroup" | "private_channel" | "public_channel" => {
+            ChatId(-1_000_000_000_000 - id)
+        }
+        "private_group" => ChatId(-id),
+        _ => ChatId(id),
+    }
+}
+
+/// `chats` or `left_chats` of Telegram's export of every chat, see [`ForEachMessage`].
+/// Returns whether `source` was found and read.
+struct ChatList<'f, M> {
+    f: &'f mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+    error: &'f mut Option<eyre::Report>,
+    source: ChatId,
+    chats: &'f mut Vec<ExportedChat>,
+}
+
+impl<'de, M: Deserialize<'de>> DeserializeSeed<'de> for ChatList<'_, M> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, M: Deserialize<'de>> de::Visitor<'de> for ChatList<'_, M> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of chats")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<bool, A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "list" {
+                found |= map.next_value_seed(ChatSeq {
+                    f: &mut *self.f,
+                    error: &mut *self.error,
+                    source: self.source,
+                    chats: &mut *self.chats,
+                })?;
+            } else {
+                map.next_value::<de::IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// The `list` of [`ChatList`].
+struct ChatSeq<'f, M> {
+    f: &'f mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+    error: &'f mut Option<eyre::Report>,
+    source: ChatId,
+    chats: &'f mut Vec<ExportedChat>,
+}
+
+impl<'de, M: Deserialize<'de>> DeserializeSeed<'de> for ChatSeq<'_, M> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, M: Deserialize<'de>> de::Visitor<'de> for ChatSeq<'_, M> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of chats")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<bool, A::Error> {
+        let mut found = false;
+        while let Some((chat, read)) = seq.next_element_seed(ChatSeed {
+            f: &mut *self.f,
+            error: &mut *self.error,
+            source: self.source,
+        })? {
+            found |= read;
+            self.chats.extend(chat);
+        }
+        Ok(found)
+    }
+}
+
+/// Chat in the `list` of [`ChatList`], with its messages only read if it's `source`.
+/// Exports write the id before the messages, which are skipped otherwise.
+struct ChatSeed<'f, M> {
+    f: &'f mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+    error: &'f mut Option<eyre::Report>,
+    source: ChatId,
+}
+
+impl<'de, M: Deserialize<'de>> DeserializeSeed<'de> for ChatSeed<'_, M> {
+    type Value = (Option<ExportedChat>, bool);
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, M: Deserialize<'de>> de::Visitor<'de> for ChatSeed<'_, M> {
+    type Value = (Option<ExportedChat>, bool);
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a chat")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (mut name, mut r#type, mut id) = (None, None, None);
+        let mut read = false;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "name" => name = map.next_value::<Option<String>>()?,
+                "type" => r#type = Some(map.next_value::<String>()?),
+                "id" => id = Some(map.next_value::<i64>()?),
+                "messages" if !read => {
+                    let chat_id = id.map(|id| bot_api_chat_id(r#type.as_deref().unwrap_or(""), id));
+                    if chat_id == Some(self.source) {
+                        map.next_value_seed(MessageSeq {
+                            f: &mut *self.f,
+                            error: &mut *self.error,
+                        })?;
+                        read = true;
+                    } else {
+                        map.next_value::<de::IgnoredAny>()?;
+                    }
+                }
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        let chat = id.map(|id| ExportedChat {
+            name,
+            id: bot_api_chat_id(r#type.as_deref().unwrap_or(""), id),
+        });
+        Ok((chat, read))
+    }
+}
+
+/// The `messages` array of a chat export, see [`ForEachMessage`].
+struct MessageSeq<'f, M> {
+    f: &'f mut dyn FnMut(Option<M>) -> eyre::Result<()>,
+    error: &'f mut Option<eyre::Report>,
+}
+
+impl<'de, M: Deserialize<'de>> DeserializeSeed<'de> for MessageSeq<'_, M> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, M: Deserialize<'de>> de::Visitor<'de> for MessageSeq<'_, M> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of messages")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(message) = seq.next_element()? {
+            let message = match message {
+                MaybeMessage::Message(message) => Some(message),
+                MaybeMessage::Malformed(_) => None,
+            };
+            if let Err(err) = (self.f)(message) {
+                *self.error = Some(err);
+                return Err(de::Error::custom("import aborted"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Element of the `messages` array, which doesn't stop the import if it's malformed.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaybeMessage<M> {
+    Message(M),
+    Malformed(de::IgnoredAny),
+}
+
+/// Blocking reader of chunks downloaded by an async task.
+struct ChunkReader {
+    chunks: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..][..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl Robot9000 {
+    /// Downloads a chat history export and parses it as it arrives, passing
+    /// every message to `sink`. Replies and returns `None` if the format is
+    /// unknown, the chat's topics are checked separately, or the file is
+    /// too large or malformed.
+    async fn stream_import<S: ImportSink>(
+        &self,
+        bot: &dyn TelegramApi,
+        user: &User,
+        message: &Message,
+        document: &Document,
+        args: &str,
+        sink: S,
+    ) -> eyre::Result<Option<S>> {
+        let locale = self.chat_locale(message.chat.id)?;
+        let Ok((format, source)) = parse_import_args(args, message.chat.id) else {
+            reply(bot, message, locale.text(Text::ImportUsage)).await?;
+            return Ok(None);
+        };
+        if self.scopes_topics(message.chat.id) {
+            reply(bot, message, locale.text(Text::ImportTopics)).await?;
+            return Ok(None);
+        }
+        if document.file.size > self.config().max_import_size {
+            tracing::info!(
+                user_id = user.id.0,
+                file_size = document.file.size,
+                max_import_size = self.config().max_import_size,
+                "/import failed due to file size",
+            );
+            let size = SizeFormatterBinary::new(document.file.size.into()).to_string();
+            let limit = SizeFormatterBinary::new(self.config().max_import_size.into()).to_string();
+            let answer = locale.text(Text::ImportTooLarge {
+                size: &size,
+                limit: &limit,
+            });
+            reply(bot, message, answer).await?;
+            return Ok(None);
+        }
+
+        let file_info = bot.get_file(document.file.id.clone()).await?;
+        let limit = self.config().max_import_size.into();
+        let now = message.date.timestamp();
+        // A local Bot API server gives paths on its own filesystem instead of
+        // serving the files, so they're read directly.
+        let local_path = Path::new(&file_info.path)
+            .is_absolute()
+            .then(|| PathBuf::from(&file_info.path));
+        let is_local = local_path.is_some();
+        let (sender, chunks) = tokio::sync::mpsc::channel(16);
+        let open = move || {
+            let reader: Box<dyn io::Read> = match local_path {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(ChunkReader {
+                    chunks,
+                    chunk: Vec::new(),
+                    position: 0,
+                }),
+            };
+            let reader = archive::decompress(BufReader::new(reader), limit, format.extension());
+            Ok(reader.map(|reader| Box::new(BufReader::new(reader)) as Box<dyn BufRead>))
+        };
+        // Dropping the sender once it's done ends the file for the parser.
+        let download = async move {
+            if is_local {
+                return None;
+            }
+            let mut download = bot.download_file(&file_info.path);
+            while let Some(chunk) = download.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if sender.send(chunk).await.is_err() {
+                            // The parser gave up early.
+                            return None;
+                        }
+                    }
+                    Err(err) => return Some(err),
+                }
+            }
+            None
+        };
+        let (download_error, result) =
+            tokio::join!(download, read_into(format, open, now, source, sink));
+        let result = result?;
+        if let Some(err) = download_error {
+            return Err(err);
+        }
+        match result {
+            Ok(sink) => Ok(Some(sink)),
+            Err(err) => {
+                tracing::info!(
+                    user_id = user.id.0,
+                    err = format_args!("{err}"),
+                    "/import failed due to malformed file",
+                );
+                let err = err.to_string();
+                reply(bot, message, locale.text(Text::ImportFailed { err: &err })).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs `/import` or `/simulate` sent as the caption of a document,
+    /// on its own instead of in the chat's queue of updates.
+    pub(crate) async fn run_document_command(self, bot: Arc<dyn TelegramApi>, message: Message) {
+        if let Err(err) = self.document_command(&*bot, &message).await {
+            tracing::error!(err = format_args!("{err}"), "import failed");
+        }
+    }
+
+    async fn document_command(&self, bot: &dyn TelegramApi, message: &Message) -> eyre::Result<()> {
+        let (MessageKind::Common(kind), Some(user)) = (&message.kind, &message.from) else {
+            return Ok(());
+        };
+        let MediaKind::Document(MediaDocument {
+            document,
+            caption: Some(caption),
+            ..
+        }) = &kind.media_kind
+        else {
+            return Ok(());
+        };
+        let config = self.config();
+        let locale = self.chat_locale(message.chat.id)?;
+        match Command::parse(caption, &self.username) {
+            Ok(Command::Import(args)) => {
+                Self::ensure_admin(
+                    &config,
+                    &self.admins,
+                    &locale,
+                    bot,
+                    message,
+                    user,
+                    self.import_document(bot, user, message, document, args.trim()),
+                )
+                .await
+            }
+            Ok(Command::Simulate(args)) => {
+                Self::ensure_admin(
+                    &config,
+                    &self.admins,
+                    &locale,
+                    bot,
+                    message,
+                    user,
+                    self.simulate_import(bot, user, message, document, args.trim()),
+                )
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) async fn import_document(
+        &self,
+        bot: &dyn TelegramApi,
+        user: &User,
+        message: &Message,
+        document: &Document,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        let namespace = self.namespace(message.chat.id);
+        let chat_id = message.chat.id;
+        let imported = self
+            .stream_import(
+                bot,
+                user,
+                message,
+                document,
+                args,
+                Importer::new(self.clone(), chat_id)?,
+            )
+            .await?;
+        // If the file turns out to be malformed, batches committed before that are kept.
+        let Some(importer) = imported else {
+            return Ok(());
+        };
+        let summary = importer.finish().await?;
+        tracing::info!(
+            user_id = user.id.0,
+            count = summary.imported,
+            duplicates = summary.duplicates,
+            service = summary.service,
+            unsupported = summary.unsupported,
+            malformed = summary.malformed,
+            namespace = format_args!("{namespace}"),
+            "/import succeeded"
+        );
+        self.audit(AuditEvent::Import {
+            chat_id,
+            admin_id: user.id,
+            imported: summary.imported,
+        });
+
+        let answer = locale.text(Text::Imported {
+            count: summary.imported,
+            duplicates: summary.duplicates,
+            service: summary.service,
+            unsupported: summary.unsupported,
+            malformed: summary.malformed,
+            shared: namespace == Namespace::Shared,
+        });
+        let event = format!(
+            "Import in {}\nUser: {}\nImported: {}\nDuplicates: {}\nService: {}\n\
+             Unsupported: {}\nMalformed: {}",
+            describe_chat(&message.chat),
+            describe_user(user),
+            summary.imported,
+            summary.duplicates,
+            summary.service,
+            summary.unsupported,
+            summary.malformed,
+        );
+        self.log_event(bot, event).await;
+        reply(bot, message, answer).await
+    }
+
+    /// Reports what `/import` would do with a document, without changing anything.
+    pub(crate) async fn simulate_import(
+        &self,
+        bot: &dyn TelegramApi,
+        user: &User,
+        message: &Message,
+        document: &Document,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        let namespace = self.namespace(message.chat.id);
+        let simulation = Simulation {
+            robot: self.clone(),
+            namespace,
+            settings: self.settings(message.chat.id)?,
+            now: message.date.timestamp(),
+            seen: HashSet::new(),
+            counts: [0; 3],
+        };
+        let simulation = self
+            .stream_import(bot, user, message, document, args, simulation)
+            .await?;
+        let Some(Simulation {
+            counts: [new, known, collisions],
+            ..
+        }) = simulation
+        else {
+            return Ok(());
+        };
+        tracing::info!(
+            user_id = user.id.0,
+            new,
+            known,
+            collisions,
+            namespace = format_args!("{namespace}"),
+            "/simulate succeeded"
+        );
+        let answer = locale.text(Text::Simulated {
+            new,
+            known,
+            collisions,
+        });
+        reply(bot, message, answer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Describes every item of a Telegram export, or the error to show.
+    fn read_telegram(export: &str, source: ChatId) -> eyre::Result<io::Result<Vec<String>>> {
+        let mut items = Vec::new();
+        let result = ImportFormat::Telegram.read(export.as_bytes(), 0, source, &mut |item| {
+            items.push(match item {
+                ImportItem::Message(text, post) => format!("{text} {post:?}"),
+                ImportItem::Service => "service".into(),
+                ImportItem::Unsupported => "unsupported".into(),
+                ImportItem::Malformed => "malformed".into(),
+            });
+            Ok(())
+        })?;
+        Ok(result.map(|()| items))
+    }
+
+    #[test]
+    fn reads_telegram_exports() -> eyre::Result<()> {
+        let export = r#"{
+            "name": "Chat",
+            "type": "private_supergroup",
+            "messages": [
+                {"id": 1, "type": "service", "action": "join_group_by_link"},
+                {
+                    "id": 2,
+                    "type": "message",
+                    "date_unixtime": "1700000000",
+                    "from_id": "user42",
+                    "text": ["see ", {"type": "text_link", "text": "here", "href": "https://example.com"}],
+                    "text_entities": [
+                        {"type": "plain", "text": "see "},
+                        {"type": "text_link", "text": "here", "href": "https://example.com"}
+                    ]
+                },
+                {"id": 3, "type": "message", "forwarded_from": "Channel", "text": "hello"},
+                {"id": 4, "type": "message", "photo": "photos/1.jpg", "text": "caption"},
+                {"id": 5, "type": "message", "file": "video.mp4", "media_type": "video_message", "text": ""},
+                {
+                    "id": 6,
+                    "type": "message",
+                    "contact_information": {"first_name": "A", "last_name": "", "phone_number": "+1 (555) 010-99"},
+                    "text": ""
+                },
+                {
+                    "id": 7,
+                    "type": "message",
+                    "place_name": " Cafe ",
+                    "location_information": {"latitude": 1.234567, "longitude": 2.5},
+                    "text": ""
+                },
+                {
+                    "id": 8,
+                    "type": "message",
+                    "location_information": {"latitude": 1.0, "longitude": 2.0},
+                    "live_location_period_seconds": 900,
+                    "text": ""
+                },
+                {"id": 9, "type": "message", "text": 42}
+            ]
+        }"#;
+        let items = read_telegram(export, ChatId(-1))??;
+        let post = |timestamp, message_id, poster_id: Option<u64>| Post {
+            timestamp,
+            message_id: Some(MessageId(message_id)),
+            poster_id: poster_id.map(UserId),
+        };
+        assert_eq!(
+            items,
+            [
+                "service".to_owned(),
+                format!(
+                    "see https://example.com {:?}",
+                    post(1_700_000_000, 2, Some(42))
+                ),
+                format!("hello {:?}", post(0, 3, None)),
+                "unsupported".into(),
+                "unsupported".into(),
+                format!("\0contact phone 155501099 {:?}", post(0, 6, None)),
+                format!("\0venue 1.2346,2.5000 cafe {:?}", post(0, 7, None)),
+                "unsupported".into(),
+                "malformed".into(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn picks_a_chat_from_full_exports() -> eyre::Result<()> {
+        let export = r#"{
+            "personal_information": {"user_id": 1},
+            "chats": {
+                "about": "",
+                "list": [
+                    {"name": "Friend", "type": "personal_chat", "id": 2, "messages": [
+                        {"id": 1, "type": "message", "text": "hi"}
+                    ]},
+                    {"name": "Group", "type": "private_supergroup", "id": 1234, "messages": [
+                        {"id": 7, "type": "message", "text": "hello"}
+                    ]}
+                ]
+            },
+            "left_chats": {"about": "", "list": [
+                {"name": null, "type": "private_group", "id": 55, "messages": []}
+            ]}
+        }"#;
+        let items = read_telegram(export, ChatId(-1_000_000_001_234))??;
+        let post = Post {
+            timestamp: 0,
+            message_id: Some(MessageId(7)),
+            poster_id: None,
+        };
+        assert_eq!(items, [format!("hello {post:?}")]);
+        let missing = read_telegram(export, ChatId(-100))?
+            .unwrap_err()
+            .to_string();
+        assert!(
+            missing.starts_with(
+                "there's no chat -100 in the export, only \"Friend\" (2), \
+                 \"Group\" (-1000000001234), -55"
+            ),
+            "{missing}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parses_import_args() -> eyre::Result<()> {
+        let chat_id = ChatId(-100);
+        assert_eq!(
+            parse_import_args("", chat_id)?,
+            (ImportFormat::Telegram, chat_id)
+        );
+        assert_eq!(
+            parse_import_args("plain", chat_id)?,
+            (ImportFormat::Plain, chat_id)
+        );
+        assert_eq!(
+            parse_import_args("telegram -1001234", chat_id)?,
+            (ImportFormat::Telegram, ChatId(-1001234))
+        );
+        assert!(parse_import_args("1 2", chat_id).is_err());
+        assert!(parse_import_args("telegram plain", chat_id).is_err());
+        Ok(())
+    }
+}
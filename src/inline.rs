@@ -0,0 +1,133 @@
+//! Inline queries, letting people check a draft before posting it, like
+//! `@r9ktgbot would this text be deleted?`. Drafts are checked against the
+//! chat each person picked with `/precheck`, which proves they're in it.
+//!
+//! Needs inline mode to be turned on with @BotFather.
+//!
+//! Admins of the picked chat can also send messages to the bot in private
+//! to see in detail how they'd be checked there, without posting them.
+
+use std::sync::Arc;
+
+use color_eyre::eyre;
+use teloxide::types::{
+    ChatId, InlineQuery, InlineQueryResult, InlineQueryResultArticle, InputMessageContent,
+    InputMessageContentText, Message, User, UserId,
+};
+
+use crate::{
+    api::TelegramApi,
+    i18n::Text,
+    robot::{reply, Robot9000},
+    storage::{unix_now, Scope},
+};
+
+impl Robot9000 {
+    /// Handles `/precheck`, picking the chat inline queries of the user check drafts against.
+    pub(crate) async fn precheck(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        if message.chat.is_private() {
+            return reply(bot, message, locale.text(Text::PrecheckUsage)).await;
+        }
+        self.inline_chats
+            .insert(user.id.0.to_be_bytes(), &message.chat.id.0.to_be_bytes())?;
+        tracing::info!(user_id = user.id.0, "picked chat for inline checks");
+        let picked = locale.text(Text::PrecheckPicked {
+            bot: &self.username,
+        });
+        reply(bot, message, picked).await
+    }
+
+    /// Chat the user picked with `/precheck`, if the bot is still there.
+    fn precheck_chat(&self, user_id: UserId) -> eyre::Result<Option<ChatId>> {
+        let Some(chat_id) = self.inline_chats.get(user_id.0.to_be_bytes())? else {
+            return Ok(None);
+        };
+        if !self.chats.contains_key(&chat_id)? {
+            return Ok(None);
+        }
+        Ok(Some(ChatId(i64::from_be_bytes(chat_id[..].try_into()?))))
+    }
+
+    /// Answers an inline query with what would happen to the draft in the picked chat.
+    /// Picking the answer posts the draft.
+    pub async fn process_inline_query(
+        &self,
+        query: InlineQuery,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        let draft = query.query.trim();
+        if draft.is_empty() {
+            return bot.answer_inline_query(query.id, Vec::new()).await;
+        }
+        let Some(chat_id) = self.precheck_chat(query.from.id)? else {
+            let locale = self.chat_locale(ChatId::from(query.from.id))?;
+            let usage = locale.text(Text::PrecheckUsage);
+            let article = InlineQueryResultArticle::new(
+                "usage",
+                usage.clone(),
+                InputMessageContent::Text(InputMessageContentText::new(usage)),
+            );
+            return bot
+                .answer_inline_query(query.id, vec![InlineQueryResult::Article(article)])
+                .await;
+        };
+        let settings = self.settings(chat_id)?;
+        let scope = Scope {
+            poster_id: settings.per_user.then_some(query.from.id),
+            ..self.topic_scope(chat_id, None)
+        };
+        let key = self.hash_message(scope, draft)?;
+        // Inline queries have no date, drafts are checked as if posted now.
+        let answer = self.check_hash(&settings, key, unix_now()).await?;
+        tracing::debug!(
+            user_id = query.from.id.0,
+            chat_id = chat_id.0,
+            "checked draft"
+        );
+        let article = InlineQueryResultArticle::new(
+            "draft",
+            answer,
+            InputMessageContent::Text(InputMessageContentText::new(draft)),
+        );
+        bot.answer_inline_query(query.id, vec![InlineQueryResult::Article(article)])
+            .await
+    }
+
+    /// Answers a message sent in private with how it would be checked in the chat
+    /// the user picked with `/precheck`, if they administer it. Nothing is remembered.
+    pub(crate) async fn sandbox(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let chat_id = match self.precheck_chat(user.id)? {
+            Some(chat_id)
+                if Self::is_chat_admin(&self.config(), &self.admins, bot, chat_id, user.id)
+                    .await? =>
+            {
+                chat_id
+            }
+            _ => {
+                let locale = self.chat_locale(message.chat.id)?;
+                return reply(bot, message, locale.text(Text::SandboxUsage)).await;
+            }
+        };
+        let settings = self.settings(chat_id)?;
+        let scope = Scope {
+            poster_id: settings.per_user.then_some(user.id),
+            ..self.topic_scope(chat_id, None)
+        };
+        let answer = self
+            .explain_in(chat_id, scope, message, &settings, message.date.timestamp())
+            .await?;
+        tracing::debug!(chat_id = chat_id.0, "checked message in sandbox");
+        reply(bot, message, answer).await
+    }
+}
@@ -0,0 +1,63 @@
+//! R9K Telegram bot, deleting messages that were already posted.
+
+mod activity;
+mod admin_api;
+mod alerts;
+mod api;
+mod archive;
+mod audit;
+mod backups;
+mod blocklists;
+mod botstats;
+mod cli;
+mod commands;
+mod config;
+mod digests;
+mod errors;
+mod flood;
+mod fuzzy;
+mod hashing;
+mod health;
+mod http;
+mod i18n;
+mod import;
+mod inline;
+mod mutes;
+mod newcomers;
+mod normalize;
+mod notices;
+mod phrases;
+mod policy;
+mod reactions;
+mod replication;
+mod robot;
+mod scripts;
+mod settings_files;
+mod splits;
+mod storage;
+mod store;
+mod storms;
+mod sync;
+#[cfg(unix)]
+mod systemd;
+mod texts;
+mod throttle;
+mod why;
+
+#[cfg(unix)]
+pub use crate::systemd::{sd_notify, watchdog};
+pub use crate::{
+    api::{SendOptions, TelegramApi, ThrottledBot},
+    cli::{
+        check_config, check_text, import_file, print_db_stats, replay_audit_logs, restore_backup,
+        write_backup_file,
+    },
+    commands::Command,
+    config::{Config, LogFormat},
+    errors::ErrorReporter,
+    import::ImportFormat,
+    policy::Settings,
+    replication::follow,
+    robot::Robot9000,
+    storage::{open_database, Entry, Key, Namespace, Post, Scope, Status},
+};
@@ -1,385 +1,416 @@
-use std::{borrow::Cow, fmt, future::Future, path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc};
 
+use clap::{Parser, Subcommand};
 use color_eyre::eyre;
-use serde::Deserialize;
-use size_format::SizeFormatterBinary;
-use sled::CompareAndSwapError;
+use futures::future;
+use r9ktg::{
+    check_config, check_text, import_file, print_db_stats, replay_audit_logs, restore_backup,
+    write_backup_file, Config, ErrorReporter, ImportFormat, LogFormat, Robot9000, ThrottledBot,
+};
 use teloxide::{
-    dispatching::UpdateFilterExt,
+    dispatching::{ShutdownToken, UpdateFilterExt},
     dptree,
-    net::Download,
-    payloads::SendMessageSetters as _,
-    prelude::{Dispatcher, Request as _, Requester as _},
+    error_handlers::LoggingErrorHandler,
+    prelude::Dispatcher,
     types::{
-        Chat, ChatId, Document, MediaDocument, MediaKind, MediaText, Message, MessageCommon,
-        MessageKind, Update, User,
+        AllowedUpdate, CallbackQuery, ChatId, ChatMemberUpdated, InlineQuery, Message, MessageId,
+        MessageReactionUpdated, ThreadId, Update,
     },
+    update_listeners::Polling,
     Bot,
 };
 use tracing_futures::Instrument as _;
 use tracing_subscriber::EnvFilter;
-use xxhash_rust::xxh3::Xxh3;
-
-#[derive(Deserialize)]
-#[serde(transparent)]
-struct Token(String);
 
-impl fmt::Debug for Token {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("Token(hidden)")
-    }
+/// R9K Telegram bot, deleting messages that were posted before.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file. `R9KTG_` environment variables take precedence over it.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Bot from the `[bots]` table of the config file that subcommands work with,
+    /// instead of the main one.
+    #[arg(long, global = true)]
+    bot: Option<String>,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    token: Token,
-    db_path: PathBuf,
-    #[serde(default = "default_max_import_size")]
-    max_import_size: u32,
-    #[serde(default)]
-    allow_duplicates_in_replies: bool,
-}
-
-fn default_max_import_size() -> u32 {
-    50 * 1024 * 1024
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Rebuild an empty database from audit logs or directories containing them.
+    ReplayAudit {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Write a backup of the whole database, which can be restored on any host
+    /// and by later versions. The bot must be stopped.
+    Backup { path: PathBuf },
+    /// Restore an empty database from a backup made by `backup` or `/backup`.
+    #[command(alias = "restore-backup")]
+    Restore { backup: PathBuf },
+    /// Import a chat history into the database without going through the bot.
+    Import {
+        /// Chat whose known messages the history is added to.
+        #[arg(long, allow_negative_numbers = true)]
+        chat_id: i64,
+        /// Chat to read from Telegram's export of every chat, if it's not `--chat-id`.
+        #[arg(long, allow_negative_numbers = true)]
+        from_chat: Option<i64>,
+        /// One of `telegram`, `plain`, `discord` or `whatsapp`.
+        #[arg(long, default_value = "telegram", value_parser = str::parse::<ImportFormat>)]
+        format: ImportFormat,
+        path: PathBuf,
+    },
+    /// Print the number of known messages per chat and the size of the database.
+    DbStats,
+    /// Keep the database of a standby instance up to date with a primary
+    /// streaming its changes from `replication_addr`.
+    Follow {
+        /// Host and port of the primary's `replication_addr`.
+        primary: String,
+    },
+    /// Validate the config of every bot, or only of `--bot`, without starting them,
+    /// failing if there are problems.
+    CheckConfig {
+        /// Also log in to Telegram with each token.
+        #[arg(long)]
+        online: bool,
+    },
+    /// Describe what the bot would do with a message, like `/check` does.
+    Check {
+        #[arg(long, allow_negative_numbers = true)]
+        chat_id: i64,
+        /// Forum topic the message is posted in.
+        #[arg(long)]
+        thread_id: Option<i32>,
+        text: String,
+    },
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum ImportTextChunk<'a> {
-    Simple(#[serde(borrow)] Cow<'a, str>),
-    Typed {
-        #[serde(borrow)]
-        text: Cow<'a, str>,
-    },
+async fn process_message_free(
+    message: Message,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "message",
+        bot = robot.username(),
+        chat_id = message.chat.id.0,
+        id = message.id.0,
+        date = format_args!("{:?}", message.date),
+    );
+    robot
+        .process_message(message, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-impl ImportTextChunk<'_> {
-    fn as_str(&self) -> &str {
-        match self {
-            ImportTextChunk::Simple(text) | ImportTextChunk::Typed { text } => text.as_ref(),
-        }
-    }
+async fn process_channel_post_free(
+    message: Message,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "channel_post",
+        bot = robot.username(),
+        chat_id = message.chat.id.0,
+        id = message.id.0,
+        date = format_args!("{:?}", message.date),
+    );
+    robot
+        .process_channel_post(message, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum ImportText<'a> {
-    Simple(#[serde(borrow)] Cow<'a, str>),
-    Chunked(#[serde(borrow)] Vec<ImportTextChunk<'a>>),
+async fn process_my_chat_member_free(
+    update: ChatMemberUpdated,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "my_chat_member",
+        bot = robot.username(),
+        chat_id = update.chat.id.0,
+        user_id = update.from.id.0,
+    );
+    robot
+        .process_my_chat_member(update, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-impl<'a> ImportText<'a> {
-    fn moo(self) -> Cow<'a, str> {
-        match self {
-            ImportText::Simple(cow) => cow,
-            ImportText::Chunked(chunks) => chunks.iter().map(ImportTextChunk::as_str).collect(),
-        }
-    }
+async fn process_chat_member_free(
+    update: ChatMemberUpdated,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    robot.process_chat_member(update)
 }
 
-#[derive(Deserialize)]
-struct ImportMessage<'a> {
-    #[serde(borrow)]
-    r#type: Cow<'a, str>,
-    #[serde(borrow)]
-    text: ImportText<'a>,
+async fn process_reaction_free(
+    update: MessageReactionUpdated,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "reaction",
+        bot = robot.username(),
+        chat_id = update.chat.id.0,
+        id = update.message_id.0,
+    );
+    robot
+        .process_reaction(update, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-#[derive(Deserialize)]
-struct Import<'a> {
-    #[serde(borrow)]
-    messages: Vec<ImportMessage<'a>>,
+async fn process_callback_free(
+    query: CallbackQuery,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "callback",
+        bot = robot.username(),
+        user_id = query.from.id.0,
+        data = format_args!("{:?}", query.data),
+    );
+    robot
+        .process_callback(query, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-#[derive(Clone)]
-struct Robot9000 {
-    db: sled::Db,
-    hasher: Box<Xxh3>,
-    config: Arc<Config>,
+async fn process_inline_query_free(
+    query: InlineQuery,
+    bot: ThrottledBot,
+    robot: Arc<Robot9000>,
+) -> eyre::Result<()> {
+    let span = tracing::info_span!(
+        "inline_query",
+        bot = robot.username(),
+        user_id = query.from.id.0,
+    );
+    robot
+        .process_inline_query(query, Arc::new(bot))
+        .instrument(span)
+        .await
 }
 
-impl Robot9000 {
-    fn hash_message(&mut self, chat_id: ChatId, text: impl AsRef<[u8]>) -> [u8; 16] {
-        self.hasher.reset();
-        self.hasher.update(&chat_id.0.to_le_bytes());
-        self.hasher.update(text.as_ref());
-        self.hasher.digest128().to_le_bytes()
-    }
+#[cfg(unix)]
+async fn reload_on_hangup(robot: Arc<Robot9000>) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    fn store_message(&mut self, chat_id: ChatId, text: impl AsRef<[u8]>) -> eyre::Result<bool> {
-        let hash = self.hash_message(chat_id, text);
-        match self.db.compare_and_swap(&hash, None::<&[u8]>, Some(&[]))? {
-            Err(CompareAndSwapError {
-                current: Some(current),
-                ..
-            }) => Ok(current.len() == 0),
-            _ => Ok(false),
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::error!(err = format_args!("{err}"), "couldn't listen for SIGHUP");
+            return;
         }
-    }
-
-    fn allow_message(&mut self, chat_id: ChatId, text: impl AsRef<[u8]>) -> eyre::Result<()> {
-        let hash = self.hash_message(chat_id, text);
-        self.db.insert(&hash, &[1])?;
-        Ok(())
-    }
-
-    fn forbid_message(&mut self, chat_id: ChatId, text: impl AsRef<[u8]>) -> eyre::Result<()> {
-        let hash = self.hash_message(chat_id, text);
-        self.db.insert(&hash, &[])?;
-        Ok(())
-    }
-
-    async fn is_admin(bot: &Bot, chat: &Chat, user: &User) -> eyre::Result<bool> {
-        Ok(chat.is_private()
-            || bot
-                .get_chat_member(chat.id, user.id)
-                .send()
-                .await?
-                .can_delete_messages())
-    }
-
-    async fn ensure_admin<Fut>(
-        bot: &Bot,
-        message: &Message,
-        user: &User,
-        f: Fut,
-    ) -> eyre::Result<()>
-    where
-        Fut: Future<Output = eyre::Result<()>>,
-    {
-        if !Self::is_admin(bot, &message.chat, user).await? {
-            tracing::info!(user_id = user.id.0, "someone tried to run admin command");
-            bot.send_message(message.chat.id, "Nice try")
-                .reply_to_message_id(message.id)
-                .send()
-                .await?;
-            Ok(())
-        } else {
-            f.await
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(err) = robot.reload_config() {
+            tracing::error!(err = format_args!("{err}"), "couldn't reload config");
         }
     }
+}
 
-    async fn import_document(
-        &mut self,
-        bot: &Bot,
-        user: &User,
-        message: &Message,
-        document: &Document,
-    ) -> eyre::Result<()> {
-        if document.file_size > self.config.max_import_size {
-            tracing::info!(
-                user_id = user.id.0,
-                file_size = document.file_size,
-                max_import_size = self.config.max_import_size,
-                "/import failed due to file size",
-            );
-            let reply = format!(
-                "Come on, there's no way I'll import a {}B file (my limit is {}B)",
-                SizeFormatterBinary::new(document.file_size.into()),
-                SizeFormatterBinary::new(self.config.max_import_size.into()),
-            );
-            bot.send_message(message.chat.id, reply)
-                .reply_to_message_id(message.id)
-                .send()
-                .await?;
-            return Ok(());
-        }
-
-        let mut file = Vec::with_capacity(document.file_size as usize);
-        let file_info = bot.get_file(&document.file_id).send().await?;
-        bot.download_file(&file_info.file_path, &mut file).await?;
-        match serde_json::from_slice::<Import>(&*file) {
-            Ok(import) => {
-                let imported_count = import
-                    .messages
-                    .into_iter()
-                    .filter_map(|import_message| {
-                        (import_message.r#type == "message").then(|| {
-                            self.store_message(message.chat.id, &*import_message.text.moo())
-                                .map(|b| usize::from(!b))
-                        })
-                    })
-                    .sum::<Result<usize, _>>()?;
-                tracing::info!(
-                    user_id = user.id.0,
-                    count = imported_count,
-                    "/import succeeded"
-                );
+/// Stops like on Ctrl-C when the service manager asks to.
+#[cfg(unix)]
+async fn stop_on_terminate(token: ShutdownToken) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-                let reply = format!(
-                    "Sucessfully imported {imported_count} messages (excluding duplicates)"
-                );
-                bot.send_message(message.chat.id, reply)
-                    .reply_to_message_id(message.id)
-                    .send()
-                    .await?;
-            }
-            Err(err) => {
-                tracing::info!(
-                    user_id = user.id.0,
-                    err = format_args!("{err}"),
-                    "/import failed due to deserialization error",
-                );
-                let reply = format!("Failed to parse your import, sorry :(\nError: {err}");
-                bot.send_message(message.chat.id, reply)
-                    .reply_to_message_id(message.id)
-                    .send()
-                    .await?;
-            }
+    let mut terminates = match signal(SignalKind::terminate()) {
+        Ok(terminates) => terminates,
+        Err(err) => {
+            tracing::error!(err = format_args!("{err}"), "couldn't listen for SIGTERM");
+            return;
         }
-
-        Ok(())
-    }
-
-    async fn reply_command(
-        &mut self,
-        bot: &Bot,
-        message: &Message,
-        reply_to: &Message,
-        user: &User,
-        text: &str,
-    ) -> eyre::Result<bool> {
-        let reply_to_text = match &reply_to.kind {
-            MessageKind::Common(MessageCommon {
-                media_kind: MediaKind::Text(MediaText { text, .. }),
-                ..
-            }) => text,
-            _ => return Ok(false),
-        };
-
-        match text.trim() {
-            "/allow" => {
-                tracing::info!(allowed_message_id = reply_to.id, "allowed message");
-                Self::ensure_admin(bot, message, user, async {
-                    self.allow_message(message.chat.id, reply_to_text)
-                })
-                .await?;
-                Ok(true)
-            }
-            "/forbid" => {
-                tracing::info!(allowed_message_id = reply_to.id, "forbade message");
-                Self::ensure_admin(bot, message, user, async {
-                    self.forbid_message(message.chat.id, reply_to_text)
-                })
-                .await?;
-                Ok(true)
-            }
-            _ => Ok(false),
+    };
+    if terminates.recv().await.is_some() {
+        tracing::info!("Received SIGTERM, stopping");
+        match token.shutdown() {
+            Ok(stopped) => stopped.await,
+            Err(err) => tracing::warn!(err = format_args!("{err}"), "couldn't stop"),
         }
     }
+}
 
-    async fn process_message(&mut self, message: Message, bot: Bot) -> eyre::Result<()> {
-        if let MessageKind::Common(
-            kind @ MessageCommon {
-                from: Some(user),
-                reply_to_message,
-                ..
-            },
-        ) = &message.kind
-        {
-            match &kind.media_kind {
-                MediaKind::Text(text) => {
-                    if let Some(reply_to) = reply_to_message {
-                        if self
-                            .reply_command(&bot, &message, reply_to, user, &text.text)
-                            .await?
-                        {
-                            return Ok(());
-                        }
-
-                        if self.config.allow_duplicates_in_replies {
-                            return Ok(());
-                        }
-                    }
-
-                    if self.store_message(message.chat.id, &text.text)? {
-                        tracing::debug!(
-                            text = format_args!("{:?}", text.text),
-                            "deleted duplicate message"
-                        );
-                        bot.delete_message(message.chat.id, message.id)
-                            .send()
-                            .await?;
-                    } else {
-                        tracing::debug!(
-                            text = format_args!("{:?}", text.text),
-                            "ignoring unique message"
-                        );
-                    }
-                }
-                MediaKind::Document(MediaDocument {
-                    document,
-                    caption: Some(caption),
-                    ..
-                }) => {
-                    if caption.trim() == "/import" {
-                        Self::ensure_admin(
-                            &bot,
-                            &message,
-                            user,
-                            self.import_document(&bot, user, &message, document),
-                        )
-                        .await?;
-                    }
-                }
-                _ => (),
-            }
-        }
+/// Runs every configured bot until all of them stop.
+async fn do_main(configs: Vec<Config>, config_path: Option<PathBuf>) -> eyre::Result<()> {
+    let db = r9ktg::open_database(&configs[0])?;
+    let mut robots = Vec::new();
+    let mut dispatchers = Vec::new();
+    for config in configs {
+        let span = tracing::info_span!("bot", name = config.bot_name().unwrap_or("main"));
+        let (robot, bot, dispatcher, polling) = start_bot(config, db.clone(), config_path.clone())
+            .instrument(span)
+            .await?;
+        robots.push((robot, bot));
+        dispatchers.push((dispatcher, polling));
+    }
+    #[cfg(unix)]
+    {
+        tokio::spawn(r9ktg::watchdog());
+        r9ktg::sd_notify("READY=1");
+    }
+    // Returns once the handlers of updates already received finish.
+    future::join_all(
+        dispatchers
+            .into_iter()
+            .map(|(mut dispatcher, polling)| async move {
+                let error_handler =
+                    LoggingErrorHandler::with_custom_text("An error from the update listener");
+                dispatcher
+                    .dispatch_with_listener(polling, error_handler)
+                    .await;
+            }),
+    )
+    .await;
+    #[cfg(unix)]
+    r9ktg::sd_notify("STOPPING=1");
 
-        Ok(())
+    // Duplicates waiting for `delete_batch_delay` are deleted right away.
+    for (robot, bot) in robots {
+        robot.shutdown(&bot).await?;
     }
+    tracing::info!("Stopped");
+    Ok(())
 }
 
-async fn process_message_free(
-    message: Message,
-    bot: Bot,
-    mut robot: Robot9000,
-) -> eyre::Result<()> {
-    let span = tracing::info_span!(
-        "message",
-        chat_id = message.chat.id.0,
-        id = message.id,
-        date = format_args!("{:?}", message.date),
-    );
-    robot.process_message(message, bot).instrument(span).await
-}
+type BotDispatcher = Dispatcher<Bot, eyre::Report, ChatId>;
+
+type BotPolling = Polling<Bot>;
 
-async fn do_main() -> eyre::Result<()> {
-    let config: Config = envy::prefixed("R9KTG_").from_env()?;
+async fn start_bot(
+    config: Config,
+    db: sled::Db,
+    config_path: Option<PathBuf>,
+) -> eyre::Result<(Arc<Robot9000>, ThrottledBot, BotDispatcher, BotPolling)> {
     tracing::info!(
         config = format_args!("{config:?}"),
         "Starting R9K Telegram bot"
     );
 
-    let bot = Bot::new(&config.token.0);
-    let db = sled::open(&config.db_path)?;
-    tracing::debug!("Opened database");
-    let hasher = Box::new(Xxh3::new());
-    let robot = Robot9000 {
-        db,
-        hasher,
-        config: Arc::new(config),
-    };
-
-    Dispatcher::builder(
-        bot,
-        Update::filter_message().chain(dptree::endpoint(process_message_free)),
-    )
-    .enable_ctrlc_handler()
-    .dependencies(dptree::deps![robot])
-    .build()
-    .dispatch()
-    .await;
+    let bot = config.bot()?;
+    let update_queue_size = config.update_queue_size();
+    let polling = config.polling(bot.clone()).await;
+    let allowed = |kind| config.allows_update(kind);
+    let mut handler = dptree::entry().inspect(|robot: Arc<Robot9000>| robot.count_update());
+    if allowed(AllowedUpdate::Message) {
+        handler =
+            handler.branch(Update::filter_message().chain(dptree::endpoint(process_message_free)));
+    }
+    if allowed(AllowedUpdate::ChannelPost) {
+        handler = handler.branch(
+            Update::filter_channel_post().chain(dptree::endpoint(process_channel_post_free)),
+        );
+    }
+    if allowed(AllowedUpdate::MyChatMember) {
+        handler = handler.branch(
+            Update::filter_my_chat_member().chain(dptree::endpoint(process_my_chat_member_free)),
+        );
+    }
+    if allowed(AllowedUpdate::ChatMember) {
+        handler = handler
+            .branch(Update::filter_chat_member().chain(dptree::endpoint(process_chat_member_free)));
+    }
+    if allowed(AllowedUpdate::MessageReaction) {
+        handler = handler.branch(
+            Update::filter_message_reaction_updated()
+                .chain(dptree::endpoint(process_reaction_free)),
+        );
+    }
+    if allowed(AllowedUpdate::InlineQuery) {
+        handler = handler.branch(
+            Update::filter_inline_query().chain(dptree::endpoint(process_inline_query_free)),
+        );
+    }
+    if allowed(AllowedUpdate::CallbackQuery) {
+        handler = handler
+            .branch(Update::filter_callback_query().chain(dptree::endpoint(process_callback_free)));
+    }
+    let api = ThrottledBot::new(bot.clone());
+    let robot = Robot9000::start(config, db, config_path, Arc::new(api.clone())).await?;
+    let robot = Arc::new(robot);
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(robot.clone()).in_current_span());
+
+    let error_reporter = ErrorReporter::new(robot.clone(), Arc::new(api.clone()));
+    let dispatcher = Dispatcher::builder(bot, handler)
+        .enable_ctrlc_handler()
+        // Updates are handled concurrently, but in order within each chat.
+        .distribution_function(|update| update.chat().map(|chat| chat.id))
+        .worker_queue_size(update_queue_size)
+        .dependencies(dptree::deps![robot.clone(), api.clone()])
+        .error_handler(error_reporter)
+        .build();
+    #[cfg(unix)]
+    tokio::spawn(stop_on_terminate(dispatcher.shutdown_token()).in_current_span());
+    Ok((robot, api, dispatcher, polling))
+}
 
-    Ok(())
+/// Logs to stderr, filtered by `RUST_LOG`.
+fn init_logging(format: LogFormat) {
+    let logs = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().flatten_event(true).init(),
+    }
 }
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-    do_main().await
+    let cli = Cli::parse();
+    let Some(command) = cli.command else {
+        if cli.bot.is_some() {
+            eyre::bail!("`--bot` only picks the bot of a subcommand, every bot is run without one");
+        }
+        let configs = Config::load_all(cli.config.as_deref())?;
+        init_logging(configs[0].log_format());
+        return do_main(configs, cli.config).await;
+    };
+    if let CliCommand::CheckConfig { online } = command {
+        let configs = match cli.bot {
+            Some(name) => vec![Config::load_bot(cli.config.as_deref(), Some(&name))?],
+            None => Config::load_all(cli.config.as_deref())?,
+        };
+        init_logging(configs[0].log_format());
+        return check_config(configs, online).await;
+    }
+    let config = Config::load_bot(cli.config.as_deref(), cli.bot.as_deref())?;
+    init_logging(config.log_format());
+    match command {
+        CliCommand::ReplayAudit { paths } => replay_audit_logs(config, paths).await,
+        CliCommand::Backup { path } => write_backup_file(config, &path),
+        CliCommand::Restore { backup } => restore_backup(config, &backup),
+        CliCommand::Import {
+            chat_id,
+            from_chat,
+            format,
+            path,
+        } => {
+            let source = ChatId(from_chat.unwrap_or(chat_id));
+            import_file(config, ChatId(chat_id), source, format, path).await
+        }
+        CliCommand::DbStats => print_db_stats(config),
+        CliCommand::Follow { primary } => r9ktg::follow(config, primary).await,
+        CliCommand::Check {
+            chat_id,
+            thread_id,
+            text,
+        } => {
+            check_text(
+                config,
+                ChatId(chat_id),
+                thread_id.map(|id| ThreadId(MessageId(id))),
+                &text,
+            )
+            .await
+        }
+        CliCommand::CheckConfig { .. } => unreachable!("checked before loading one config"),
+    }
 }
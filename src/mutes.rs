@@ -0,0 +1,143 @@
+//! Mutes the bot gives out, remembered so `/amnesty` can lift them early.
+
+use chrono::{TimeDelta, Utc};
+use color_eyre::eyre;
+use teloxide::types::{ChatId, Message, User, UserId};
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    i18n::Text,
+    robot::{describe_chat, describe_user, explicit_reply, reply, Robot9000},
+    storage::unix_now,
+};
+
+fn mute_key(chat_id: ChatId, user_id: UserId) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+    key[8..].copy_from_slice(&user_id.0.to_be_bytes());
+    key
+}
+
+impl Robot9000 {
+    /// Mutes a user for `mute_duration` seconds.
+    pub(crate) async fn mute(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> eyre::Result<()> {
+        let until = Utc::now() + TimeDelta::seconds(self.config().mute_duration as i64);
+        bot.mute(chat_id, user_id, until).await?;
+        self.mutes
+            .insert(mute_key(chat_id, user_id), &until.timestamp().to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Users the bot muted in a chat whose mutes haven't run out yet,
+    /// only `user_id` if given. Mutes that ran out are forgotten.
+    fn active_mutes(
+        &self,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        now: i64,
+    ) -> eyre::Result<Vec<UserId>> {
+        let mut active = Vec::new();
+        for item in self.mutes.scan_prefix(chat_id.0.to_be_bytes()) {
+            let (key, value) = item?;
+            let muted = UserId(u64::from_be_bytes(key[8..].try_into()?));
+            if i64::from_be_bytes(value[..].try_into()?) <= now {
+                self.mutes.remove(key)?;
+            } else if user_id.is_none_or(|user_id| user_id == muted) {
+                active.push(muted);
+            }
+        }
+        Ok(active)
+    }
+
+    /// Forgets the mutes the bot gave out in a chat, only `user_id`'s if given.
+    pub(crate) fn forget_mutes(
+        &self,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+    ) -> eyre::Result<()> {
+        match user_id {
+            Some(user_id) => {
+                self.mutes.remove(mute_key(chat_id, user_id))?;
+            }
+            None => {
+                for key in self.mutes.scan_prefix(chat_id.0.to_be_bytes()).keys() {
+                    self.mutes.remove(key?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `/amnesty`, lifting the mutes the bot gave out in the chat,
+    /// or only the ones of the author of the message it replies to.
+    pub(crate) async fn amnesty(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let target = match explicit_reply(message) {
+                    Some(reply_to) => match &reply_to.from {
+                        Some(target) => Some(target),
+                        None => {
+                            return reply(bot, message, locale.text(Text::CantTellSender)).await
+                        }
+                    },
+                    None => None,
+                };
+                let chat_id = message.chat.id;
+                let user_id = target.map(|target| target.id);
+                let mut count = 0;
+                for muted in self.active_mutes(chat_id, user_id, unix_now())? {
+                    match bot.unmute(chat_id, muted).await {
+                        Ok(()) => count += 1,
+                        Err(err) => tracing::info!(
+                            user_id = muted.0,
+                            err = format_args!("{err}"),
+                            "couldn't lift mute"
+                        ),
+                    }
+                }
+                tracing::info!(
+                    user_id = user_id.map(|user_id| user_id.0),
+                    admin_id = user.id.0,
+                    count,
+                    "granted amnesty"
+                );
+                self.forget_mutes(chat_id, user_id)?;
+                self.audit(AuditEvent::Amnesty {
+                    chat_id,
+                    admin_id: user.id,
+                    user_id,
+                });
+                let event = format!(
+                    "Amnesty in {}{}\nAdmin: {}\nMutes lifted: {count}",
+                    describe_chat(&message.chat),
+                    target.map_or_else(String::new, |target| format!(
+                        " for {}",
+                        describe_user(target)
+                    )),
+                    describe_user(user),
+                );
+                self.log_event(bot, event).await;
+                reply(bot, message, locale.text(Text::Amnesty { count })).await
+            },
+        )
+        .await
+    }
+}
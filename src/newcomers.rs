@@ -0,0 +1,133 @@
+//! Grace period of people who just joined a chat, who often repost
+//! what's pinned without knowing better, so they're warned instead.
+
+use color_eyre::eyre;
+use teloxide::types::{ChatId, Message, User, UserId};
+
+use crate::{
+    api::TelegramApi,
+    i18n::Text,
+    robot::{reply, Robot9000},
+};
+
+fn newcomer_key(chat_id: ChatId, user_id: UserId) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+    key[8..].copy_from_slice(&user_id.0.to_be_bytes());
+    key
+}
+
+/// When a newcomer joined, and how many of their messages were checked since.
+struct Newcomer {
+    joined_at: i64,
+    messages: u32,
+}
+
+impl Newcomer {
+    fn encode(&self) -> [u8; 12] {
+        let mut value = [0; 12];
+        value[..8].copy_from_slice(&self.joined_at.to_le_bytes());
+        value[8..].copy_from_slice(&self.messages.to_le_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> eyre::Result<Self> {
+        let value: &[u8; 12] = value
+            .try_into()
+            .map_err(|_| eyre::eyre!("malformed newcomer: {value:?}"))?;
+        Ok(Self {
+            joined_at: i64::from_le_bytes(value[..8].try_into().unwrap()),
+            messages: u32::from_le_bytes(value[8..].try_into().unwrap()),
+        })
+    }
+}
+
+impl Robot9000 {
+    /// Starts the grace period of someone who just joined, if there's one.
+    pub(crate) fn record_join(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        joined_at: i64,
+    ) -> eyre::Result<()> {
+        let config = self.config();
+        if config.newcomer_grace.is_none() && config.newcomer_grace_messages.is_none() {
+            return Ok(());
+        }
+        tracing::debug!(user_id = user_id.0, "newcomer joined");
+        let newcomer = Newcomer {
+            joined_at,
+            messages: 0,
+        };
+        self.newcomers
+            .insert(newcomer_key(chat_id, user_id), &newcomer.encode())?;
+        Ok(())
+    }
+
+    /// Counts a checked message of a user, returning whether they're still
+    /// within `newcomer_grace` seconds and `newcomer_grace_messages` messages
+    /// of joining. Newcomers are forgotten once either runs out.
+    pub(crate) fn check_newcomer(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        now: i64,
+    ) -> eyre::Result<bool> {
+        let config = self.config();
+        if config.newcomer_grace.is_none() && config.newcomer_grace_messages.is_none() {
+            return Ok(false);
+        }
+        let key = newcomer_key(chat_id, user_id);
+        let Some(value) = self.newcomers.get(key)? else {
+            return Ok(false);
+        };
+        let mut newcomer = Newcomer::decode(&value)?;
+        newcomer.messages = newcomer.messages.saturating_add(1);
+        let graceful = config
+            .newcomer_grace
+            .is_none_or(|grace| now.saturating_sub(newcomer.joined_at) < grace as i64)
+            && config
+                .newcomer_grace_messages
+                .is_none_or(|limit| newcomer.messages <= limit);
+        if graceful {
+            self.newcomers.insert(key, &newcomer.encode())?;
+        } else {
+            self.newcomers.remove(key)?;
+        }
+        Ok(graceful)
+    }
+
+    /// Forgets newcomers whose grace period ran out before they posted anything.
+    pub(crate) fn prune_newcomers(&self, now: i64) -> eyre::Result<usize> {
+        let Some(grace) = self.config().newcomer_grace else {
+            return Ok(0);
+        };
+        let mut removed = 0;
+        for item in self.newcomers.iter() {
+            let (key, value) = item?;
+            if now.saturating_sub(Newcomer::decode(&value)?.joined_at) >= grace as i64 {
+                self.newcomers.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Warns a newcomer about a duplicate they posted, instead of deleting it.
+    pub(crate) async fn warn_newcomer(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        tracing::debug!(user_id = user.id.0, "warning newcomer about duplicate");
+        let locale = self.chat_locale(message.chat.id)?;
+        let who = user.mention().unwrap_or_else(|| user.full_name());
+        reply(
+            bot,
+            message,
+            locale.text(Text::NewcomerWarning { user: &who }),
+        )
+        .await
+    }
+}
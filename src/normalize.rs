@@ -0,0 +1,318 @@
+//! Turning messages into the hashes they're checked by.
+
+use std::borrow::Cow;
+
+use color_eyre::eyre;
+use teloxide::types::{
+    MediaContact, MediaKind, MediaLocation, MediaText, MediaVenue, Message, MessageCommon,
+    MessageEntity, MessageEntityKind, MessageEntityRef, MessageKind, ThreadId, UserId,
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization as _};
+
+use crate::{
+    robot::Robot9000,
+    storage::{Key, Namespace, Scope},
+};
+
+/// Text of a message as it's hashed, see [`canonical_text`].
+pub fn message_text(message: &Message) -> Option<Cow<'_, str>> {
+    match &message.kind {
+        MessageKind::Common(MessageCommon {
+            media_kind: MediaKind::Text(MediaText { text, entities, .. }),
+            ..
+        }) => Some(canonical_text(text, entities)),
+        _ => None,
+    }
+}
+
+/// What media without text is hashed as, if it's checked for duplicates.
+/// Starts with NUL, so it can't collide with text.
+pub fn media_placeholder(media: &MediaKind) -> Option<String> {
+    match media {
+        MediaKind::VideoNote(note) => {
+            Some(format!("\0video note {}", note.video_note.file.unique_id))
+        }
+        MediaKind::Contact(MediaContact { contact }) => match contact.user_id {
+            Some(user_id) => Some(format!("\0contact user {user_id}")),
+            None => Some(phone_placeholder(&contact.phone_number)),
+        },
+        // Live locations move, and are shared to be followed rather than spammed.
+        MediaKind::Location(MediaLocation { location }) if location.live_period.is_none() => {
+            Some(location_placeholder(location.latitude, location.longitude))
+        }
+        MediaKind::Venue(MediaVenue { venue }) => Some(venue_placeholder(
+            venue.location.latitude,
+            venue.location.longitude,
+            &venue.title,
+        )),
+        _ => None,
+    }
+}
+
+/// Placeholder of a contact shared without its Telegram account.
+pub(crate) fn phone_placeholder(phone_number: &str) -> String {
+    let digits = phone_number
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    format!("\0contact phone {digits}")
+}
+
+/// Rounded to about ten meters, so the same place shared twice matches.
+fn coordinates(latitude: f64, longitude: f64) -> String {
+    format!("{latitude:.4},{longitude:.4}")
+}
+
+pub(crate) fn location_placeholder(latitude: f64, longitude: f64) -> String {
+    format!("\0location {}", coordinates(latitude, longitude))
+}
+
+pub(crate) fn venue_placeholder(latitude: f64, longitude: f64, title: &str) -> String {
+    format!(
+        "\0venue {} {}",
+        coordinates(latitude, longitude),
+        title.trim().to_lowercase()
+    )
+}
+
+/// Spells out links hidden behind other text, so "click here" linking
+/// somewhere hashes the same as the bare link.
+///
+/// Formatting and custom emoji are entities over the same text,
+/// with the base emoji in it, so they never change the hash anyway.
+pub fn canonical_text<'a>(text: &'a str, entities: &[MessageEntity]) -> Cow<'a, str> {
+    let parsed = MessageEntityRef::parse(text, entities);
+    let mut links = parsed
+        .iter()
+        .filter_map(|entity| match entity.kind() {
+            MessageEntityKind::TextLink { url } => Some((entity.range(), url.as_str())),
+            _ => None,
+        })
+        .peekable();
+    if links.peek().is_none() {
+        return Cow::Borrowed(text);
+    }
+    let mut canonical = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (range, url) in links {
+        // Links can't overlap, but a malformed update shouldn't panic.
+        if range.start < copied {
+            continue;
+        }
+        canonical.push_str(&text[copied..range.start]);
+        canonical.push_str(url);
+        copied = range.end;
+    }
+    canonical.push_str(&text[copied..]);
+    Cow::Owned(canonical)
+}
+
+/// Drops what only changes how emoji look: variation selectors, skin tones,
+/// and the joiners and gender signs of ZWJ sequences, so "👍🏻" is the same as "👍".
+pub fn normalize_emoji(text: &str) -> Cow<'_, str> {
+    let cosmetic = |c| {
+        matches!(
+            c,
+            '\u{FE0E}' | '\u{FE0F}' | '\u{1F3FB}'..='\u{1F3FF}' | '\u{200D}'
+        )
+    };
+    if !text.chars().any(cosmetic) {
+        return Cow::Borrowed(text);
+    }
+    let mut normalized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\u{200D}' {
+            // Gendered emoji are the base one joined with a sign.
+            chars.next_if(|&next| matches!(next, '\u{2640}' | '\u{2642}'));
+        } else if !cosmetic(c) {
+            normalized.push(c);
+        }
+    }
+    Cow::Owned(normalized)
+}
+
+/// Drops characters that can't be seen, like zero-width spaces and joiners,
+/// direction marks and soft hyphens, which are slipped into copypasta to change its hash.
+pub fn strip_invisible(text: &str) -> Cow<'_, str> {
+    let invisible = |c| {
+        matches!(
+            c,
+            '\u{AD}'
+                | '\u{34F}'
+                | '\u{61C}'
+                | '\u{115F}'
+                | '\u{1160}'
+                | '\u{17B4}'
+                | '\u{17B5}'
+                | '\u{180E}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{206F}'
+                | '\u{3164}'
+                | '\u{FEFF}'
+                | '\u{FFA0}'
+        )
+    };
+    if !text.chars().any(invisible) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.chars().filter(|&c| !invisible(c)).collect())
+}
+
+/// Replaces characters with the ones they're meant to be mistaken for, by the
+/// Unicode confusables table, so "Нello" with a Cyrillic "Н" is the same as "Hello".
+pub fn fold_confusables(text: &str) -> Cow<'_, str> {
+    let folded = unicode_security::skeleton(text).collect::<String>();
+    if folded == text {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(folded)
+    }
+}
+
+/// Aggressively folds deliberately mangled text: drops diacritics, lowercases,
+/// and undoes common leetspeak, so "Fr33 Mönëy" is the same as "free money".
+pub fn fold_leetspeak(text: &str) -> Cow<'_, str> {
+    let folded = text
+        .nfd()
+        .filter(|&c| !is_combining_mark(c))
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' | '|' | 'l' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            '8' => 'b',
+            c => c,
+        })
+        .collect::<String>();
+    if folded == text {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(folded)
+    }
+}
+
+/// Applies a step to a text that might already be normalized.
+fn and_then<'a>(text: Cow<'a, str>, step: fn(&str) -> Cow<'_, str>) -> Cow<'a, str> {
+    match step(&text) {
+        Cow::Borrowed(_) => text,
+        Cow::Owned(normalized) => Cow::Owned(normalized),
+    }
+}
+
+/// Which normalization steps texts go through before they're hashed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Normalization {
+    pub(crate) invisible: bool,
+    pub(crate) emoji: bool,
+    pub(crate) confusables: bool,
+    pub(crate) leetspeak: bool,
+}
+
+/// Step of normalization, named by the setting turning it on.
+type Step = (&'static str, fn(&str) -> Cow<'_, str>);
+
+impl Normalization {
+    /// Steps that are on, in the order they're applied.
+    fn steps(self) -> impl Iterator<Item = Step> {
+        // Emoji first, since their joiners are invisible too.
+        let steps: [(bool, Step); 4] = [
+            (self.emoji, ("normalize_emoji", normalize_emoji)),
+            (self.invisible, ("strip_invisible", strip_invisible)),
+            (self.confusables, ("fold_confusables", fold_confusables)),
+            (self.leetspeak, ("fold_leetspeak", fold_leetspeak)),
+        ];
+        steps
+            .into_iter()
+            .filter(|&(on, _)| on)
+            .map(|(_, step)| step)
+    }
+
+    pub(crate) fn apply(self, text: &str) -> Cow<'_, str> {
+        self.steps()
+            .fold(Cow::Borrowed(text), |text, (_, step)| and_then(text, step))
+    }
+
+    /// Names of the steps that change the text.
+    pub(crate) fn changes(self, text: &str) -> Vec<&'static str> {
+        let mut text = Cow::Borrowed(text);
+        let mut changes = Vec::new();
+        for (name, step) in self.steps() {
+            if let Cow::Owned(normalized) = step(&text) {
+                changes.push(name);
+                text = Cow::Owned(normalized);
+            }
+        }
+        changes
+    }
+}
+
+impl Robot9000 {
+    /// How texts are normalized in a namespace. Chats sharing known messages
+    /// must hash them the same way, so they all go by the config.
+    pub(crate) fn normalization(&self, namespace: Namespace) -> eyre::Result<Normalization> {
+        let (emoji, confusables, leetspeak) = match namespace {
+            Namespace::Chat(chat_id) => {
+                let settings = self.settings(chat_id)?;
+                (
+                    settings.normalize_emoji,
+                    settings.fold_confusables,
+                    settings.fold_leetspeak,
+                )
+            }
+            Namespace::Shared => {
+                let config = self.config();
+                (
+                    config.normalize_emoji,
+                    config.fold_confusables,
+                    config.fold_leetspeak,
+                )
+            }
+        };
+        Ok(Normalization {
+            invisible: self.config().strip_invisible,
+            emoji,
+            confusables,
+            leetspeak,
+        })
+    }
+
+    pub(crate) fn hash_message(&self, scope: Scope, text: &str) -> eyre::Result<Key> {
+        let salt = self.salts.get(scope.namespace.prefix())?;
+        let mut hasher = self.hash_function.hasher();
+        if let Namespace::Chat(chat_id) = scope.namespace {
+            hasher.update(&chat_id.0.to_le_bytes());
+        }
+        if let Some(salt) = salt {
+            hasher.update(&salt);
+        }
+        if let Some(ThreadId(thread_root)) = scope.thread_id {
+            hasher.update(&thread_root.0.to_le_bytes());
+        }
+        if let Some(UserId(poster_id)) = scope.poster_id {
+            // Tagged, so it can't be mistaken for a topic.
+            hasher.update(b"user");
+            hasher.update(&poster_id.to_le_bytes());
+        }
+        // Placeholders hold ids, which stop being unique when case or lookalikes are folded.
+        if text.starts_with('\0') {
+            hasher.update(text.as_bytes());
+        } else {
+            let normalization = self.normalization(scope.namespace)?;
+            hasher.update(normalization.apply(text).as_bytes());
+        }
+        let mut hash = hasher.finish();
+        if let Some(cipher) = &self.cipher {
+            hash = cipher.hash(hash);
+        }
+        Ok(Key {
+            namespace: scope.namespace,
+            hash,
+        })
+    }
+}
@@ -0,0 +1,162 @@
+//! Keeping in-chat deletion notices from flooding the chat during spam waves.
+//!
+//! Duplicates are still deleted, but once a chat gets `notice_limit` notices
+//! within `notice_window` seconds, the rest are summed up in one notice
+//! after the window passes.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use color_eyre::eyre;
+use teloxide::types::ChatId;
+
+use crate::{
+    api::{SendOptions, TelegramApi},
+    i18n::Text,
+    robot::Robot9000,
+    storage::unix_now,
+};
+
+/// How often chats are checked for summaries that are due.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct ChatNotices {
+    /// When the notices within the window were sent.
+    sent: VecDeque<i64>,
+    /// Deletions whose notices were held back, to be summed up.
+    held_back: u32,
+    /// When the first of them was held back.
+    held_back_since: i64,
+}
+
+/// Recent notices of every chat, kept in memory only, since windows are short.
+#[derive(Clone, Default)]
+pub(crate) struct NoticeLimiter {
+    chats: Arc<Mutex<HashMap<ChatId, ChatNotices>>>,
+}
+
+impl NoticeLimiter {
+    fn lock(&self) -> MutexGuard<'_, HashMap<ChatId, ChatNotices>> {
+        self.chats.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns whether a notice may be sent to the chat now, counting it
+    /// as held back otherwise. Once one is held back, the rest are too,
+    /// until they're summed up.
+    pub(crate) fn allow(&self, chat_id: ChatId, now: i64, limit: u32, window: i64) -> bool {
+        let mut chats = self.lock();
+        let chat = chats.entry(chat_id).or_default();
+        while chat
+            .sent
+            .front()
+            .is_some_and(|&sent_at| now - sent_at >= window)
+        {
+            chat.sent.pop_front();
+        }
+        if chat.held_back == 0 && chat.sent.len() < limit as usize {
+            chat.sent.push_back(now);
+            return true;
+        }
+        if chat.held_back == 0 {
+            chat.held_back_since = now;
+        }
+        chat.held_back += 1;
+        false
+    }
+
+    /// Takes the number of held back notices of every chat where they were
+    /// held back for at least `window` seconds, counting the summaries as sent.
+    fn take_due(&self, now: i64, window: i64) -> Vec<(ChatId, u32)> {
+        let mut chats = self.lock();
+        let mut due = Vec::new();
+        for (&chat_id, chat) in chats.iter_mut() {
+            if chat.held_back > 0 && now - chat.held_back_since >= window {
+                due.push((chat_id, chat.held_back));
+                chat.held_back = 0;
+                chat.sent.push_back(now);
+            }
+        }
+        chats.retain(|_, chat| {
+            chat.held_back > 0 || chat.sent.back().is_some_and(|&at| now - at < window)
+        });
+        due
+    }
+}
+
+impl Robot9000 {
+    /// Returns whether an in-chat deletion notice may be sent now,
+    /// which is always the case without a `notice_limit`.
+    pub(crate) fn allow_notice(&self, chat_id: ChatId) -> bool {
+        let config = self.config();
+        let Some(limit) = config.notice_limit else {
+            return true;
+        };
+        let window = config.notice_window.try_into().unwrap_or(i64::MAX);
+        self.notices.allow(chat_id, unix_now(), limit, window)
+    }
+
+    /// Sums up held back notices once their window passes, until the bot stops.
+    pub(crate) async fn summarize_notices_periodically(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            tokio::time::sleep(SUMMARY_INTERVAL).await;
+            let window = self.config().notice_window;
+            let due = self
+                .notices
+                .take_due(unix_now(), window.try_into().unwrap_or(i64::MAX));
+            for (chat_id, count) in due {
+                if let Err(err) = self.summarize_notices(&*bot, chat_id, count, window).await {
+                    tracing::warn!(
+                        chat_id = chat_id.0,
+                        err = format_args!("{err}"),
+                        "couldn't sum up deletion notices"
+                    );
+                }
+            }
+        }
+    }
+
+    async fn summarize_notices(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        count: u32,
+        seconds: u64,
+    ) -> eyre::Result<()> {
+        tracing::debug!(chat_id = chat_id.0, count, "summing up deletion notices");
+        let text = self
+            .chat_locale(chat_id)?
+            .text(Text::DeletedDuplicatesSummary { count, seconds });
+        let sent = bot
+            .send_message(chat_id, text, SendOptions::default())
+            .await?;
+        if let Some(lifetime) = self.config().notice_lifetime {
+            let at = unix_now().saturating_add(lifetime as i64);
+            self.deletions.schedule(sent.chat.id, sent.id, at)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_notices_over_the_limit() {
+        let notices = NoticeLimiter::default();
+        let chat_id = ChatId(-100);
+        assert!(notices.allow(chat_id, 0, 2, 60));
+        assert!(notices.allow(chat_id, 1, 2, 60));
+        assert!(!notices.allow(chat_id, 2, 2, 60));
+        // Old notices leaving the window don't let new ones skip the summary.
+        assert!(!notices.allow(chat_id, 61, 2, 60));
+        assert!(notices.take_due(61, 60).is_empty());
+        assert_eq!(notices.take_due(62, 60), [(chat_id, 2)]);
+        assert!(notices.allow(chat_id, 63, 2, 60));
+        assert!(!notices.allow(chat_id, 64, 2, 60));
+    }
+}
@@ -0,0 +1,78 @@
+//! Everyday phrases like "thanks" or "+1", which are never checked,
+//! so admins don't have to `/allow` each of them.
+//!
+//! The config has a list every chat starts with, and chats add phrases
+//! to it or remove them with `/phrases`.
+
+use std::collections::BTreeSet;
+
+use color_eyre::eyre;
+use teloxide::types::ChatId;
+
+use crate::{normalize::normalize_emoji, robot::Robot9000};
+
+/// Form messages are compared to phrases in: lowercase, with whitespace collapsed
+/// and trailing punctuation dropped, so "Thanks!" is the same as "thanks".
+pub fn normalize_phrase(text: &str) -> String {
+    let text = normalize_emoji(text).to_lowercase();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    text.trim_end_matches(['!', '?', '.', ',', ')']).to_owned()
+}
+
+/// Chat's override of a phrase, with a single byte value:
+/// 1 if the chat added it, 0 if it removed one from the config.
+fn phrase_key(chat_id: ChatId, phrase: &str) -> Vec<u8> {
+    [&chat_id.0.to_be_bytes()[..], phrase.as_bytes()].concat()
+}
+
+impl Robot9000 {
+    /// Whether a message is one of the common phrases of its chat.
+    pub(crate) fn is_common_phrase(&self, chat_id: ChatId, text: &str) -> eyre::Result<bool> {
+        let phrase = normalize_phrase(text);
+        if phrase.is_empty() {
+            return Ok(false);
+        }
+        match self.phrases.get(phrase_key(chat_id, &phrase))?.as_deref() {
+            Some([common]) => Ok(*common == 1),
+            _ => Ok(self
+                .config()
+                .common_phrases
+                .iter()
+                .any(|common| normalize_phrase(common) == phrase)),
+        }
+    }
+
+    /// Every common phrase of a chat, sorted.
+    pub(crate) fn common_phrases(&self, chat_id: ChatId) -> eyre::Result<Vec<String>> {
+        let mut phrases = (self.config().common_phrases.iter())
+            .map(|phrase| normalize_phrase(phrase))
+            .collect::<BTreeSet<_>>();
+        for item in self.phrases.scan_prefix(chat_id.0.to_be_bytes()) {
+            let (db_key, value) = item?;
+            let phrase = String::from_utf8(db_key[8..].to_vec())?;
+            if *value == [1] {
+                phrases.insert(phrase);
+            } else {
+                phrases.remove(&phrase);
+            }
+        }
+        Ok(phrases.into_iter().collect())
+    }
+
+    /// Adds a phrase to the common ones of a chat or removes it.
+    /// Returns the phrase as it's compared to messages.
+    pub(crate) fn set_common_phrase(
+        &self,
+        chat_id: ChatId,
+        phrase: &str,
+        common: bool,
+    ) -> eyre::Result<String> {
+        let phrase = normalize_phrase(phrase);
+        if phrase.is_empty() {
+            eyre::bail!("no phrase given");
+        }
+        self.phrases
+            .insert(phrase_key(chat_id, &phrase), &[u8::from(common)])?;
+        Ok(phrase)
+    }
+}
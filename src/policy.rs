@@ -0,0 +1,903 @@
+//! Deciding what happens to duplicates in each chat.
+
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike as _, FixedOffset, Utc};
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{
+    ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MediaText, Message, MessageId,
+    ReactionType, ThreadId, User, UserId,
+};
+use tokio::sync::Notify;
+
+use crate::{
+    activity::day_of,
+    api::{SendOptions, TelegramApi},
+    audit::AuditEvent,
+    config::{
+        chat_override, Action, Actions, AutomaticForwards, DeletionNotice, Digest, Enforcement,
+        QuietHours, Timezone, Weekdays,
+    },
+    i18n::{Language, Text},
+    robot::{describe_chat, describe_user, format_timestamp, hex, reply, snippet, Robot9000},
+    storage::{unix_now, Entry, Key, Namespace, Post, Scope, Status},
+    storms::RaidDeletion,
+};
+
+/// What a message was caught as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Detection {
+    Duplicate,
+    /// Similar enough to a known message, by `fuzzy_threshold`.
+    NearDuplicate,
+    /// Posted too many times in a row, by `flood_limit`.
+    Burst,
+    Forbidden,
+    /// Picked by the filter script, without being a duplicate.
+    Script,
+}
+
+impl Detection {
+    /// What a message whose entry is now `entry` was caught as, given whether
+    /// it was matched exactly or only found similar to a known one.
+    pub(crate) fn of(entry: &Entry, exact: bool) -> Self {
+        match entry.status {
+            Status::Forbidden => Detection::Forbidden,
+            _ if exact => Detection::Duplicate,
+            _ => Detection::NearDuplicate,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Detection::Duplicate => "duplicate",
+            Detection::NearDuplicate => "near_duplicate",
+            Detection::Burst => "burst",
+            Detection::Forbidden => "forbidden",
+            Detection::Script => "script",
+        }
+    }
+}
+
+/// Why a message is acted on, and how.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Verdict {
+    pub(crate) detection: Detection,
+    pub(crate) actions: Actions,
+}
+
+/// Actions taken on each kind of detection in a chat, where they're set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DuplicatePolicy {
+    duplicate: Option<Actions>,
+    near_duplicate: Option<Actions>,
+    burst: Option<Actions>,
+    forbidden: Option<Actions>,
+}
+
+impl DuplicatePolicy {
+    /// Actions taken on a detection, falling back to the ones the enforcement mode implies.
+    pub(crate) fn actions(&self, detection: Detection, enforcement: Enforcement) -> Actions {
+        let set = match detection {
+            Detection::Duplicate => self.duplicate,
+            Detection::NearDuplicate => self.near_duplicate,
+            Detection::Burst => self.burst,
+            Detection::Forbidden => self.forbidden,
+            Detection::Script => self.duplicate,
+        };
+        set.unwrap_or_else(|| match (detection, enforcement) {
+            // Bursts are deleted whatever the mode, and their author already knows why.
+            (Detection::Burst, _) => Actions::new(&[Action::Log, Action::Delete]),
+            (_, Enforcement::Delete) => {
+                Actions::new(&[Action::Log, Action::Delete, Action::Notify])
+            }
+            (_, Enforcement::React) => Actions::new(&[Action::Log, Action::React]),
+            (_, Enforcement::Mute) => {
+                Actions::new(&[Action::Log, Action::Delete, Action::Mute, Action::Notify])
+            }
+        })
+    }
+}
+
+/// Enforcement mode recorded in the audit log for actions.
+pub(crate) fn audited_enforcement(actions: Actions) -> Enforcement {
+    match (
+        actions.contains(Action::Delete),
+        actions.contains(Action::Mute),
+    ) {
+        (true, true) => Enforcement::Mute,
+        (true, false) => Enforcement::Delete,
+        (false, _) => Enforcement::React,
+    }
+}
+
+/// Message caught by a detection, with what's needed to act on it.
+pub(crate) struct Caught<'a> {
+    pub(crate) message: &'a Message,
+    /// Missing for channel posts, which can't be warned, muted or notified about.
+    pub(crate) user: Option<&'a User>,
+    /// Known message it duplicates, which notices are about.
+    pub(crate) original: Option<([u8; 16], &'a Entry)>,
+    pub(crate) text: Option<&'a MediaText>,
+}
+
+/// Behavior of a single chat, from the config and `/set` overrides.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub(crate) allow_duplicates_in_replies: bool,
+    pub(crate) exempt_admins: bool,
+    pub(crate) ignore_bots: bool,
+    pub(crate) automatic_forwards: AutomaticForwards,
+    pub(crate) normalize_emoji: bool,
+    pub(crate) fold_confusables: bool,
+    pub(crate) fold_leetspeak: bool,
+    pub(crate) dedup_window: Option<i64>,
+    /// Only delete copies of messages the same person posted before.
+    pub(crate) per_user: bool,
+    quiet_hours: Option<QuietHours>,
+    quiet_days: Weekdays,
+    timezone: Timezone,
+    max_repeats: u32,
+    pub(crate) enforcement: Enforcement,
+    pub(crate) policy: DuplicatePolicy,
+    pub(crate) language: Language,
+    /// Whether the chat consented to its texts being kept.
+    pub(crate) retain_texts: bool,
+    pub(crate) weekly_digest: Digest,
+}
+
+impl Settings {
+    pub(crate) fn is_expired(&self, seen_at: i64, now: i64) -> bool {
+        self.dedup_window
+            .is_some_and(|window| now.saturating_sub(seen_at) >= window)
+    }
+
+    pub(crate) fn local_time(&self, timestamp: DateTime<Utc>) -> DateTime<FixedOffset> {
+        timestamp.with_timezone(&self.timezone.offset_at(timestamp))
+    }
+
+    /// Day `timestamp` falls on in the chat's time zone, in days since the epoch.
+    pub(crate) fn local_day(&self, timestamp: i64) -> i64 {
+        let offset = DateTime::from_timestamp(timestamp, 0)
+            .map_or(0, |utc| self.timezone.offset_at(utc).local_minus_utc());
+        day_of(timestamp + i64::from(offset))
+    }
+
+    /// Whether duplicates posted at `timestamp` are tolerated.
+    pub(crate) fn is_quiet(&self, timestamp: DateTime<Utc>) -> bool {
+        let local = self.local_time(timestamp);
+        self.quiet_days.contains(local.weekday())
+            || self
+                .quiet_hours
+                .is_some_and(|hours| hours.contains(local.time()))
+    }
+
+    pub(crate) fn actions(&self, detection: Detection) -> Actions {
+        self.policy.actions(detection, self.enforcement)
+    }
+
+    pub(crate) fn is_duplicate(&self, entry: &Entry) -> bool {
+        match entry.status {
+            Status::Allowed => false,
+            Status::Forbidden => true,
+            Status::Seen => entry.count > self.max_repeats,
+        }
+    }
+
+    /// Returns the entry after another copy of a message is posted,
+    /// or `None` if it stays as it is.
+    pub(crate) fn next_entry(&self, entry: Option<Entry>, post: Post) -> Option<Entry> {
+        match entry {
+            Some(
+                entry @ Entry {
+                    status: Status::Allowed,
+                    repeats_left: Some(left),
+                    ..
+                },
+            ) => Some(Entry {
+                // Checked again once no repeats are left.
+                status: if left == 0 {
+                    Status::Seen
+                } else {
+                    Status::Allowed
+                },
+                count: entry.count.saturating_add(1),
+                repeats_left: left.checked_sub(1),
+                ..entry
+            }),
+            Some(entry) if entry.status != Status::Seen => None,
+            Some(entry) if !self.is_expired(entry.first_seen, post.timestamp) => Some(Entry {
+                count: entry.count.saturating_add(1),
+                ..entry
+            }),
+            _ => Some(Entry::new(Status::Seen, post)),
+        }
+    }
+}
+
+/// Setting adjustable at runtime with `/set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    AllowDuplicatesInReplies,
+    ExemptAdmins,
+    IgnoreBots,
+    AutomaticForwards,
+    NormalizeEmoji,
+    FoldConfusables,
+    FoldLeetspeak,
+    DedupWindow,
+    PerUser,
+    QuietHours,
+    QuietDays,
+    Timezone,
+    MaxRepeats,
+    Enforcement,
+    DuplicateActions,
+    NearDuplicateActions,
+    ForbiddenActions,
+    BurstActions,
+    Language,
+    RetainTexts,
+    WeeklyDigest,
+}
+
+impl Setting {
+    pub(crate) const ALL: [Setting; 21] = [
+        Setting::AllowDuplicatesInReplies,
+        Setting::ExemptAdmins,
+        Setting::IgnoreBots,
+        Setting::AutomaticForwards,
+        Setting::NormalizeEmoji,
+        Setting::FoldConfusables,
+        Setting::FoldLeetspeak,
+        Setting::DedupWindow,
+        Setting::PerUser,
+        Setting::QuietHours,
+        Setting::QuietDays,
+        Setting::Timezone,
+        Setting::MaxRepeats,
+        Setting::Enforcement,
+        Setting::DuplicateActions,
+        Setting::NearDuplicateActions,
+        Setting::ForbiddenActions,
+        Setting::BurstActions,
+        Setting::Language,
+        Setting::RetainTexts,
+        Setting::WeeklyDigest,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Setting::AllowDuplicatesInReplies => "allow_duplicates_in_replies",
+            Setting::ExemptAdmins => "exempt_admins",
+            Setting::IgnoreBots => "ignore_bots",
+            Setting::AutomaticForwards => "automatic_forwards",
+            Setting::NormalizeEmoji => "normalize_emoji",
+            Setting::FoldConfusables => "fold_confusables",
+            Setting::FoldLeetspeak => "fold_leetspeak",
+            Setting::DedupWindow => "dedup_window",
+            Setting::PerUser => "per_user",
+            Setting::QuietHours => "quiet_hours",
+            Setting::QuietDays => "quiet_days",
+            Setting::Timezone => "timezone",
+            Setting::MaxRepeats => "max_repeats",
+            Setting::Enforcement => "enforcement",
+            Setting::DuplicateActions => "duplicate_actions",
+            Setting::NearDuplicateActions => "near_duplicate_actions",
+            Setting::ForbiddenActions => "forbidden_actions",
+            Setting::BurstActions => "burst_actions",
+            Setting::Language => "language",
+            Setting::RetainTexts => "retain_texts",
+            Setting::WeeklyDigest => "weekly_digest",
+        }
+    }
+
+    /// Overrides the setting with a value given to `/set`.
+    pub(crate) fn apply(self, settings: &mut Settings, value: &str) -> eyre::Result<()> {
+        match self {
+            Setting::AllowDuplicatesInReplies => {
+                settings.allow_duplicates_in_replies = value.parse()?;
+            }
+            Setting::ExemptAdmins => settings.exempt_admins = value.parse()?,
+            Setting::IgnoreBots => settings.ignore_bots = value.parse()?,
+            Setting::AutomaticForwards => settings.automatic_forwards = value.parse()?,
+            Setting::NormalizeEmoji => settings.normalize_emoji = value.parse()?,
+            Setting::FoldConfusables => settings.fold_confusables = value.parse()?,
+            Setting::FoldLeetspeak => settings.fold_leetspeak = value.parse()?,
+            Setting::DedupWindow => {
+                settings.dedup_window = match value {
+                    "off" => None,
+                    _ => Some(value.parse::<u32>()?.into()),
+                };
+            }
+            Setting::PerUser => settings.per_user = value.parse()?,
+            Setting::QuietHours => {
+                settings.quiet_hours = match value {
+                    "off" => None,
+                    _ => Some(value.parse()?),
+                };
+            }
+            Setting::QuietDays => settings.quiet_days = value.parse()?,
+            Setting::Timezone => settings.timezone = value.parse()?,
+            Setting::MaxRepeats => settings.max_repeats = value.parse()?,
+            Setting::Enforcement => settings.enforcement = value.parse()?,
+            Setting::DuplicateActions => settings.policy.duplicate = Some(value.parse()?),
+            Setting::NearDuplicateActions => {
+                settings.policy.near_duplicate = Some(value.parse()?);
+            }
+            Setting::ForbiddenActions => settings.policy.forbidden = Some(value.parse()?),
+            Setting::BurstActions => settings.policy.burst = Some(value.parse()?),
+            Setting::Language => settings.language = value.parse()?,
+            Setting::RetainTexts => settings.retain_texts = value.parse()?,
+            Setting::WeeklyDigest => settings.weekly_digest = value.parse()?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn show(self, settings: &Settings) -> String {
+        match self {
+            Setting::AllowDuplicatesInReplies => settings.allow_duplicates_in_replies.to_string(),
+            Setting::ExemptAdmins => settings.exempt_admins.to_string(),
+            Setting::IgnoreBots => settings.ignore_bots.to_string(),
+            Setting::AutomaticForwards => settings.automatic_forwards.as_str().into(),
+            Setting::NormalizeEmoji => settings.normalize_emoji.to_string(),
+            Setting::FoldConfusables => settings.fold_confusables.to_string(),
+            Setting::FoldLeetspeak => settings.fold_leetspeak.to_string(),
+            Setting::DedupWindow => settings
+                .dedup_window
+                .map_or_else(|| "off".into(), |window| format!("{window} seconds")),
+            Setting::PerUser => settings.per_user.to_string(),
+            Setting::QuietHours => settings
+                .quiet_hours
+                .map_or_else(|| "off".into(), |hours| hours.to_string()),
+            Setting::QuietDays => settings.quiet_days.to_string(),
+            Setting::Timezone => settings.timezone.to_string(),
+            Setting::MaxRepeats => settings.max_repeats.to_string(),
+            Setting::Enforcement => format!("{:?}", settings.enforcement).to_lowercase(),
+            Setting::DuplicateActions => settings.actions(Detection::Duplicate).to_string(),
+            Setting::NearDuplicateActions => settings.actions(Detection::NearDuplicate).to_string(),
+            Setting::ForbiddenActions => settings.actions(Detection::Forbidden).to_string(),
+            Setting::BurstActions => settings.actions(Detection::Burst).to_string(),
+            Setting::Language => settings.language.code().into(),
+            Setting::RetainTexts => settings.retain_texts.to_string(),
+            Setting::WeeklyDigest => settings.weekly_digest.as_str().into(),
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.name() == s)
+            .ok_or_else(|| eyre::eyre!("unknown setting: {s:?}"))
+    }
+}
+
+/// Bot messages waiting to be deleted, persisted to survive restarts.
+///
+/// Keys are big-endian deletion time, chat id and message id, so the
+/// earliest deletion always comes first.
+#[derive(Clone)]
+pub struct DeletionQueue {
+    tree: sled::Tree,
+    notify: Arc<Notify>,
+}
+
+impl DeletionQueue {
+    pub(crate) fn open(tree: sled::Tree) -> Self {
+        Self {
+            tree,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn schedule(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        at: i64,
+    ) -> eyre::Result<()> {
+        let mut key = [0; 20];
+        key[..8].copy_from_slice(&at.to_be_bytes());
+        key[8..16].copy_from_slice(&chat_id.0.to_be_bytes());
+        key[16..].copy_from_slice(&message_id.0.to_be_bytes());
+        self.tree.insert(key, &[])?;
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    pub(crate) async fn run(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            if let Err(err) = self.process(&*bot).await {
+                tracing::error!(err = format_args!("{err}"), "deletion queue failed");
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        }
+    }
+
+    async fn process(&self, bot: &dyn TelegramApi) -> eyre::Result<()> {
+        loop {
+            let Some((key, _)) = self.tree.first()? else {
+                self.notify.notified().await;
+                continue;
+            };
+            let at = i64::from_be_bytes(key[..8].try_into()?);
+            let wait = at - unix_now();
+            if wait > 0 {
+                // Something earlier might get scheduled while we wait.
+                tokio::select! {
+                    () = tokio::time::sleep(Duration::from_secs(wait as u64)) => {}
+                    () = self.notify.notified() => {}
+                }
+                continue;
+            }
+
+            // Everything due is deleted at once, a batch per chat.
+            let now = unix_now();
+            let mut due = BTreeMap::<ChatId, Vec<MessageId>>::new();
+            let mut keys = Vec::new();
+            for item in self.tree.iter() {
+                let (key, _) = item?;
+                if i64::from_be_bytes(key[..8].try_into()?) > now {
+                    break;
+                }
+                let chat_id = ChatId(i64::from_be_bytes(key[8..16].try_into()?));
+                let message_id = MessageId(i32::from_be_bytes(key[16..20].try_into()?));
+                due.entry(chat_id).or_default().push(message_id);
+                keys.push(key);
+            }
+            for (chat_id, message_ids) in due {
+                let count = message_ids.len();
+                if let Err(err) = bot.delete_messages(chat_id, message_ids).await {
+                    tracing::info!(
+                        chat_id = chat_id.0,
+                        count,
+                        err = format_args!("{err}"),
+                        "couldn't delete scheduled messages",
+                    );
+                }
+            }
+            for key in keys {
+                self.tree.remove(key)?;
+            }
+        }
+    }
+}
+
+/// Duplicates waiting a moment to be deleted along with others of their chat,
+/// when `delete_batch_delay` is set.
+#[derive(Clone, Default)]
+pub(crate) struct DeletionBatches {
+    pending: Arc<Mutex<BTreeMap<ChatId, Vec<MessageId>>>>,
+    notify: Arc<Notify>,
+}
+
+impl DeletionBatches {
+    fn push(&self, chat_id: ChatId, message_id: MessageId) {
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        pending.entry(chat_id).or_default().push(message_id);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> BTreeMap<ChatId, Vec<MessageId>> {
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        std::mem::take(&mut *pending)
+    }
+}
+
+/// Appeal against a deletion, waiting for an admin to allow the message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Appeal {
+    pub(crate) user_id: UserId,
+    pub(crate) hash: [u8; 16],
+}
+
+impl Appeal {
+    pub(crate) fn key(namespace: Namespace, id: u64) -> [u8; 16] {
+        let mut key = [0; 16];
+        key[..8].copy_from_slice(&namespace.prefix());
+        key[8..].copy_from_slice(&id.to_be_bytes());
+        key
+    }
+}
+
+impl Robot9000 {
+    /// Copies a message about to be deleted to the quarantine channel, if there's one.
+    /// Failing to doesn't stop the deletion.
+    pub(crate) async fn quarantine(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) {
+        let Some(quarantine_chat_id) = self.config().quarantine_chat_id else {
+            return;
+        };
+        let result = bot
+            .copy_message(ChatId(quarantine_chat_id), chat_id, message_id)
+            .await;
+        if let Err(err) = result {
+            tracing::warn!(
+                chat_id = chat_id.0,
+                message_id = message_id.0,
+                err = format_args!("{err}"),
+                "couldn't copy message to the quarantine channel"
+            );
+        }
+    }
+
+    /// Deletes a duplicate after quarantining it, counting it towards a storm.
+    pub(crate) async fn delete_duplicate(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> eyre::Result<()> {
+        self.quarantine(bot, chat_id, message_id).await;
+        self.delete_batched(bot, chat_id, message_id).await?;
+        self.record_storm(bot, chat_id, unix_now()).await
+    }
+
+    /// Deletes a message, or leaves it for the next batch of its chat
+    /// if `delete_batch_delay` is set.
+    pub(crate) async fn delete_batched(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> eyre::Result<()> {
+        if self.config().delete_batch_delay.is_some() {
+            self.deletion_batches.push(chat_id, message_id);
+            return Ok(());
+        }
+        bot.delete_message(chat_id, message_id).await
+    }
+
+    /// Deletes the pending batches a moment after the first duplicate of one,
+    /// until the bot stops.
+    pub(crate) async fn delete_batches_periodically(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            self.deletion_batches.notify.notified().await;
+            let delay = self.config().delete_batch_delay.unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.delete_batches(&*bot).await;
+        }
+    }
+
+    pub(crate) async fn delete_batches(&self, bot: &dyn TelegramApi) {
+        for (chat_id, message_ids) in self.deletion_batches.take() {
+            let count = message_ids.len();
+            tracing::debug!(chat_id = chat_id.0, count, "deleting a batch of duplicates");
+            if let Err(err) = bot.delete_messages(chat_id, message_ids).await {
+                tracing::warn!(
+                    chat_id = chat_id.0,
+                    count,
+                    err = format_args!("{err}"),
+                    "couldn't delete a batch of duplicates",
+                );
+            }
+        }
+    }
+
+    pub(crate) fn namespace(&self, chat_id: ChatId) -> Namespace {
+        if self.config().shared_chats.contains(&chat_id.0) {
+            Namespace::Shared
+        } else {
+            Namespace::Chat(chat_id)
+        }
+    }
+
+    /// Where a message is checked, given the settings of its chat.
+    pub(crate) fn scope(&self, message: &Message, settings: &Settings) -> Scope {
+        let thread_id = message.thread_id.filter(|_| message.is_topic_message);
+        Scope {
+            poster_id: (message.from.as_ref())
+                .filter(|_| settings.per_user)
+                .map(|user| user.id),
+            ..self.topic_scope(message.chat.id, thread_id)
+        }
+    }
+
+    /// Where messages posted in a topic of a chat are checked.
+    pub(crate) fn topic_scope(&self, chat_id: ChatId, thread_id: Option<ThreadId>) -> Scope {
+        Scope {
+            namespace: self.namespace(chat_id),
+            thread_id: thread_id.filter(|_| self.scopes_topics(chat_id)),
+            poster_id: None,
+        }
+    }
+
+    /// Whether every topic of a chat is checked on its own.
+    pub(crate) fn scopes_topics(&self, chat_id: ChatId) -> bool {
+        // Topic ids are only meaningful within a single chat.
+        self.namespace(chat_id) != Namespace::Shared
+            && self.config().topic_scoped_chats.contains(&chat_id.0)
+    }
+
+    /// Resolves the settings of a chat, with its `/set` overrides applied.
+    pub(crate) fn settings(&self, chat_id: ChatId) -> eyre::Result<Settings> {
+        let config = self.config();
+        let mut settings = Settings {
+            allow_duplicates_in_replies: config.allow_duplicates_in_replies,
+            exempt_admins: config.exempt_admins,
+            ignore_bots: config.ignore_bots,
+            automatic_forwards: config.automatic_forwards,
+            normalize_emoji: config.normalize_emoji,
+            fold_confusables: config.fold_confusables,
+            fold_leetspeak: config.fold_leetspeak,
+            dedup_window: config.dedup_window,
+            per_user: config.per_user,
+            quiet_hours: config.quiet_hours,
+            quiet_days: config.quiet_days,
+            timezone: config.timezone,
+            max_repeats: chat_override(&config.chat_max_repeats, chat_id, config.max_repeats),
+            enforcement: chat_override(&config.chat_enforcement, chat_id, config.enforcement),
+            policy: DuplicatePolicy {
+                duplicate: config.duplicate_actions,
+                near_duplicate: config.near_duplicate_actions,
+                burst: config.burst_actions,
+                forbidden: config.forbidden_actions,
+            },
+            language: config.language,
+            retain_texts: false,
+            weekly_digest: config.weekly_digest,
+        };
+        for item in self.chat_settings.scan_prefix(chat_id.0.to_be_bytes()) {
+            let (key, value) = item?;
+            let setting = std::str::from_utf8(&key[8..])?.parse::<Setting>()?;
+            setting.apply(&mut settings, std::str::from_utf8(&value)?)?;
+        }
+        // Consent only counts while the operator allows keeping texts.
+        settings.retain_texts &= config.retain_texts;
+        Ok(settings)
+    }
+
+    /// Describes what would happen to the next copy of a message, without changing anything.
+    pub(crate) async fn check_hash(
+        &self,
+        settings: &Settings,
+        key: Key,
+        now: i64,
+    ) -> eyre::Result<String> {
+        let Some(entry) = self.store.get(key).await? else {
+            return Ok(self.locale(settings.language).text(Text::CheckUnknown));
+        };
+        let since = format_timestamp(entry.first_seen);
+        let answer = match entry.status {
+            Status::Allowed => match entry.repeats_left {
+                Some(repeats) => Text::CheckAllowedRepeats { repeats },
+                None => Text::CheckAllowed,
+            },
+            Status::Forbidden => Text::CheckForbidden,
+            Status::Seen if settings.is_expired(entry.first_seen, now) => Text::CheckExpired,
+            Status::Seen => {
+                let next = Entry {
+                    count: entry.count.saturating_add(1),
+                    ..entry
+                };
+                Text::CheckSeen {
+                    count: entry.count,
+                    first_seen: &since,
+                    will_be_deleted: settings.is_duplicate(&next),
+                }
+            }
+        };
+        Ok(self.locale(settings.language).text(answer))
+    }
+
+    pub(crate) async fn enforce(
+        &self,
+        bot: &dyn TelegramApi,
+        Verdict { detection, actions }: Verdict,
+        message: &Message,
+        user: &User,
+        (hash, entry): ([u8; 16], &Entry),
+        text: Option<&MediaText>,
+    ) -> eyre::Result<()> {
+        if actions.contains(Action::Delete) && self.is_suspended(message.chat.id)? {
+            tracing::debug!("can't delete messages, ignoring duplicate");
+            return Ok(());
+        }
+        tracing::debug!(
+            text = format_args!("{:?}", text.map(|text| &text.text)),
+            detection = format_args!("{detection:?}"),
+            actions = format_args!("{actions}"),
+            "enforcing on duplicate message"
+        );
+        let event = format!(
+            "Duplicate in {}\nUser: {}\nDetection: {detection:?}\nActions: {actions}\nHash: {}\n\
+             First seen: {}\nCopies: {}\nText: {}",
+            describe_chat(&message.chat),
+            describe_user(user),
+            hex(&hash),
+            format_timestamp(entry.first_seen),
+            entry.count,
+            text.map_or_else(|| "(media)".into(), |text| snippet(&text.text)),
+        );
+        self.audit(AuditEvent::Enforce {
+            chat_id: message.chat.id,
+            hash,
+            message_id: message.id.0,
+            user_id: Some(user.id),
+            enforcement: audited_enforcement(actions),
+        });
+        let caught = Caught {
+            message,
+            user: Some(user),
+            original: Some((hash, entry)),
+            text,
+        };
+        self.take_actions(bot, &caught, actions, event).await?;
+        Ok(())
+    }
+
+    /// Takes actions on a caught message in order, reporting `event` to the log chat
+    /// for `log`. Returns whether the message was deleted.
+    pub(crate) async fn take_actions(
+        &self,
+        bot: &dyn TelegramApi,
+        caught: &Caught<'_>,
+        actions: Actions,
+        event: String,
+    ) -> eyre::Result<bool> {
+        let message = caught.message;
+        let mut deleted = false;
+        for action in actions.iter() {
+            match (action, caught.user) {
+                (Action::Log, _) => self.log_event(bot, event.clone()).await,
+                (Action::Delete, user) => {
+                    self.delete_duplicate(bot, message.chat.id, message.id)
+                        .await?;
+                    let user_id = user.map(|user| user.id);
+                    self.count_deleted(message.chat.id, message.date.timestamp(), user_id)
+                        .await?;
+                    let text = message.text().or_else(|| message.caption());
+                    let deletion = RaidDeletion::new(unix_now(), user, text);
+                    self.record_raid(bot, &message.chat, deletion).await?;
+                    deleted = true;
+                }
+                (Action::React, _) => {
+                    let reaction = ReactionType::Emoji {
+                        emoji: self.config().duplicate_reaction.clone(),
+                    };
+                    bot.set_message_reaction(message.chat.id, message.id, reaction)
+                        .await?;
+                }
+                (Action::Warn, Some(user)) => {
+                    let locale = self.chat_locale(message.chat.id)?;
+                    let who = user.mention().unwrap_or_else(|| user.full_name());
+                    let warning = locale.text(Text::DuplicateWarning { user: &who });
+                    reply(bot, message, warning).await?;
+                }
+                (Action::Mute, Some(user)) => {
+                    self.mute(bot, message.chat.id, user.id).await?;
+                }
+                (Action::Notify, Some(user)) => {
+                    if let Some((hash, entry)) = caught.original.filter(|_| deleted) {
+                        self.send_deletion_notice(bot, message, user, hash, entry, caught.text)
+                            .await?;
+                    }
+                }
+                // Channel posts have no author.
+                (Action::Warn | Action::Mute | Action::Notify, None) => {}
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn send_deletion_notice(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        hash: [u8; 16],
+        entry: &Entry,
+        text: Option<&MediaText>,
+    ) -> eyre::Result<()> {
+        let config = self.config();
+        // The original could be in any chat of a shared namespace,
+        // but only its id is stored.
+        let original = entry
+            .first_message_id
+            .filter(|_| self.namespace(message.chat.id) != Namespace::Shared)
+            .and_then(|id| Message::url_of(message.chat.id, message.chat.username(), id));
+
+        let locale = self.chat_locale(message.chat.id)?;
+        let key = Key {
+            namespace: self.namespace(message.chat.id),
+            hash,
+        };
+        let duplicated = if self.settings(message.chat.id)?.retain_texts {
+            self.retained_text(message.chat.id, key, entry)?
+        } else {
+            None
+        };
+        let history = locale.text(Text::DuplicateHistory {
+            first_seen: &format_timestamp(entry.first_seen),
+            seconds_ago: message.date.timestamp() - entry.first_seen,
+            reposts: entry.count.saturating_sub(1),
+        });
+        let with_duplicated = |notice: String| match &duplicated {
+            Some(text) => format!(
+                "{notice}\n{history}\n{}",
+                locale.text(Text::DuplicateOf {
+                    text: &snippet(text)
+                })
+            ),
+            None => format!("{notice}\n{history}"),
+        };
+        let original = original.as_ref().map(|url| url.as_str());
+        if config.deletion_notice == DeletionNotice::Chat && self.allow_notice(message.chat.id) {
+            let who = user.mention().unwrap_or_else(|| user.full_name());
+            let notice = with_duplicated(locale.text(Text::DeletedDuplicate {
+                user: &who,
+                original,
+            }));
+            let mut options = SendOptions {
+                thread_id: message.thread_id.filter(|_| message.is_topic_message),
+                ..SendOptions::default()
+            };
+            if config.appeals {
+                let appeal_id = self.db.generate_id()?;
+                let appeal = Appeal {
+                    user_id: user.id,
+                    hash,
+                };
+                self.appeals.insert(
+                    Appeal::key(self.namespace(message.chat.id), appeal_id),
+                    serde_json::to_vec(&appeal)?,
+                )?;
+                options.markup = Some(InlineKeyboardMarkup::new([[
+                    InlineKeyboardButton::callback(
+                        locale.text(Text::AppealButton),
+                        format!("appeal {appeal_id}"),
+                    ),
+                ]]));
+            }
+            let sent = bot.send_message(message.chat.id, notice, options).await?;
+            if let Some(lifetime) = config.notice_lifetime {
+                let at = unix_now().saturating_add(lifetime as i64);
+                self.deletions.schedule(sent.chat.id, sent.id, at)?;
+            }
+        }
+
+        if config.deletion_notice != DeletionNotice::Private && !config.return_deleted_text {
+            return Ok(());
+        }
+        let notice = with_duplicated(locale.text(Text::DeletedYourDuplicate {
+            chat: message.chat.title(),
+            original,
+            text_follows: config.return_deleted_text && text.is_some(),
+        }));
+        // Fails if the user never started a conversation with the bot.
+        let result = bot
+            .send_message(user.id.into(), notice, SendOptions::default())
+            .await;
+        if let Err(err) = result {
+            tracing::info!(
+                user_id = user.id.0,
+                err = format_args!("{err}"),
+                "couldn't send deletion notice",
+            );
+            return Ok(());
+        }
+        if let Some(text) = text.filter(|_| config.return_deleted_text) {
+            let options = SendOptions {
+                entities: Some(text.entities.clone()),
+                ..SendOptions::default()
+            };
+            bot.send_message(user.id.into(), text.text.clone(), options)
+                .await?;
+        }
+        Ok(())
+    }
+}
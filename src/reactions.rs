@@ -0,0 +1,137 @@
+//! Allowing and forbidding messages by reacting to them, for admins
+//! who'd rather not type commands.
+//!
+//! Reactions only say which message they're on, so the keys of messages
+//! are remembered by their ids while reactions are configured.
+
+use std::sync::Arc;
+
+use color_eyre::eyre;
+use teloxide::types::{ChatId, MessageId, MessageReactionUpdated, ReactionType};
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    robot::{describe_chat, describe_user, hex, Robot9000},
+    storage::{Key, Post, Status},
+};
+
+fn message_key(chat_id: ChatId, message_id: MessageId) -> [u8; 12] {
+    let mut db_key = [0; 12];
+    db_key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+    db_key[8..].copy_from_slice(&message_id.0.to_be_bytes());
+    db_key
+}
+
+impl Robot9000 {
+    /// Remembers the key of a message, so reactions to it can be mapped back to it.
+    pub(crate) fn remember_message_key(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        key: Key,
+    ) -> eyre::Result<()> {
+        let config = self.config();
+        if config.allow_reaction.is_none() && config.forbid_reaction.is_none() {
+            return Ok(());
+        }
+        self.message_keys
+            .insert(message_key(chat_id, message_id), &key.encode())?;
+        Ok(())
+    }
+
+    /// Allows or forbids a message an admin just reacted to with
+    /// `allow_reaction` or `forbid_reaction`.
+    pub async fn process_reaction(
+        &self,
+        update: MessageReactionUpdated,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        if !self.is_allowed(&update.chat) {
+            return Ok(());
+        }
+        let config = self.config();
+        let added = |emoji: &Option<String>| {
+            emoji.as_ref().is_some_and(|emoji| {
+                let reaction = ReactionType::Emoji {
+                    emoji: emoji.clone(),
+                };
+                update.new_reaction.contains(&reaction) && !update.old_reaction.contains(&reaction)
+            })
+        };
+        let status = if added(&config.allow_reaction) {
+            Status::Allowed
+        } else if added(&config.forbid_reaction) {
+            Status::Forbidden
+        } else {
+            return Ok(());
+        };
+        let Some(user) = update.user() else {
+            tracing::debug!("ignoring anonymous reaction");
+            return Ok(());
+        };
+        if !Self::is_admin(&config, &self.admins, &*bot, &update.chat, user).await? {
+            return Ok(());
+        }
+        let db_key = message_key(update.chat.id, update.message_id);
+        let Some(key) = self.message_keys.get(db_key)? else {
+            tracing::debug!("ignoring reaction to a message that isn't known");
+            return Ok(());
+        };
+        let key = Key::decode(&key)?;
+        tracing::info!(
+            user_id = user.id.0,
+            status = format_args!("{status:?}"),
+            "changing status on reaction"
+        );
+        let post = Post {
+            timestamp: update.date.timestamp(),
+            message_id: Some(update.message_id),
+            poster_id: None,
+        };
+        self.set_hash_status(key, status, None, post).await?;
+
+        let (chat_id, hash, admin_id) = (update.chat.id, key.hash, user.id);
+        let (event, action) = match status {
+            Status::Allowed => (
+                AuditEvent::Allow {
+                    chat_id,
+                    hash,
+                    admin_id,
+                    repeats: None,
+                },
+                "Allowed",
+            ),
+            _ => (
+                AuditEvent::Forbid {
+                    chat_id,
+                    hash,
+                    admin_id,
+                },
+                "Forbidden",
+            ),
+        };
+        self.audit(event);
+        let event = format!(
+            "{action} by reaction in {}\nAdmin: {}\nHash: {}",
+            describe_chat(&update.chat),
+            describe_user(user),
+            hex(&hash),
+        );
+        self.log_event(&*bot, event).await;
+        Ok(())
+    }
+
+    /// Forgets the keys of messages that aren't known anymore.
+    pub(crate) async fn prune_message_keys(&self) -> eyre::Result<usize> {
+        let mut removed = 0;
+        for item in self.message_keys.iter() {
+            let (db_key, value) = item?;
+            if self.store.peek(Key::decode(&value)?).await?.is_none() {
+                self.message_keys.remove(db_key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
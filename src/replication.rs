@@ -0,0 +1,198 @@
+//! Streaming changes to known messages from a primary to standby instances.
+//!
+//! A standby connects and sends the big-endian `u64` number of the first change
+//! it wants. The primary answers with a status byte, 0 if it still has that change
+//! and 1 if it was already pruned or never made, and then with every change from there on,
+//! waiting for new ones once caught up. Each change is its big-endian `u64`
+//! number, its big-endian `u32` length and the change itself. Changes of zero
+//! length are only heartbeats, so a standby can tell a dead connection apart.
+
+use std::{net::SocketAddr, time::Duration};
+
+use color_eyre::eyre;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    config::Config,
+    robot::Robot9000,
+    storage::open_tree,
+    store::{Change, ReplicationLog},
+};
+
+/// How many changes are read from the log in one go.
+const READ_BATCH: usize = 1000;
+
+/// How long an idle primary waits before sending a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a standby waits for anything before reconnecting.
+const READ_TIMEOUT: Duration = Duration::from_secs(90);
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const OFFSET_KEY: &[u8] = b"offset";
+
+/// Streams the log to standby instances until the bot stops.
+pub(crate) async fn serve(listener: TcpListener, log: ReplicationLog) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(err = format_args!("{err}"), "couldn't accept standby");
+                continue;
+            }
+        };
+        let log = log.clone();
+        tokio::spawn(async move {
+            tracing::info!(%addr, "Standby connected");
+            if let Err(err) = stream_changes(stream, &log).await {
+                tracing::info!(%addr, err = format_args!("{err}"), "Standby disconnected");
+            }
+        });
+    }
+}
+
+async fn stream_changes(stream: TcpStream, log: &ReplicationLog) -> eyre::Result<()> {
+    let mut stream = BufWriter::new(stream);
+    let mut next = stream.read_u64().await?;
+    let mut changes = read(log, next, 1).await;
+    stream.write_u8(u8::from(changes.is_err())).await?;
+    stream.flush().await?;
+    loop {
+        // Created before reading, so changes appended meanwhile still wake it up.
+        let appended = log.wait_for_changes();
+        let batch = changes?;
+        if batch.is_empty() {
+            stream.flush().await?;
+            if tokio::time::timeout(HEARTBEAT_INTERVAL, appended)
+                .await
+                .is_err()
+            {
+                stream.write_u64(next).await?;
+                stream.write_u32(0).await?;
+                stream.flush().await?;
+            }
+        }
+        for (number, change) in batch {
+            let change = change.encode();
+            stream.write_u64(number).await?;
+            stream.write_u32(change.len().try_into()?).await?;
+            stream.write_all(&change).await?;
+            next = number + 1;
+        }
+        changes = read(log, next, READ_BATCH).await;
+    }
+}
+
+async fn read(log: &ReplicationLog, from: u64, limit: usize) -> eyre::Result<Vec<(u64, Change)>> {
+    let log = log.clone();
+    tokio::task::spawn_blocking(move || log.read(from, limit)).await?
+}
+
+/// Keeps the database of a standby instance up to date with the primary at
+/// `primary`, until interrupted.
+///
+/// It resumes from the last change applied, or from the end of the log
+/// in a backup of the primary restored into an empty database.
+pub async fn follow(config: Config, primary: String) -> eyre::Result<()> {
+    let robot = Robot9000::open(config)?;
+    let state = open_tree(&robot.db, robot.config().bot_name(), "replication_state")?;
+    let restored_log = open_tree(&robot.db, robot.config().bot_name(), "replication_log")?;
+    let mut next = match (state.get(OFFSET_KEY)?, restored_log.last()?) {
+        (Some(offset), _) => u64::from_be_bytes(offset[..].try_into()?),
+        (None, Some((number, _))) => u64::from_be_bytes(number[..].try_into()?) + 1,
+        (None, None) => 0,
+    };
+
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let result = tokio::select! {
+            result = apply_changes(&robot, &state, &primary, &mut next, &mut backoff) => result,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        if let Err(err) = result {
+            if err.downcast_ref::<Unavailable>().is_some() {
+                return Err(err);
+            }
+            tracing::warn!(
+                err = format_args!("{err}"),
+                backoff = format_args!("{backoff:?}"),
+                "Lost the primary, reconnecting"
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    robot.flush().await?;
+    state.flush_async().await?;
+    tracing::info!(next, "Stopped following");
+    Ok(())
+}
+
+/// The primary doesn't have the changes the standby needs, since they were
+/// pruned or the standby is ahead of it.
+#[derive(Debug)]
+struct Unavailable(u64);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the primary can't stream changes from {} on, restore a fresh backup of it first",
+            self.0,
+        )
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+async fn apply_changes(
+    robot: &Robot9000,
+    state: &sled::Tree,
+    primary: &str,
+    next: &mut u64,
+    backoff: &mut Duration,
+) -> eyre::Result<()> {
+    let addr = tokio::net::lookup_host(primary)
+        .await?
+        .next()
+        .ok_or_else(|| eyre::eyre!("{primary} doesn't resolve to any address"))?;
+    let mut stream = connect(addr, *next).await?;
+    tracing::info!(%addr, next, "Following the primary");
+    *backoff = INITIAL_BACKOFF;
+    loop {
+        let (number, change) = tokio::time::timeout(READ_TIMEOUT, async {
+            let number = stream.read_u64().await?;
+            let len = stream.read_u32().await?;
+            let mut change = vec![0; len.try_into()?];
+            stream.read_exact(&mut change).await?;
+            Ok::<_, eyre::Report>((number, change))
+        })
+        .await
+        .map_err(|_| eyre::eyre!("the primary stopped sending anything"))??;
+        if change.is_empty() {
+            continue;
+        }
+        if number != *next {
+            eyre::bail!("expected change {next}, got {number}");
+        }
+        Change::decode(&change)?.apply(&*robot.store).await?;
+        *next = number + 1;
+        state.insert(OFFSET_KEY, &next.to_be_bytes())?;
+    }
+}
+
+async fn connect(addr: SocketAddr, next: u64) -> eyre::Result<BufReader<TcpStream>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_u64(next).await?;
+    let mut stream = BufReader::new(stream);
+    if stream.read_u8().await? != 0 {
+        return Err(Unavailable(next).into());
+    }
+    Ok(stream)
+}
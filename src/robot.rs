@@ -0,0 +1,2470 @@
+//! Handling of Telegram updates.
+
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre;
+use teloxide::{
+    types::{
+        Chat, ChatId, ChatMemberUpdated, MediaDocument, MediaKind, Message, MessageKind,
+        MessageOrigin, ThreadId, User,
+    },
+    utils::command::BotCommands,
+};
+use tokio::net::TcpListener;
+use tracing_futures::Instrument as _;
+
+use crate::{
+    admin_api,
+    alerts::ErrorTracker,
+    api::{SendOptions, TelegramApi},
+    audit::{AuditEvent, AuditLog},
+    commands::{AdminCache, Command},
+    config::{Action, AutomaticForwards, Backlog, Config},
+    errors::ErrorCounts,
+    flood::FloodTracker,
+    hashing::{self, HashFunction},
+    health,
+    i18n::{Language, Locale, Text},
+    normalize::{canonical_text, media_placeholder, message_text},
+    notices::NoticeLimiter,
+    policy::{audited_enforcement, Caught, DeletionBatches, DeletionQueue, Detection, Verdict},
+    replication,
+    scripts::ScriptVerdict,
+    splits::SplitTracker,
+    storage::{open_database, open_tree, unix_now, Post},
+    store::{self, Cipher, MessageStore, ReplicatedStore, ReplicationLog},
+    storms::StormTracker,
+};
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    match DateTime::from_timestamp(timestamp, 0) {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => format!("unix time {timestamp}"),
+    }
+}
+
+pub fn describe_chat(chat: &Chat) -> String {
+    match chat.title() {
+        Some(title) => format!("{title} ({})", chat.id),
+        None => chat.id.to_string(),
+    }
+}
+
+pub fn describe_user(user: &User) -> String {
+    let name = user.mention().unwrap_or_else(|| user.full_name());
+    format!("{name} ({})", user.id)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Shortens long texts for reports.
+pub fn snippet(text: &str) -> Cow<'_, str> {
+    const MAX_CHARS: usize = 200;
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]).into(),
+        None => text.into(),
+    }
+}
+
+pub async fn reply(
+    bot: &dyn TelegramApi,
+    message: &Message,
+    text: impl Into<String>,
+) -> eyre::Result<()> {
+    let options = SendOptions {
+        reply_to: Some(message.id),
+        ..SendOptions::default()
+    };
+    bot.send_message(message.chat.id, text.into(), options)
+        .await?;
+    Ok(())
+}
+
+/// Returns the message this one replies to, ignoring the implicit reply
+/// to the topic's creation message that every forum topic message has.
+pub fn explicit_reply(message: &Message) -> Option<&Message> {
+    let reply_to = message.reply_to_message()?;
+    match message.thread_id {
+        Some(ThreadId(thread_root)) if message.is_topic_message && reply_to.id == thread_root => {
+            None
+        }
+        _ => Some(reply_to),
+    }
+}
+
+/// Checks whether the message was sent by an admin posting as the chat itself,
+/// in which case `from` is a placeholder bot instead of their account.
+pub fn is_anonymous_admin(message: &Message) -> bool {
+    message
+        .sender_chat
+        .as_ref()
+        .is_some_and(|sender_chat| sender_chat.id == message.chat.id)
+}
+
+#[derive(Clone)]
+pub struct Robot9000 {
+    pub(crate) db: sled::Db,
+    pub(crate) deletions: DeletionQueue,
+    pub(crate) deletion_batches: DeletionBatches,
+    /// Known messages, which may live outside of `db`.
+    pub(crate) store: Arc<dyn MessageStore>,
+    pub(crate) appeals: sled::Tree,
+    /// Chats the bot is in, with a single byte value set to 1 while
+    /// enforcement is suspended there for lack of permissions.
+    pub(crate) chats: sled::Tree,
+    /// Groups the bot was added to, waiting for an admin to `/activate` it.
+    pub(crate) pending_chats: sled::Tree,
+    /// Users excluded from duplicate checks, keyed by chat id and user id.
+    pub(crate) exemptions: sled::Tree,
+    /// Users who can run some admin commands without being admins,
+    /// keyed like the exemptions.
+    pub(crate) moderators: sled::Tree,
+    /// Chats picked with `/precheck` for checking drafts, keyed by user id.
+    pub(crate) inline_chats: sled::Tree,
+    /// Chats where `/pause` stopped enforcement, with the unix time it resumes at.
+    pub(crate) pauses: sled::Tree,
+    /// Join times and message counts of people in their grace period,
+    /// keyed by chat id and user id.
+    pub(crate) newcomers: sled::Tree,
+    /// Keys of recent messages while reactions are configured, keyed by chat id and message id.
+    pub(crate) message_keys: sled::Tree,
+    /// Per-chat changes to the common phrases, keyed by chat id and phrase.
+    pub(crate) phrases: sled::Tree,
+    /// Known messages forbidden by blocklists, keyed like the messages.
+    pub(crate) blocklisted: sled::Tree,
+    /// Per-chat `/set` overrides, keyed by chat id and setting name.
+    pub(crate) chat_settings: sled::Tree,
+    /// Random bytes mixed into the hashes of each namespace rotated at least once.
+    pub(crate) salts: sled::Tree,
+    pub(crate) admins: AdminCache,
+    pub(crate) floods: FloodTracker,
+    pub(crate) notices: NoticeLimiter,
+    pub(crate) storms: StormTracker,
+    pub(crate) splits: SplitTracker,
+    pub(crate) errors: ErrorTracker,
+    pub(crate) error_counts: ErrorCounts,
+    /// Per-chat daily counters, keyed by chat id and day.
+    pub(crate) activity: sled::Tree,
+    /// Duplicates deleted from each user per chat and day, for top offenders.
+    pub(crate) offenders: sled::Tree,
+    /// Mutes the bot gave out, keyed by chat id and user id, with when each ends.
+    pub(crate) mutes: sled::Tree,
+    /// When each chat last got its weekly digest, keyed by chat id.
+    pub(crate) digests: sled::Tree,
+    /// Texts of known messages in chats that opted in, keyed by chat id and message key.
+    pub(crate) texts: sled::Tree,
+    /// MinHash signatures of long messages, keyed like the messages.
+    pub(crate) minhash_signatures: sled::Tree,
+    /// Bands of the signatures, keyed by namespace, band number, band hash and message hash.
+    pub(crate) minhash_bands: sled::Tree,
+    /// Last write of the health check, proving the database is writable.
+    pub(crate) health: sled::Tree,
+    pub(crate) audit_log: Option<Arc<AuditLog>>,
+    /// Encrypts known messages where they're stored, if configured.
+    pub(crate) cipher: Option<Arc<Cipher>>,
+    pub(crate) hash_function: Arc<HashFunction>,
+    /// Changes to known messages, for standby instances to follow.
+    pub(crate) replication: Option<ReplicationLog>,
+    live_config: Arc<RwLock<Arc<Config>>>,
+    config_path: Option<PathBuf>,
+    /// Bot's own username, used to tell `/command@username` apart from
+    /// commands meant for other bots.
+    pub(crate) username: Arc<str>,
+    /// Unix time the bot started at, to tell messages it missed while stopped.
+    pub(crate) started_at: i64,
+    /// Messages only remembered by `catch_up` since the last one handled normally.
+    pub(crate) caught_up: Arc<AtomicUsize>,
+    /// Updates received since the start, for `/botstats`.
+    pub(crate) updates: Arc<AtomicU64>,
+}
+
+impl Robot9000 {
+    pub(crate) fn open(config: Config) -> eyre::Result<Self> {
+        let db = open_database(&config)?;
+        Self::open_in(config, db)
+    }
+
+    /// Opens the trees of the configured bot in an already opened database.
+    fn open_in(config: Config, db: sled::Db) -> eyre::Result<Self> {
+        let config = Arc::new(config);
+        let tree = |name| open_tree(&db, config.bot_name.as_deref(), name);
+        let cipher = (config.encryption_key.as_ref()).map(|key| Arc::new(Cipher::new(&key.0)));
+        store::prepare_encryption(
+            &db,
+            config.bot_name.as_deref(),
+            config.storage,
+            cipher.as_deref(),
+        )?;
+        let hash_function = HashFunction::new(config.hash_algorithm, config.hash_key.as_ref())?;
+        hashing::prepare(&db, config.bot_name.as_deref(), &hash_function)?;
+        let mut store = store::open(&config, &db, cipher.clone())?;
+        let replication = match config.replication_addr {
+            Some(_) => {
+                let log =
+                    ReplicationLog::open(tree("replication_log")?, config.replication_log_size)?;
+                store = Arc::new(ReplicatedStore::new(store, log.clone()));
+                Some(log)
+            }
+            None => None,
+        };
+        Ok(Self {
+            store,
+            cipher,
+            hash_function: Arc::new(hash_function),
+            replication,
+            deletions: DeletionQueue::open(tree("scheduled_deletions")?),
+            deletion_batches: DeletionBatches::default(),
+            appeals: tree("appeals")?,
+            salts: tree("salts")?,
+            activity: tree("activity")?,
+            offenders: tree("offenders")?,
+            mutes: tree("mutes")?,
+            digests: tree("digests")?,
+            admins: AdminCache::default(),
+            floods: FloodTracker::default(),
+            notices: NoticeLimiter::default(),
+            storms: StormTracker::default(),
+            splits: SplitTracker::default(),
+            errors: ErrorTracker::default(),
+            error_counts: ErrorCounts::default(),
+            health: tree("health")?,
+            texts: tree("texts")?,
+            minhash_signatures: tree("minhash_signatures")?,
+            minhash_bands: tree("minhash_bands")?,
+            message_keys: tree("message_keys")?,
+            newcomers: tree("newcomers")?,
+            phrases: tree("phrases")?,
+            chat_settings: tree("settings")?,
+            blocklisted: tree("blocklisted")?,
+            exemptions: tree("exemptions")?,
+            moderators: tree("moderators")?,
+            inline_chats: tree("inline_chats")?,
+            pauses: tree("pauses")?,
+            pending_chats: tree("pending_chats")?,
+            chats: tree("chats")?,
+            audit_log: None,
+            live_config: Arc::new(RwLock::new(config)),
+            config_path: None,
+            username: Arc::from(""),
+            started_at: Utc::now().timestamp(),
+            caught_up: Arc::default(),
+            updates: Arc::default(),
+            db,
+        })
+    }
+
+    /// Opens the trees of the bot in `db` and starts background tasks
+    /// for handling updates from `bot`.
+    pub async fn start(
+        config: Config,
+        db: sled::Db,
+        config_path: Option<PathBuf>,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<Self> {
+        config.validate()?;
+        let audit_log = config.open_audit_log()?;
+        let health = match config.health_addr {
+            Some(addr) => Some(health::bind(addr).await?),
+            None => None,
+        };
+        let replication = match config.replication_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let admin_api = match config.admin_api_addr {
+            Some(addr) => Some(admin_api::bind(addr).await?),
+            None => None,
+        };
+        let me = bot.get_me().await?;
+        bot.set_my_commands(Command::autocompleted()).await?;
+        let mut robot = Robot9000::open_in(config, db)?;
+        robot.audit_log = audit_log;
+        robot.config_path = config_path;
+        robot.username = Arc::from(me.username());
+        // Background tasks log in the span of the bot they belong to.
+        tokio::spawn(robot.deletions.clone().run(bot.clone()).in_current_span());
+        tokio::spawn(
+            robot
+                .clone()
+                .check_permissions(bot.clone())
+                .in_current_span(),
+        );
+        tokio::spawn(
+            robot
+                .clone()
+                .delete_batches_periodically(bot.clone())
+                .in_current_span(),
+        );
+        tokio::spawn(robot.clone().maintain_periodically().in_current_span());
+        tokio::spawn(
+            robot
+                .clone()
+                .refresh_blocklists_periodically()
+                .in_current_span(),
+        );
+        tokio::spawn(
+            robot
+                .clone()
+                .summarize_notices_periodically(bot.clone())
+                .in_current_span(),
+        );
+        tokio::spawn(
+            robot
+                .clone()
+                .post_digests_periodically(bot.clone())
+                .in_current_span(),
+        );
+        if robot.config().bot_name.is_none() {
+            tokio::spawn(
+                robot
+                    .clone()
+                    .back_up_periodically(bot.clone())
+                    .in_current_span(),
+            );
+        }
+        if let Some(listener) = health {
+            tokio::spawn(health::serve(listener, robot.clone(), bot.clone()).in_current_span());
+        }
+        if let Some(listener) = admin_api {
+            tokio::spawn(admin_api::serve(listener, robot.clone(), bot.clone()).in_current_span());
+        }
+        if let (Some(listener), Some(log)) = (replication, &robot.replication) {
+            tokio::spawn(replication::serve(listener, log.clone()).in_current_span());
+        }
+        Ok(robot)
+    }
+
+    /// Makes sure everything written so far is persisted.
+    pub async fn flush(&self) -> eyre::Result<()> {
+        self.store.flush().await?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Deletes the duplicates still waiting for their batch, then persists
+    /// everything, once no more updates are handled.
+    pub async fn shutdown(&self, bot: &dyn TelegramApi) -> eyre::Result<()> {
+        self.delete_batches(bot).await;
+        self.flush().await
+    }
+
+    /// Bot's own username, to tell bots apart in logs.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Counts an update received, whatever it is.
+    pub fn count_update(&self) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The latest config. Hold on to it for things that must agree with each other,
+    /// since a reload can replace it at any time.
+    pub(crate) fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.live_config.read().unwrap())
+    }
+
+    /// Reloads the config file and environment for updates handled from now on.
+    pub fn reload_config(&self) -> eyre::Result<()> {
+        let config = Config::load_bot(self.config_path.as_deref(), self.config().bot_name())?;
+        let mut live_config = self.live_config.write().unwrap();
+        if config.token.0 != live_config.token.0
+            || config.db_path != live_config.db_path
+            || config.audit_log != live_config.audit_log
+            || config.audit_log_max_size != live_config.audit_log_max_size
+            || config.audit_log_rotate_daily != live_config.audit_log_rotate_daily
+        {
+            tracing::warn!("Token, database and audit log changes only apply after a restart");
+        }
+        *live_config = Arc::new(config);
+        tracing::info!(config = format_args!("{live_config:?}"), "Reloaded config");
+        Ok(())
+    }
+
+    pub(crate) fn is_allowed(&self, chat: &Chat) -> bool {
+        chat.is_private()
+            || self.config().allowed_chats.is_empty()
+            || self.config().allowed_chats.contains(&chat.id.0)
+    }
+
+    async fn leave(&self, bot: &dyn TelegramApi, chat: &Chat) -> eyre::Result<()> {
+        tracing::info!(chat_id = chat.id.0, "leaving chat that isn't allowed");
+        bot.leave_chat(chat.id).await?;
+        self.log_event(bot, format!("Left {}", describe_chat(chat)))
+            .await;
+        Ok(())
+    }
+
+    /// Remembers that the bot is in the chat, so its permissions get checked.
+    fn track_chat(&self, chat_id: ChatId) -> eyre::Result<()> {
+        let _ = self
+            .chats
+            .compare_and_swap(chat_id.0.to_be_bytes(), None::<&[u8]>, Some(&[0]))?;
+        Ok(())
+    }
+
+    pub(crate) fn is_suspended(&self, chat_id: ChatId) -> eyre::Result<bool> {
+        Ok(self
+            .chats
+            .get(chat_id.0.to_be_bytes())?
+            .is_some_and(|health| health[..] == [1]))
+    }
+
+    /// Suspends or resumes enforcement when the bot loses or gets the permission to delete messages.
+    async fn update_health(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        chat_description: &str,
+        can_delete: bool,
+    ) -> eyre::Result<()> {
+        let health = [u8::from(!can_delete)];
+        let old = self.chats.insert(chat_id.0.to_be_bytes(), &health)?;
+        if old.as_deref() == Some(&health[..]) || (old.is_none() && can_delete) {
+            return Ok(());
+        }
+        tracing::info!(chat_id = chat_id.0, can_delete, "delete permission changed");
+        let (notice, event) = if can_delete {
+            (Text::Resumed, "Resumed")
+        } else {
+            (Text::Suspended, "Suspended for lack of permissions")
+        };
+        self.log_event(bot, format!("{event} in {chat_description}"))
+            .await;
+        if !can_delete {
+            let alert = format!("Can't delete messages in {chat_description} anymore");
+            self.alert_owners(bot, alert).await;
+        }
+        let notice = self.chat_locale(chat_id)?.text(notice);
+        bot.send_message(chat_id, notice, SendOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Periodically checks that the bot can still delete messages in every chat it's in.
+    async fn check_permissions(self, bot: Arc<dyn TelegramApi>) {
+        loop {
+            if let Err(err) = self.check_permissions_once(&*bot).await {
+                tracing::error!(err = format_args!("{err}"), "permission check failed");
+            }
+            let interval = Duration::from_secs(self.config().permission_check_interval);
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    async fn check_permissions_once(&self, bot: &dyn TelegramApi) -> eyre::Result<()> {
+        let me = bot.get_me().await?;
+        for key in self.chats.iter().keys() {
+            let chat_id = ChatId(i64::from_be_bytes(key?[..].try_into()?));
+            let result = async {
+                let member = bot.get_chat_member(chat_id, me.id).await?;
+                let description = chat_id.to_string();
+                self.update_health(bot, chat_id, &description, member.can_delete_messages())
+                    .await
+            };
+            if let Err(err) = result.await {
+                tracing::warn!(
+                    chat_id = chat_id.0,
+                    err = format_args!("{err}"),
+                    "couldn't check permissions"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn locale(&self, language: Language) -> Locale {
+        Locale::new(language, Arc::clone(&self.config().templates))
+    }
+
+    pub(crate) fn chat_locale(&self, chat_id: ChatId) -> eyre::Result<Locale> {
+        Ok(self.locale(self.settings(chat_id)?.language))
+    }
+
+    /// Reports a moderation event to the log chat, if there's one.
+    pub(crate) async fn log_event(&self, bot: &dyn TelegramApi, event: String) {
+        let Some(log_chat_id) = self.config().log_chat_id else {
+            return;
+        };
+        let result = bot
+            .send_message(ChatId(log_chat_id), event, SendOptions::default())
+            .await;
+        if let Err(err) = result {
+            tracing::warn!(
+                err = format_args!("{err}"),
+                "couldn't report to the log chat"
+            );
+        }
+    }
+
+    /// Handles the bot being added to or removed from a chat.
+    pub async fn process_my_chat_member(
+        &self,
+        update: ChatMemberUpdated,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        if !update.new_chat_member.is_present() {
+            tracing::info!(chat_id = update.chat.id.0, "removed from chat");
+            self.chats.remove(update.chat.id.0.to_be_bytes())?;
+            return Ok(());
+        }
+        if !self.is_allowed(&update.chat) {
+            return self.leave(&*bot, &update.chat).await;
+        }
+        // Adding a bot to a channel takes making it an admin, which is explicit enough.
+        let added = !update.old_chat_member.is_present() && update.new_chat_member.is_present();
+        if added && (update.chat.is_group() || update.chat.is_supergroup()) {
+            tracing::info!(chat_id = update.chat.id.0, "added to chat");
+            self.pending_chats
+                .insert(update.chat.id.0.to_be_bytes(), &[])?;
+            let intro = self.chat_locale(update.chat.id)?.text(Text::Intro);
+            bot.send_message(update.chat.id, intro, SendOptions::default())
+                .await?;
+        }
+        if !update.chat.is_private() {
+            self.update_health(
+                &*bot,
+                update.chat.id,
+                &describe_chat(&update.chat),
+                update.new_chat_member.can_delete_messages(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the cached admin status of a chat member whose status changed,
+    /// and starts the grace period of people who joined.
+    pub fn process_chat_member(&self, update: ChatMemberUpdated) -> eyre::Result<()> {
+        tracing::debug!(
+            chat_id = update.chat.id.0,
+            user_id = update.new_chat_member.user.id.0,
+            "chat member changed"
+        );
+        self.admins
+            .invalidate(update.chat.id, update.new_chat_member.user.id);
+        if !update.old_chat_member.is_present() && update.new_chat_member.is_present() {
+            self.record_join(
+                update.chat.id,
+                update.new_chat_member.user.id,
+                update.date.timestamp(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// What to do with a message, depending on how long ago it was posted.
+    fn backlog(&self, message: &Message) -> Backlog {
+        let config = self.config();
+        if config.catch_up && message.date.timestamp() < self.started_at {
+            self.caught_up.fetch_add(1, Ordering::Relaxed);
+            return Backlog::HashOnly;
+        }
+        let caught_up = self.caught_up.swap(0, Ordering::Relaxed);
+        if caught_up > 0 {
+            tracing::info!(caught_up, "caught up on messages posted while stopped");
+        }
+        let age = Utc::now().signed_duration_since(message.date).num_seconds();
+        if age > i64::try_from(config.backlog_age).unwrap_or(i64::MAX) {
+            config.backlog
+        } else {
+            Backlog::Process
+        }
+    }
+
+    /// Enforces in channels, where posts have no author to notify or mute,
+    /// and there are no admin commands.
+    pub async fn process_channel_post(
+        &self,
+        message: Message,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        if !self.is_allowed(&message.chat) {
+            return self.leave(&*bot, &message.chat).await;
+        }
+        self.track_chat(message.chat.id)?;
+        let backlog = self.backlog(&message);
+        if backlog == Backlog::Skip {
+            tracing::debug!("skipping old post");
+            return Ok(());
+        }
+        let Some(text) = message_text(&message) else {
+            return Ok(());
+        };
+        if self.is_common_phrase(message.chat.id, &text)? {
+            tracing::debug!("ignoring common phrase");
+            return Ok(());
+        }
+        let settings = self.settings(message.chat.id)?;
+        let scope = self.scope(&message, &settings);
+        let exact = self.hash_message(scope, &text)?;
+        let (key, signature) = self.fuzzy_key(scope, exact, &text).await?;
+        let post = Post::from(&message);
+        let entry = self.store_hash(key, post, &settings).await?;
+        if let Some(signature) = signature {
+            self.index_signature(key, &signature)?;
+        }
+        self.audit(AuditEvent::store(message.chat.id, key, post, &entry));
+        self.remember_message_key(message.chat.id, message.id, key)?;
+        self.retain_text(message.chat.id, &settings, key, &entry, &text)?;
+        self.count_seen(message.chat.id, post.timestamp, &entry)
+            .await?;
+        if !settings.is_duplicate(&entry) {
+            tracing::debug!(text = format_args!("{text:?}"), "ignoring unique post");
+            return Ok(());
+        }
+        if backlog == Backlog::HashOnly {
+            tracing::debug!("ignoring old duplicate post");
+            return Ok(());
+        }
+        if settings.is_quiet(message.date) {
+            tracing::debug!("ignoring duplicate post during quiet hours");
+            return Ok(());
+        }
+        if self.is_paused(message.chat.id, message.date.timestamp())? {
+            tracing::debug!("ignoring duplicate post while paused");
+            return Ok(());
+        }
+
+        let detection = Detection::of(&entry, key == exact);
+        let actions = settings.actions(detection);
+        if actions.contains(Action::Delete) && self.is_suspended(message.chat.id)? {
+            tracing::debug!("can't delete posts, ignoring duplicate");
+            return Ok(());
+        }
+        tracing::debug!(
+            text = format_args!("{text:?}"),
+            detection = format_args!("{detection:?}"),
+            actions = format_args!("{actions}"),
+            "enforcing on duplicate post"
+        );
+        let event = format!(
+            "Duplicate in {}\nDetection: {detection:?}\nActions: {actions}\nHash: {}\n\
+             First seen: {}\nCopies: {}\nText: {}",
+            describe_chat(&message.chat),
+            hex(&key.hash),
+            format_timestamp(entry.first_seen),
+            entry.count,
+            snippet(&text),
+        );
+        self.audit(AuditEvent::Enforce {
+            chat_id: message.chat.id,
+            hash: key.hash,
+            message_id: message.id.0,
+            user_id: None,
+            enforcement: audited_enforcement(actions),
+        });
+        let caught = Caught {
+            message: &message,
+            user: None,
+            original: None,
+            text: None,
+        };
+        self.take_actions(&*bot, &caught, actions, event).await?;
+        Ok(())
+    }
+
+    pub async fn process_message(
+        &self,
+        message: Message,
+        bot: Arc<dyn TelegramApi>,
+    ) -> eyre::Result<()> {
+        if !self.is_allowed(&message.chat) {
+            return self.leave(&*bot, &message.chat).await;
+        }
+        if !message.chat.is_private() {
+            self.track_chat(message.chat.id)?;
+        }
+        if let MessageKind::NewChatMembers(joined) = &message.kind {
+            for user in &joined.new_chat_members {
+                self.record_join(message.chat.id, user.id, message.date.timestamp())?;
+            }
+        }
+        let (MessageKind::Common(kind), Some(user)) = (&message.kind, &message.from) else {
+            return Ok(());
+        };
+        let backlog = self.backlog(&message);
+        if backlog == Backlog::Skip {
+            tracing::debug!("skipping old message");
+            return Ok(());
+        }
+        // Old messages are only remembered, commands in them are stale too.
+        let stale = backlog == Backlog::HashOnly;
+        let (text, mut hashed_text) = match &kind.media_kind {
+            MediaKind::Text(text) => {
+                if let Ok(command) = Command::parse(&text.text, &self.username) {
+                    if stale {
+                        tracing::debug!("ignoring old command");
+                        return Ok(());
+                    }
+                    if self
+                        .admins
+                        .is_ignored(&self.config(), message.chat.id, user.id, unix_now())
+                    {
+                        tracing::debug!(user_id = user.id.0, "ignoring command of someone denied");
+                        return Ok(());
+                    }
+                    return self.command(&*bot, &message, user, command).await;
+                }
+                (Some(text), canonical_text(&text.text, &text.entities))
+            }
+            MediaKind::Document(MediaDocument {
+                caption: Some(caption),
+                ..
+            }) => {
+                if let (false, Ok(Command::Import(_) | Command::Simulate(_))) =
+                    (stale, Command::parse(caption, &self.username))
+                {
+                    // Imports can take minutes, and the chat's other updates
+                    // can't wait for them.
+                    let import = self.clone().run_document_command(bot, message.clone());
+                    tokio::spawn(import.in_current_span());
+                }
+                return Ok(());
+            }
+            media => match media_placeholder(media) {
+                Some(placeholder) => (None, Cow::Owned(placeholder)),
+                None => return Ok(()),
+            },
+        };
+        if message.chat.is_private() {
+            if stale {
+                return Ok(());
+            }
+            return self.sandbox(&*bot, &message, user).await;
+        }
+
+        if self
+            .pending_chats
+            .contains_key(message.chat.id.0.to_be_bytes())?
+        {
+            tracing::debug!("ignoring message in chat that isn't activated");
+            return Ok(());
+        }
+        if self.is_exempt(message.chat.id, user.id)? {
+            tracing::debug!(user_id = user.id.0, "ignoring exempt user");
+            return Ok(());
+        }
+        let settings = self.settings(message.chat.id)?;
+        if settings.ignore_bots && user.is_bot && !is_anonymous_admin(&message) {
+            tracing::debug!(user_id = user.id.0, "ignoring bot");
+            return Ok(());
+        }
+        // Bursts are deleted even where duplicates are tolerated.
+        let flood_text = text.map_or(&*hashed_text, |text| &text.text);
+        if !stale && self.check_flood(&*bot, &message, user, flood_text).await? {
+            return Ok(());
+        }
+        if !stale && self.check_slow_mode(&*bot, &message, user).await? {
+            return Ok(());
+        }
+        if settings.allow_duplicates_in_replies && explicit_reply(&message).is_some() {
+            return Ok(());
+        }
+
+        if self.is_common_phrase(message.chat.id, &hashed_text)? {
+            tracing::debug!("ignoring common phrase");
+            return Ok(());
+        }
+        if kind.is_automatic_forward {
+            match (settings.automatic_forwards, &kind.forward_origin) {
+                (AutomaticForwards::Skip, _) => {
+                    tracing::debug!("ignoring automatic forward");
+                    return Ok(());
+                }
+                (
+                    AutomaticForwards::ChannelPost,
+                    Some(MessageOrigin::Channel {
+                        chat, message_id, ..
+                    }),
+                ) => {
+                    hashed_text =
+                        Cow::Owned(format!("\0channel post {}/{}", chat.id, message_id.0));
+                }
+                _ => (),
+            }
+        }
+
+        let scope = self.scope(&message, &settings);
+        let exact = self.hash_message(scope, &hashed_text)?;
+        let (key, signature) = self.fuzzy_key(scope, exact, &hashed_text).await?;
+        let post = Post::from(&message);
+        let entry = self.store_hash(key, post, &settings).await?;
+        if let Some(signature) = signature {
+            self.index_signature(key, &signature)?;
+        }
+        self.audit(AuditEvent::store(message.chat.id, key, post, &entry));
+        self.remember_message_key(message.chat.id, message.id, key)?;
+        // Placeholders of channel posts and media aren't worth keeping.
+        if !hashed_text.starts_with('\0') {
+            self.retain_text(message.chat.id, &settings, key, &entry, &hashed_text)?;
+        }
+        self.count_seen(message.chat.id, post.timestamp, &entry)
+            .await?;
+        let newcomer = self.check_newcomer(message.chat.id, user.id, post.timestamp)?;
+        let duplicate = settings.is_duplicate(&entry);
+        let detection = match duplicate {
+            true => Detection::of(&entry, key == exact),
+            false => Detection::Script,
+        };
+        let script = match stale {
+            true => ScriptVerdict::Default,
+            false => {
+                let text = text.map(|text| text.text.as_str());
+                self.filter(&message, user, text, &entry, duplicate.then_some(detection))
+                    .await
+            }
+        };
+        let verdict = match script {
+            ScriptVerdict::Ignore => {
+                tracing::debug!("ignoring message the filter script let through");
+                return Ok(());
+            }
+            ScriptVerdict::Default if !duplicate => None,
+            ScriptVerdict::Default | ScriptVerdict::Enforce => Some(Verdict {
+                detection,
+                actions: settings.actions(detection),
+            }),
+            ScriptVerdict::Actions(actions) => Some(Verdict { detection, actions }),
+        };
+        let Some(verdict) = verdict else {
+            tracing::debug!(
+                text = format_args!("{hashed_text:?}"),
+                "ignoring unique message"
+            );
+            if !stale && text.is_some() && !hashed_text.starts_with('\0') {
+                self.check_split(&*bot, &message, user, scope, &settings, &hashed_text)
+                    .await?;
+            }
+            return Ok(());
+        };
+        if stale {
+            tracing::debug!("ignoring old duplicate");
+        } else if settings.is_quiet(message.date) {
+            tracing::debug!("ignoring duplicate during quiet hours");
+        } else if self.is_paused(message.chat.id, message.date.timestamp())? {
+            tracing::debug!("ignoring duplicate while paused");
+        } else if settings.exempt_admins
+            && (is_anonymous_admin(&message)
+                || Self::is_admin(&self.config(), &self.admins, &*bot, &message.chat, user).await?)
+        {
+            tracing::debug!(user_id = user.id.0, "ignoring duplicate from an admin");
+        } else if newcomer {
+            self.warn_newcomer(&*bot, &message, user).await?;
+        } else {
+            let original = (key.hash, &entry);
+            self.enforce(&*bot, verdict, &message, user, original, text)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::{Utc, Weekday};
+    use futures::{future, stream, FutureExt as _, StreamExt as _};
+    use serde_json::json;
+    use teloxide::types::{
+        BotCommand, CallbackQueryId, ChatMember, File, FileId, InlineKeyboardMarkup, InlineQuery,
+        InlineQueryId, InlineQueryResult, InputFile, Me, MessageId, MessageReactionUpdated,
+        ReactionType, UserId,
+    };
+
+    use super::*;
+    use crate::activity::{activity_since, Activity};
+    use crate::import::{ImportItem, ImportSink as _, Importer};
+    use crate::policy::Setting;
+    use crate::settings_files::{SettingsFile, SettingsImport};
+    use crate::storage::Status;
+    use crate::sync::SyncSummary;
+
+    const CHAT_ID: i64 = -100;
+    const ADMIN_ID: u64 = 1;
+    const USER_ID: u64 = 2;
+
+    /// What the bot asked Telegram to do, in order.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Call {
+        Send(ChatId, String),
+        Delete(ChatId, MessageId),
+        DeleteMany(ChatId, Vec<MessageId>),
+        /// Copy of a message to another chat.
+        Copy(ChatId, MessageId),
+        Unmute(UserId),
+        /// Titles of the results an inline query was answered with.
+        AnswerInline(Vec<String>),
+        Other(&'static str),
+    }
+
+    /// Telegram with `ADMIN_ID` owning every chat, recording everything asked of it.
+    #[derive(Default)]
+    struct FakeApi {
+        calls: Mutex<Vec<Call>>,
+    }
+
+    impl FakeApi {
+        fn record<T: Send + 'static>(
+            &self,
+            call: Call,
+            result: T,
+        ) -> future::BoxFuture<'_, eyre::Result<T>> {
+            self.calls.lock().unwrap().push(call);
+            future::ready(Ok(result)).boxed()
+        }
+
+        fn count(&self, name: &str) -> usize {
+            let calls = self.calls.lock().unwrap();
+            calls
+                .iter()
+                .filter(|call| matches!(call, Call::Other(other) if *other == name))
+                .count()
+        }
+
+        fn deletions(&self) -> Vec<MessageId> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|call| match call {
+                    Call::Delete(_, message_id) => vec![*message_id],
+                    Call::DeleteMany(_, message_ids) => message_ids.clone(),
+                    _ => Vec::new(),
+                })
+                .collect()
+        }
+    }
+
+    fn user(id: u64) -> serde_json::Value {
+        json!({ "id": id, "is_bot": false, "first_name": format!("User {id}") })
+    }
+
+    fn message(id: i32, from: u64, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": id,
+            "date": Utc::now().timestamp(),
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Test" },
+            "from": user(from),
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    impl TelegramApi for FakeApi {
+        fn get_me(&self) -> future::BoxFuture<'_, eyre::Result<Me>> {
+            async { Err(eyre::eyre!("unexpected call to get_me in a test")) }.boxed()
+        }
+
+        fn set_my_commands(
+            &self,
+            _commands: Vec<BotCommand>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("set_my_commands"), ())
+        }
+
+        fn send_message(
+            &self,
+            chat_id: ChatId,
+            text: String,
+            _options: SendOptions,
+        ) -> future::BoxFuture<'_, eyre::Result<Message>> {
+            let sent = message(1000, ADMIN_ID, &text);
+            self.record(Call::Send(chat_id, text), sent)
+        }
+
+        fn edit_message_text(
+            &self,
+            _chat_id: ChatId,
+            _message_id: MessageId,
+            _text: String,
+            _markup: Option<InlineKeyboardMarkup>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("edit_message_text"), ())
+        }
+
+        fn delete_message(
+            &self,
+            chat_id: ChatId,
+            message_id: MessageId,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Delete(chat_id, message_id), ())
+        }
+
+        fn delete_messages(
+            &self,
+            chat_id: ChatId,
+            message_ids: Vec<MessageId>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::DeleteMany(chat_id, message_ids), ())
+        }
+
+        fn copy_message(
+            &self,
+            chat_id: ChatId,
+            _from_chat_id: ChatId,
+            message_id: MessageId,
+        ) -> future::BoxFuture<'_, eyre::Result<MessageId>> {
+            self.record(
+                Call::Copy(chat_id, message_id),
+                MessageId(message_id.0 + 1000),
+            )
+        }
+
+        fn set_message_reaction(
+            &self,
+            _chat_id: ChatId,
+            _message_id: MessageId,
+            _reaction: ReactionType,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("set_message_reaction"), ())
+        }
+
+        fn mute(
+            &self,
+            _chat_id: ChatId,
+            _user_id: UserId,
+            _until: chrono::DateTime<Utc>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("mute"), ())
+        }
+
+        fn unmute(
+            &self,
+            _chat_id: ChatId,
+            user_id: UserId,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Unmute(user_id), ())
+        }
+
+        fn get_chat_member(
+            &self,
+            _chat_id: ChatId,
+            user_id: UserId,
+        ) -> future::BoxFuture<'_, eyre::Result<ChatMember>> {
+            let member = if user_id == UserId(ADMIN_ID) {
+                json!({ "status": "creator", "user": user(user_id.0), "is_anonymous": false })
+            } else {
+                json!({ "status": "member", "user": user(user_id.0) })
+            };
+            let member = serde_json::from_value(member).unwrap();
+            self.record(Call::Other("get_chat_member"), member)
+        }
+
+        fn get_chat_administrators(
+            &self,
+            _chat_id: ChatId,
+        ) -> future::BoxFuture<'_, eyre::Result<Vec<ChatMember>>> {
+            let admin =
+                json!({ "status": "creator", "user": user(ADMIN_ID), "is_anonymous": false });
+            let admins = vec![serde_json::from_value(admin).unwrap()];
+            self.record(Call::Other("get_chat_administrators"), admins)
+        }
+
+        fn leave_chat(&self, _chat_id: ChatId) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("leave_chat"), ())
+        }
+
+        fn answer_callback_query(
+            &self,
+            _id: CallbackQueryId,
+            _text: Option<String>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("answer_callback_query"), ())
+        }
+
+        fn answer_inline_query(
+            &self,
+            _id: InlineQueryId,
+            results: Vec<InlineQueryResult>,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            let titles = results
+                .into_iter()
+                .map(|result| match result {
+                    InlineQueryResult::Article(article) => article.title,
+                    other => panic!("unexpected inline query result: {other:?}"),
+                })
+                .collect();
+            self.record(Call::AnswerInline(titles), ())
+        }
+
+        fn send_document(
+            &self,
+            _chat_id: ChatId,
+            _file: InputFile,
+        ) -> future::BoxFuture<'_, eyre::Result<()>> {
+            self.record(Call::Other("send_document"), ())
+        }
+
+        fn get_file(&self, file_id: FileId) -> future::BoxFuture<'_, eyre::Result<File>> {
+            async move {
+                Err(eyre::eyre!(
+                    "unexpected call to get_file({file_id}) in a test"
+                ))
+            }
+            .boxed()
+        }
+
+        fn download_file(&self, _path: &str) -> stream::BoxStream<'static, eyre::Result<Vec<u8>>> {
+            stream::empty().boxed()
+        }
+    }
+
+    /// Bot with a throwaway database, configured by `extra` environment-style variables.
+    fn robot(db_path: &std::path::Path, extra: &[(&str, &str)]) -> eyre::Result<Robot9000> {
+        let mut vars = vec![
+            ("token".to_owned(), "123:abc".to_owned()),
+            ("db_path".to_owned(), db_path.display().to_string()),
+            ("storage".to_owned(), "memory".to_owned()),
+        ];
+        vars.extend(
+            extra
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        Robot9000::open(envy::from_iter(vars)?)
+    }
+
+    fn temp_db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("r9ktg-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn deletes_second_copy() -> eyre::Result<()> {
+        let path = temp_db_path("dedup");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "hello world"), api.clone())
+            .await?;
+        robot
+            .process_message(message(2, USER_ID, "something else"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), []);
+        robot
+            .process_message(message(3, USER_ID, "hello world"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(3)]);
+        let activity = activity_since(&robot.activity, 0)
+            .map(|item| item.map(|(_, _, activity)| activity))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let expected = Activity {
+            seen: 3,
+            unique: 2,
+            deleted: 1,
+        };
+        assert_eq!(activity, [expected]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spells_out_text_links() -> eyre::Result<()> {
+        let path = temp_db_path("links");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "see https://example.com/"), api.clone())
+            .await?;
+        let mut disguised = serde_json::to_value(message(2, USER_ID, "see click here"))?;
+        disguised["entities"] = json!([
+            { "type": "bold", "offset": 0, "length": 3 },
+            { "type": "text_link", "offset": 4, "length": 10, "url": "https://example.com/" },
+        ]);
+        robot
+            .process_message(serde_json::from_value(disguised)?, api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(2)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn normalizes_emoji() -> eyre::Result<()> {
+        let path = temp_db_path("emoji");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let texts = ["nice 👍🏻", "nice 👍", "🏃\u{200D}\u{2640}\u{FE0F}", "🏃"];
+        for (id, text) in (1..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(2), MessageId(4)]);
+
+        let set = message(5, ADMIN_ID, "/set normalize_emoji false");
+        robot.process_message(set, api.clone()).await?;
+        robot
+            .process_message(message(6, USER_ID, "nice 👍🏿"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(2), MessageId(4)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strips_invisible_characters() -> eyre::Result<()> {
+        let path = temp_db_path("invisible");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let texts = ["free money", "free\u{200B} mo\u{2060}ney\u{200F}"];
+        for (id, text) in (1..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(2)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn folds_confusables() -> eyre::Result<()> {
+        let path = temp_db_path("confusables");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        // The second one starts with Cyrillic "Н" and "е".
+        let texts = ["Hello there", "\u{41D}\u{435}llo there"];
+        for (id, text) in (1..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), []);
+
+        let set = message(3, ADMIN_ID, "/set fold_confusables true");
+        robot.process_message(set, api.clone()).await?;
+        for (id, text) in (4..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(4), MessageId(5)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn folds_leetspeak() -> eyre::Result<()> {
+        let path = temp_db_path("leetspeak");
+        let robot = robot(&path, &[("fold_leetspeak", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        let texts = [
+            "Free money",
+            "FR33 M\u{F6}n\u{EB}y",
+            "fr\u{65}\u{301}e m0ney",
+        ];
+        for (id, text) in (1..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(2), MessageId(3)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn catches_split_duplicates() -> eyre::Result<()> {
+        let path = temp_db_path("splits");
+        let robot = robot(&path, &[("split_parts", "3")])?;
+        let api = Arc::new(FakeApi::default());
+        let posts = [
+            (3, "Win a free phone\nClick the link below"),
+            (USER_ID, "Hello everyone"),
+            (USER_ID, "Win a free phone"),
+            (USER_ID, "Click the link below"),
+        ];
+        for (id, (from, text)) in (1..).zip(posts) {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(3), MessageId(4)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lifts_mutes_on_amnesty() -> eyre::Result<()> {
+        let path = temp_db_path("amnesty");
+        let robot = robot(&path, &[("duplicate_actions", "delete,mute")])?;
+        let api = Arc::new(FakeApi::default());
+        let posts = [
+            (USER_ID, "first"),
+            (USER_ID, "first"),
+            (3, "second"),
+            (3, "second"),
+        ];
+        for (id, (from, text)) in (1..).zip(posts) {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        let unmuted = || {
+            let calls = api.calls.lock().unwrap();
+            (calls.iter())
+                .filter_map(|call| match call {
+                    Call::Unmute(user_id) => Some(*user_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut pardon = serde_json::to_value(message(5, ADMIN_ID, "/amnesty"))?;
+        pardon["reply_to_message"] = serde_json::to_value(message(3, 3, "second"))?;
+        robot
+            .process_message(serde_json::from_value(pardon)?, api.clone())
+            .await?;
+        assert_eq!(unmuted(), [UserId(3)]);
+
+        robot
+            .process_message(message(6, ADMIN_ID, "/amnesty"), api.clone())
+            .await?;
+        assert_eq!(unmuted(), [UserId(3), UserId(USER_ID)]);
+        robot
+            .process_message(message(7, ADMIN_ID, "/amnesty"), api.clone())
+            .await?;
+        assert_eq!(unmuted(), [UserId(3), UserId(USER_ID)]);
+
+        let denied = message(8, USER_ID, "/amnesty");
+        robot.process_message(denied, api.clone()).await?;
+        assert_eq!(unmuted(), [UserId(3), UserId(USER_ID)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checks_private_messages_against_administered_chat() -> eyre::Result<()> {
+        let path = temp_db_path("sandbox");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let private = |id, from: u64, text| -> eyre::Result<Message> {
+            let mut message = serde_json::to_value(message(id, from, text))?;
+            message["chat"] = json!({ "id": from, "type": "private", "first_name": "Someone" });
+            Ok(serde_json::from_value(message)?)
+        };
+        let last_answer = || match api.calls.lock().unwrap().last() {
+            Some(Call::Send(_, answer)) => answer.clone(),
+            call => panic!("unexpected call: {call:?}"),
+        };
+        for (id, from, text) in [(1, USER_ID, "hello world"), (2, ADMIN_ID, "/precheck")] {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        // Checks aren't remembered, so the next copy stays the second one.
+        for id in 3..=4 {
+            robot
+                .process_message(private(id, ADMIN_ID, "hello world")?, api.clone())
+                .await?;
+            let answer = last_answer();
+            assert!(answer.contains("seen this message 1 times"));
+            assert!(answer.ends_with("the next copy will be deleted"));
+        }
+        robot
+            .process_message(private(5, ADMIN_ID, "something new")?, api.clone())
+            .await?;
+        assert!(last_answer().ends_with("the next copy will count as the first one"));
+
+        robot
+            .process_message(message(6, USER_ID, "/precheck"), api.clone())
+            .await?;
+        robot
+            .process_message(private(7, USER_ID, "hello world")?, api.clone())
+            .await?;
+        assert!(last_answer().starts_with("Send /precheck in a chat you administer"));
+        assert_eq!(api.deletions(), []);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quarantines_before_deleting() -> eyre::Result<()> {
+        let path = temp_db_path("quarantine");
+        let robot = robot(&path, &[("quarantine_chat_id", "-200")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=2 {
+            robot
+                .process_message(message(id, USER_ID, "hello world"), api.clone())
+                .await?;
+        }
+        let calls = api.calls.lock().unwrap();
+        let copied = calls
+            .iter()
+            .position(|call| *call == Call::Copy(ChatId(-200), MessageId(2)));
+        let deleted = calls
+            .iter()
+            .position(|call| *call == Call::Delete(ChatId(CHAT_ID), MessageId(2)));
+        assert!(copied.is_some() && copied < deleted);
+        drop(calls);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deduplicates_video_notes() -> eyre::Result<()> {
+        let path = temp_db_path("video_notes");
+        let robot = robot(
+            &path,
+            &[("fold_leetspeak", "true"), ("fold_confusables", "true")],
+        )?;
+        let api = Arc::new(FakeApi::default());
+        let notes = [
+            (1, "circle"),
+            (2, "other"),
+            (3, "circle"),
+            (4, "Circle"),
+            (5, "c1rcle"),
+        ];
+        for (id, unique_id) in notes {
+            let mut note = serde_json::to_value(message(id, USER_ID, ""))?;
+            note.as_object_mut().unwrap().remove("text");
+            note["video_note"] = json!({
+                "file_id": format!("{unique_id}-{id}"),
+                "file_unique_id": unique_id,
+                "file_size": 1024,
+                "length": 240,
+                "duration": 5,
+            });
+            robot
+                .process_message(serde_json::from_value(note)?, api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(3)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_hashes_backlog() -> eyre::Result<()> {
+        let path = temp_db_path("backlog");
+        let robot = robot(&path, &[("backlog", "hash_only"), ("backlog_age", "600")])?;
+        let api = Arc::new(FakeApi::default());
+        let hour_ago = Utc::now().timestamp() - 3600;
+        for (id, text) in [(1, "Hello there"), (2, "Hello there"), (3, "/stats")] {
+            let mut old = serde_json::to_value(message(id, USER_ID, text))?;
+            old["date"] = json!(hour_ago);
+            robot
+                .process_message(serde_json::from_value(old)?, api.clone())
+                .await?;
+        }
+        let answered = api
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|call| matches!(call, Call::Send(..)));
+        assert!(!answered);
+        robot
+            .process_message(message(4, USER_ID, "Hello there"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(4)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn catches_up_after_downtime() -> eyre::Result<()> {
+        let path = temp_db_path("catch_up");
+        let robot = robot(&path, &[("catch_up", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in [1, 2] {
+            let mut missed = serde_json::to_value(message(id, USER_ID, "Hello there"))?;
+            missed["date"] = json!(robot.started_at - 60);
+            robot
+                .process_message(serde_json::from_value(missed)?, api.clone())
+                .await?;
+        }
+        assert!(api.deletions().is_empty());
+        robot
+            .process_message(message(3, USER_ID, "Hello there"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(3)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deduplicates_contacts_and_places() -> eyre::Result<()> {
+        let path = temp_db_path("contacts");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let media = [
+            (
+                "contact",
+                json!({ "phone_number": "+1 555 0100", "first_name": "Seller" }),
+            ),
+            (
+                "contact",
+                json!({ "phone_number": "15550100", "first_name": "Other" }),
+            ),
+            (
+                "location",
+                json!({ "latitude": 55.75222, "longitude": 37.61556 }),
+            ),
+            (
+                "location",
+                json!({ "latitude": 55.752221, "longitude": 37.615561 }),
+            ),
+            (
+                "location",
+                json!({ "latitude": 55.75222, "longitude": 37.61556, "live_period": 900 }),
+            ),
+        ];
+        for (id, (kind, value)) in (1..).zip(media) {
+            let mut media = serde_json::to_value(message(id, USER_ID, ""))?;
+            media.as_object_mut().unwrap().remove("text");
+            media[kind] = value;
+            robot
+                .process_message(serde_json::from_value(media)?, api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(2), MessageId(4)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scopes_duplicates_to_their_sender() -> eyre::Result<()> {
+        let path = temp_db_path("per_user");
+        let robot = robot(&path, &[("per_user", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        let posts = [
+            (USER_ID, "hello world"),
+            (3, "hello world"),
+            (USER_ID, "hello world"),
+        ];
+        for (id, (from, text)) in (1..).zip(posts) {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(3)]);
+
+        let set = message(4, ADMIN_ID, "/set per_user false");
+        robot.process_message(set, api.clone()).await?;
+        for (id, from) in [(5, USER_ID), (6, 3)] {
+            robot
+                .process_message(message(id, from, "goodbye"), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(3), MessageId(6)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_common_phrases() -> eyre::Result<()> {
+        let path = temp_db_path("phrases");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let commands = [
+            "/phrases add Good  morning!",
+            "/phrases remove thanks",
+            "/phrases",
+        ];
+        for (id, command) in (1..).zip(commands) {
+            robot
+                .process_message(message(id, ADMIN_ID, command), api.clone())
+                .await?;
+        }
+        let texts = [
+            "+1",
+            "+1",
+            "good morning",
+            "GOOD MORNING!",
+            "thanks",
+            "thanks",
+        ];
+        for (id, text) in (4..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(9)]);
+        assert!(robot
+            .common_phrases(ChatId(CHAT_ID))?
+            .contains(&"good morning".into()));
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allows_a_number_of_copies() -> eyre::Result<()> {
+        let path = temp_db_path("allow_repeats");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let announcement = message(1, USER_ID, "meeting at noon");
+        robot
+            .process_message(announcement.clone(), api.clone())
+            .await?;
+        let mut allow = serde_json::to_value(message(2, ADMIN_ID, "/allow 2"))?;
+        allow["reply_to_message"] = serde_json::to_value(announcement)?;
+        robot
+            .process_message(serde_json::from_value(allow)?, api.clone())
+            .await?;
+        for id in 3..=5 {
+            robot
+                .process_message(message(id, USER_ID, "meeting at noon"), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(5)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delegates_to_moderators() -> eyre::Result<()> {
+        let path = temp_db_path("moderators");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let announcement = message(1, USER_ID, "meeting at noon");
+        robot
+            .process_message(announcement.clone(), api.clone())
+            .await?;
+        let reply_to = |id, from, text| -> eyre::Result<Message> {
+            let mut command = serde_json::to_value(message(id, from, text))?;
+            command["reply_to_message"] = serde_json::to_value(&announcement)?;
+            Ok(serde_json::from_value(command)?)
+        };
+        robot
+            .process_message(reply_to(2, USER_ID, "/allow")?, api.clone())
+            .await?;
+        robot
+            .process_message(message(3, 3, "meeting at noon"), api.clone())
+            .await?;
+        robot
+            .process_message(reply_to(4, ADMIN_ID, "/mod")?, api.clone())
+            .await?;
+        assert!(robot.is_moderator(ChatId(CHAT_ID), UserId(USER_ID))?);
+        robot
+            .process_message(reply_to(5, USER_ID, "/allow")?, api.clone())
+            .await?;
+        robot
+            .process_message(message(6, 3, "meeting at noon"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(3)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_denied_commands() -> eyre::Result<()> {
+        let path = temp_db_path("denied_commands");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=3 {
+            robot
+                .process_message(message(id, USER_ID, "/pause 1h"), api.clone())
+                .await?;
+        }
+        let calls = api.calls.lock().unwrap();
+        let count = |kind: fn(&Call) -> bool| calls.iter().filter(|call| kind(call)).count();
+        assert_eq!(count(|call| matches!(call, Call::Send(..))), 1);
+        assert_eq!(
+            count(|call| matches!(call, Call::Other("get_chat_member"))),
+            1
+        );
+        drop(calls);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prechecks_drafts() -> eyre::Result<()> {
+        let path = temp_db_path("precheck");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let query = |text: &str| -> eyre::Result<InlineQuery> {
+            Ok(serde_json::from_value(json!({
+                "id": "1",
+                "from": user(USER_ID),
+                "query": text,
+                "offset": "",
+            }))?)
+        };
+        robot
+            .process_message(message(1, USER_ID, "Hello there"), api.clone())
+            .await?;
+        robot
+            .process_inline_query(query("Hello there")?, api.clone())
+            .await?;
+        robot
+            .process_message(message(2, USER_ID, "/precheck"), api.clone())
+            .await?;
+        for draft in ["Hello there", "Something new"] {
+            robot
+                .process_inline_query(query(draft)?, api.clone())
+                .await?;
+        }
+        let answers: Vec<_> = api
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|call| match call {
+                Call::AnswerInline(titles) => Some(titles.join("")),
+                _ => None,
+            })
+            .collect();
+        let locale = robot.locale(Language::English);
+        assert_eq!(answers[0], locale.text(Text::PrecheckUsage));
+        assert!(answers[1].ends_with("will be deleted"), "{answers:?}");
+        assert_eq!(answers[2], locale.text(Text::CheckUnknown));
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prechecks_drafts_per_user() -> eyre::Result<()> {
+        let path = temp_db_path("precheck_per_user");
+        let robot = robot(&path, &[("per_user", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        let query = |from: u64| -> eyre::Result<InlineQuery> {
+            Ok(serde_json::from_value(json!({
+                "id": "1",
+                "from": user(from),
+                "query": "Hello there",
+                "offset": "",
+            }))?)
+        };
+        for (id, from, text) in [
+            (1, USER_ID, "Hello there"),
+            (2, USER_ID, "/precheck"),
+            (3, 3, "/precheck"),
+        ] {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        for from in [USER_ID, 3] {
+            robot
+                .process_inline_query(query(from)?, api.clone())
+                .await?;
+        }
+        let answers: Vec<_> = api
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|call| match call {
+                Call::AnswerInline(titles) => Some(titles.join("")),
+                _ => None,
+            })
+            .collect();
+        assert!(answers[0].ends_with("will be deleted"), "{answers:?}");
+        let locale = robot.locale(Language::English);
+        assert_eq!(answers[1], locale.text(Text::CheckUnknown));
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_admin_api() -> eyre::Result<()> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let path = temp_db_path("admin_api");
+        let mut robot = robot(&path, &[("admin_api_token", "secret")])?;
+        let audit_log = AuditLog::open(&path.join("audit.jsonl"), None, false)?;
+        robot.audit_log = Some(Arc::new(audit_log));
+        let api = Arc::new(FakeApi::default());
+        let listener = admin_api::bind("127.0.0.1:0".parse()?).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(admin_api::serve(listener, robot.clone(), api.clone()));
+        let request = |method: &str, path: &str, token: &str, body: &str| {
+            let request = format!(
+                "{method} {path} HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\
+                 Content-Length: {}\r\n\r\n{body}",
+                body.len(),
+            );
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await?;
+                stream.write_all(request.as_bytes()).await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                let (head, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+                let status = head.split(' ').nth(1).unwrap_or_default().to_owned();
+                Ok::<_, eyre::Report>((status, serde_json::from_str::<serde_json::Value>(body)?))
+            }
+        };
+
+        let (status, _) = request("GET", "/chats/-100/stats", "wrong", "").await?;
+        assert_eq!(status, "401");
+        let text = r#"{"text": "Buy followers"}"#;
+        let (status, known) = request("POST", "/chats/-100/entries", "secret", text).await?;
+        assert_eq!(status, "200");
+        let hash = known["hash"].as_str().unwrap().to_owned();
+        let forbid = format!("/chats/-100/entries/{hash}/forbid");
+        let (_, entry) = request("POST", &forbid, "secret", "").await?;
+        assert_eq!(entry["status"], "forbidden");
+        let (_, known) = request("POST", "/chats/-100/check", "secret", text).await?;
+        assert_eq!(known["entry"]["status"], "forbidden");
+        let (status, _) = request("GET", "/chats/-100/entries/00", "secret", "").await?;
+        assert_eq!(status, "400");
+        let (_, stats) = request("GET", "/chats/-100/stats?days=3", "secret", "").await?;
+        assert_eq!(stats["days"].as_array().map(Vec::len), Some(3));
+
+        robot
+            .process_message(message(1, USER_ID, "Buy followers"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(1)]);
+        let (_, deletions) = request("GET", "/chats/-100/deletions", "secret", "").await?;
+        assert_eq!(deletions[0]["event"], "enforce");
+        assert_eq!(deletions[0]["message_id"], 1);
+
+        let setting = "/chats/-100/settings/exempt_admins";
+        let (status, _) = request("PUT", setting, "secret", r#"{"value": "maybe"}"#).await?;
+        assert_eq!(status, "400");
+        let (_, changed) = request("PUT", setting, "secret", r#"{"value": "false"}"#).await?;
+        assert_eq!(changed["value"], "false");
+        assert!(!robot.settings(ChatId(CHAT_ID))?.exempt_admins);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn moderates_by_reaction() -> eyre::Result<()> {
+        let path = temp_db_path("reactions");
+        let robot = robot(
+            &path,
+            &[("allow_reaction", "✅"), ("forbid_reaction", "🚫")],
+        )?;
+        let api = Arc::new(FakeApi::default());
+        let react = |id: i32, from, emoji| {
+            serde_json::from_value::<MessageReactionUpdated>(json!({
+                "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Test" },
+                "message_id": id,
+                "user": user(from),
+                "date": Utc::now().timestamp(),
+                "old_reaction": [],
+                "new_reaction": [{ "type": "emoji", "emoji": emoji }],
+            }))
+        };
+        robot
+            .process_message(
+                message(1, USER_ID, "rules are in the pinned message"),
+                api.clone(),
+            )
+            .await?;
+        robot
+            .process_reaction(react(1, USER_ID, "✅")?, api.clone())
+            .await?;
+        robot
+            .process_message(
+                message(2, USER_ID, "rules are in the pinned message"),
+                api.clone(),
+            )
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(2)]);
+
+        robot
+            .process_reaction(react(1, ADMIN_ID, "✅")?, api.clone())
+            .await?;
+        robot
+            .process_message(
+                message(3, USER_ID, "rules are in the pinned message"),
+                api.clone(),
+            )
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(2)]);
+
+        robot
+            .process_message(message(4, USER_ID, "buy my course"), api.clone())
+            .await?;
+        robot
+            .process_reaction(react(4, ADMIN_ID, "🚫")?, api.clone())
+            .await?;
+        robot
+            .process_message(message(5, USER_ID, "buy my course"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(2), MessageId(5)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletes_similar_long_messages() -> eyre::Result<()> {
+        let path = temp_db_path("fuzzy");
+        let robot = robot(
+            &path,
+            &[("fuzzy_threshold", "0.6"), ("fuzzy_min_words", "10")],
+        )?;
+        let api = Arc::new(FakeApi::default());
+        let pasta = "what the hell did you just say about me, I will have you know \
+                     I graduated top of my class and I have been involved in numerous raids";
+        let texts = [
+            pasta.to_owned(),
+            pasta.replace("numerous", "countless"),
+            "a completely different message that is long enough to get a signature".to_owned(),
+        ];
+        for (id, text) in (1..).zip(texts) {
+            robot
+                .process_message(message(id, USER_ID, &text), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(2)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn matches_imported_long_messages() -> eyre::Result<()> {
+        let path = temp_db_path("fuzzy-import");
+        let robot = robot(
+            &path,
+            &[("fuzzy_threshold", "0.6"), ("fuzzy_min_words", "10")],
+        )?;
+        let api = Arc::new(FakeApi::default());
+        let pasta = "what the hell did you just say about me, I will have you know \
+                     I graduated top of my class and I have been involved in numerous raids";
+        let post = Post {
+            timestamp: Utc::now().timestamp(),
+            message_id: None,
+            poster_id: None,
+        };
+        let mut importer = Importer::new(robot.clone(), ChatId(CHAT_ID))?;
+        importer.consume(ImportItem::Message(pasta, post)).await?;
+        importer.finish().await?;
+        let copy = pasta.replace("numerous", "countless");
+        robot
+            .process_message(message(1, USER_ID, &copy), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(1)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_keeps_changes_made_meanwhile() -> eyre::Result<()> {
+        let path = temp_db_path("import-race");
+        let robot = robot(&path, &[])?;
+        let post = Post {
+            timestamp: Utc::now().timestamp(),
+            message_id: None,
+            poster_id: None,
+        };
+        let mut importer = Importer::new(robot.clone(), ChatId(CHAT_ID))?;
+        importer
+            .consume(ImportItem::Message("hello world", post))
+            .await?;
+        // Forbidden by an admin before the batch is written.
+        let key = robot.hash_message(robot.topic_scope(ChatId(CHAT_ID), None), "hello world")?;
+        robot
+            .set_hash_status(key, Status::Forbidden, None, post)
+            .await?;
+        importer.finish().await?;
+        let entry = robot.store.get(key).await?.expect("entry is kept");
+        assert_eq!(entry.status, Status::Forbidden);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exempts_admins() -> eyre::Result<()> {
+        let path = temp_db_path("admins");
+        let robot = robot(&path, &[("exempt_admins", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "hello world"), api.clone())
+            .await?;
+        robot
+            .process_message(message(2, ADMIN_ID, "hello world"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), []);
+        robot
+            .process_message(message(3, USER_ID, "hello world"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(3)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caches_admin_status() -> eyre::Result<()> {
+        let path = temp_db_path("admin-cache");
+        let robot = robot(&path, &[("exempt_admins", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=3 {
+            robot
+                .process_message(message(id, ADMIN_ID, "hello world"), api.clone())
+                .await?;
+        }
+        assert_eq!(api.count("get_chat_member"), 1);
+
+        let member = json!({ "status": "creator", "user": user(ADMIN_ID), "is_anonymous": false });
+        let update = serde_json::from_value(json!({
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Test" },
+            "from": user(ADMIN_ID),
+            "date": Utc::now().timestamp(),
+            "old_chat_member": member,
+            "new_chat_member": member,
+        }))?;
+        robot.process_chat_member(update)?;
+        robot
+            .process_message(message(4, ADMIN_ID, "hello world"), api.clone())
+            .await?;
+        assert_eq!(api.count("get_chat_member"), 2);
+        assert_eq!(api.deletions(), []);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warns_newcomers() -> eyre::Result<()> {
+        let path = temp_db_path("newcomers");
+        let robot = robot(&path, &[("newcomer_grace_messages", "1")])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "rules are pinned"), api.clone())
+            .await?;
+        let joined = serde_json::from_value(json!({
+            "message_id": 2,
+            "date": Utc::now().timestamp(),
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Test" },
+            "from": user(USER_ID + 1),
+            "new_chat_members": [user(USER_ID + 1)],
+        }))?;
+        robot.process_message(joined, api.clone()).await?;
+        for id in 3..=4 {
+            robot
+                .process_message(message(id, USER_ID + 1, "rules are pinned"), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(4)]);
+        assert!(robot.newcomers.is_empty());
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletes_floods() -> eyre::Result<()> {
+        let path = temp_db_path("flood");
+        let robot = robot(&path, &[("flood_limit", "2"), ("max_repeats", "100")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=4 {
+            robot
+                .process_message(message(id, USER_ID, "spam"), api.clone())
+                .await?;
+        }
+        robot
+            .process_message(message(5, ADMIN_ID + 10, "spam"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(3), MessageId(4)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follows_duplicate_policy() -> eyre::Result<()> {
+        let path = temp_db_path("duplicate-policy");
+        let robot = robot(&path, &[("duplicate_actions", "warn,react")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=2 {
+            robot
+                .process_message(message(id, USER_ID, "same old text"), api.clone())
+                .await?;
+        }
+        assert!(api.deletions().is_empty());
+        {
+            let calls = api.calls.lock().unwrap();
+            let [Call::Send(_, warning), Call::Other("set_message_reaction")] = &calls[..] else {
+                panic!("unexpected calls: {calls:?}");
+            };
+            assert!(warning.contains("already posted"));
+        }
+
+        let set = message(3, ADMIN_ID, "/set duplicate_actions delete,mute");
+        robot.process_message(set, api.clone()).await?;
+        robot
+            .process_message(message(4, USER_ID, "same old text"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(4)]);
+        assert!(api
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|call| matches!(call, Call::Other("mute"))));
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_filter_script() -> eyre::Result<()> {
+        let path = temp_db_path("filter-script");
+        std::fs::create_dir_all(&path)?;
+        let script = path.join("filter.rhai");
+        std::fs::write(
+            &script,
+            r#"
+                fn filter(message) {
+                    if message.text == "free money" {
+                        return "delete";
+                    }
+                    if message.duplicate && message.user_id == 3 {
+                        return "ignore";
+                    }
+                    if message.text == "spin" {
+                        loop {}
+                    }
+                }
+            "#,
+        )?;
+        let script = script.display().to_string();
+        let robot = robot(&path.join("db"), &[("filter_script", &script)])?;
+        let api = Arc::new(FakeApi::default());
+        let messages = [
+            (1, USER_ID, "free money"),
+            (2, USER_ID, "hello there"),
+            (3, USER_ID + 1, "hello there"),
+            (4, USER_ID, "spin"),
+            (5, USER_ID, "spin"),
+        ];
+        for (id, from, text) in messages {
+            robot
+                .process_message(message(id, from, text), api.clone())
+                .await?;
+        }
+        // Scripts that run for too long leave messages to the usual rules.
+        assert_eq!(api.deletions(), [MessageId(1), MessageId(5)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explains_matches() -> eyre::Result<()> {
+        let path = temp_db_path("why");
+        let robot = robot(&path, &[("fold_leetspeak", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "free money now"), api.clone())
+            .await?;
+        let repost = message(2, USER_ID + 1, "Fr33 M0n3y now");
+        robot.process_message(repost.clone(), api.clone()).await?;
+        let mut why = serde_json::to_value(message(3, ADMIN_ID, "/why"))?;
+        why["reply_to_message"] = serde_json::to_value(repost)?;
+        robot
+            .process_message(serde_json::from_value(why)?, api.clone())
+            .await?;
+
+        let calls = api.calls.lock().unwrap();
+        let Some(Call::Send(_, explanation)) = calls.last() else {
+            panic!("no explanation: {calls:?}");
+        };
+        let lines = explanation.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Before hashing: fold_leetspeak");
+        assert!(lines[1].starts_with("Hash: "));
+        assert_eq!(lines[2], "Matched a known message exactly");
+        assert!(lines[3].starts_with("I've seen this message 2 times"));
+        drop(calls);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Serves each of `bodies` once, in order, over plain HTTP.
+    async fn serve_bodies(bodies: &[&'static str]) -> eyre::Result<std::net::SocketAddr> {
+        use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let bodies = bodies.to_vec();
+        tokio::spawn(async move {
+            for body in bodies {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap() > 2 {
+                    line.clear();
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn forbids_blocklisted_texts() -> eyre::Result<()> {
+        let path = temp_db_path("blocklists");
+        let addr = serve_bodies(&[r#"["Buy crypto now!"]"#, "[]"]).await?;
+        let subscription = format!("{CHAT_ID}=http://{addr}/list.json");
+        let robot = robot(&path, &[("chat_blocklists", &subscription)])?;
+        let api = Arc::new(FakeApi::default());
+        assert_eq!(robot.refresh_blocklists().await?.forbidden, 1);
+        robot
+            .process_message(message(1, USER_ID, "Buy crypto now!"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(1)]);
+        assert_eq!(robot.refresh_blocklists().await?.unforbidden, 1);
+        robot
+            .process_message(message(2, USER_ID, "Buy crypto now!"), api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(1)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletes_duplicates_in_batches() -> eyre::Result<()> {
+        let path = temp_db_path("batches");
+        let robot = robot(&path, &[("delete_batch_delay", "100")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=3 {
+            robot
+                .process_message(message(id, USER_ID, "spam"), api.clone())
+                .await?;
+        }
+        assert!(api.deletions().is_empty());
+        robot.delete_batches(&*api).await;
+        assert_eq!(
+            api.calls.lock().unwrap().last(),
+            Some(&Call::DeleteMany(
+                ChatId(CHAT_ID),
+                vec![MessageId(2), MessageId(3)]
+            ))
+        );
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletes_pending_batches_on_shutdown() -> eyre::Result<()> {
+        let path = temp_db_path("batches-shutdown");
+        let robot = robot(&path, &[("delete_batch_delay", "60000")])?;
+        let api = Arc::new(FakeApi::default());
+        for id in 1..=2 {
+            robot
+                .process_message(message(id, USER_ID, "spam"), api.clone())
+                .await?;
+        }
+        robot.shutdown(&*api).await?;
+        assert_eq!(
+            api.calls.lock().unwrap().last(),
+            Some(&Call::DeleteMany(ChatId(CHAT_ID), vec![MessageId(2)]))
+        );
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retains_texts_with_consent() -> eyre::Result<()> {
+        let path = temp_db_path("texts");
+        let robot = robot(
+            &path,
+            &[("retain_texts", "true"), ("deletion_notice", "chat")],
+        )?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, USER_ID, "before consent"), api.clone())
+            .await?;
+        assert!(robot.texts.is_empty());
+
+        let set = |id, value| message(id, ADMIN_ID, &format!("/set retain_texts {value}"));
+        robot.process_message(set(2, "true"), api.clone()).await?;
+        for id in 3..=4 {
+            robot
+                .process_message(message(id, USER_ID, "Hello world"), api.clone())
+                .await?;
+        }
+        assert_eq!(api.deletions(), [MessageId(4)]);
+        let notice = api
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find_map(|call| match call {
+                Call::Send(_, text) => Some(text.clone()),
+                _ => None,
+            });
+        let notice = notice.unwrap();
+        assert!(notice.ends_with("Duplicated: Hello world"));
+        assert!(notice.contains("First posted less than a minute ago, reposted once since"));
+        let found = robot.search_texts(ChatId(CHAT_ID), "hell", 10).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].0.as_str(), found[0].1.count), ("Hello world", 2));
+
+        robot.process_message(set(5, "false"), api.clone()).await?;
+        assert!(robot.texts.is_empty());
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn posts_weekly_digests() -> eyre::Result<()> {
+        let path = temp_db_path("digests");
+        let robot = robot(&path, &[("weekly_digest", "chat")])?;
+        let api = Arc::new(FakeApi::default());
+        robot.chats.insert(CHAT_ID.to_be_bytes(), &[0])?;
+        for id in 1..=2 {
+            robot
+                .process_message(message(id, USER_ID, "Buy my course"), api.clone())
+                .await?;
+        }
+        let digests = || {
+            api.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|call| match call {
+                    Call::Send(_, text) if text.starts_with("This week") => Some(text.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let monday = Utc::now().date_naive().week(Weekday::Mon).first_day();
+        let morning = monday.and_hms_opt(8, 0, 0).unwrap().and_utc();
+        assert_eq!(robot.post_digests(&*api, morning).await?, 0);
+        let later = monday.and_hms_opt(10, 0, 0).unwrap().and_utc();
+        assert_eq!(robot.post_digests(&*api, later).await?, 1);
+        assert_eq!(robot.post_digests(&*api, later).await?, 0);
+        let digests = digests();
+        assert_eq!(digests.len(), 1);
+        assert!(digests[0].starts_with("This week: 1 duplicates deleted"));
+        assert!(digests[0].ends_with("Most duplicates deleted from: 2 (1)"));
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_top_duplicates() -> eyre::Result<()> {
+        let path = temp_db_path("top");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        for (id, text) in [(1, "first"), (2, "second"), (3, "second"), (4, "once")] {
+            robot
+                .process_message(message(id, USER_ID, text), api.clone())
+                .await?;
+        }
+        robot
+            .process_message(message(5, ADMIN_ID, "/top"), api.clone())
+            .await?;
+        let calls = api.calls.lock().unwrap();
+        let Some(Call::Send(_, answer)) = calls.last() else {
+            panic!("no answer to /top");
+        };
+        assert!(answer.starts_with("Message "));
+        assert!(answer.contains("(posted 2 times since"));
+        assert!(!answer.contains("\n\n"));
+        drop(calls);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn syncs_from_another_chat() -> eyre::Result<()> {
+        let path = temp_db_path("sync");
+        let robot = robot(&path, &[("retain_texts", "true")])?;
+        let api = Arc::new(FakeApi::default());
+        robot
+            .process_message(message(1, ADMIN_ID, "/set retain_texts true"), api.clone())
+            .await?;
+        let spam = message(2, USER_ID, "Join my channel");
+        robot.process_message(spam.clone(), api.clone()).await?;
+        let mut forbid = serde_json::to_value(message(3, ADMIN_ID, "/forbid"))?;
+        forbid["reply_to_message"] = serde_json::to_value(spam)?;
+        robot
+            .process_message(serde_json::from_value(forbid)?, api.clone())
+            .await?;
+        robot
+            .process_message(message(4, USER_ID, "Just chatting"), api.clone())
+            .await?;
+
+        let other = ChatId(-200);
+        let summary = robot
+            .copy_entries(ChatId(CHAT_ID), other, false, UserId(ADMIN_ID))
+            .await?;
+        assert_eq!(
+            summary,
+            SyncSummary {
+                copied: 1,
+                skipped: 0
+            }
+        );
+        let mut repost = serde_json::to_value(message(5, USER_ID, "Join my channel"))?;
+        repost["chat"]["id"] = other.0.into();
+        robot
+            .process_message(serde_json::from_value(repost)?, api.clone())
+            .await?;
+        assert_eq!(api.deletions(), [MessageId(5)]);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copies_settings_between_chats() -> eyre::Result<()> {
+        let path = temp_db_path("settings-files");
+        let robot = robot(&path, &[])?;
+        let api = Arc::new(FakeApi::default());
+        let commands = [
+            "/set max_repeats 2",
+            "/set duplicate_actions log,delete",
+            "/phrases add good morning",
+            "/phrases remove thanks",
+        ];
+        for (id, command) in (1..).zip(commands) {
+            robot
+                .process_message(message(id, ADMIN_ID, command), api.clone())
+                .await?;
+        }
+        robot.set_exempt(ChatId(CHAT_ID), UserId(USER_ID), true)?;
+        let export = serde_json::to_value(robot.export_settings(ChatId(CHAT_ID))?)?;
+
+        let other = ChatId(-200);
+        let mut invalid = export.clone();
+        invalid["settings"]["max_repeats"] = "lots".into();
+        let invalid = serde_json::from_value::<SettingsFile>(invalid)?;
+        assert!(robot
+            .import_settings(other, &invalid, UserId(ADMIN_ID))
+            .is_err());
+        assert!(!robot.is_exempt(other, UserId(USER_ID))?);
+
+        let file = serde_json::from_value::<SettingsFile>(export)?;
+        let import = robot.import_settings(other, &file, UserId(ADMIN_ID))?;
+        assert_eq!(
+            import,
+            SettingsImport {
+                settings: 2,
+                phrases: 2,
+                exemptions: 1,
+            }
+        );
+        let (settings, other_settings) = (robot.settings(ChatId(CHAT_ID))?, robot.settings(other)?);
+        for setting in Setting::ALL {
+            assert_eq!(setting.show(&other_settings), setting.show(&settings));
+        }
+        assert_eq!(
+            robot.common_phrases(other)?,
+            robot.common_phrases(ChatId(CHAT_ID))?
+        );
+        assert!(robot.is_exempt(other, UserId(USER_ID))?);
+        let again = robot.import_settings(other, &file, UserId(ADMIN_ID))?;
+        assert_eq!(again, SettingsImport::default());
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+}
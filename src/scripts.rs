@@ -0,0 +1,212 @@
+//! Operator-written Rhai filter for rules too specific to a chat for the config.
+//!
+//! The script defines `fn filter(message)`, which gets a map of the message and
+//! what's known about it, and returns `"ignore"`, `"enforce"`, actions like
+//! `"warn,log"`, or nothing to leave the message to the usual rules.
+//!
+//! Scripts can't touch files or the network, and are stopped once they run
+//! for too many operations or too long.
+
+use std::{
+    cell::Cell,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde::{de, Deserialize, Deserializer};
+use teloxide::types::{Message, User};
+
+use crate::{
+    config::Actions,
+    policy::Detection,
+    robot::{explicit_reply, Robot9000},
+    storage::{Entry, Status},
+};
+
+/// Name of the function scripts define.
+const FILTER_FN: &str = "filter";
+
+/// How often the clock is checked, in operations.
+const CLOCK_CHECK_INTERVAL: u64 = 256;
+
+thread_local! {
+    /// Operations and deadline the running script is allowed, if one is running.
+    static LIMITS: Cell<Option<(u64, Instant)>> = const { Cell::new(None) };
+}
+
+/// What the script decided about a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScriptVerdict {
+    /// The usual rules decide.
+    Default,
+    /// Leave the message be, even if it's a duplicate.
+    Ignore,
+    /// Act on the message as on a duplicate, even if it isn't one.
+    Enforce,
+    /// Take these actions on the message.
+    Actions(Actions),
+}
+
+pub struct FilterScript {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+}
+
+impl fmt::Debug for FilterScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FilterScript").field(&self.path).finish()
+    }
+}
+
+impl FilterScript {
+    pub(crate) fn load(path: &Path) -> eyre::Result<Self> {
+        let source = fs::read_to_string(path)
+            .wrap_err_with(|| format!("couldn't read filter script {}", path.display()))?;
+        let mut engine = Engine::new();
+        engine
+            .disable_symbol("eval")
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .on_print(|text| tracing::info!(text, "filter script printed"))
+            .on_debug(|text, _, _| tracing::debug!(text, "filter script printed"))
+            .on_progress(|operations| {
+                let (max_operations, deadline) = LIMITS.get()?;
+                let timed_out = operations % CLOCK_CHECK_INTERVAL == 0 && Instant::now() > deadline;
+                (operations > max_operations || timed_out).then(|| Dynamic::from(operations))
+            });
+        let ast = engine
+            .compile(&source)
+            .map_err(|err| eyre::eyre!("{err}"))
+            .wrap_err_with(|| format!("malformed filter script {}", path.display()))?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == FILTER_FN && function.params.len() == 1)
+        {
+            eyre::bail!(
+                "filter script {} doesn't define `fn {FILTER_FN}(message)`",
+                path.display()
+            );
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            engine,
+            ast,
+        })
+    }
+
+    /// Runs the filter on a message, stopping it after `max_operations` or `timeout`.
+    pub(crate) fn run(
+        &self,
+        message: Map,
+        max_operations: u64,
+        timeout: Duration,
+    ) -> eyre::Result<ScriptVerdict> {
+        LIMITS.set(Some((max_operations, Instant::now() + timeout)));
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            FILTER_FN,
+            (Dynamic::from_map(message),),
+        );
+        LIMITS.set(None);
+        let verdict = result.map_err(|err| eyre::eyre!("filter script failed: {err}"))?;
+        if verdict.is_unit() {
+            return Ok(ScriptVerdict::Default);
+        }
+        let verdict = verdict
+            .into_string()
+            .map_err(|kind| eyre::eyre!("filter script returned a {kind} instead of a string"))?;
+        match verdict.as_str() {
+            "ignore" => Ok(ScriptVerdict::Ignore),
+            "enforce" => Ok(ScriptVerdict::Enforce),
+            actions => {
+                Ok(ScriptVerdict::Actions(actions.parse().wrap_err_with(
+                    || format!("filter script returned {actions:?}"),
+                )?))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FilterScript {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = PathBuf::deserialize(deserializer)?;
+        Self::load(&path).map_err(|err| de::Error::custom(format!("{err:#}")))
+    }
+}
+
+impl Robot9000 {
+    /// Runs the filter script on a message, if there's one, off the async executor.
+    /// Scripts that fail leave the message to the usual rules.
+    pub(crate) async fn filter(
+        &self,
+        message: &Message,
+        user: &User,
+        text: Option<&str>,
+        entry: &Entry,
+        detection: Option<Detection>,
+    ) -> ScriptVerdict {
+        let config = self.config();
+        if config.filter_script.is_none() {
+            return ScriptVerdict::Default;
+        }
+        let optional = |value: Option<Dynamic>| value.unwrap_or(Dynamic::UNIT);
+        let mut map = Map::new();
+        let mut set = |key: &str, value: Dynamic| {
+            map.insert(key.into(), value);
+        };
+        set("text", optional(text.map(|text| text.into())));
+        set("chat_id", message.chat.id.0.into());
+        set("message_id", i64::from(message.id.0).into());
+        set(
+            "thread_id",
+            optional(message.thread_id.map(|id| i64::from(id.0 .0).into())),
+        );
+        set("user_id", (user.id.0 as i64).into());
+        set(
+            "username",
+            optional(user.username.clone().map(Dynamic::from)),
+        );
+        set("is_bot", user.is_bot.into());
+        set("is_reply", explicit_reply(message).is_some().into());
+        set("is_forward", message.forward_origin().is_some().into());
+        set("duplicate", detection.is_some().into());
+        set(
+            "detection",
+            optional(detection.map(|detection| detection.as_str().into())),
+        );
+        set("allowed", (entry.status == Status::Allowed).into());
+        set("count", i64::from(entry.count).into());
+        set("first_seen", entry.first_seen.into());
+        let timeout = Duration::from_millis(config.filter_script_timeout);
+        let verdict = tokio::task::spawn_blocking(move || {
+            config
+                .filter_script
+                .as_ref()
+                .map_or(Ok(ScriptVerdict::Default), |script| {
+                    script.run(map, config.filter_script_max_operations, timeout)
+                })
+        })
+        .await;
+        match verdict
+            .map_err(eyre::Report::from)
+            .and_then(|verdict| verdict)
+        {
+            Ok(verdict) => {
+                tracing::debug!(verdict = format_args!("{verdict:?}"), "ran filter script");
+                verdict
+            }
+            Err(err) => {
+                tracing::warn!(err = format_args!("{err:#}"), "filter script failed");
+                ScriptVerdict::Default
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! A chat's settings, common phrases and exemptions as a JSON file, so admins can
+//! copy a carefully tuned chat to their other groups with `/settings_export`
+//! and `/settings_import`.
+//!
+//! Templates come from the config, so they're the same in every chat and not included.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, InputFile, Message, User, UserId};
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    backups::download,
+    i18n::Text,
+    phrases::normalize_phrase,
+    policy::Setting,
+    robot::{describe_chat, describe_user, explicit_reply, reply, Robot9000},
+    storage::unix_now,
+};
+
+/// Largest settings file accepted, in bytes.
+const MAX_SETTINGS_FILE_SIZE: u32 = 256 * 1024;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SettingsFile {
+    /// Chat the settings come from, for reference only.
+    chat_id: ChatId,
+    exported_at: i64,
+    /// Values given to `/set`. Settings left out follow the config.
+    settings: BTreeMap<String, String>,
+    /// Every common phrase of the chat, including the config's.
+    #[serde(default)]
+    phrases: Vec<String>,
+    #[serde(default)]
+    exempt_users: Vec<UserId>,
+}
+
+/// What importing a settings file changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct SettingsImport {
+    pub(crate) settings: usize,
+    pub(crate) phrases: usize,
+    pub(crate) exemptions: usize,
+}
+
+impl Robot9000 {
+    /// Values the chat gave to `/set`, by setting.
+    fn setting_overrides(&self, chat_id: ChatId) -> eyre::Result<BTreeMap<String, String>> {
+        self.chat_settings
+            .scan_prefix(chat_id.0.to_be_bytes())
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((
+                    String::from_utf8(key[8..].to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn exempt_users(&self, chat_id: ChatId) -> eyre::Result<Vec<UserId>> {
+        self.exemptions
+            .scan_prefix(chat_id.0.to_be_bytes())
+            .keys()
+            .map(|key| Ok(UserId(u64::from_be_bytes(key?[8..].try_into()?))))
+            .collect()
+    }
+
+    pub(crate) fn export_settings(&self, chat_id: ChatId) -> eyre::Result<SettingsFile> {
+        Ok(SettingsFile {
+            chat_id,
+            exported_at: unix_now(),
+            settings: self.setting_overrides(chat_id)?,
+            phrases: self.common_phrases(chat_id)?,
+            exempt_users: self.exempt_users(chat_id)?,
+        })
+    }
+
+    /// Makes the settings and common phrases of a chat the ones in the file,
+    /// and exempts the users it lists on top of those exempt already.
+    /// Nothing changes unless every value in the file is valid.
+    pub(crate) fn import_settings(
+        &self,
+        chat_id: ChatId,
+        file: &SettingsFile,
+        admin_id: UserId,
+    ) -> eyre::Result<SettingsImport> {
+        for (name, value) in &file.settings {
+            let setting = name.parse::<Setting>()?;
+            self.check_setting(chat_id, setting, value)
+                .wrap_err_with(|| format!("invalid value of {name}: {value:?}"))?;
+        }
+        let phrases = (file.phrases.iter())
+            .map(|phrase| normalize_phrase(phrase))
+            .filter(|phrase| !phrase.is_empty())
+            .collect::<BTreeSet<_>>();
+
+        let mut import = SettingsImport::default();
+        let overrides = self.setting_overrides(chat_id)?;
+        for setting in Setting::ALL {
+            let value = file.settings.get(setting.name());
+            if value != overrides.get(setting.name()) {
+                self.change_setting(chat_id, setting, value.map(String::as_str), admin_id)?;
+                import.settings += 1;
+            }
+        }
+        let current = self.common_phrases(chat_id)?.into_iter().collect();
+        for (phrase, common) in (phrases.difference(&current).map(|phrase| (phrase, true)))
+            .chain(current.difference(&phrases).map(|phrase| (phrase, false)))
+        {
+            self.set_common_phrase(chat_id, phrase, common)?;
+            self.audit(AuditEvent::Phrase {
+                chat_id,
+                admin_id,
+                phrase: phrase.clone(),
+                common,
+            });
+            import.phrases += 1;
+        }
+        for &user_id in &file.exempt_users {
+            if !self.is_exempt(chat_id, user_id)? {
+                self.set_exempt(chat_id, user_id, true)?;
+                self.audit(AuditEvent::Exempt {
+                    chat_id,
+                    user_id,
+                    admin_id,
+                });
+                import.exemptions += 1;
+            }
+        }
+        Ok(import)
+    }
+
+    /// Handles `/settings_export`, sending the chat's settings as a file.
+    pub(crate) async fn settings_export(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let export = self.export_settings(message.chat.id)?;
+                tracing::info!(user_id = user.id.0, "exporting settings");
+                let file = InputFile::memory(serde_json::to_vec_pretty(&export)?)
+                    .file_name(format!("r9ktg-settings-{}.json", message.chat.id));
+                bot.send_document(message.chat.id, file).await
+            },
+        )
+        .await
+    }
+
+    /// Handles `/settings_import` sent as a reply to a file from `/settings_export`.
+    pub(crate) async fn settings_import(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        Self::ensure_admin(
+            &self.config(),
+            &self.admins,
+            &locale,
+            bot,
+            message,
+            user,
+            async {
+                let Some(reply_to) = explicit_reply(message).filter(|reply_to| {
+                    reply_to
+                        .document()
+                        .is_some_and(|document| document.file.size <= MAX_SETTINGS_FILE_SIZE)
+                }) else {
+                    return reply(bot, message, locale.text(Text::SettingsImportUsage)).await;
+                };
+                let Some(contents) = download(bot, reply_to).await? else {
+                    return reply(bot, message, locale.text(Text::SettingsImportUsage)).await;
+                };
+                let imported = serde_json::from_slice::<SettingsFile>(&contents)
+                    .map_err(eyre::Report::from)
+                    .and_then(|file| self.import_settings(message.chat.id, &file, user.id));
+                let import = match imported {
+                    Ok(import) => import,
+                    Err(err) => {
+                        tracing::info!(
+                            user_id = user.id.0,
+                            err = format_args!("{err:#}"),
+                            "/settings_import failed"
+                        );
+                        let err = format!("{err:#}");
+                        let answer = locale.text(Text::SettingsImportFailed { err: &err });
+                        return reply(bot, message, answer).await;
+                    }
+                };
+                tracing::info!(
+                    user_id = user.id.0,
+                    settings = import.settings,
+                    phrases = import.phrases,
+                    exemptions = import.exemptions,
+                    "imported settings"
+                );
+                let event = format!(
+                    "Imported settings in {}\nAdmin: {}\nSettings changed: {}\n\
+                     Phrases changed: {}\nUsers exempted: {}",
+                    describe_chat(&message.chat),
+                    describe_user(user),
+                    import.settings,
+                    import.phrases,
+                    import.exemptions,
+                );
+                self.log_event(bot, event).await;
+                // The language may have just changed.
+                let answer = self
+                    .chat_locale(message.chat.id)?
+                    .text(Text::SettingsImported {
+                        settings: import.settings,
+                        phrases: import.phrases,
+                        exemptions: import.exemptions,
+                    });
+                reply(bot, message, answer).await
+            },
+        )
+        .await
+    }
+}
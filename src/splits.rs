@@ -0,0 +1,213 @@
+//! Catching known messages posted split across several consecutive messages,
+//! like copypasta sent a line at a time to dodge its hash.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use color_eyre::eyre;
+use teloxide::types::{ChatId, Message, MessageId, User, UserId};
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    config::Action,
+    policy::{Detection, Settings},
+    robot::{describe_chat, describe_user, is_anonymous_admin, snippet, Robot9000},
+    storage::{Entry, Key, Scope, Status},
+    storms::RaidDeletion,
+};
+
+/// Recent texts of each user in each chat, with their message ids and the times they were posted.
+type RecentParts = HashMap<(ChatId, UserId), VecDeque<(MessageId, String, i64)>>;
+
+/// Recent messages of every user, kept in memory only, since parts follow each other closely.
+#[derive(Clone, Default)]
+pub(crate) struct SplitTracker {
+    parts: Arc<Mutex<RecentParts>>,
+}
+
+impl SplitTracker {
+    /// How many users are tracked before the ones that went quiet are dropped.
+    const PRUNE_AT: usize = 10_000;
+
+    fn lock(&self) -> MutexGuard<'_, RecentParts> {
+        self.parts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records a message, returning the user's last few ones in the chat posted
+    /// within `window` seconds of each other, oldest first, this one included.
+    fn record(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        part: (MessageId, &str, i64),
+        max_parts: usize,
+        window: i64,
+    ) -> Vec<(MessageId, String)> {
+        let (message_id, text, timestamp) = part;
+        let since = timestamp.saturating_sub(window);
+        let mut parts = self.lock();
+        if parts.len() >= Self::PRUNE_AT {
+            parts.retain(|_, recent| recent.back().is_some_and(|&(_, _, at)| at > since));
+        }
+        let recent = parts.entry((chat_id, user_id)).or_default();
+        if recent.back().is_some_and(|&(_, _, at)| at <= since) {
+            recent.clear();
+        }
+        recent.push_back((message_id, text.to_owned(), timestamp));
+        while recent.len() > max_parts {
+            recent.pop_front();
+        }
+        recent
+            .iter()
+            .map(|(id, text, _)| (*id, text.clone()))
+            .collect()
+    }
+
+    fn forget(&self, chat_id: ChatId, user_id: UserId) {
+        self.lock().remove(&(chat_id, user_id));
+    }
+}
+
+impl Robot9000 {
+    /// Known message the latest few messages of a user make up when joined,
+    /// and how many of them it took.
+    async fn find_split(
+        &self,
+        scope: Scope,
+        settings: &Settings,
+        parts: &[(MessageId, String)],
+        now: i64,
+    ) -> eyre::Result<Option<(Key, usize)>> {
+        for count in 2..=parts.len() {
+            let window = &parts[parts.len() - count..];
+            let texts = window.iter().map(|(_, text)| text.as_str());
+            // Parts are usually lines or sentences of the original.
+            for separator in ["\n", " "] {
+                let joined = texts.clone().collect::<Vec<_>>().join(separator);
+                let key = self.hash_message(scope, &joined)?;
+                let Some(entry) = self.store.peek(key).await? else {
+                    continue;
+                };
+                let expired =
+                    entry.status == Status::Seen && settings.is_expired(entry.first_seen, now);
+                let next = Entry {
+                    count: entry.count.saturating_add(1),
+                    ..entry
+                };
+                if !expired && settings.is_duplicate(&next) {
+                    return Ok(Some((key, count)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deletes a user's latest messages if, joined together, they're a duplicate of
+    /// a known message, as long as `split_parts` is set. Returns whether they were deleted.
+    pub(crate) async fn check_split(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        scope: Scope,
+        settings: &Settings,
+        text: &str,
+    ) -> eyre::Result<bool> {
+        let config = self.config();
+        let Some(max_parts) = config.split_parts else {
+            return Ok(false);
+        };
+        // Splits are only ever deleted, acting on every part otherwise
+        // would be noisier than the spam.
+        let actions = settings.actions(Detection::Duplicate);
+        if !actions.contains(Action::Delete) {
+            return Ok(false);
+        }
+        let now = message.date.timestamp();
+        let window = config.split_window.try_into().unwrap_or(i64::MAX);
+        let part = (message.id, text, now);
+        let parts = self
+            .splits
+            .record(message.chat.id, user.id, part, max_parts, window);
+        let Some((key, count)) = self.find_split(scope, settings, &parts, now).await? else {
+            return Ok(false);
+        };
+        if settings.is_quiet(message.date)
+            || self.is_paused(message.chat.id, now)?
+            || self.is_suspended(message.chat.id)?
+        {
+            return Ok(false);
+        }
+        if settings.exempt_admins
+            && (is_anonymous_admin(message)
+                || Self::is_admin(&config, &self.admins, bot, &message.chat, user).await?)
+        {
+            tracing::debug!(
+                user_id = user.id.0,
+                "ignoring split duplicate from an admin"
+            );
+            return Ok(false);
+        }
+
+        let message_ids = parts[parts.len() - count..]
+            .iter()
+            .map(|&(id, _)| id)
+            .collect::<Vec<_>>();
+        tracing::debug!(
+            user_id = user.id.0,
+            parts = count,
+            "deleting split duplicate"
+        );
+        let event = format!(
+            "Duplicate split into {count} messages in {}\nUser: {}\nText: {}",
+            describe_chat(&message.chat),
+            describe_user(user),
+            snippet(text),
+        );
+        if actions.contains(Action::Log) {
+            self.log_event(bot, event).await;
+        }
+        self.audit(AuditEvent::Split {
+            chat_id: message.chat.id,
+            hash: key.hash,
+            message_ids: message_ids.iter().map(|id| id.0).collect(),
+            user_id: user.id,
+        });
+        self.splits.forget(message.chat.id, user.id);
+        for &message_id in &message_ids {
+            self.quarantine(bot, message.chat.id, message_id).await;
+            self.delete_batched(bot, message.chat.id, message_id)
+                .await?;
+        }
+        self.record_storm(bot, message.chat.id, now).await?;
+        let deletion = RaidDeletion::new(now, Some(user), Some(text));
+        self.record_raid(bot, &message.chat, deletion).await?;
+        self.count_deleted(message.chat.id, now, Some(user.id))
+            .await?;
+        if actions.contains(Action::Mute) {
+            self.mute(bot, message.chat.id, user.id).await?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_recent_parts() {
+        let splits = SplitTracker::default();
+        let (chat_id, user_id) = (ChatId(-100), UserId(2));
+        let record = |id, at| splits.record(chat_id, user_id, (MessageId(id), "part", at), 3, 60);
+        assert_eq!(record(1, 0).len(), 1);
+        assert_eq!(record(2, 10).len(), 2);
+        assert_eq!(record(3, 20).len(), 3);
+        let parts = record(4, 30);
+        assert_eq!(parts.first().map(|&(id, _)| id), Some(MessageId(2)));
+        assert_eq!(record(5, 100).len(), 1);
+    }
+}
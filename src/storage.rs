@@ -0,0 +1,886 @@
+//! Known messages and other state kept in the sled database.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre;
+use miniz_oxide::inflate::TINFLStatus;
+use serde::Serialize;
+use teloxide::types::{ChatId, Message, MessageId, ThreadId, UserId};
+
+use crate::{
+    backups::apply_pending_restore,
+    config::Config,
+    policy::{Setting, Settings},
+    robot::Robot9000,
+};
+
+/// Set of chats sharing known messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    Chat(ChatId),
+    Shared,
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Namespace::Chat(chat_id) => write!(f, "chat {chat_id}"),
+            Namespace::Shared => f.write_str("shared"),
+        }
+    }
+}
+
+/// Where a message is checked for duplicates.
+#[derive(Debug, Clone, Copy)]
+pub struct Scope {
+    pub(crate) namespace: Namespace,
+    pub(crate) thread_id: Option<ThreadId>,
+    /// Author of the message, in chats where only their own copies count.
+    pub(crate) poster_id: Option<UserId>,
+}
+
+impl From<Namespace> for Scope {
+    fn from(namespace: Namespace) -> Self {
+        Self {
+            namespace,
+            thread_id: None,
+            poster_id: None,
+        }
+    }
+}
+
+impl Namespace {
+    /// Prefix of all keys in the namespace.
+    ///
+    /// Chat ids are never zero, so it's free to use for the shared namespace.
+    pub(crate) fn prefix(self) -> [u8; 8] {
+        match self {
+            Namespace::Chat(chat_id) => chat_id.0.to_be_bytes(),
+            Namespace::Shared => [0; 8],
+        }
+    }
+}
+
+/// Database key of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub(crate) namespace: Namespace,
+    pub(crate) hash: [u8; 16],
+}
+
+impl Key {
+    pub(crate) fn encode(&self) -> [u8; 24] {
+        let mut key = [0; 24];
+        key[..8].copy_from_slice(&self.namespace.prefix());
+        key[8..].copy_from_slice(&self.hash);
+        key
+    }
+
+    pub(crate) fn decode(key: &[u8]) -> eyre::Result<Self> {
+        let key: &[u8; 24] = key
+            .try_into()
+            .map_err(|_| eyre::eyre!("malformed database key: {key:?}"))?;
+        let namespace = match i64::from_be_bytes(key[..8].try_into().unwrap()) {
+            0 => Namespace::Shared,
+            chat_id => Namespace::Chat(ChatId(chat_id)),
+        };
+        Ok(Self {
+            namespace,
+            hash: key[8..].try_into().unwrap(),
+        })
+    }
+}
+
+/// Removes every key starting with `prefix` from `tree`. Returns how many there were.
+pub(crate) fn remove_prefix(tree: &sled::Tree, prefix: impl AsRef<[u8]>) -> eyre::Result<usize> {
+    let mut batch = sled::Batch::default();
+    let mut removed = 0;
+    for key in tree.scan_prefix(prefix).keys() {
+        batch.remove(key?);
+        removed += 1;
+        if removed % 10_000 == 0 {
+            tree.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(removed)
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+/// Opens the database shared by every bot, migrating it to the current schema.
+/// A backup sent with `/restore` replaces it first.
+pub fn open_database(config: &Config) -> eyre::Result<sled::Db> {
+    apply_pending_restore(&config.db_path)?;
+    let db = sled::open(&config.db_path)?;
+    tracing::debug!("Opened database");
+    migrate(&db)?;
+    Ok(db)
+}
+
+/// Opens a tree of a bot. The main bot's trees have plain names,
+/// and the ones of the bot called `name` are under `bots/<name>/`.
+pub(crate) fn open_tree(
+    db: &sled::Db,
+    bot_name: Option<&str>,
+    name: &str,
+) -> sled::Result<sled::Tree> {
+    match bot_name {
+        None => db.open_tree(name),
+        Some(bot_name) => db.open_tree(format!("bots/{bot_name}/{name}")),
+    }
+}
+
+/// Tree of the known messages of a bot, which is the default one for the main bot.
+pub(crate) fn messages_tree(db: &sled::Db, bot_name: Option<&str>) -> sled::Result<sled::Tree> {
+    match bot_name {
+        None => Ok(sled::Tree::clone(db)),
+        Some(_) => open_tree(db, bot_name, "messages"),
+    }
+}
+
+/// Whether a bot has known messages in sled, legacy ones included.
+pub(crate) fn has_known_messages(db: &sled::Db, bot_name: Option<&str>) -> eyre::Result<bool> {
+    Ok(!messages_tree(db, bot_name)?.is_empty() || !open_tree(db, bot_name, "legacy")?.is_empty())
+}
+
+/// How often expired messages are swept and the store is trimmed down to `max_entries`.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+pub(crate) const ENTRY_SIZE: usize = 29;
+
+/// Size of entries written before allowances could run out, which are still read,
+/// since encrypted ones and the ones in Redis can't be migrated.
+pub(crate) const LEGACY_ENTRY_SIZE: usize = 25;
+
+/// Moderator decision about a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Handled by the usual duplicate rules.
+    Seen = 0,
+    /// Never deleted.
+    Allowed = 1,
+    /// Always deleted.
+    Forbidden = 2,
+}
+
+/// Where and when a message was posted.
+#[derive(Debug, Clone, Copy)]
+pub struct Post {
+    pub(crate) timestamp: i64,
+    pub(crate) message_id: Option<MessageId>,
+    pub(crate) poster_id: Option<UserId>,
+}
+
+impl From<&Message> for Post {
+    fn from(message: &Message) -> Self {
+        Self {
+            timestamp: message.date.timestamp(),
+            message_id: Some(message.id),
+            poster_id: message.from.as_ref().map(|user| user.id),
+        }
+    }
+}
+
+/// Value stored for every known hash.
+///
+/// Encoded as a status byte followed by the little-endian first-seen
+/// timestamp, count, message id and poster id, with zero ids meaning unknown,
+/// and the repeats left plus one, with zero meaning there's no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub(crate) status: Status,
+    pub(crate) first_seen: i64,
+    /// Number of times the message was posted, including deleted copies.
+    pub(crate) count: u32,
+    pub(crate) first_message_id: Option<MessageId>,
+    pub(crate) poster_id: Option<UserId>,
+    /// Copies that may still be posted before an allowed message
+    /// is checked again, if it was only allowed a number of times.
+    pub(crate) repeats_left: Option<u32>,
+}
+
+impl Entry {
+    pub(crate) fn new(status: Status, post: Post) -> Self {
+        Self {
+            status,
+            first_seen: post.timestamp,
+            count: 1,
+            first_message_id: post.message_id,
+            poster_id: post.poster_id,
+            repeats_left: None,
+        }
+    }
+
+    pub(crate) fn decode(value: &[u8]) -> eyre::Result<Self> {
+        let repeats_left = match value.len() {
+            LEGACY_ENTRY_SIZE => 0,
+            ENTRY_SIZE => u32::from_le_bytes(value[25..29].try_into().unwrap()),
+            _ => eyre::bail!("malformed database entry: {value:?}"),
+        };
+        let status = match value[0] {
+            0 => Status::Seen,
+            1 => Status::Allowed,
+            2 => Status::Forbidden,
+            other => eyre::bail!("unknown entry status: {other}"),
+        };
+        let message_id = i32::from_le_bytes(value[13..17].try_into().unwrap());
+        let poster_id = u64::from_le_bytes(value[17..25].try_into().unwrap());
+        Ok(Self {
+            status,
+            first_seen: i64::from_le_bytes(value[1..9].try_into().unwrap()),
+            count: u32::from_le_bytes(value[9..13].try_into().unwrap()),
+            first_message_id: (message_id != 0).then_some(MessageId(message_id)),
+            poster_id: (poster_id != 0).then_some(UserId(poster_id)),
+            repeats_left: repeats_left.checked_sub(1),
+        })
+    }
+
+    pub(crate) fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut value = [0; ENTRY_SIZE];
+        value[0] = self.status as u8;
+        value[1..9].copy_from_slice(&self.first_seen.to_le_bytes());
+        value[9..13].copy_from_slice(&self.count.to_le_bytes());
+        let message_id = self.first_message_id.map_or(0, |id| id.0);
+        value[13..17].copy_from_slice(&message_id.to_le_bytes());
+        let poster_id = self.poster_id.map_or(0, |id| id.0);
+        value[17..25].copy_from_slice(&poster_id.to_le_bytes());
+        let repeats_left = self.repeats_left.map_or(0, |left| left.saturating_add(1));
+        value[25..29].copy_from_slice(&repeats_left.to_le_bytes());
+        value
+    }
+
+    /// Decodes an entry written before schema v2, which only knew about
+    /// the message being seen or allowed.
+    fn decode_v1(value: &[u8], migrated_at: i64) -> eyre::Result<Self> {
+        let seen = |first_seen, count| Entry {
+            status: Status::Seen,
+            first_seen,
+            count,
+            first_message_id: None,
+            poster_id: None,
+            repeats_left: None,
+        };
+        let timestamp = |bytes: &[u8]| i64::from_le_bytes(bytes.try_into().unwrap());
+        match value.len() {
+            // Forbidden messages were stored just like seen ones,
+            // so there's no way to tell them apart.
+            0 => Ok(seen(migrated_at, 1)),
+            1 if value[0] == 1 => Ok(Entry {
+                status: Status::Allowed,
+                ..seen(migrated_at, 1)
+            }),
+            8 => Ok(seen(timestamp(value), 1)),
+            12 => Ok(seen(
+                timestamp(&value[..8]),
+                u32::from_le_bytes(value[8..].try_into().unwrap()),
+            )),
+            // Left over from an interrupted migration.
+            LEGACY_ENTRY_SIZE | ENTRY_SIZE => Entry::decode(value),
+            _ => Err(eyre::eyre!("malformed v1 database entry: {value:?}")),
+        }
+    }
+}
+
+/// Schema version of the database. Empty ones count as current,
+/// and ones from before versions were recorded as v1.
+pub fn schema_version(db: &sled::Db) -> eyre::Result<u32> {
+    let meta = db.open_tree("meta")?;
+    Ok(match meta.get(SCHEMA_VERSION_KEY)? {
+        Some(version) => u32::from_le_bytes(
+            version
+                .as_ref()
+                .try_into()
+                .map_err(|_| eyre::eyre!("malformed schema version: {version:?}"))?,
+        ),
+        None if db.is_empty() => SCHEMA_VERSION,
+        None => 1,
+    })
+}
+
+/// Upgrades from every older schema version to the next one, in order.
+/// The one at index `i` upgrades from version `i + 1`.
+const MIGRATIONS: &[fn(&sled::Db) -> eyre::Result<()>] =
+    &[migrate_from_v1, migrate_from_v2, migrate_from_v3];
+
+/// Upgrades the database to the current schema, one version at a time.
+///
+/// The version is recorded after every step, so an interrupted upgrade
+/// picks up where it stopped.
+pub fn migrate(db: &sled::Db) -> eyre::Result<()> {
+    let meta = db.open_tree("meta")?;
+    let version = schema_version(db)?;
+    if version == 0 || version > SCHEMA_VERSION {
+        eyre::bail!(
+            "database has schema version {version}, but this build only supports up to {SCHEMA_VERSION}"
+        );
+    }
+
+    for (from, migration) in (version..).zip(&MIGRATIONS[version as usize - 1..]) {
+        migration(db)?;
+        meta.insert(SCHEMA_VERSION_KEY, &(from + 1).to_le_bytes())?;
+        db.flush()?;
+    }
+    // A fresh database starts out at the current version.
+    meta.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_le_bytes())?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Entries used to be empty values.
+fn migrate_from_v1(db: &sled::Db) -> eyre::Result<()> {
+    tracing::info!(entries = db.len(), "Migrating database from schema v1");
+    let migrated_at = unix_now();
+    let mut batch = sled::Batch::default();
+    let mut batch_len = 0;
+    for item in db.iter() {
+        let (key, value) = item?;
+        batch.insert(key, &Entry::decode_v1(&value, migrated_at)?.encode());
+        batch_len += 1;
+        if batch_len == 10_000 {
+            db.apply_batch(std::mem::take(&mut batch))?;
+            batch_len = 0;
+        }
+    }
+    db.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Keys used to be bare hashes, and there's no way to tell which chat
+/// they belong to, so they're adopted lazily by `SledStore::adopt_legacy`.
+fn migrate_from_v2(db: &sled::Db) -> eyre::Result<()> {
+    tracing::info!(entries = db.len(), "Migrating database from schema v2");
+    let legacy = db.open_tree("legacy")?;
+    let mut inserts = sled::Batch::default();
+    let mut removals = sled::Batch::default();
+    let mut batch_len = 0;
+    for item in db.iter() {
+        let (key, value) = item?;
+        if key.len() != 16 {
+            continue;
+        }
+        removals.remove(key.clone());
+        inserts.insert(key, value);
+        batch_len += 1;
+        if batch_len == 10_000 {
+            // Inserting first keeps an interrupted migration from losing entries.
+            legacy.apply_batch(std::mem::take(&mut inserts))?;
+            db.apply_batch(std::mem::take(&mut removals))?;
+            batch_len = 0;
+        }
+    }
+    legacy.apply_batch(inserts)?;
+    db.apply_batch(removals)?;
+    Ok(())
+}
+
+/// Appeals used to be keyed by their id alone. They're short-lived
+/// and don't know their chat, so pending ones are just dropped.
+fn migrate_from_v3(db: &sled::Db) -> eyre::Result<()> {
+    let appeals = db.open_tree("appeals")?;
+    tracing::info!(appeals = appeals.len(), "Dropping pending appeals");
+    appeals.clear()?;
+    Ok(())
+}
+
+/// Marks backups in the versioned format, as opposed to sled's export format
+/// written by older versions.
+const BACKUP_MAGIC: &[u8; 8] = b"R9KTGBAK";
+
+const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Largest a backup may get once decompressed, in bytes, so a crafted one
+/// can't take all the memory before it's validated.
+const MAX_BACKUP_SIZE: usize = 1 << 30;
+
+/// Name of a tree with its key-value pairs.
+type BackupTree = (Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>);
+
+/// Trees of the database as read from a backup.
+pub struct Backup {
+    /// Schema version of the database the backup was made from,
+    /// unknown for backups made by older versions.
+    pub(crate) schema_version: Option<u32>,
+    pub(crate) trees: Vec<BackupTree>,
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> eyre::Result<()> {
+    out.extend_from_slice(&u32::try_from(bytes.len())?.to_le_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> eyre::Result<&'a [u8]> {
+    let (len, rest) = input
+        .split_first_chunk::<4>()
+        .ok_or_else(|| eyre::eyre!("truncated backup"))?;
+    let len = u32::from_le_bytes(*len) as usize;
+    eyre::ensure!(rest.len() >= len, "truncated backup");
+    let (bytes, rest) = rest.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_u32(input: &mut &[u8]) -> eyre::Result<u32> {
+    let (value, rest) = input
+        .split_first_chunk::<4>()
+        .ok_or_else(|| eyre::eyre!("truncated backup"))?;
+    *input = rest;
+    Ok(u32::from_le_bytes(*value))
+}
+
+/// Reads key-value pairs, each prefixed with a one byte, up to a zero byte.
+fn read_pairs(input: &mut &[u8]) -> eyre::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    loop {
+        let Some((&marker, rest)) = input.split_first() else {
+            eyre::bail!("truncated backup");
+        };
+        *input = rest;
+        if marker == 0 {
+            return Ok(pairs);
+        }
+        let key = read_bytes(input)?.to_vec();
+        let value = read_bytes(input)?.to_vec();
+        pairs.push((key, value));
+    }
+}
+
+/// Serializes every tree of the database into a zlib-compressed backup,
+/// which doesn't depend on how sled lays out its files.
+///
+/// The backup starts with [`BACKUP_MAGIC`], the little-endian `u32` format
+/// and schema versions. Every tree is then written as its name followed
+/// by its key-value pairs, each prefixed with a one byte, and a zero byte
+/// at the end. All byte strings are prefixed with their little-endian
+/// `u32` length.
+///
+/// sled has no snapshots, so entries changed while the backup is made
+/// may end up either old or new, but each of them is intact.
+pub fn write_backup(db: &sled::Db) -> eyre::Result<Vec<u8>> {
+    let mut out = BACKUP_MAGIC.to_vec();
+    out.extend_from_slice(&BACKUP_FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&schema_version(db)?.to_le_bytes());
+    for name in db.tree_names() {
+        write_bytes(&mut out, &name)?;
+        for item in db.open_tree(&name)?.iter() {
+            let (key, value) = item?;
+            out.push(1);
+            write_bytes(&mut out, &key)?;
+            write_bytes(&mut out, &value)?;
+        }
+        out.push(0);
+    }
+    Ok(miniz_oxide::deflate::compress_to_vec_zlib(&out, 6))
+}
+
+fn read_backup_data(backup: &[u8], limit: usize) -> eyre::Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(backup, limit).map_err(|status| {
+        match status {
+            TINFLStatus::HasMoreOutput => {
+                eyre::eyre!("decompressed backup is larger than the limit of {limit} bytes")
+            }
+            status => eyre::eyre!("corrupted backup: {status:?}"),
+        }
+    })
+}
+
+/// Parses a backup made by [`write_backup`], or in sled's export format
+/// by older versions.
+pub fn read_backup(backup: &[u8]) -> eyre::Result<Backup> {
+    let data = read_backup_data(backup, MAX_BACKUP_SIZE)?;
+    let mut input = &data[..];
+    let mut trees = Vec::new();
+    let Some(rest) = input.strip_prefix(BACKUP_MAGIC) else {
+        // Every tree of sled's export is prefixed with its type.
+        while !input.is_empty() {
+            let collection_type = read_bytes(&mut input)?;
+            eyre::ensure!(
+                collection_type == b"tree",
+                "unknown collection type in backup: {collection_type:?}"
+            );
+            let name = read_bytes(&mut input)?.to_vec();
+            trees.push((name, read_pairs(&mut input)?));
+        }
+        return Ok(Backup {
+            schema_version: None,
+            trees,
+        });
+    };
+    input = rest;
+    let format_version = read_u32(&mut input)?;
+    if format_version != BACKUP_FORMAT_VERSION {
+        eyre::bail!(
+            "backup has format version {format_version}, but this build only supports {BACKUP_FORMAT_VERSION}"
+        );
+    }
+    let schema_version = read_u32(&mut input)?;
+    if schema_version == 0 || schema_version > SCHEMA_VERSION {
+        eyre::bail!(
+            "backup has schema version {schema_version}, but this build only supports up to {SCHEMA_VERSION}"
+        );
+    }
+    while !input.is_empty() {
+        let name = read_bytes(&mut input)?.to_vec();
+        trees.push((name, read_pairs(&mut input)?));
+    }
+    Ok(Backup {
+        schema_version: Some(schema_version),
+        trees,
+    })
+}
+
+/// Writes the trees of a backup into the database, overwriting what's there.
+///
+/// Backups made by older versions are migrated on the next start.
+pub fn restore_into(db: &sled::Db, backup: Backup) -> eyre::Result<usize> {
+    let mut restored = 0;
+    for (name, pairs) in backup.trees {
+        let tree = db.open_tree(name)?;
+        let mut batch = sled::Batch::default();
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            batch.insert(key, value);
+            restored += 1;
+            if (i + 1) % 10_000 == 0 {
+                tree.apply_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        tree.apply_batch(batch)?;
+    }
+    db.flush()?;
+    Ok(restored)
+}
+
+impl Robot9000 {
+    /// Replaces the salt of a namespace, so no message posted before is recognized anymore.
+    pub(crate) fn rotate_salt(&self, namespace: Namespace) -> eyre::Result<[u8; 16]> {
+        let mut salt = [0; 16];
+        getrandom::getrandom(&mut salt)?;
+        self.salts.insert(namespace.prefix(), &salt)?;
+        Ok(salt)
+    }
+
+    fn member_key(chat_id: ChatId, user_id: UserId) -> [u8; 16] {
+        let mut key = [0; 16];
+        key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+        key[8..].copy_from_slice(&user_id.0.to_be_bytes());
+        key
+    }
+
+    pub(crate) fn is_exempt(&self, chat_id: ChatId, user_id: UserId) -> eyre::Result<bool> {
+        Ok(self
+            .exemptions
+            .contains_key(Self::member_key(chat_id, user_id))?)
+    }
+
+    pub(crate) fn set_exempt(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        exempt: bool,
+    ) -> eyre::Result<()> {
+        let key = Self::member_key(chat_id, user_id);
+        if exempt {
+            self.exemptions.insert(key, &[])?;
+        } else {
+            self.exemptions.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_moderator(&self, chat_id: ChatId, user_id: UserId) -> eyre::Result<bool> {
+        Ok(self
+            .moderators
+            .contains_key(Self::member_key(chat_id, user_id))?)
+    }
+
+    pub(crate) fn set_moderator(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        moderator: bool,
+    ) -> eyre::Result<()> {
+        let key = Self::member_key(chat_id, user_id);
+        if moderator {
+            self.moderators.insert(key, &[])?;
+        } else {
+            self.moderators.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_paused(&self, chat_id: ChatId, now: i64) -> eyre::Result<bool> {
+        let Some(until) = self.pauses.get(chat_id.0.to_be_bytes())? else {
+            return Ok(false);
+        };
+        Ok(i64::from_be_bytes(until[..].try_into()?) > now)
+    }
+
+    /// Pauses enforcement in a chat until the given unix time, or resumes it with `None`.
+    pub(crate) fn set_pause(&self, chat_id: ChatId, until: Option<i64>) -> eyre::Result<()> {
+        match until {
+            Some(until) => self
+                .pauses
+                .insert(chat_id.0.to_be_bytes(), &until.to_be_bytes())?,
+            None => self.pauses.remove(chat_id.0.to_be_bytes())?,
+        };
+        Ok(())
+    }
+
+    /// Overrides a setting in a chat, or goes back to the config with `None`.
+    pub(crate) fn set_setting(
+        &self,
+        chat_id: ChatId,
+        setting: Setting,
+        value: Option<&str>,
+    ) -> eyre::Result<()> {
+        let mut key = chat_id.0.to_be_bytes().to_vec();
+        key.extend_from_slice(setting.name().as_bytes());
+        match value {
+            Some(value) => self.chat_settings.insert(key, value)?,
+            None => self.chat_settings.remove(key)?,
+        };
+        Ok(())
+    }
+
+    /// Remembers a posted message.
+    pub(crate) async fn store_hash(
+        &self,
+        key: Key,
+        post: Post,
+        settings: &Settings,
+    ) -> eyre::Result<Entry> {
+        let entry = self
+            .store
+            .update(key, settings.dedup_window, &mut |entry| {
+                settings.next_entry(entry, post)
+            })
+            .await?;
+        Ok(entry.expect("entry is always created"))
+    }
+
+    /// Sets the status of a message, remembering it as posted by `post` if it's new.
+    /// Allowed messages can be limited to `repeats` more copies.
+    pub(crate) async fn set_hash_status(
+        &self,
+        key: Key,
+        status: Status,
+        repeats: Option<u32>,
+        post: Post,
+    ) -> eyre::Result<()> {
+        self.store
+            .update(key, None, &mut |entry| {
+                Some(Entry {
+                    status,
+                    repeats_left: repeats.filter(|_| status == Status::Allowed),
+                    ..entry.unwrap_or_else(|| Entry::new(status, post))
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets every message in a namespace. Returns how many there were.
+    pub(crate) async fn reset_namespace(&self, namespace: Namespace) -> eyre::Result<usize> {
+        // Appeals would allow messages that aren't known anymore.
+        remove_prefix(&self.appeals, namespace.prefix())?;
+        self.forget_namespace_texts(namespace)?;
+        self.forget_signatures(namespace)?;
+        self.store.clear(namespace).await
+    }
+
+    /// Runs [`Robot9000::maintain`] every `MAINTENANCE_INTERVAL`.
+    pub(crate) async fn maintain_periodically(self) {
+        let mut ticks = tokio::time::interval(MAINTENANCE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            self.maintain().await;
+        }
+    }
+
+    /// Forgets messages out of the dedup window, then keeps the store
+    /// within `max_entries`, if it's set, and drops texts, signatures,
+    /// message keys and newcomers nobody needs anymore.
+    /// Returns how many entries were removed in total.
+    ///
+    /// Failed steps are logged and skipped, so the others still run.
+    pub(crate) async fn maintain(&self) -> usize {
+        let mut removed = 0;
+        match self.remove_expired(unix_now()).await {
+            Ok(0) => {}
+            Ok(expired) => {
+                tracing::info!(expired, "forgot expired messages");
+                removed += expired;
+            }
+            Err(err) => tracing::error!(err = format_args!("{err}"), "expiry sweep failed"),
+        }
+        if let Some(max_entries) = self.config().max_entries {
+            match self.store.evict(max_entries).await {
+                Ok(0) => {}
+                Ok(evicted) => {
+                    tracing::info!(evicted, "forgot oldest messages");
+                    removed += evicted;
+                }
+                Err(err) => tracing::error!(err = format_args!("{err}"), "eviction failed"),
+            }
+        }
+        match self.prune_texts().await {
+            Ok(0) => {}
+            Ok(pruned) => {
+                tracing::info!(pruned, "forgot texts");
+                removed += pruned;
+            }
+            Err(err) => tracing::error!(err = format_args!("{err}"), "pruning texts failed"),
+        }
+        match self.prune_newcomers(unix_now()) {
+            Ok(0) => {}
+            Ok(pruned) => {
+                tracing::info!(pruned, "forgot newcomers");
+                removed += pruned;
+            }
+            Err(err) => {
+                tracing::error!(err = format_args!("{err}"), "pruning newcomers failed")
+            }
+        }
+        match self.prune_message_keys().await {
+            Ok(0) => {}
+            Ok(pruned) => {
+                tracing::info!(pruned, "forgot message keys");
+                removed += pruned;
+            }
+            Err(err) => {
+                tracing::error!(err = format_args!("{err}"), "pruning message keys failed")
+            }
+        }
+        match self.prune_signatures().await {
+            Ok(0) => {}
+            Ok(pruned) => {
+                tracing::info!(pruned, "forgot signatures");
+                removed += pruned;
+            }
+            Err(err) => {
+                tracing::error!(err = format_args!("{err}"), "pruning signatures failed")
+            }
+        }
+        removed
+    }
+
+    /// Forgets messages of every known chat that are out of its dedup window at `now`.
+    ///
+    /// Chats sharing known messages may have different windows,
+    /// so the shared namespace is swept with the longest one.
+    pub(crate) async fn remove_expired(&self, now: i64) -> eyre::Result<usize> {
+        let config = self.config();
+        let mut chats = config
+            .shared_chats
+            .iter()
+            .copied()
+            .map(ChatId)
+            .collect::<Vec<_>>();
+        for key in self.chats.iter().keys() {
+            chats.push(ChatId(i64::from_be_bytes(key?[..].try_into()?)));
+        }
+        chats.sort_unstable();
+        chats.dedup();
+
+        let mut removed = 0;
+        // Stays `Some(None)` if a shared chat never expires messages.
+        let mut shared_window = None;
+        for chat_id in chats {
+            let window = self.settings(chat_id)?.dedup_window;
+            match self.namespace(chat_id) {
+                Namespace::Shared => {
+                    shared_window = Some(match (shared_window, window) {
+                        (None, window) => window,
+                        (Some(Some(longest)), Some(window)) => Some(window.max(longest)),
+                        (Some(_), _) => None,
+                    });
+                }
+                namespace => {
+                    if let Some(window) = window {
+                        removed += self.store.remove_expired(namespace, now - window).await?;
+                    }
+                }
+            }
+        }
+        if let Some(Some(window)) = shared_window {
+            removed += self
+                .store
+                .remove_expired(Namespace::Shared, now - window)
+                .await?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_from_v1() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        db.insert([1; 16], &[])?;
+        db.insert([2; 16], &7_i64.to_le_bytes())?;
+        db.open_tree("appeals")?.insert("appeal", &[])?;
+        assert_eq!(schema_version(&db)?, 1);
+
+        migrate(&db)?;
+        assert_eq!(schema_version(&db)?, SCHEMA_VERSION);
+        assert!(db.is_empty());
+        assert!(db.open_tree("appeals")?.is_empty());
+        let legacy = db.open_tree("legacy")?;
+        let entry = Entry::decode(&legacy.get([2; 16])?.unwrap())?;
+        assert_eq!((entry.status, entry.first_seen), (Status::Seen, 7));
+        assert_eq!(legacy.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_decompressed_backups() -> eyre::Result<()> {
+        let bomb = miniz_oxide::deflate::compress_to_vec_zlib(&[0; 1 << 20], 10);
+        assert!(bomb.len() < 4096);
+        let err = read_backup_data(&bomb, 1 << 16).unwrap_err();
+        assert!(err.to_string().contains("larger than the limit"));
+        assert_eq!(read_backup_data(&bomb, 1 << 21)?.len(), 1 << 20);
+        Ok(())
+    }
+
+    #[test]
+    fn restores_backups() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        migrate(&db)?;
+        db.insert("message", "entry")?;
+        db.open_tree("appeals")?.insert("appeal", "")?;
+        let backup = read_backup(&write_backup(&db)?)?;
+        assert_eq!(backup.schema_version, Some(SCHEMA_VERSION));
+
+        let restored = sled::Config::new().temporary(true).open()?;
+        restore_into(&restored, backup)?;
+        assert_eq!(schema_version(&restored)?, SCHEMA_VERSION);
+        assert_eq!(restored.get("message")?.as_deref(), Some(&b"entry"[..]));
+        assert!(restored.open_tree("appeals")?.contains_key("appeal")?);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_schema() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        db.open_tree("meta")?
+            .insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_le_bytes())?;
+        assert!(migrate(&db).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,659 @@
+//! Backends the known messages can be stored in.
+
+use std::{collections::BinaryHeap, sync::Arc};
+
+use color_eyre::eyre;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt as _, StreamExt as _, TryStreamExt as _,
+};
+use serde::Deserialize;
+use sled::CompareAndSwapError;
+
+use self::bloom::Bloom;
+pub(crate) use self::cipher::prepare as prepare_encryption;
+#[cfg(feature = "redb")]
+pub use self::redb::RedbStore;
+pub use self::{
+    cipher::Cipher,
+    memory::MemoryStore,
+    redis::RedisStore,
+    replicated::{Change, ReplicatedStore, ReplicationLog},
+    sql::SqlStore,
+};
+use crate::{
+    config::Config,
+    storage::{messages_tree, open_tree, remove_prefix, Entry, Key, Namespace, Status},
+};
+
+mod bloom;
+mod cipher;
+mod memory;
+#[cfg(feature = "redb")]
+mod redb;
+mod redis;
+mod replicated;
+mod sql;
+
+/// Which backend the known messages are stored in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// The same sled database as everything else.
+    #[default]
+    Sled,
+    /// Redis server at `redis_url`, which several bots can share.
+    Redis,
+    /// Nowhere, forgetting everything once the bot stops.
+    Memory,
+    /// redb database at `redb_path`, if built with the `redb` feature.
+    Redb,
+    /// SQLite or Postgres database at `sql_url`, in columns that can be queried.
+    Sql,
+}
+
+/// Function deciding the next entry in [`MessageStore::update`].
+pub type Update<'a> = dyn FnMut(Option<Entry>) -> Option<Entry> + Send + 'a;
+
+/// Known messages with their entries.
+pub trait MessageStore: Send + Sync {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>>;
+
+    /// Like `get`, but never changes anything, so it's safe for dry runs.
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>>;
+
+    /// Atomically replaces the entry for `key` with the result of `f`,
+    /// unless it returns `None`. Returns the resulting entry.
+    ///
+    /// `f` may be called again if the entry changes in the meantime.
+    /// Seen entries expire `window` seconds after they're first seen,
+    /// so the store may drop them by then. Stores that keep them until
+    /// a sweep overwrite expired entries with the next copy instead.
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>>;
+
+    /// Writes many entries at once, overwriting what's there.
+    /// `window` is the same as in `update`.
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>>;
+
+    /// Forgets a message. Returns whether it was known.
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>>;
+
+    /// Forgets every message in a namespace. Returns how many there were.
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>>;
+
+    /// Forgets seen messages of a namespace first seen at or before `seen_until`,
+    /// since they're out of the window. Stores that expire entries on their own
+    /// may do nothing. Returns how many were forgotten.
+    fn remove_expired(
+        &self,
+        namespace: Namespace,
+        seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>>;
+
+    /// Every message known in a namespace.
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>>;
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>>;
+
+    /// Number of messages known in every namespace.
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>>;
+
+    /// Forgets the longest known seen messages until at most `max_entries`
+    /// are left, never touching allowed or forbidden ones.
+    /// Returns how many were forgotten.
+    fn evict(&self, max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>>;
+
+    /// Makes sure everything written so far is persisted.
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>>;
+}
+
+/// Opens the store picked by the config. Its state may live in `db` too.
+/// Entries are encrypted with `cipher` where they're persisted.
+pub fn open(
+    config: &Config,
+    db: &sled::Db,
+    cipher: Option<Arc<Cipher>>,
+) -> eyre::Result<Arc<dyn MessageStore>> {
+    let bot_name = config.bot_name.as_deref();
+    match config.storage {
+        Storage::Sled => Ok(Arc::new(SledStore::open(db, bot_name, cipher)?)),
+        Storage::Redis => {
+            let url = config
+                .redis_url
+                .as_ref()
+                .ok_or_else(|| eyre::eyre!("`storage = \"redis\"` needs a `redis_url`"))?;
+            Ok(Arc::new(RedisStore::new(url, bot_name, cipher)?))
+        }
+        Storage::Memory => Ok(Arc::new(MemoryStore::new())),
+        #[cfg(feature = "redb")]
+        Storage::Redb => {
+            let path = config
+                .redb_path
+                .as_ref()
+                .ok_or_else(|| eyre::eyre!("`storage = \"redb\"` needs a `redb_path`"))?;
+            Ok(Arc::new(RedbStore::open(path, bot_name, cipher)?))
+        }
+        #[cfg(not(feature = "redb"))]
+        Storage::Redb => {
+            eyre::bail!("`storage = \"redb\"` needs the bot built with the `redb` feature")
+        }
+        Storage::Sql => {
+            let url = config
+                .sql_url
+                .as_ref()
+                .ok_or_else(|| eyre::eyre!("`storage = \"sql\"` needs an `sql_url`"))?;
+            if cipher.is_some() {
+                eyre::bail!(
+                    "`storage = \"sql\"` keeps entries in the clear, so it can't have an `encryption_key`"
+                );
+            }
+            Ok(Arc::new(SqlStore::new(url, bot_name)?))
+        }
+    }
+}
+
+/// Picks the keys of the `count` entries seen first, out of seen entries
+/// given with the time they were first seen.
+fn oldest<K: Ord>(
+    seen: impl Iterator<Item = eyre::Result<(K, i64)>>,
+    count: usize,
+) -> eyre::Result<Vec<K>> {
+    // Newest of the oldest so far on top, to be replaced by older ones.
+    let mut oldest = BinaryHeap::with_capacity(count + 1);
+    for item in seen {
+        let (key, first_seen) = item?;
+        oldest.push((first_seen, key));
+        if oldest.len() > count {
+            oldest.pop();
+        }
+    }
+    Ok(oldest.into_iter().map(|(_, key)| key).collect())
+}
+
+/// How many entries [`SledStore::entries`] reads in one go.
+const SCAN_BATCH: usize = 1000;
+
+/// Runs blocking sled calls on a thread meant for them, so disk I/O
+/// doesn't stall the async executor.
+fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> eyre::Result<T> + Send + 'static,
+) -> BoxFuture<'static, eyre::Result<T>> {
+    async move { tokio::task::spawn_blocking(f).await? }.boxed()
+}
+
+/// Known messages in a tree of the sled database, which is the default one
+/// for the main bot.
+#[derive(Clone)]
+pub struct SledStore {
+    tree: sled::Tree,
+    /// Entries from before keys had namespaces.
+    legacy: sled::Tree,
+    /// Namespaces reset since then, which must not adopt legacy entries.
+    legacy_resets: sled::Tree,
+    /// Every key stored, so unique messages can skip the disk.
+    known: Arc<Bloom>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl SledStore {
+    /// Opens the store, reading every key to fill the filter of known ones.
+    pub fn open(
+        db: &sled::Db,
+        bot_name: Option<&str>,
+        cipher: Option<Arc<Cipher>>,
+    ) -> eyre::Result<Self> {
+        let tree = messages_tree(db, bot_name)?;
+        let known = Bloom::with_capacity(tree.len());
+        for key in tree.iter().keys() {
+            known.insert(&key?);
+        }
+        tracing::debug!("Loaded known keys");
+        Ok(Self {
+            tree,
+            legacy: open_tree(db, bot_name, "legacy")?,
+            legacy_resets: open_tree(db, bot_name, "legacy_resets")?,
+            known: Arc::new(known),
+            cipher,
+        })
+    }
+
+    fn encode(&self, db_key: &[u8], entry: &Entry) -> eyre::Result<Vec<u8>> {
+        cipher::encode(self.cipher.as_deref(), db_key, entry)
+    }
+
+    fn decode(&self, db_key: &[u8], value: &[u8]) -> eyre::Result<Entry> {
+        cipher::decode(self.cipher.as_deref(), db_key, value)
+    }
+
+    /// Checks whether `key` is surely not stored, without touching the disk.
+    fn is_unknown(&self, key: Key) -> bool {
+        // Legacy entries are only added to the filter once adopted.
+        !self.known.may_contain(&key.encode()) && self.legacy.is_empty()
+    }
+
+    /// Moves the entry for `key` from before keys had namespaces, if there's one.
+    ///
+    /// Legacy hashes include the chat id just like current ones,
+    /// so each of them can only ever be found through a single namespace.
+    fn adopt_legacy(&self, key: Key) -> eyre::Result<()> {
+        if self.legacy.is_empty() || self.legacy_resets.contains_key(key.namespace.prefix())? {
+            return Ok(());
+        }
+        if let Some(value) = self.legacy.remove(key.hash)? {
+            self.known.insert(&key.encode());
+            // Encrypted entries are bound to their key, which changes here.
+            let value = self.encode(&key.encode(), &self.decode(&key.hash, &value)?)?;
+            // Anything stored in the meantime is newer, so it wins.
+            let _ = self
+                .tree
+                .compare_and_swap(key.encode(), None::<&[u8]>, Some(value))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: Key) -> eyre::Result<Option<Entry>> {
+        if self.is_unknown(key) {
+            return Ok(None);
+        }
+        self.adopt_legacy(key)?;
+        let value = self.tree.get(key.encode())?;
+        value
+            .map(|value| self.decode(&key.encode(), &value))
+            .transpose()
+    }
+
+    /// Like `get`, but leaves legacy entries where they are.
+    fn peek(&self, key: Key) -> eyre::Result<Option<Entry>> {
+        if self.is_unknown(key) {
+            return Ok(None);
+        }
+        if let Some(value) = self.tree.get(key.encode())? {
+            return Ok(Some(self.decode(&key.encode(), &value)?));
+        }
+        if self.legacy_resets.contains_key(key.namespace.prefix())? {
+            return Ok(None);
+        }
+        let value = self.legacy.get(key.hash)?;
+        value
+            .map(|value| self.decode(&key.hash, &value))
+            .transpose()
+    }
+
+    fn insert_many(&self, entries: Vec<(Key, Entry)>) -> eyre::Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, entry) in entries {
+            let key = key.encode();
+            self.known.insert(&key);
+            batch.insert(&key[..], self.encode(&key, &entry)?);
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: Key) -> eyre::Result<bool> {
+        let removed = self.tree.remove(key.encode())?.is_some();
+        let removed_legacy = self.legacy.remove(key.hash)?.is_some();
+        Ok(removed || removed_legacy)
+    }
+
+    /// Entries from before keys had namespaces are neither counted nor evicted.
+    fn evict(&self, max_entries: usize) -> eyre::Result<usize> {
+        let Some(excess) = self.tree.len().checked_sub(max_entries).filter(|&n| n > 0) else {
+            return Ok(0);
+        };
+        let seen = self.tree.iter().filter_map(|item| {
+            let result = item.map_err(eyre::Report::from).and_then(|(key, value)| {
+                let entry = self.decode(&key, &value)?;
+                Ok((entry.status == Status::Seen).then_some((key, entry.first_seen)))
+            });
+            result.transpose()
+        });
+        let keys = oldest(seen, excess)?;
+        let mut batch = sled::Batch::default();
+        for key in &keys {
+            batch.remove(key);
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(keys.len())
+    }
+
+    /// Removes with compare-and-swap, so a message posted again meanwhile
+    /// isn't forgotten with its fresh entry.
+    fn remove_expired(&self, namespace: Namespace, seen_until: i64) -> eyre::Result<usize> {
+        let mut removed = 0;
+        for item in self.tree.scan_prefix(namespace.prefix()) {
+            let (key, value) = item?;
+            let entry = self.decode(&key, &value)?;
+            if entry.status == Status::Seen
+                && entry.first_seen <= seen_until
+                && self
+                    .tree
+                    .compare_and_swap(&key, Some(&value), None as Option<&[u8]>)?
+                    .is_ok()
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn clear(&self, namespace: Namespace) -> eyre::Result<usize> {
+        // Legacy entries can't be told apart by namespace, so they're
+        // just never adopted again.
+        self.legacy_resets.insert(namespace.prefix(), &[])?;
+        remove_prefix(&self.tree, namespace.prefix())
+    }
+}
+
+impl MessageStore for SledStore {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        let store = self.clone();
+        blocking(move || store.get(key))
+    }
+
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        let store = self.clone();
+        blocking(move || store.peek(key))
+    }
+
+    /// `f` runs on the async side, in between the blocking reads and writes.
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        _window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>> {
+        async move {
+            let store = self.clone();
+            let mut current = if self.is_unknown(key) {
+                None
+            } else {
+                blocking(move || {
+                    store.adopt_legacy(key)?;
+                    Ok(store.tree.get(key.encode())?)
+                })
+                .await?
+            };
+            let db_key = key.encode();
+            loop {
+                let entry = current
+                    .as_deref()
+                    .map(|value| self.decode(&db_key, value))
+                    .transpose()?;
+                let Some(next) = f(entry) else {
+                    return Ok(entry);
+                };
+                // Added before the write, so concurrent lookups can't miss it.
+                self.known.insert(&db_key);
+                let store = self.clone();
+                let value = self.encode(&db_key, &next)?;
+                let swapped = blocking(move || {
+                    Ok(store.tree.compare_and_swap(db_key, current, Some(value))?)
+                })
+                .await?;
+                match swapped {
+                    Ok(()) => return Ok(Some(next)),
+                    Err(CompareAndSwapError {
+                        current: actual, ..
+                    }) => current = actual,
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        _window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        let store = self.clone();
+        blocking(move || store.insert_many(entries))
+    }
+
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>> {
+        let store = self.clone();
+        blocking(move || store.remove(key))
+    }
+
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.clear(namespace))
+    }
+
+    fn remove_expired(
+        &self,
+        namespace: Namespace,
+        seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.remove_expired(namespace, seen_until))
+    }
+
+    /// Entries from before keys had namespaces are only included once adopted.
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>> {
+        let scan = self.tree.scan_prefix(namespace.prefix());
+        let store = self.clone();
+        stream::try_unfold(Some(scan), move |scan| {
+            let store = store.clone();
+            async move {
+                let Some(mut scan) = scan else {
+                    return Ok::<_, eyre::Report>(None);
+                };
+                let (batch, done) = blocking(move || {
+                    let batch = scan
+                        .by_ref()
+                        .take(SCAN_BATCH)
+                        .map(|item| {
+                            let (key, value) = item?;
+                            Ok((Key::decode(&key)?, store.decode(&key, &value)?))
+                        })
+                        .collect::<eyre::Result<Vec<_>>>()?;
+                    let done = batch.len() < SCAN_BATCH;
+                    Ok((batch, (!done).then_some(scan)))
+                })
+                .await?;
+                Ok(Some((stream::iter(batch.into_iter().map(Ok)), done)))
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>> {
+        let tree = self.tree.clone();
+        blocking(move || Ok(tree.is_empty()))
+    }
+
+    /// Entries from before keys had namespaces are only counted once adopted.
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>> {
+        let tree = self.tree.clone();
+        blocking(move || Ok(tree.len()))
+    }
+
+    fn evict(&self, max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.evict(max_entries))
+    }
+
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            self.tree.flush_async().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::ChatId;
+    use url::Url;
+
+    use super::*;
+    use crate::storage::{Post, Status};
+
+    fn key(chat_id: i64, hash: u8) -> Key {
+        Key {
+            namespace: Namespace::Chat(ChatId(chat_id)),
+            hash: [hash; 16],
+        }
+    }
+
+    fn seen(timestamp: i64) -> Entry {
+        let post = Post {
+            timestamp,
+            message_id: None,
+            poster_id: None,
+        };
+        Entry::new(Status::Seen, post)
+    }
+
+    async fn exercise(store: &dyn MessageStore) -> eyre::Result<()> {
+        assert!(store.is_empty().await?);
+        assert_eq!(store.get(key(-1, 1)).await?, None);
+
+        let created = store
+            .update(key(-1, 1), None, &mut |_| Some(seen(10)))
+            .await?;
+        assert_eq!(created, Some(seen(10)));
+        let unchanged = store.update(key(-1, 1), None, &mut |_| None).await?;
+        assert_eq!(unchanged, Some(seen(10)));
+        let bumped = store
+            .update(key(-1, 1), None, &mut |entry| {
+                entry.map(|entry| Entry {
+                    count: entry.count + 1,
+                    ..entry
+                })
+            })
+            .await?;
+        assert_eq!(bumped.map(|entry| entry.count), Some(2));
+        assert_eq!(store.peek(key(-1, 1)).await?, bumped);
+
+        store
+            .insert_many(vec![(key(-1, 2), seen(20)), (key(-2, 1), seen(30))], None)
+            .await?;
+        let mut entries = store
+            .entries(Namespace::Chat(ChatId(-1)))
+            .try_collect::<Vec<_>>()
+            .await?;
+        entries.sort_by_key(|(key, _)| key.hash);
+        assert_eq!(
+            entries,
+            [(key(-1, 1), bumped.unwrap()), (key(-1, 2), seen(20))]
+        );
+
+        assert!(store.remove(key(-1, 2)).await?);
+        assert!(!store.remove(key(-1, 2)).await?);
+        assert_eq!(store.clear(Namespace::Chat(ChatId(-1))).await?, 1);
+        assert_eq!(store.get(key(-1, 1)).await?, None);
+        assert_eq!(store.get(key(-2, 1)).await?, Some(seen(30)));
+        assert!(!store.is_empty().await?);
+
+        let forbidden = Entry {
+            status: Status::Forbidden,
+            ..seen(5)
+        };
+        store
+            .insert_many(
+                vec![
+                    (key(-3, 1), seen(40)),
+                    (key(-3, 2), seen(50)),
+                    (key(-3, 3), forbidden),
+                ],
+                None,
+            )
+            .await?;
+        assert_eq!(store.evict(4).await?, 0);
+        assert_eq!(store.evict(2).await?, 2);
+        assert_eq!(store.peek(key(-2, 1)).await?, None);
+        assert_eq!(store.peek(key(-3, 1)).await?, None);
+        assert_eq!(store.peek(key(-3, 2)).await?, Some(seen(50)));
+        assert_eq!(store.peek(key(-3, 3)).await?, Some(forbidden));
+
+        let chat = Namespace::Chat(ChatId(-3));
+        assert_eq!(store.remove_expired(chat, 49).await?, 0);
+        assert_eq!(store.remove_expired(chat, 50).await?, 1);
+        assert_eq!(store.peek(key(-3, 2)).await?, None);
+        assert_eq!(store.peek(key(-3, 3)).await?, Some(forbidden));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_store() -> eyre::Result<()> {
+        exercise(&MemoryStore::new()).await
+    }
+
+    #[tokio::test]
+    async fn sled_store() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        exercise(&SledStore::open(&db, None, None)?).await
+    }
+
+    #[cfg(feature = "redb")]
+    #[tokio::test]
+    async fn redb_store() -> eyre::Result<()> {
+        let path = std::env::temp_dir().join(format!("r9ktg-test-redb-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let result = async {
+            exercise(&RedbStore::open(&path, None, None)?).await?;
+            // Another bot's table in the same file, opened through the same handle.
+            scans_in_batches(&RedbStore::open(&path, Some("other"), None)?).await
+        }
+        .await;
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn sql_store() -> eyre::Result<()> {
+        let path = std::env::temp_dir().join(format!("r9ktg-test-sql-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = Url::parse(&format!("sqlite://{}?mode=rwc", path.display()))?;
+        let result = async {
+            exercise(&SqlStore::new(&url, None)?).await?;
+            // Another bot's rows in the same table.
+            scans_in_batches(&SqlStore::new(&url, Some("other"))?).await
+        }
+        .await;
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    async fn scans_in_batches(store: &dyn MessageStore) -> eyre::Result<()> {
+        let entries = (0..SCAN_BATCH as u32 * 2 + 1)
+            .map(|i| {
+                let mut key = key(-1, 0);
+                key.hash[..4].copy_from_slice(&i.to_be_bytes());
+                (key, seen(i.into()))
+            })
+            .collect::<Vec<_>>();
+        store.insert_many(entries.clone(), None).await?;
+        let scanned = store
+            .entries(Namespace::Chat(ChatId(-1)))
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(scanned, entries);
+        assert_eq!(
+            store.clear(Namespace::Chat(ChatId(-1))).await?,
+            entries.len()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sled_store_scans_in_batches() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        scans_in_batches(&SledStore::open(&db, None, None)?).await
+    }
+}
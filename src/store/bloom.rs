@@ -0,0 +1,71 @@
+//! Bloom filter of known keys, so looking up a message that was never seen
+//! doesn't have to touch the disk.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Bits per expected key, which with `HASHES` gives about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+
+const HASHES: u64 = 7;
+
+/// The filter never expects fewer keys than this, so a fresh database
+/// doesn't start with a tiny one.
+const MIN_KEYS: usize = 1 << 20;
+
+/// Set of keys that may have false positives, but never false negatives.
+/// Keys can't be removed, so forgotten ones just become false positives.
+pub struct Bloom {
+    bits: Box<[AtomicU64]>,
+}
+
+impl Bloom {
+    /// Empty filter with room for twice as many keys as `expected`.
+    pub fn with_capacity(expected: usize) -> Self {
+        let keys = expected.saturating_mul(2).max(MIN_KEYS);
+        let words = (keys * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Bit positions of a key, by double hashing.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = xxh3_128(key);
+        let (h1, h2) = (hash as u64, (hash >> 64) as u64 | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn insert(&self, key: &[u8]) {
+        for position in self.positions(key) {
+            self.bits[position / 64].fetch_or(1 << (position % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns `false` only if the key was never inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key).all(|position| {
+            self.bits[position / 64].load(Ordering::Relaxed) & (1 << (position % 64)) != 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let bloom = Bloom::with_capacity(0);
+        for i in 0_u32..10_000 {
+            bloom.insert(&i.to_be_bytes());
+        }
+        assert!((0_u32..10_000).all(|i| bloom.may_contain(&i.to_be_bytes())));
+        let false_positives = (10_000_u32..20_000)
+            .filter(|i| bloom.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 100, "{false_positives} false positives");
+    }
+}
@@ -0,0 +1,265 @@
+//! Encryption of stored entries, so a stolen database can't be used
+//! to confirm whether specific texts were posted in specific chats.
+
+use color_eyre::eyre;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hmac,
+};
+
+use super::Storage;
+use crate::storage::{messages_tree, open_tree, Entry, Key, ENTRY_SIZE, LEGACY_ENTRY_SIZE};
+
+/// Size of an encrypted entry: its nonce, the entry and the tag.
+const SEALED_SIZE: usize = NONCE_LEN + ENTRY_SIZE + 16;
+
+const LEGACY_SEALED_SIZE: usize = NONCE_LEN + LEGACY_ENTRY_SIZE + 16;
+
+const FINGERPRINT_KEY: &[u8] = b"fingerprint";
+
+/// Keys derived from the configured encryption key.
+pub struct Cipher {
+    /// Keys the hashes of messages, so they can't be computed from texts.
+    hashes: hmac::Key,
+    entries: LessSafeKey,
+    /// Identifies the key without revealing it, to refuse opening
+    /// a database encrypted with another one.
+    fingerprint: [u8; 32],
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let master = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let derive = |purpose: &[u8]| -> [u8; 32] {
+            hmac::sign(&master, purpose).as_ref().try_into().unwrap()
+        };
+        Self {
+            hashes: hmac::Key::new(hmac::HMAC_SHA256, &derive(b"r9ktg hashes")),
+            entries: LessSafeKey::new(
+                UnboundKey::new(&CHACHA20_POLY1305, &derive(b"r9ktg entries")).unwrap(),
+            ),
+            fingerprint: derive(b"r9ktg fingerprint"),
+        }
+    }
+
+    pub(crate) fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// Keys a message hash. Only the hash goes in, so bare legacy hashes
+    /// get the same treatment as namespaced ones.
+    pub(crate) fn hash(&self, hash: [u8; 16]) -> [u8; 16] {
+        hmac::sign(&self.hashes, &hash).as_ref()[..16]
+            .try_into()
+            .unwrap()
+    }
+
+    pub(crate) fn hash_key(&self, key: Key) -> Key {
+        Key {
+            hash: self.hash(key.hash),
+            ..key
+        }
+    }
+
+    /// Encrypts a value, binding it to the database key it's stored under.
+    pub(crate) fn seal(&self, db_key: &[u8], plaintext: &[u8]) -> eyre::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)?;
+        let mut value = plaintext.to_vec();
+        self.entries
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(db_key),
+                &mut value,
+            )
+            .map_err(|_| eyre::eyre!("couldn't encrypt value"))?;
+        Ok([&nonce[..], &value].concat())
+    }
+
+    pub(crate) fn open(&self, db_key: &[u8], value: &[u8]) -> eyre::Result<Vec<u8>> {
+        let (nonce, sealed) = value
+            .split_at_checked(NONCE_LEN)
+            .ok_or_else(|| eyre::eyre!("encrypted value is too short"))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+        let mut sealed = sealed.to_vec();
+        let len = self
+            .entries
+            .open_in_place(nonce, Aad::from(db_key), &mut sealed)
+            .map_err(|_| eyre::eyre!("value doesn't decrypt with this key"))?
+            .len();
+        sealed.truncate(len);
+        Ok(sealed)
+    }
+}
+
+/// Whether a value was written in the clear, before encryption was turned on.
+pub(crate) fn is_plain(value: &[u8]) -> bool {
+    matches!(value.len(), ENTRY_SIZE | LEGACY_ENTRY_SIZE)
+}
+
+/// Encodes an entry stored under `db_key`, encrypting it if there's a cipher.
+pub(crate) fn encode(
+    cipher: Option<&Cipher>,
+    db_key: &[u8],
+    entry: &Entry,
+) -> eyre::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal(db_key, &entry.encode()),
+        None => Ok(entry.encode().to_vec()),
+    }
+}
+
+/// Decodes an entry stored under `db_key`. Entries written in the clear
+/// are still read once there's a cipher, since the store may not have been
+/// converted, but encrypted ones need it.
+pub(crate) fn decode(cipher: Option<&Cipher>, db_key: &[u8], value: &[u8]) -> eyre::Result<Entry> {
+    match (cipher, value.len()) {
+        (_, ENTRY_SIZE | LEGACY_ENTRY_SIZE) => Entry::decode(value),
+        (Some(cipher), SEALED_SIZE | LEGACY_SEALED_SIZE) => {
+            Entry::decode(&cipher.open(db_key, value)?)
+        }
+        (None, SEALED_SIZE | LEGACY_SEALED_SIZE) => Err(eyre::eyre!(
+            "entry is encrypted, but there's no `encryption_key`"
+        )),
+        _ => Err(eyre::eyre!("malformed database entry: {value:?}")),
+    }
+}
+
+/// Makes sure the database of a bot is encrypted with `cipher` or not at all,
+/// like it was the first time. When encryption is turned on, entries stored
+/// in sled are encrypted under their keyed hashes. Redis and redb aren't
+/// converted, so what they had is forgotten, and so are retained texts, which can't be
+/// told apart from encrypted ones.
+pub(crate) fn prepare(
+    db: &sled::Db,
+    bot_name: Option<&str>,
+    storage: Storage,
+    cipher: Option<&Cipher>,
+) -> eyre::Result<()> {
+    let state = open_tree(db, bot_name, "encryption")?;
+    let fingerprint = state.get(FINGERPRINT_KEY)?;
+    let cipher = match (cipher, fingerprint) {
+        (None, None) => return Ok(()),
+        (Some(cipher), Some(fingerprint)) if fingerprint == cipher.fingerprint() => {
+            return Ok(());
+        }
+        (None, Some(_)) => {
+            eyre::bail!("the database is encrypted, but there's no `encryption_key`")
+        }
+        (Some(_), Some(_)) => {
+            eyre::bail!("the database is encrypted with another `encryption_key`")
+        }
+        (Some(cipher), None) => cipher,
+    };
+    if storage == Storage::Sled {
+        let encrypted = encrypt_tree(&messages_tree(db, bot_name)?, cipher, |key| {
+            Ok(cipher.hash_key(Key::decode(key)?).encode().to_vec())
+        })? + encrypt_tree(&open_tree(db, bot_name, "legacy")?, cipher, |key| {
+            Ok(cipher.hash(key.try_into()?).to_vec())
+        })?;
+        tracing::info!(entries = encrypted, "Encrypted known messages");
+    }
+    open_tree(db, bot_name, "texts")?.clear()?;
+    state.insert(FINGERPRINT_KEY, cipher.fingerprint())?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Moves every entry stored in the clear to its keyed hash, encrypted.
+/// Entries encrypted already are left alone, so it can be run again
+/// if it's interrupted.
+fn encrypt_tree(
+    tree: &sled::Tree,
+    cipher: &Cipher,
+    rekey: impl Fn(&[u8]) -> eyre::Result<Vec<u8>>,
+) -> eyre::Result<usize> {
+    let mut batch = sled::Batch::default();
+    let mut encrypted = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        if !is_plain(&value) {
+            continue;
+        }
+        let new_key = rekey(&key)?;
+        let value = cipher.seal(&new_key, &Entry::decode(&value)?.encode())?;
+        // Both in one batch, so an entry is never lost or stored twice.
+        batch.remove(key);
+        batch.insert(new_key, value);
+        encrypted += 1;
+        if encrypted % 10_000 == 0 {
+            tree.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(encrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use teloxide::types::ChatId;
+
+    use super::*;
+    use crate::{
+        storage::{Namespace, Post, Status},
+        store::SledStore,
+    };
+
+    #[test]
+    fn seals_entries() -> eyre::Result<()> {
+        let cipher = Cipher::new(&[7; 32]);
+        let key = Key {
+            namespace: Namespace::Chat(ChatId(-1)),
+            hash: [1; 16],
+        };
+        let post = Post {
+            timestamp: 10,
+            message_id: None,
+            poster_id: None,
+        };
+        let entry = Entry::new(Status::Seen, post);
+
+        let hashed = cipher.hash_key(key);
+        assert_ne!(hashed.hash, key.hash);
+        assert_eq!(hashed, cipher.hash_key(key));
+        let value = encode(Some(&cipher), &hashed.encode(), &entry)?;
+        assert!(!is_plain(&value));
+        assert_eq!(decode(Some(&cipher), &hashed.encode(), &value)?, entry);
+        // Moving a value to another key doesn't go unnoticed.
+        assert!(decode(Some(&cipher), &key.encode(), &value).is_err());
+        assert!(decode(Some(&Cipher::new(&[8; 32])), &hashed.encode(), &value).is_err());
+        assert!(decode(None, &hashed.encode(), &value).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn encrypts_existing_entries() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let key = Key {
+            namespace: Namespace::Chat(ChatId(-1)),
+            hash: [1; 16],
+        };
+        let post = Post {
+            timestamp: 10,
+            message_id: None,
+            poster_id: None,
+        };
+        let entry = Entry::new(Status::Seen, post);
+        SledStore::open(&db, None, None)?.insert_many(vec![(key, entry)])?;
+
+        let cipher = Arc::new(Cipher::new(&[7; 32]));
+        prepare(&db, None, Storage::Sled, Some(&cipher))?;
+        // Running it again, like on the next start, changes nothing.
+        prepare(&db, None, Storage::Sled, Some(&cipher))?;
+        let store = SledStore::open(&db, None, Some(cipher.clone()))?;
+        assert_eq!(store.get(key)?, None);
+        assert_eq!(store.get(cipher.hash_key(key))?, Some(entry));
+        let (_, value) = messages_tree(&db, None)?.first()?.unwrap();
+        assert!(!is_plain(&value));
+
+        assert!(prepare(&db, None, Storage::Sled, None).is_err());
+        assert!(prepare(&db, None, Storage::Sled, Some(&Cipher::new(&[8; 32]))).is_err());
+        Ok(())
+    }
+}
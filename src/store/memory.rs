@@ -0,0 +1,136 @@
+//! Known messages kept in memory only, for tests and throwaway bots.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use color_eyre::eyre;
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt as _, StreamExt as _,
+};
+
+use super::{oldest, MessageStore, Update};
+use crate::storage::{Entry, Key, Namespace, Status};
+
+/// Known messages, forgotten once the bot stops.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Entry>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        future::ready(Ok(self.lock().get(&key).copied())).boxed()
+    }
+
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        self.get(key)
+    }
+
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        _window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>> {
+        let mut entries = self.lock();
+        let entry = entries.get(&key).copied();
+        let result = match f(entry) {
+            Some(next) => {
+                entries.insert(key, next);
+                Some(next)
+            }
+            None => entry,
+        };
+        future::ready(Ok(result)).boxed()
+    }
+
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        _window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        self.lock().extend(entries);
+        future::ready(Ok(())).boxed()
+    }
+
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>> {
+        future::ready(Ok(self.lock().remove(&key).is_some())).boxed()
+    }
+
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>> {
+        let mut entries = self.lock();
+        let before = entries.len();
+        entries.retain(|key, _| key.namespace != namespace);
+        future::ready(Ok(before - entries.len())).boxed()
+    }
+
+    fn remove_expired(
+        &self,
+        namespace: Namespace,
+        seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>> {
+        let mut entries = self.lock();
+        let before = entries.len();
+        entries.retain(|key, entry| {
+            key.namespace != namespace
+                || entry.status != Status::Seen
+                || entry.first_seen > seen_until
+        });
+        future::ready(Ok(before - entries.len())).boxed()
+    }
+
+    /// Entries are copied out, so the stream doesn't hold the lock.
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>> {
+        let entries = self
+            .lock()
+            .iter()
+            .filter(|(key, _)| key.namespace == namespace)
+            .map(|(&key, &entry)| Ok((key, entry)))
+            .collect::<Vec<_>>();
+        stream::iter(entries).boxed()
+    }
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>> {
+        future::ready(Ok(self.lock().is_empty())).boxed()
+    }
+
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>> {
+        future::ready(Ok(self.lock().len())).boxed()
+    }
+
+    fn evict(&self, max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>> {
+        let mut entries = self.lock();
+        let excess = entries.len().saturating_sub(max_entries);
+        let seen = entries
+            .iter()
+            .filter(|(_, entry)| entry.status == Status::Seen)
+            .map(|(key, entry)| Ok((key.encode(), entry.first_seen)));
+        let result = oldest(seen, excess).map(|keys| {
+            for key in &keys {
+                if let Ok(key) = Key::decode(key) {
+                    entries.remove(&key);
+                }
+            }
+            keys.len()
+        });
+        future::ready(result).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+}
@@ -0,0 +1,370 @@
+//! Known messages in a redb database, for operators who'd rather not trust sled
+//! with them. Only built with the `redb` feature.
+
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+};
+
+use ::redb::{
+    Database, Durability, ReadableTable as _, ReadableTableMetadata as _, TableDefinition,
+};
+use color_eyre::eyre;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt as _, StreamExt as _, TryStreamExt as _,
+};
+
+use super::{blocking, cipher, oldest, Cipher, MessageStore, Update, SCAN_BATCH};
+use crate::storage::{Entry, Key, Namespace, Status};
+
+type Table<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+/// Databases open in this process. A redb file can only be opened once,
+/// so bots sharing it, and a bot reloading its config, share the handle.
+static DATABASES: Mutex<BTreeMap<PathBuf, Weak<Database>>> = Mutex::new(BTreeMap::new());
+
+fn open_database(path: &Path) -> eyre::Result<Arc<Database>> {
+    let mut databases = DATABASES.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(db) = databases.get(path).and_then(Weak::upgrade) {
+        return Ok(db);
+    }
+    let db = Arc::new(Database::create(path)?);
+    databases.insert(path.to_owned(), Arc::downgrade(&db));
+    Ok(db)
+}
+
+/// Known messages in a table of a redb database, one table per bot.
+#[derive(Clone)]
+pub struct RedbStore {
+    db: Arc<Database>,
+    table: Arc<str>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl RedbStore {
+    /// Opens the database at `path`, creating it and the bot's table if needed.
+    pub fn open(
+        path: &Path,
+        bot_name: Option<&str>,
+        cipher: Option<Arc<Cipher>>,
+    ) -> eyre::Result<Self> {
+        let table = match bot_name {
+            None => "messages".into(),
+            Some(bot_name) => format!("bots/{bot_name}/messages").into(),
+        };
+        let store = Self {
+            db: open_database(path)?,
+            table,
+            cipher,
+        };
+        let txn = store.db.begin_write()?;
+        txn.open_table(store.table())?;
+        txn.commit()?;
+        Ok(store)
+    }
+
+    fn table(&self) -> Table<'_> {
+        TableDefinition::new(&self.table)
+    }
+
+    fn encode(&self, db_key: &[u8], entry: &Entry) -> eyre::Result<Vec<u8>> {
+        cipher::encode(self.cipher.as_deref(), db_key, entry)
+    }
+
+    fn decode(&self, db_key: &[u8], value: &[u8]) -> eyre::Result<Entry> {
+        cipher::decode(self.cipher.as_deref(), db_key, value)
+    }
+
+    /// Runs `f` in a write transaction, committed without waiting for the disk.
+    /// [`MessageStore::flush`] waits for everything committed so far.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut ::redb::Table<'_, &'static [u8], &'static [u8]>) -> eyre::Result<T>,
+    ) -> eyre::Result<T> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(Durability::Eventual);
+        let result = f(&mut txn.open_table(self.table())?)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    fn get_raw(&self, db_key: &[u8]) -> eyre::Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.table())?;
+        Ok(table.get(db_key)?.map(|value| value.value().to_vec()))
+    }
+
+    fn get(&self, key: Key) -> eyre::Result<Option<Entry>> {
+        let db_key = key.encode();
+        self.get_raw(&db_key)?
+            .map(|value| self.decode(&db_key, &value))
+            .transpose()
+    }
+
+    /// Writes `next` unless the stored value isn't `current` anymore,
+    /// in which case the stored one is returned instead.
+    fn compare_and_swap(
+        &self,
+        db_key: &[u8],
+        current: Option<&[u8]>,
+        next: &[u8],
+    ) -> eyre::Result<Result<(), Option<Vec<u8>>>> {
+        self.write(|table| {
+            let actual = table.get(db_key)?.map(|value| value.value().to_vec());
+            if actual.as_deref() != current {
+                return Ok(Err(actual));
+            }
+            table.insert(db_key, next)?;
+            Ok(Ok(()))
+        })
+    }
+
+    fn insert_many(&self, entries: Vec<(Key, Entry)>) -> eyre::Result<()> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, entry)| {
+                let key = key.encode();
+                Ok((key, self.encode(&key, &entry)?))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        self.write(|table| {
+            for (key, value) in &entries {
+                table.insert(&key[..], &value[..])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: Key) -> eyre::Result<bool> {
+        self.write(|table| Ok(table.remove(&key.encode()[..])?.is_some()))
+    }
+
+    /// Reads up to [`SCAN_BATCH`] entries of a namespace stored after `after`.
+    fn scan(
+        &self,
+        namespace: Namespace,
+        after: Option<&[u8]>,
+    ) -> eyre::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = namespace.prefix();
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Included(&prefix[..]),
+        };
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.table())?;
+        let mut batch = Vec::new();
+        for item in table.range::<&[u8]>((start, Bound::Unbounded))? {
+            let (key, value) = item?;
+            if !key.value().starts_with(&prefix) || batch.len() == SCAN_BATCH {
+                break;
+            }
+            batch.push((key.value().to_vec(), value.value().to_vec()));
+        }
+        Ok(batch)
+    }
+
+    /// Removes the keys for which `f` is true from a namespace, in batches,
+    /// so it never holds the writer for long.
+    fn remove_where(
+        &self,
+        namespace: Namespace,
+        f: impl Fn(&[u8], &[u8]) -> eyre::Result<bool>,
+    ) -> eyre::Result<usize> {
+        let mut removed = 0;
+        let mut after = None;
+        loop {
+            let batch = self.scan(namespace, after.as_deref())?;
+            let done = batch.len() < SCAN_BATCH;
+            let mut doomed = Vec::new();
+            for (key, value) in &batch {
+                if f(key, value)? {
+                    doomed.push((key, value));
+                }
+            }
+            removed += self.write(|table| {
+                let mut removed = 0;
+                for (key, value) in doomed {
+                    // A message posted again meanwhile keeps its fresh entry.
+                    if table
+                        .get(&key[..])?
+                        .is_some_and(|actual| actual.value() == &value[..])
+                    {
+                        table.remove(&key[..])?;
+                        removed += 1;
+                    }
+                }
+                Ok(removed)
+            })?;
+            if done {
+                return Ok(removed);
+            }
+            after = batch.last().map(|(key, _)| key.clone());
+        }
+    }
+
+    fn remove_expired(&self, namespace: Namespace, seen_until: i64) -> eyre::Result<usize> {
+        self.remove_where(namespace, |key, value| {
+            let entry = self.decode(key, value)?;
+            Ok(entry.status == Status::Seen && entry.first_seen <= seen_until)
+        })
+    }
+
+    fn len(&self) -> eyre::Result<usize> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(self.table())?.len()? as usize)
+    }
+
+    fn evict(&self, max_entries: usize) -> eyre::Result<usize> {
+        let Some(excess) = self.len()?.checked_sub(max_entries).filter(|&n| n > 0) else {
+            return Ok(0);
+        };
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.table())?;
+        let seen = table.iter()?.filter_map(|item| {
+            let result =
+                item.map_err(eyre::Report::from).and_then(|(key, value)| {
+                    let entry = self.decode(key.value(), value.value())?;
+                    Ok((entry.status == Status::Seen)
+                        .then(|| (key.value().to_vec(), entry.first_seen)))
+                });
+            result.transpose()
+        });
+        let keys = oldest(seen, excess)?;
+        self.write(|table| {
+            for key in &keys {
+                table.remove(&key[..])?;
+            }
+            Ok(keys.len())
+        })
+    }
+}
+
+impl MessageStore for RedbStore {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        let store = self.clone();
+        blocking(move || store.get(key))
+    }
+
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        MessageStore::get(self, key)
+    }
+
+    /// `f` runs on the async side, in between the blocking reads and writes.
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        _window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>> {
+        async move {
+            let db_key = key.encode();
+            let store = self.clone();
+            let mut current = blocking(move || store.get_raw(&db_key)).await?;
+            loop {
+                let entry = current
+                    .as_deref()
+                    .map(|value| self.decode(&db_key, value))
+                    .transpose()?;
+                let Some(next) = f(entry) else {
+                    return Ok(entry);
+                };
+                let store = self.clone();
+                let value = self.encode(&db_key, &next)?;
+                let swapped =
+                    blocking(move || store.compare_and_swap(&db_key, current.as_deref(), &value))
+                        .await?;
+                match swapped {
+                    Ok(()) => return Ok(Some(next)),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        _window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        let store = self.clone();
+        blocking(move || store.insert_many(entries))
+    }
+
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>> {
+        let store = self.clone();
+        blocking(move || store.remove(key))
+    }
+
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.remove_where(namespace, |_, _| Ok(true)))
+    }
+
+    fn remove_expired(
+        &self,
+        namespace: Namespace,
+        seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.remove_expired(namespace, seen_until))
+    }
+
+    /// Each batch is read in a transaction of its own, so entries
+    /// changed while the stream is read may or may not be included.
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>> {
+        let store = self.clone();
+        stream::try_unfold(Some(None), move |after: Option<Option<Vec<u8>>>| {
+            let store = store.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok::<_, eyre::Report>(None);
+                };
+                let (batch, next) = blocking(move || {
+                    let batch = store.scan(namespace, after.as_deref())?;
+                    let next = (batch.len() == SCAN_BATCH)
+                        .then(|| batch.last().map(|(key, _)| key.clone()));
+                    let batch = batch
+                        .iter()
+                        .map(|(key, value)| Ok((Key::decode(key)?, store.decode(key, value)?)))
+                        .collect::<eyre::Result<Vec<_>>>()?;
+                    Ok((batch, next))
+                })
+                .await?;
+                Ok(Some((stream::iter(batch.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>> {
+        let store = self.clone();
+        blocking(move || Ok(store.len()? == 0))
+    }
+
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.len())
+    }
+
+    fn evict(&self, max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>> {
+        let store = self.clone();
+        blocking(move || store.evict(max_entries))
+    }
+
+    /// Commits nothing, but durably, which persists every commit before it.
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>> {
+        let db = self.db.clone();
+        blocking(move || {
+            let mut txn = db.begin_write()?;
+            txn.set_durability(Durability::Immediate);
+            txn.commit()?;
+            Ok(())
+        })
+    }
+}
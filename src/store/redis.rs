@@ -0,0 +1,354 @@
+//! Known messages in Redis, so several bots can share them.
+
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::eyre;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt as _, StreamExt as _, TryStreamExt as _,
+};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Script,
+};
+use url::Url;
+
+use super::{cipher, Cipher, MessageStore, Update};
+use crate::storage::{unix_now, Entry, Key, Namespace, Status};
+
+/// Prefix of every key of the main bot, so the database can be shared with other tools.
+/// Other bots use `r9ktg@<name>:`.
+const KEY_PREFIX: &[u8] = b"r9ktg:";
+
+/// How many keys `SCAN` is asked to look at in one go.
+const SCAN_COUNT: usize = 1000;
+
+/// How long connecting to Redis may take before it's tried again.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a command may wait for its reply before it fails.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sets `KEYS[1]` to `ARGV[2]`, expiring at `ARGV[3]` if it's given,
+/// unless its value isn't `ARGV[1]` anymore. Returns whether it was set.
+///
+/// Does what `WATCH` would, but `WATCH` needs a connection of its own.
+/// Values are never empty, so an empty `ARGV[1]` means there was none.
+const UPDATE_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (current or '') ~= ARGV[1] then
+    return 0
+end
+if ARGV[3] then
+    redis.call('SET', KEYS[1], ARGV[2], 'EXAT', ARGV[3])
+else
+    redis.call('SET', KEYS[1], ARGV[2])
+end
+return 1
+";
+
+/// Known messages stored as `r9ktg:` followed by their database key,
+/// with seen entries expiring with the dedup window.
+#[derive(Clone)]
+pub struct RedisStore {
+    key_prefix: Arc<[u8]>,
+    cipher: Option<Arc<Cipher>>,
+    /// Multiplexed connection, opened again once it breaks.
+    connection: ConnectionManager,
+    update_script: Arc<Script>,
+}
+
+impl RedisStore {
+    /// Takes `redis://` and `rediss://` URLs. Doesn't connect until the store is used.
+    pub fn new(
+        url: &Url,
+        bot_name: Option<&str>,
+        cipher: Option<Arc<Cipher>>,
+    ) -> eyre::Result<Self> {
+        if !matches!(url.scheme(), "redis" | "rediss") {
+            eyre::bail!("unsupported redis URL scheme: {:?}", url.scheme());
+        }
+        let key_prefix = match bot_name {
+            None => KEY_PREFIX.into(),
+            Some(name) => format!("r9ktg@{name}:").into_bytes().into(),
+        };
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(Some(CONNECT_TIMEOUT))
+            .set_response_timeout(Some(RESPONSE_TIMEOUT));
+        let client = redis::Client::open(url.as_str())?;
+        Ok(Self {
+            key_prefix,
+            cipher,
+            connection: ConnectionManager::new_lazy_with_config(client, config)?,
+            update_script: Arc::new(Script::new(UPDATE_SCRIPT)),
+        })
+    }
+
+    async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> eyre::Result<T> {
+        Ok(cmd.query_async(&mut self.connection.clone()).await?)
+    }
+
+    fn redis_key(&self, key: Key) -> Vec<u8> {
+        [&self.key_prefix[..], &key.encode()].concat()
+    }
+
+    /// Pattern matching every key in the namespace, with glob characters
+    /// in the chat id escaped.
+    fn pattern(&self, namespace: Namespace) -> Vec<u8> {
+        let mut pattern = self.key_prefix.to_vec();
+        for byte in namespace.prefix() {
+            if matches!(byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+                pattern.push(b'\\');
+            }
+            pattern.push(byte);
+        }
+        pattern.push(b'*');
+        pattern
+    }
+
+    fn encode(&self, key: Key, entry: &Entry) -> eyre::Result<Vec<u8>> {
+        cipher::encode(self.cipher.as_deref(), &key.encode(), entry)
+    }
+
+    fn decode(&self, key: Key, value: &[u8]) -> eyre::Result<Entry> {
+        cipher::decode(self.cipher.as_deref(), &key.encode(), value)
+    }
+
+    /// When `entry` should expire, once it's out of the window.
+    fn expires_at(entry: &Entry, window: Option<i64>) -> Option<i64> {
+        let window = window.filter(|_| entry.status == Status::Seen)?;
+        // Redis refuses expiry times that already passed.
+        Some(entry.first_seen.saturating_add(window).max(unix_now() + 1))
+    }
+
+    /// One `SCAN` step, returning the next cursor and the keys found.
+    async fn scan(&self, cursor: u64, pattern: &[u8]) -> eyre::Result<(u64, Vec<Vec<u8>>)> {
+        self.query(
+            redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT),
+        )
+        .await
+    }
+
+    /// Every key matching the pattern, a `SCAN` step at a time.
+    fn scan_all(&self, pattern: Vec<u8>) -> BoxStream<'static, eyre::Result<Vec<Vec<u8>>>> {
+        stream::try_unfold((self.clone(), Some(0)), move |(store, cursor)| {
+            let pattern = pattern.clone();
+            async move {
+                let Some(cursor) = cursor else {
+                    return Ok(None);
+                };
+                let (next, keys) = store.scan(cursor, &pattern).await?;
+                let next = (next != 0).then_some(next);
+                Ok(Some((keys, (store, next))))
+            }
+        })
+        .boxed()
+    }
+
+    /// Every key in the namespace, a `SCAN` step at a time.
+    fn keys(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<Vec<Vec<u8>>>> {
+        self.scan_all(self.pattern(namespace))
+    }
+
+    /// Every key of this bot, a `SCAN` step at a time.
+    fn all_keys(&self) -> BoxStream<'static, eyre::Result<Vec<Vec<u8>>>> {
+        let mut pattern = self.key_prefix.to_vec();
+        pattern.push(b'*');
+        self.scan_all(pattern)
+    }
+
+    async fn get(&self, key: Key) -> eyre::Result<Option<Entry>> {
+        let value: Option<Vec<u8>> = self
+            .query(redis::cmd("GET").arg(self.redis_key(key)))
+            .await?;
+        value.map(|value| self.decode(key, &value)).transpose()
+    }
+
+    /// Retries whenever the entry is changed before it's written.
+    async fn update(
+        &self,
+        key: Key,
+        window: Option<i64>,
+        f: &mut Update<'_>,
+    ) -> eyre::Result<Option<Entry>> {
+        let redis_key = self.redis_key(key);
+        loop {
+            let current: Option<Vec<u8>> = self.query(redis::cmd("GET").arg(&redis_key)).await?;
+            let entry = (current.as_deref())
+                .map(|value| self.decode(key, value))
+                .transpose()?;
+            let Some(next) = f(entry) else {
+                return Ok(entry);
+            };
+            let set: bool = self
+                .update_script
+                .key(&redis_key)
+                .arg(current.unwrap_or_default())
+                .arg(self.encode(key, &next)?)
+                .arg(Self::expires_at(&next, window))
+                .invoke_async(&mut self.connection.clone())
+                .await?;
+            if set {
+                return Ok(Some(next));
+            }
+        }
+    }
+
+    async fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        window: Option<i64>,
+    ) -> eyre::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, entry) in &entries {
+            let set = pipe
+                .cmd("SET")
+                .arg(self.redis_key(*key))
+                .arg(self.encode(*key, entry)?);
+            if let Some(expires_at) = Self::expires_at(entry, window) {
+                set.arg("EXAT").arg(expires_at);
+            }
+            set.ignore();
+        }
+        pipe.exec_async(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: Key) -> eyre::Result<bool> {
+        let removed: usize = self
+            .query(redis::cmd("DEL").arg(self.redis_key(key)))
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn clear(&self, namespace: Namespace) -> eyre::Result<usize> {
+        let mut removed = 0;
+        let mut keys = self.keys(namespace);
+        while let Some(batch) = keys.try_next().await? {
+            if batch.is_empty() {
+                continue;
+            }
+            removed += self.query::<usize>(redis::cmd("DEL").arg(batch)).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn is_empty(&self) -> eyre::Result<bool> {
+        let mut keys = self.all_keys();
+        while let Some(batch) = keys.try_next().await? {
+            if !batch.is_empty() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// `SCAN` may return a key more than once while Redis resizes
+    /// its tables, so the count is only approximate then.
+    async fn len(&self) -> eyre::Result<usize> {
+        self.all_keys()
+            .try_fold(0, |len, batch| async move { Ok(len + batch.len()) })
+            .await
+    }
+}
+
+impl MessageStore for RedisStore {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        self.get(key).boxed()
+    }
+
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        self.get(key).boxed()
+    }
+
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>> {
+        self.update(key, window, f).boxed()
+    }
+
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        self.insert_many(entries, window).boxed()
+    }
+
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>> {
+        self.remove(key).boxed()
+    }
+
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>> {
+        self.clear(namespace).boxed()
+    }
+
+    /// Seen entries are written with a TTL, so Redis expires them by itself.
+    fn remove_expired(
+        &self,
+        _namespace: Namespace,
+        _seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>> {
+        async { Ok(0) }.boxed()
+    }
+
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>> {
+        let store = self.clone();
+        self.keys(namespace)
+            .and_then(move |keys| {
+                let store = store.clone();
+                async move {
+                    if keys.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    let values: Vec<Option<Vec<u8>>> =
+                        store.query(redis::cmd("MGET").arg(&keys)).await?;
+                    let mut entries = Vec::with_capacity(keys.len());
+                    for (key, value) in keys.iter().zip(values) {
+                        // Expired or removed since the scan.
+                        let Some(value) = value else {
+                            continue;
+                        };
+                        let key = Key::decode(&key[store.key_prefix.len()..])?;
+                        entries.push((key, store.decode(key, &value)?));
+                    }
+                    Ok(entries)
+                }
+            })
+            .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>> {
+        self.is_empty().boxed()
+    }
+
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>> {
+        self.len().boxed()
+    }
+
+    /// Redis persists writes on its own.
+    /// Redis evicts by itself when given `maxmemory` and a `maxmemory-policy`,
+    /// which also works for several bots sharing it.
+    fn evict(&self, _max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>> {
+        async { Ok(0) }.boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
@@ -0,0 +1,354 @@
+//! Log of changes to known messages, streamed to standby instances
+//! so they can take over with an up-to-date database.
+
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt as _};
+use tokio::sync::Notify;
+
+use super::{blocking, MessageStore, Update};
+use crate::storage::{Entry, Key, Namespace};
+
+/// How many changes are appended between prunings of the log.
+const PRUNE_EVERY: u64 = 1000;
+
+/// Change to known messages, applied the same way on every instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Put {
+        key: Key,
+        entry: Entry,
+        window: Option<i64>,
+    },
+    Remove(Key),
+    Clear(Namespace),
+}
+
+impl Change {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Change::Put { key, entry, window } => {
+                let mut value = vec![u8::from(window.is_some())];
+                value.extend_from_slice(&key.encode());
+                value.extend_from_slice(&entry.encode());
+                if let Some(window) = window {
+                    value.extend_from_slice(&window.to_be_bytes());
+                }
+                value
+            }
+            Change::Remove(key) => [&[2][..], &key.encode()].concat(),
+            Change::Clear(namespace) => [&[3][..], &namespace.prefix()].concat(),
+        }
+    }
+
+    pub(crate) fn decode(value: &[u8]) -> eyre::Result<Self> {
+        let malformed = || eyre::eyre!("malformed replicated change: {value:?}");
+        let (&tag, rest) = value.split_first().ok_or_else(malformed)?;
+        match (tag, rest.len()) {
+            (0 | 1, _) => {
+                let (key, rest) = rest.split_at_checked(24).ok_or_else(malformed)?;
+                // Entries may be from an older instance, so they're told by the window.
+                let window_len = if tag == 1 { 8 } else { 0 };
+                let entry_len = rest.len().checked_sub(window_len).ok_or_else(malformed)?;
+                let (entry, window) = rest.split_at(entry_len);
+                let window = (tag == 1).then(|| i64::from_be_bytes(window.try_into().unwrap()));
+                Ok(Change::Put {
+                    key: Key::decode(key)?,
+                    entry: Entry::decode(entry)?,
+                    window,
+                })
+            }
+            (2, _) => Ok(Change::Remove(Key::decode(rest)?)),
+            (3, 8) => Ok(Change::Clear(
+                Key::decode(&[rest, &[0; 16]].concat())?.namespace,
+            )),
+            _ => Err(malformed()),
+        }
+    }
+
+    /// Makes the same change to another store.
+    pub(crate) async fn apply(self, store: &dyn MessageStore) -> eyre::Result<()> {
+        match self {
+            Change::Put { key, entry, window } => {
+                store.insert_many(vec![(key, entry)], window).await?;
+            }
+            Change::Remove(key) => {
+                store.remove(key).await?;
+            }
+            Change::Clear(namespace) => {
+                store.clear(namespace).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Changes numbered in the order they were made, keyed by their big-endian number.
+/// Only the most recent ones are kept.
+#[derive(Clone)]
+pub struct ReplicationLog {
+    tree: sled::Tree,
+    /// Number of the next change, locked while a change is appended,
+    /// so every change before it is always in the tree unless pruned.
+    next: Arc<Mutex<u64>>,
+    appended: Arc<Notify>,
+    max_changes: u64,
+}
+
+impl ReplicationLog {
+    pub(crate) fn open(tree: sled::Tree, max_changes: u64) -> eyre::Result<Self> {
+        let next = match tree.last()? {
+            Some((key, _)) => u64::from_be_bytes(key[..].try_into()?) + 1,
+            None => 0,
+        };
+        Ok(Self {
+            tree,
+            next: Arc::new(Mutex::new(next)),
+            appended: Arc::new(Notify::new()),
+            max_changes,
+        })
+    }
+
+    /// Number the next change will get.
+    pub(crate) fn next(&self) -> u64 {
+        *self.next.lock().unwrap()
+    }
+
+    fn append(&self, changes: &[Change]) -> eyre::Result<()> {
+        let mut next = self.next.lock().unwrap();
+        for change in changes {
+            self.tree.insert(next.to_be_bytes(), change.encode())?;
+            *next += 1;
+            if next.is_multiple_of(PRUNE_EVERY) && *next > self.max_changes {
+                let oldest_kept = *next - self.max_changes;
+                for key in self.tree.range(..oldest_kept.to_be_bytes()).keys() {
+                    self.tree.remove(key?)?;
+                }
+            }
+        }
+        drop(next);
+        self.appended.notify_waiters();
+        Ok(())
+    }
+
+    /// Up to `limit` changes from number `from` on.
+    /// Fails if the ones from `from` were already pruned.
+    pub(crate) fn read(&self, from: u64, limit: usize) -> eyre::Result<Vec<(u64, Change)>> {
+        let end = self.next();
+        if from > end {
+            eyre::bail!("changes from {from} on were never made");
+        }
+        let mut changes = Vec::new();
+        for item in self
+            .tree
+            .range(from.to_be_bytes()..end.to_be_bytes())
+            .take(limit)
+        {
+            let (key, value) = item?;
+            let number = u64::from_be_bytes(key[..].try_into()?);
+            if number != from + changes.len() as u64 {
+                eyre::bail!("changes from {from} on were already pruned");
+            }
+            changes.push((number, Change::decode(&value)?));
+        }
+        if changes.is_empty() && from < end {
+            eyre::bail!("changes from {from} on were already pruned");
+        }
+        Ok(changes)
+    }
+
+    /// Future resolving once changes are appended from now on,
+    /// for waiting without missing changes appended in the meantime.
+    pub(crate) fn wait_for_changes(&self) -> tokio::sync::futures::Notified<'_> {
+        self.appended.notified()
+    }
+}
+
+/// Store recording every change it makes to the replication log.
+///
+/// Expired and evicted messages aren't replicated, since every instance
+/// sweeps them by itself once it's running the bot.
+pub struct ReplicatedStore {
+    inner: Arc<dyn MessageStore>,
+    log: ReplicationLog,
+}
+
+impl ReplicatedStore {
+    pub fn new(inner: Arc<dyn MessageStore>, log: ReplicationLog) -> Self {
+        Self { inner, log }
+    }
+
+    async fn record(&self, changes: Vec<Change>) -> eyre::Result<()> {
+        let log = self.log.clone();
+        blocking(move || log.append(&changes)).await
+    }
+}
+
+impl MessageStore for ReplicatedStore {
+    fn get(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        self.inner.get(key)
+    }
+
+    fn peek(&self, key: Key) -> BoxFuture<'_, eyre::Result<Option<Entry>>> {
+        self.inner.peek(key)
+    }
+
+    fn update<'a>(
+        &'a self,
+        key: Key,
+        window: Option<i64>,
+        f: &'a mut Update<'_>,
+    ) -> BoxFuture<'a, eyre::Result<Option<Entry>>> {
+        async move {
+            let mut changed = false;
+            let entry = self
+                .inner
+                .update(key, window, &mut |entry| {
+                    let next = f(entry);
+                    changed = next.is_some();
+                    next
+                })
+                .await?;
+            if let (true, Some(entry)) = (changed, entry) {
+                self.record(vec![Change::Put { key, entry, window }])
+                    .await?;
+            }
+            Ok(entry)
+        }
+        .boxed()
+    }
+
+    fn insert_many(
+        &self,
+        entries: Vec<(Key, Entry)>,
+        window: Option<i64>,
+    ) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            let changes = entries
+                .iter()
+                .map(|&(key, entry)| Change::Put { key, entry, window })
+                .collect();
+            self.inner.insert_many(entries, window).await?;
+            self.record(changes).await
+        }
+        .boxed()
+    }
+
+    fn remove(&self, key: Key) -> BoxFuture<'_, eyre::Result<bool>> {
+        async move {
+            let removed = self.inner.remove(key).await?;
+            if removed {
+                self.record(vec![Change::Remove(key)]).await?;
+            }
+            Ok(removed)
+        }
+        .boxed()
+    }
+
+    fn clear(&self, namespace: Namespace) -> BoxFuture<'_, eyre::Result<usize>> {
+        async move {
+            let removed = self.inner.clear(namespace).await?;
+            self.record(vec![Change::Clear(namespace)]).await?;
+            Ok(removed)
+        }
+        .boxed()
+    }
+
+    fn remove_expired(
+        &self,
+        namespace: Namespace,
+        seen_until: i64,
+    ) -> BoxFuture<'_, eyre::Result<usize>> {
+        self.inner.remove_expired(namespace, seen_until)
+    }
+
+    fn entries(&self, namespace: Namespace) -> BoxStream<'static, eyre::Result<(Key, Entry)>> {
+        self.inner.entries(namespace)
+    }
+
+    fn is_empty(&self) -> BoxFuture<'_, eyre::Result<bool>> {
+        self.inner.is_empty()
+    }
+
+    fn len(&self) -> BoxFuture<'_, eyre::Result<usize>> {
+        self.inner.len()
+    }
+
+    fn evict(&self, max_entries: usize) -> BoxFuture<'_, eyre::Result<usize>> {
+        self.inner.evict(max_entries)
+    }
+
+    fn flush(&self) -> BoxFuture<'_, eyre::Result<()>> {
+        async move {
+            self.inner.flush().await?;
+            self.log.tree.flush_async().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt as _;
+    use teloxide::types::ChatId;
+
+    use super::*;
+    use crate::{
+        storage::{Post, Status},
+        store::MemoryStore,
+    };
+
+    #[tokio::test]
+    async fn replays_changes() -> eyre::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let log = ReplicationLog::open(db.open_tree("replication_log")?, 10_000)?;
+        let store = ReplicatedStore::new(Arc::new(MemoryStore::new()), log.clone());
+        let namespace = Namespace::Chat(ChatId(-1));
+        let key = |hash| Key {
+            namespace,
+            hash: [hash; 16],
+        };
+        let post = Post {
+            timestamp: 10,
+            message_id: None,
+            poster_id: None,
+        };
+        let seen = Entry::new(Status::Seen, post);
+
+        store.update(key(1), Some(60), &mut |_| Some(seen)).await?;
+        store.update(key(1), Some(60), &mut |_| None).await?;
+        store
+            .insert_many(vec![(key(2), seen), (key(3), seen)], None)
+            .await?;
+        store.remove(key(2)).await?;
+        assert_eq!(log.next(), 4);
+
+        let changes = log.read(0, 100)?;
+        assert_eq!(
+            changes[0],
+            (
+                0,
+                Change::Put {
+                    key: key(1),
+                    entry: seen,
+                    window: Some(60),
+                }
+            )
+        );
+        assert_eq!(changes[3], (3, Change::Remove(key(2))));
+        let replica = MemoryStore::new();
+        for (_, change) in log.read(1, 100)? {
+            assert_eq!(Change::decode(&change.encode())?, change);
+            change.apply(&replica).await?;
+        }
+        let entries = replica.entries(namespace).try_collect::<Vec<_>>().await?;
+        assert_eq!(entries, [(key(3), seen)]);
+
+        store.clear(namespace).await?;
+        assert_eq!(log.read(4, 100)?, [(4, Change::Clear(namespace))]);
+        assert!(log.read(5, 100)?.is_empty());
+        Ok(())
+    }
+}
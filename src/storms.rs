@@ -0,0 +1,393 @@
+//! Slowing a chat down while duplicates pour in, like during raids.
+//!
+//! Bots can't change Telegram's own slow mode, so the bot enforces one itself:
+//! once `storm_threshold` duplicates are deleted within `storm_window` seconds,
+//! for `storm_cooldown` seconds everyone but admins may only post once every
+//! `storm_slow_mode_delay` seconds, and sooner messages are deleted.
+//!
+//! Separately, once `raid_alert_threshold` duplicates are deleted within a minute,
+//! the chat's admins and the log chat are told what's being posted and by whom,
+//! so someone can ban the raiders while the bot keeps deleting.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use color_eyre::eyre;
+use teloxide::types::{Chat, ChatId, Message, User, UserId};
+
+use crate::{
+    api::{SendOptions, TelegramApi},
+    audit::AuditEvent,
+    i18n::Text,
+    robot::{describe_chat, describe_user, is_anonymous_admin, snippet, Robot9000},
+};
+
+/// Seconds over which deletions count towards a raid alert.
+const RAID_WINDOW: i64 = 60;
+
+/// Seconds after a raid alert during which the chat isn't alerted about again.
+const RAID_ALERT_INTERVAL: i64 = 10 * 60;
+
+/// Most texts and users listed in a raid alert.
+const RAID_ALERT_ENTRIES: usize = 5;
+
+#[derive(Default)]
+struct ChatStorm {
+    /// When duplicates were deleted within the window.
+    deletions: VecDeque<i64>,
+    /// When slow mode ends, while it's on.
+    slow_until: Option<i64>,
+    /// When each user last posted while slow mode is on.
+    last_posts: HashMap<UserId, i64>,
+    /// Duplicates deleted within the last `RAID_WINDOW` seconds.
+    raid: VecDeque<RaidDeletion>,
+    /// When admins were last alerted about a raid.
+    alerted_at: Option<i64>,
+}
+
+/// A deleted duplicate, as listed in raid alerts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RaidDeletion {
+    deleted_at: i64,
+    /// Who posted it, missing for channel posts.
+    user: Option<String>,
+    text: String,
+}
+
+impl RaidDeletion {
+    pub(crate) fn new(deleted_at: i64, user: Option<&User>, text: Option<&str>) -> Self {
+        Self {
+            deleted_at,
+            user: user.map(describe_user),
+            text: text.map_or_else(|| "(media)".into(), |text| snippet(text).into_owned()),
+        }
+    }
+}
+
+/// The most frequent values first, with how many times each came up, one per line.
+fn most_frequent<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    let mut counts = BTreeMap::<_, usize>::new();
+    for value in values {
+        *counts.entry(value).or_default() += 1;
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+    counts
+        .into_iter()
+        .take(RAID_ALERT_ENTRIES)
+        .map(|(value, count)| format!("{value} ×{count}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether a message may be posted, as far as slow mode is concerned.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SlowMode {
+    Off,
+    /// Slow mode was on, but ran out before this message.
+    Ended,
+    Allowed,
+    TooSoon,
+}
+
+/// Recent deletions and slow mode of every chat, kept in memory only,
+/// since storms are short.
+#[derive(Clone, Default)]
+pub(crate) struct StormTracker {
+    chats: Arc<Mutex<HashMap<ChatId, ChatStorm>>>,
+}
+
+impl StormTracker {
+    fn lock(&self) -> MutexGuard<'_, HashMap<ChatId, ChatStorm>> {
+        self.chats.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records a deleted duplicate, turning slow mode on for `cooldown` seconds
+    /// if there were `threshold` of them within `window` seconds.
+    /// Returns whether it was turned on just now.
+    fn record_deletion(
+        &self,
+        chat_id: ChatId,
+        now: i64,
+        threshold: u32,
+        window: i64,
+        cooldown: i64,
+    ) -> bool {
+        let mut chats = self.lock();
+        let storm = chats.entry(chat_id).or_default();
+        while storm
+            .deletions
+            .front()
+            .is_some_and(|&deleted_at| now - deleted_at >= window)
+        {
+            storm.deletions.pop_front();
+        }
+        storm.deletions.push_back(now);
+        if storm.slow_until.is_some() || storm.deletions.len() < threshold as usize {
+            return false;
+        }
+        storm.deletions.clear();
+        storm.slow_until = Some(now.saturating_add(cooldown));
+        true
+    }
+
+    /// Records a deleted duplicate towards a raid alert. Returns the deletions
+    /// to alert about once there are `threshold` of them within `RAID_WINDOW` seconds,
+    /// unless the chat was alerted about in the last `RAID_ALERT_INTERVAL` seconds.
+    fn record_raid(
+        &self,
+        chat_id: ChatId,
+        deletion: RaidDeletion,
+        threshold: u32,
+    ) -> Option<Vec<RaidDeletion>> {
+        let mut chats = self.lock();
+        let storm = chats.entry(chat_id).or_default();
+        let now = deletion.deleted_at;
+        while storm
+            .raid
+            .front()
+            .is_some_and(|deleted| now - deleted.deleted_at >= RAID_WINDOW)
+        {
+            storm.raid.pop_front();
+        }
+        storm.raid.push_back(deletion);
+        if storm.raid.len() < threshold as usize
+            || storm
+                .alerted_at
+                .is_some_and(|alerted_at| now - alerted_at < RAID_ALERT_INTERVAL)
+        {
+            return None;
+        }
+        storm.alerted_at = Some(now);
+        Some(storm.raid.drain(..).collect())
+    }
+
+    /// Checks a message against slow mode, remembering when it was posted if it's allowed.
+    fn check(&self, chat_id: ChatId, user_id: UserId, now: i64, delay: i64) -> SlowMode {
+        let mut chats = self.lock();
+        let Some(storm) = chats.get_mut(&chat_id) else {
+            return SlowMode::Off;
+        };
+        match storm.slow_until {
+            None => SlowMode::Off,
+            Some(until) if now >= until => {
+                storm.slow_until = None;
+                storm.last_posts.clear();
+                SlowMode::Ended
+            }
+            Some(_) => match storm.last_posts.get(&user_id) {
+                Some(&posted_at) if now - posted_at < delay => SlowMode::TooSoon,
+                _ => {
+                    storm.last_posts.insert(user_id, now);
+                    SlowMode::Allowed
+                }
+            },
+        }
+    }
+}
+
+impl Robot9000 {
+    /// Counts a deleted duplicate towards a storm, turning slow mode on once there's one.
+    pub(crate) async fn record_storm(
+        &self,
+        bot: &dyn TelegramApi,
+        chat_id: ChatId,
+        now: i64,
+    ) -> eyre::Result<()> {
+        let config = self.config();
+        let Some(threshold) = config.storm_threshold else {
+            return Ok(());
+        };
+        let window = config.storm_window.try_into().unwrap_or(i64::MAX);
+        let cooldown = config.storm_cooldown.try_into().unwrap_or(i64::MAX);
+        if !self
+            .storms
+            .record_deletion(chat_id, now, threshold, window, cooldown)
+        {
+            return Ok(());
+        }
+        tracing::info!(chat_id = chat_id.0, "duplicate storm, slow mode on");
+        self.log_event(bot, format!("Duplicate storm in {chat_id}, slow mode on"))
+            .await;
+        let notice = self.chat_locale(chat_id)?.text(Text::SlowModeOn {
+            cooldown: config.storm_cooldown,
+            delay: config.storm_slow_mode_delay,
+        });
+        bot.send_message(chat_id, notice, SendOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Counts a deleted duplicate towards a raid, alerting the chat's admins
+    /// in private and the log chat once there's one.
+    pub(crate) async fn record_raid(
+        &self,
+        bot: &dyn TelegramApi,
+        chat: &Chat,
+        deletion: RaidDeletion,
+    ) -> eyre::Result<()> {
+        let Some(threshold) = self.config().raid_alert_threshold else {
+            return Ok(());
+        };
+        let Some(deletions) = self.storms.record_raid(chat.id, deletion, threshold) else {
+            return Ok(());
+        };
+        let count = deletions.len();
+        let texts = most_frequent(deletions.iter().map(|deletion| deletion.text.as_str()));
+        let users = most_frequent(
+            deletions
+                .iter()
+                .filter_map(|deletion| deletion.user.as_deref()),
+        );
+        tracing::info!(chat_id = chat.id.0, count, "raid, alerting admins");
+        let event = format!(
+            "Possible raid in {}: {count} duplicates deleted within a minute\n\
+             Texts:\n{texts}\nUsers:\n{users}",
+            describe_chat(chat),
+        );
+        self.log_event(bot, event).await;
+        let alert = self.chat_locale(chat.id)?.text(Text::RaidAlert {
+            chat: &describe_chat(chat),
+            count,
+            texts: &texts,
+            users: &users,
+        });
+        for admin in bot.get_chat_administrators(chat.id).await? {
+            if admin.user.is_bot {
+                continue;
+            }
+            let result = bot
+                .send_message(admin.user.id.into(), alert.clone(), SendOptions::default())
+                .await;
+            if let Err(err) = result {
+                tracing::info!(
+                    user_id = admin.user.id.0,
+                    err = format_args!("{err}"),
+                    "couldn't send raid alert",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a message sent too soon while slow mode is on. Returns whether it was deleted.
+    pub(crate) async fn check_slow_mode(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+    ) -> eyre::Result<bool> {
+        let config = self.config();
+        if config.storm_threshold.is_none() {
+            return Ok(false);
+        }
+        let delay = config.storm_slow_mode_delay.try_into().unwrap_or(i64::MAX);
+        let now = message.date.timestamp();
+        match self.storms.check(message.chat.id, user.id, now, delay) {
+            SlowMode::Off | SlowMode::Allowed => return Ok(false),
+            SlowMode::Ended => {
+                tracing::info!("slow mode off");
+                let event = format!("Slow mode off in {}", describe_chat(&message.chat));
+                self.log_event(bot, event).await;
+                let notice = self.chat_locale(message.chat.id)?.text(Text::SlowModeOff);
+                bot.send_message(message.chat.id, notice, SendOptions::default())
+                    .await?;
+                return Ok(false);
+            }
+            SlowMode::TooSoon => {}
+        }
+        // Telegram's slow mode doesn't apply to admins either.
+        if is_anonymous_admin(message)
+            || Self::is_admin(&config, &self.admins, bot, &message.chat, user).await?
+        {
+            return Ok(false);
+        }
+        tracing::debug!(
+            user_id = user.id.0,
+            "deleting message sent too soon in slow mode"
+        );
+        let event = format!(
+            "Sent too soon in slow mode in {}\nUser: {}",
+            describe_chat(&message.chat),
+            describe_user(user),
+        );
+        self.log_event(bot, event).await;
+        self.audit(AuditEvent::SlowMode {
+            chat_id: message.chat.id,
+            message_id: message.id.0,
+            user_id: user.id,
+        });
+        self.delete_batched(bot, message.chat.id, message.id)
+            .await?;
+        self.count_deleted(message.chat.id, now, Some(user.id))
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slows_down_during_storms() {
+        let storms = StormTracker::default();
+        let (chat_id, user_id) = (ChatId(-100), UserId(2));
+        assert_eq!(storms.check(chat_id, user_id, 0, 30), SlowMode::Off);
+        assert!(!storms.record_deletion(chat_id, 0, 3, 60, 600));
+        assert!(!storms.record_deletion(chat_id, 1, 3, 60, 600));
+        assert!(storms.record_deletion(chat_id, 2, 3, 60, 600));
+        assert!(!storms.record_deletion(chat_id, 3, 3, 60, 600));
+        assert_eq!(storms.check(chat_id, user_id, 10, 30), SlowMode::Allowed);
+        assert_eq!(storms.check(chat_id, user_id, 20, 30), SlowMode::TooSoon);
+        assert_eq!(storms.check(chat_id, user_id, 40, 30), SlowMode::Allowed);
+        assert_eq!(storms.check(chat_id, user_id, 602, 30), SlowMode::Ended);
+        assert_eq!(storms.check(chat_id, user_id, 603, 30), SlowMode::Off);
+    }
+
+    #[test]
+    fn alerts_about_raids() {
+        let storms = StormTracker::default();
+        let chat_id = ChatId(-100);
+        let deletion = |deleted_at, user: Option<&str>, text: &str| RaidDeletion {
+            deleted_at,
+            user: user.map(str::to_owned),
+            text: text.to_owned(),
+        };
+        assert!(storms
+            .record_raid(chat_id, deletion(0, Some("a"), "spam"), 3)
+            .is_none());
+        assert!(storms
+            .record_raid(chat_id, deletion(50, Some("b"), "spam"), 3)
+            .is_none());
+        assert!(storms
+            .record_raid(chat_id, deletion(70, Some("a"), "eggs"), 3)
+            .is_none());
+        let alert = storms
+            .record_raid(chat_id, deletion(80, None, "spam"), 3)
+            .unwrap();
+        assert_eq!(alert.len(), 3);
+        let texts = most_frequent(alert.iter().map(|deletion| deletion.text.as_str()));
+        assert_eq!(texts, "spam ×2\neggs ×1");
+        let users = most_frequent(alert.iter().filter_map(|deletion| deletion.user.as_deref()));
+        assert_eq!(users, "a ×1\nb ×1");
+
+        for deleted_at in 90..100 {
+            assert!(storms
+                .record_raid(chat_id, deletion(deleted_at, None, "spam"), 3)
+                .is_none());
+        }
+        assert!(storms
+            .record_raid(chat_id, deletion(80 + RAID_ALERT_INTERVAL, None, "spam"), 3)
+            .is_none());
+        assert!(storms
+            .record_raid(chat_id, deletion(81 + RAID_ALERT_INTERVAL, None, "spam"), 3)
+            .is_none());
+        assert!(storms
+            .record_raid(chat_id, deletion(82 + RAID_ALERT_INTERVAL, None, "spam"), 3)
+            .is_some());
+    }
+}
@@ -0,0 +1,154 @@
+//! `/sync_from`, copying what another chat knows into this one,
+//! for admins running several related groups.
+//!
+//! Hashes depend on the namespace, so entries can only be copied if their
+//! source chat kept their texts, which are hashed again for this chat.
+
+use color_eyre::eyre;
+use futures::TryStreamExt as _;
+use teloxide::types::{ChatId, Message, User, UserId};
+
+use crate::{
+    api::TelegramApi,
+    audit::AuditEvent,
+    i18n::Text,
+    robot::{describe_chat, describe_user, reply, Robot9000},
+    storage::{Entry, Status},
+};
+
+/// What `/sync_from` copied.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct SyncSummary {
+    pub(crate) copied: usize,
+    /// Entries whose texts weren't kept, so they couldn't be hashed for this chat.
+    pub(crate) skipped: usize,
+}
+
+impl Robot9000 {
+    /// Handles `/sync_from <chat_id> [all]`.
+    pub(crate) async fn sync_from(
+        &self,
+        bot: &dyn TelegramApi,
+        message: &Message,
+        user: &User,
+        args: &str,
+    ) -> eyre::Result<()> {
+        let locale = self.chat_locale(message.chat.id)?;
+        self.ensure_owner(bot, message, user, async {
+            let mut args = args.split_whitespace();
+            let source = args.next().and_then(|chat_id| chat_id.parse().ok());
+            let everything = match (args.next(), args.next()) {
+                (None, None) => Some(false),
+                (Some("all"), None) => Some(true),
+                _ => None,
+            };
+            let (Some(source), Some(everything)) = (source.map(ChatId), everything) else {
+                return reply(bot, message, locale.text(Text::SyncFromUsage)).await;
+            };
+            if message.chat.is_private()
+                || self.namespace(source) == self.namespace(message.chat.id)
+            {
+                return reply(bot, message, locale.text(Text::SyncFromUsage)).await;
+            }
+            let summary = self
+                .copy_entries(source, message.chat.id, everything, user.id)
+                .await?;
+            tracing::info!(
+                source = source.0,
+                everything,
+                copied = summary.copied,
+                skipped = summary.skipped,
+                "synced entries from another chat"
+            );
+            let event = format!(
+                "Synced {} entries from {source} to {}\nOwner: {}",
+                summary.copied,
+                describe_chat(&message.chat),
+                describe_user(user),
+            );
+            self.log_event(bot, event).await;
+            let answer = locale.text(Text::Synced {
+                copied: summary.copied,
+                skipped: summary.skipped,
+            });
+            reply(bot, message, answer).await
+        })
+        .await
+    }
+
+    /// Copies the allowed and forbidden messages of `source` into `target`,
+    /// along with every other known one if `everything` is set.
+    /// Messages `target` already knows keep their history.
+    pub(crate) async fn copy_entries(
+        &self,
+        source: ChatId,
+        target: ChatId,
+        everything: bool,
+        admin_id: UserId,
+    ) -> eyre::Result<SyncSummary> {
+        let texts = self.retained_texts(source)?;
+        let settings = self.settings(target)?;
+        let scope = self.topic_scope(target, None);
+        let mut summary = SyncSummary::default();
+        let mut entries = self.store.entries(self.namespace(source));
+        while let Some((key, entry)) = entries.try_next().await? {
+            if entry.status == Status::Seen && !everything {
+                continue;
+            }
+            let Some(text) = texts.get(&key).filter(|text| text.belongs_to(&entry)) else {
+                summary.skipped += 1;
+                continue;
+            };
+            let target_key = self.hash_message(scope, &text.text)?;
+            let window = settings
+                .dedup_window
+                .filter(|_| entry.status == Status::Seen);
+            let mut copied = false;
+            self.store
+                .update(target_key, window, &mut |existing| {
+                    copied = existing.is_none() || entry.status != Status::Seen;
+                    match existing {
+                        Some(_) if entry.status == Status::Seen => None,
+                        Some(existing) => Some(Entry {
+                            status: entry.status,
+                            repeats_left: entry.repeats_left,
+                            ..existing
+                        }),
+                        // Its first message is in another chat.
+                        None => Some(Entry {
+                            first_message_id: None,
+                            ..entry
+                        }),
+                    }
+                })
+                .await?;
+            if !copied {
+                continue;
+            }
+            let hash = target_key.hash;
+            self.audit(match entry.status {
+                Status::Allowed => AuditEvent::Allow {
+                    chat_id: target,
+                    hash,
+                    admin_id,
+                    repeats: entry.repeats_left,
+                },
+                Status::Forbidden => AuditEvent::Forbid {
+                    chat_id: target,
+                    hash,
+                    admin_id,
+                },
+                Status::Seen => AuditEvent::Store {
+                    chat_id: target,
+                    hash,
+                    posted_at: entry.first_seen,
+                    message_id: None,
+                    user_id: entry.poster_id,
+                    count: entry.count,
+                },
+            });
+            summary.copied += 1;
+        }
+        Ok(summary)
+    }
+}
@@ -0,0 +1,68 @@
+//! Readiness and watchdog notifications for running as a `Type=notify` systemd service.
+//!
+//! Speaks the `sd_notify` protocol directly, which is a datagram to `$NOTIFY_SOCKET`.
+//! Everything here does nothing when not run by systemd.
+
+use std::{
+    env,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    time::Duration,
+};
+
+use color_eyre::eyre;
+
+/// Sends a state like `READY=1` to systemd, if it's listening.
+pub fn sd_notify(state: &str) {
+    if let Err(err) = try_notify(state) {
+        tracing::warn!(err = format_args!("{err}"), "couldn't notify systemd");
+    }
+}
+
+fn try_notify(state: &str) -> eyre::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.as_encoded_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt as _;
+            SocketAddr::from_abstract_name(name)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => eyre::bail!("abstract sockets are only supported on Linux"),
+        None => SocketAddr::from_pathname(std::str::from_utf8(path)?)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Pings the systemd watchdog as often as it asks, for as long as the runtime
+/// keeps running tasks, so a wedged bot gets restarted.
+pub async fn watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tracing::debug!(
+        interval = format_args!("{interval:?}"),
+        "pinging systemd watchdog"
+    );
+    // Pinging twice per interval leaves room for a late wakeup.
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        sd_notify("WATCHDOG=1");
+    }
+}
+
+/// Watchdog interval set by systemd for this very process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // Set for another process when inherited.
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
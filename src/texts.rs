@@ -0,0 +1,198 @@
+//! Texts of known messages, kept in chats that opted in with `/set retain_texts true`.
+//!
+//! Texts are kept per chat, even in the shared namespace, so withdrawing
+//! consent forgets exactly what the chat posted.
+
+use std::collections::{hash_map, HashMap};
+
+use color_eyre::eyre;
+use teloxide::types::ChatId;
+
+use crate::{
+    policy::Settings,
+    robot::Robot9000,
+    storage::{remove_prefix, Entry, Key, Namespace, Status},
+};
+
+/// Text of a known message, along with when its entry was first seen,
+/// so the text of an expired entry isn't mistaken for the one of a newer copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetainedText {
+    pub(crate) first_seen: i64,
+    pub(crate) text: String,
+}
+
+impl RetainedText {
+    fn encode(&self) -> Vec<u8> {
+        [&self.first_seen.to_le_bytes()[..], self.text.as_bytes()].concat()
+    }
+
+    fn decode(value: &[u8]) -> eyre::Result<Self> {
+        let (first_seen, text) = value
+            .split_first_chunk::<8>()
+            .ok_or_else(|| eyre::eyre!("malformed retained text: {value:?}"))?;
+        Ok(Self {
+            first_seen: i64::from_le_bytes(*first_seen),
+            text: String::from_utf8(text.to_vec())?,
+        })
+    }
+
+    /// Whether this is the text of `entry`, and not of one it replaced.
+    pub(crate) fn belongs_to(&self, entry: &Entry) -> bool {
+        self.first_seen == entry.first_seen
+    }
+}
+
+fn text_key(chat_id: ChatId, key: Key) -> [u8; 32] {
+    let mut db_key = [0; 32];
+    db_key[..8].copy_from_slice(&chat_id.0.to_be_bytes());
+    db_key[8..].copy_from_slice(&key.encode());
+    db_key
+}
+
+impl Robot9000 {
+    /// Keeps the text of the first copy of a message, if the chat opted in.
+    pub(crate) fn retain_text(
+        &self,
+        chat_id: ChatId,
+        settings: &Settings,
+        key: Key,
+        entry: &Entry,
+        text: &str,
+    ) -> eyre::Result<()> {
+        if !settings.retain_texts || entry.status != Status::Seen || entry.count != 1 {
+            return Ok(());
+        }
+        let db_key = text_key(chat_id, key);
+        let value = RetainedText {
+            first_seen: entry.first_seen,
+            text: text.into(),
+        }
+        .encode();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(&db_key, &value)?,
+            None => value,
+        };
+        self.texts.insert(db_key, value)?;
+        Ok(())
+    }
+
+    fn decode_text(&self, db_key: &[u8], value: &[u8]) -> eyre::Result<RetainedText> {
+        match &self.cipher {
+            Some(cipher) => RetainedText::decode(&cipher.open(db_key, value)?),
+            None => RetainedText::decode(value),
+        }
+    }
+
+    /// Text of a known message as it was posted in a chat, if it was kept.
+    pub(crate) fn retained_text(
+        &self,
+        chat_id: ChatId,
+        key: Key,
+        entry: &Entry,
+    ) -> eyre::Result<Option<String>> {
+        let db_key = text_key(chat_id, key);
+        let Some(value) = self.texts.get(db_key)? else {
+            return Ok(None);
+        };
+        let retained = self.decode_text(&db_key, &value)?;
+        Ok(retained.belongs_to(entry).then_some(retained.text))
+    }
+
+    /// Every text kept in a chat, by the key of its message.
+    pub(crate) fn retained_texts(
+        &self,
+        chat_id: ChatId,
+    ) -> eyre::Result<HashMap<Key, RetainedText>> {
+        let mut texts = HashMap::new();
+        for item in self.texts.scan_prefix(chat_id.0.to_be_bytes()) {
+            let (db_key, value) = item?;
+            let key = Key::decode(&db_key[8..])?;
+            texts.insert(key, self.decode_text(&db_key, &value)?);
+        }
+        Ok(texts)
+    }
+
+    /// Known messages of a chat containing `query`, ignoring case,
+    /// with the most recently seen first.
+    pub(crate) async fn search_texts(
+        &self,
+        chat_id: ChatId,
+        query: &str,
+        limit: usize,
+    ) -> eyre::Result<Vec<(String, Entry)>> {
+        let query = query.to_lowercase();
+        let mut found = Vec::new();
+        for (key, retained) in self.retained_texts(chat_id)? {
+            if !retained.text.to_lowercase().contains(&query) {
+                continue;
+            }
+            match self.store.peek(key).await? {
+                Some(entry) if retained.belongs_to(&entry) => found.push((retained.text, entry)),
+                _ => {}
+            }
+        }
+        found.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.first_seen));
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    pub(crate) fn forget_text(&self, chat_id: ChatId, key: Key) -> eyre::Result<()> {
+        self.texts.remove(text_key(chat_id, key))?;
+        Ok(())
+    }
+
+    /// Forgets every text kept in a chat. Returns how many there were.
+    pub(crate) fn forget_texts(&self, chat_id: ChatId) -> eyre::Result<usize> {
+        remove_prefix(&self.texts, chat_id.0.to_be_bytes())
+    }
+
+    /// Forgets the texts of every message in a namespace.
+    pub(crate) fn forget_namespace_texts(&self, namespace: Namespace) -> eyre::Result<usize> {
+        match namespace {
+            Namespace::Chat(chat_id) => self.forget_texts(chat_id),
+            Namespace::Shared => {
+                let mut removed = 0;
+                for &chat_id in &self.config().shared_chats {
+                    let prefix = text_key(
+                        ChatId(chat_id),
+                        Key {
+                            namespace,
+                            hash: [0; 16],
+                        },
+                    );
+                    removed += remove_prefix(&self.texts, &prefix[..16])?;
+                }
+                Ok(removed)
+            }
+        }
+    }
+
+    /// Forgets the texts of chats that no longer keep them,
+    /// and of messages that expired or were forgotten otherwise.
+    pub(crate) async fn prune_texts(&self) -> eyre::Result<usize> {
+        let mut consent = HashMap::new();
+        let mut removed = 0;
+        for item in self.texts.iter() {
+            let (db_key, value) = item?;
+            let chat_id = ChatId(i64::from_be_bytes(db_key[..8].try_into()?));
+            let retain = match consent.entry(chat_id) {
+                hash_map::Entry::Occupied(retain) => *retain.get(),
+                hash_map::Entry::Vacant(retain) => {
+                    *retain.insert(self.settings(chat_id)?.retain_texts)
+                }
+            };
+            let keep = retain && {
+                let key = Key::decode(&db_key[8..])?;
+                let retained = self.decode_text(&db_key, &value)?;
+                let entry = self.store.peek(key).await?;
+                entry.is_some_and(|entry| retained.belongs_to(&entry))
+            };
+            if !keep {
+                self.texts.remove(db_key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,145 @@
+//! Keeps messages the bot sends within Telegram's limits, so they wait
+//! their turn instead of running into flood control.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use teloxide::types::ChatId;
+
+const SECOND: Duration = Duration::from_secs(1);
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Messages the bot may send a second, over every chat.
+const MESSAGES_PER_SECOND: usize = 30;
+
+/// Least time between two messages to the same chat.
+const CHAT_INTERVAL: Duration = SECOND;
+
+/// Messages the bot may send a minute to a group or channel.
+const GROUP_MESSAGES_PER_MINUTE: usize = 20;
+
+/// Messages sent lately, oldest first.
+#[derive(Debug, Default)]
+struct Sent {
+    /// Over the last second.
+    overall: VecDeque<Instant>,
+    /// Over the last minute, for each chat messages were sent to.
+    chats: HashMap<ChatId, VecDeque<Instant>>,
+}
+
+impl Sent {
+    /// When the next message to the chat can be sent, forgetting
+    /// messages too old to count anymore.
+    fn next_slot(&mut self, chat_id: ChatId, now: Instant) -> Instant {
+        forget_before(&mut self.overall, now, SECOND);
+        self.chats.retain(|_, sent| {
+            forget_before(sent, now, MINUTE);
+            !sent.is_empty()
+        });
+        let mut slot = now;
+        if let Some(nth) = nth_last(&self.overall, MESSAGES_PER_SECOND) {
+            slot = slot.max(nth + SECOND);
+        }
+        if let Some(sent) = self.chats.get(&chat_id) {
+            if let Some(&last) = sent.back() {
+                slot = slot.max(last + CHAT_INTERVAL);
+            }
+            match nth_last(sent, GROUP_MESSAGES_PER_MINUTE) {
+                Some(nth) if !chat_id.is_user() => slot = slot.max(nth + MINUTE),
+                _ => (),
+            }
+        }
+        slot
+    }
+
+    fn record(&mut self, chat_id: ChatId, now: Instant) {
+        self.overall.push_back(now);
+        self.chats.entry(chat_id).or_default().push_back(now);
+    }
+}
+
+fn forget_before(sent: &mut VecDeque<Instant>, now: Instant, period: Duration) {
+    while sent.front().is_some_and(|&at| at + period <= now) {
+        sent.pop_front();
+    }
+}
+
+/// When the `n`th latest message was sent, if there are that many.
+fn nth_last(sent: &VecDeque<Instant>, n: usize) -> Option<Instant> {
+    sent.len().checked_sub(n).map(|index| sent[index])
+}
+
+/// Per-chat and overall limits of one bot.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    sent: Mutex<Sent>,
+}
+
+impl Throttle {
+    /// Waits until a message can be sent to the chat, and counts it as sent.
+    pub(crate) async fn wait(&self, chat_id: ChatId) {
+        loop {
+            let slot = {
+                let mut sent = self.sent.lock().unwrap();
+                let now = Instant::now();
+                let slot = sent.next_slot(chat_id, now);
+                if slot <= now {
+                    sent.record(chat_id, now);
+                    return;
+                }
+                slot
+            };
+            tokio::time::sleep_until(slot.into()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: ChatId = ChatId(1);
+    const GROUP: ChatId = ChatId(-1001);
+
+    #[test]
+    fn spaces_messages_to_a_chat() {
+        let mut sent = Sent::default();
+        let start = Instant::now();
+        assert_eq!(sent.next_slot(USER, start), start);
+        sent.record(USER, start);
+        assert_eq!(sent.next_slot(USER, start), start + CHAT_INTERVAL);
+        assert_eq!(sent.next_slot(GROUP, start), start);
+    }
+
+    #[test]
+    fn limits_groups_per_minute() {
+        let mut sent = Sent::default();
+        let start = Instant::now();
+        for second in 0..GROUP_MESSAGES_PER_MINUTE as u32 {
+            let now = start + SECOND * second;
+            assert_eq!(sent.next_slot(GROUP, now), now);
+            sent.record(GROUP, now);
+            sent.record(USER, now);
+        }
+        let now = start + SECOND * GROUP_MESSAGES_PER_MINUTE as u32;
+        assert_eq!(sent.next_slot(GROUP, now), start + MINUTE);
+        assert_eq!(sent.next_slot(USER, now), now);
+    }
+
+    #[test]
+    fn limits_every_chat_together() {
+        let mut sent = Sent::default();
+        let start = Instant::now();
+        for chat in 0..MESSAGES_PER_SECOND as i64 {
+            sent.record(ChatId(chat), start);
+        }
+        assert_eq!(sent.next_slot(USER, start), start + SECOND);
+        let later = start + SECOND;
+        assert_eq!(sent.next_slot(GROUP, later), later);
+        assert_eq!(sent.overall.len(), 0);
+    }
+}
@@ -0,0 +1,99 @@
+//! Explaining why a message is or isn't a duplicate, for `/why`.
+
+use color_eyre::eyre;
+use teloxide::types::{ChatId, Message};
+
+use crate::{
+    i18n::Text,
+    normalize::message_text,
+    policy::Settings,
+    robot::{hex, Robot9000},
+    storage::Scope,
+};
+
+impl Robot9000 {
+    /// Goes through the checks a message went through again, one line for each
+    /// that mattered, ending with what happens to its next copy.
+    pub(crate) async fn explain(
+        &self,
+        message: &Message,
+        settings: &Settings,
+        now: i64,
+    ) -> eyre::Result<String> {
+        let scope = self.scope(message, settings);
+        self.explain_in(message.chat.id, scope, message, settings, now)
+            .await
+    }
+
+    /// Goes through the checks a message would go through if posted in `chat_id`
+    /// with `settings`, like [`Self::explain`].
+    pub(crate) async fn explain_in(
+        &self,
+        chat_id: ChatId,
+        scope: Scope,
+        message: &Message,
+        settings: &Settings,
+        now: i64,
+    ) -> eyre::Result<String> {
+        let locale = self.locale(settings.language);
+        let Some(text) = message_text(message) else {
+            return Ok(locale.text(Text::UnsupportedMessage));
+        };
+        let config = self.config();
+        let mut lines = Vec::new();
+        if let Some(user) = &message.from {
+            if self.is_exempt(chat_id, user.id)? {
+                lines.push(locale.text(Text::WhyExempt));
+            }
+            if let Some(limit) = config.flood_limit {
+                let window = config.flood_window.try_into().unwrap_or(i64::MAX);
+                let raw_text = message.text().unwrap_or_default();
+                let timestamp = message.date.timestamp();
+                let copies = self
+                    .floods
+                    .copies(chat_id, user.id, raw_text, timestamp, window);
+                if copies > limit as usize {
+                    lines.push(locale.text(Text::WhyBurst { copies, limit }));
+                }
+            }
+        }
+        if self.is_common_phrase(chat_id, &text)? {
+            lines.push(locale.text(Text::WhyCommonPhrase));
+        }
+
+        let mut steps = Vec::new();
+        if message.text() != Some(&*text) {
+            steps.push("links");
+        }
+        steps.extend(self.normalization(scope.namespace)?.changes(&text));
+        lines.push(locale.text(Text::WhyNormalized {
+            steps: &steps.join(", "),
+        }));
+        let exact = self.hash_message(scope, &text)?;
+        lines.push(locale.text(Text::WhyHash {
+            hash: &hex(&exact.hash),
+            namespace: &scope.namespace.to_string(),
+        }));
+
+        let mut key = exact;
+        if self.store.peek(exact).await?.is_some() {
+            lines.push(locale.text(Text::WhyMatchedExactly));
+        } else if let Some(signature) = self.signature(scope, &text)? {
+            if let Some(similar) = self.find_similar(scope.namespace, &signature).await? {
+                let similarity = self.similarity(similar, &signature)?.unwrap_or_default();
+                lines.push(locale.text(Text::WhyMatchedSimilar {
+                    similarity: (similarity * 100.0).round() as u32,
+                }));
+                key = similar;
+            }
+        }
+        let entry = self.store.peek(key).await?;
+        if message.chat.id == chat_id
+            && entry.is_some_and(|entry| entry.first_message_id == Some(message.id))
+        {
+            lines.push(locale.text(Text::WhyOriginal));
+        }
+        lines.push(self.check_hash(settings, key, now).await?);
+        Ok(lines.join("\n"))
+    }
+}